@@ -0,0 +1,352 @@
+//! # comet-derive
+//!
+//! Derive macros for `Trace`, `Finalize` and `Collectable` traits from Comet. These macros are re-exported
+//! by `comet::api` so usually you do not need to depend on this crate directly.
+//!
+//! ```rust,ignore
+//! use comet::api::{Collectable, Finalize, Gc, Trace};
+//! use comet::gc_base::GcBase;
+//!
+//! #[derive(Trace, Finalize, Collectable)]
+//! pub enum Node<H: GcBase> {
+//!     None,
+//!     Some { value: i64, next: Gc<Node<H>, H> },
+//! }
+//! ```
+//!
+//! # Field attributes
+//!
+//! - `#[unsafe_ignore_trace]`: field is not traced. This is *unsafe* because if field contains GC pointers
+//!   they will not be marked and might be collected while still reachable. Use it only for fields
+//!   that do not implement `Trace` and never hold GC pointers (e.g `std::net::TcpStream`).
+//! - `#[custom_finalize]`: instead of dropping this field when object is finalized its own `Finalize::finalize` is invoked.
+//!   Other fields are dropped in place as usual. Note that when any field has this attribute `Drop` impl of the type itself is not invoked.
+//!
+//! # Generics
+//!
+//! Type parameters that are used by traced fields get `Trace` bound added automatically. Type parameters
+//! that are bound by `GcBase` (i.e heap type parameter) are left as is.
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2, TokenTree};
+use quote::{format_ident, quote, ToTokens};
+use syn::{
+    parse_macro_input, parse_quote, Data, DeriveInput, Field, Fields, Generics, Ident, Type,
+    TypeParamBound, WherePredicate,
+};
+
+const IGNORE_TRACE: &str = "unsafe_ignore_trace";
+const CUSTOM_FINALIZE: &str = "custom_finalize";
+
+/// Derives `unsafe impl Trace` that traces every field of a struct or every field of each enum variant.
+#[proc_macro_derive(Trace, attributes(unsafe_ignore_trace, custom_finalize))]
+pub fn derive_trace(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_trace(&input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+/// Derives `unsafe impl Finalize`. By default finalization drops value in place, see `#[custom_finalize]` attribute
+/// to change finalization of specific fields.
+#[proc_macro_derive(Finalize, attributes(unsafe_ignore_trace, custom_finalize))]
+pub fn derive_finalize(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_finalize(&input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+/// Derives `impl Collectable` with default allocation size. Type must implement `Trace` and `Finalize`.
+#[proc_macro_derive(Collectable, attributes(unsafe_ignore_trace, custom_finalize))]
+pub fn derive_collectable(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_collectable(&input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+fn has_attr(field: &Field, name: &str) -> bool {
+    field.attrs.iter().any(|attr| attr.path.is_ident(name))
+}
+
+/// Returns `(path, fields)` for each struct or enum variant.
+fn variants(input: &DeriveInput) -> syn::Result<Vec<(TokenStream2, &Fields)>> {
+    let name = &input.ident;
+    match &input.data {
+        Data::Struct(data) => Ok(vec![(quote!(#name), &data.fields)]),
+        Data::Enum(data) => Ok(data
+            .variants
+            .iter()
+            .map(|variant| {
+                let ident = &variant.ident;
+                (quote!(#name::#ident), &variant.fields)
+            })
+            .collect()),
+        Data::Union(data) => Err(syn::Error::new_spanned(
+            data.union_token,
+            "unions can't be traced automatically, implement `Trace` manually",
+        )),
+    }
+}
+
+/// Builds match pattern for `fields` that binds by `ref mut` every field for which `select` returns true.
+fn pattern<'a>(
+    path: &TokenStream2,
+    fields: &'a Fields,
+    select: impl Fn(&Field) -> bool,
+) -> (TokenStream2, Vec<(Ident, &'a Field)>) {
+    let mut bindings = vec![];
+    let pattern = match fields {
+        Fields::Named(named) => {
+            let mut entries = vec![];
+            for (i, field) in named.named.iter().enumerate() {
+                if select(field) {
+                    let binding = format_ident!("__binding_{}", i);
+                    let name = field.ident.as_ref().unwrap();
+                    entries.push(quote!(#name: ref mut #binding));
+                    bindings.push((binding, field));
+                }
+            }
+            quote!(#path { #(#entries,)* .. })
+        }
+        Fields::Unnamed(unnamed) => {
+            let mut entries = vec![];
+            for (i, field) in unnamed.unnamed.iter().enumerate() {
+                if select(field) {
+                    let binding = format_ident!("__binding_{}", i);
+                    entries.push(quote!(ref mut #binding));
+                    bindings.push((binding, field));
+                } else {
+                    entries.push(quote!(_));
+                }
+            }
+            quote!(#path ( #(#entries),* ))
+        }
+        Fields::Unit => quote!(#path),
+    };
+    (pattern, bindings)
+}
+
+/// Checks if type parameter `param` is bound by `GcBase` either inline or in where clause.
+fn is_heap_param(generics: &Generics, param: &Ident) -> bool {
+    let is_gc_base = |bound: &TypeParamBound| match bound {
+        TypeParamBound::Trait(bound) => bound
+            .path
+            .segments
+            .last()
+            .map(|segment| segment.ident == "GcBase")
+            .unwrap_or(false),
+        _ => false,
+    };
+    let inline = generics
+        .type_params()
+        .filter(|ty| ty.ident == *param)
+        .any(|ty| ty.bounds.iter().any(is_gc_base));
+    let in_where = generics
+        .where_clause
+        .iter()
+        .flat_map(|clause| clause.predicates.iter())
+        .any(|predicate| match predicate {
+            WherePredicate::Type(predicate) => {
+                let bounded = match &predicate.bounded_ty {
+                    Type::Path(path) => path.qself.is_none() && path.path.is_ident(param),
+                    _ => false,
+                };
+                bounded && predicate.bounds.iter().any(is_gc_base)
+            }
+            _ => false,
+        });
+    inline || in_where
+}
+
+fn mentions(tokens: TokenStream2, ident: &Ident) -> bool {
+    tokens.into_iter().any(|token| match token {
+        TokenTree::Ident(x) => x == *ident,
+        TokenTree::Group(group) => mentions(group.stream(), ident),
+        _ => false,
+    })
+}
+
+fn expand_trace(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let variants = variants(input)?;
+    let mut generics = input.generics.clone();
+    let traced = |field: &Field| !has_attr(field, IGNORE_TRACE);
+
+    let params = input
+        .generics
+        .type_params()
+        .map(|param| param.ident.clone())
+        .filter(|param| !is_heap_param(&input.generics, param))
+        .filter(|param| {
+            variants.iter().any(|(_, fields)| {
+                fields
+                    .iter()
+                    .filter(|field| traced(field))
+                    .any(|field| mentions(field.ty.to_token_stream(), param))
+            })
+        })
+        .collect::<Vec<_>>();
+    {
+        let clause = generics.make_where_clause();
+        for param in params.iter() {
+            clause
+                .predicates
+                .push(parse_quote!(#param: ::comet::api::Trace));
+        }
+    }
+
+    let arms = variants.iter().map(|(path, fields)| {
+        let (pattern, bindings) = pattern(path, fields, traced);
+        let bindings = bindings.iter().map(|(binding, _)| binding);
+        quote! {
+            #pattern => {
+                #(::comet::api::Trace::trace(#bindings, vis);)*
+            }
+        }
+    });
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    Ok(quote! {
+        unsafe impl #impl_generics ::comet::api::Trace for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn trace(&mut self, vis: &mut dyn ::comet::api::Visitor) {
+                match *self {
+                    #(#arms)*
+                }
+            }
+        }
+    })
+}
+
+fn expand_finalize(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let variants = variants(input)?;
+    let mut generics = input.generics.clone();
+    let custom = variants
+        .iter()
+        .flat_map(|(_, fields)| fields.iter())
+        .filter(|field| has_attr(field, CUSTOM_FINALIZE))
+        .collect::<Vec<_>>();
+    if custom.is_empty() {
+        let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+        return Ok(quote! {
+            unsafe impl #impl_generics ::comet::api::Finalize for #name #ty_generics #where_clause {}
+        });
+    }
+    {
+        let clause = generics.make_where_clause();
+        for field in custom.iter() {
+            let ty = &field.ty;
+            clause
+                .predicates
+                .push(parse_quote!(#ty: ::comet::api::Finalize));
+        }
+    }
+    let arms = variants.iter().map(|(path, fields)| {
+        let (pattern, bindings) = pattern(path, fields, |_| true);
+        let finalizers = bindings.iter().map(|(binding, field)| {
+            if has_attr(field, CUSTOM_FINALIZE) {
+                quote!(::comet::api::Finalize::finalize(#binding);)
+            } else {
+                quote!(::core::ptr::drop_in_place(#binding);)
+            }
+        });
+        quote! {
+            #pattern => {
+                #(#finalizers)*
+            }
+        }
+    });
+    let custom_types = custom.iter().map(|field| &field.ty);
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    Ok(quote! {
+        unsafe impl #impl_generics ::comet::api::Finalize for #name #ty_generics #where_clause {
+            unsafe fn finalize(&mut self) {
+                match *self {
+                    #(#arms)*
+                }
+            }
+            fn needs_finalization() -> bool {
+                ::core::mem::needs_drop::<Self>()
+                    #(|| <#custom_types as ::comet::api::Finalize>::needs_finalization())*
+            }
+        }
+    })
+}
+
+fn expand_collectable(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    variants(input)?;
+    let mut generics = input.generics.clone();
+    let static_lifetime = syn::Lifetime::new("'static", Span::call_site());
+    for param in generics.type_params_mut() {
+        param
+            .bounds
+            .push(TypeParamBound::Lifetime(static_lifetime.clone()));
+    }
+    let (_, ty_generics, _) = input.generics.split_for_impl();
+    generics
+        .make_where_clause()
+        .predicates
+        .push(parse_quote!(#name #ty_generics: ::comet::api::Trace + ::comet::api::Finalize));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::comet::api::Collectable for #name #ty_generics #where_clause {}
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trace_bounds_only_traced_parameters() {
+        let input: DeriveInput = parse_quote! {
+            struct Node<T, U, H: GcBase> {
+                value: T,
+                #[unsafe_ignore_trace]
+                untraced: U,
+                next: Option<Gc<Leaf, H>>,
+            }
+        };
+        let expanded = expand_trace(&input).unwrap().to_string();
+        assert!(expanded.contains(&quote!(T: ::comet::api::Trace).to_string()));
+        assert!(!expanded.contains(&quote!(U: ::comet::api::Trace).to_string()));
+        assert!(!expanded.contains(&quote!(H: ::comet::api::Trace).to_string()));
+    }
+
+    #[test]
+    fn finalize_impl_depends_on_custom_fields() {
+        let input: DeriveInput = parse_quote! {
+            enum Value {
+                Int(i64),
+                Custom { #[custom_finalize] handle: Handle },
+            }
+        };
+        let expanded = expand_finalize(&input).unwrap().to_string();
+        assert!(expanded.contains("needs_finalization"));
+        assert!(expanded.contains(&quote!(Handle: ::comet::api::Finalize).to_string()));
+
+        let input: DeriveInput = parse_quote! {
+            struct Plain(i64);
+        };
+        let expanded = expand_finalize(&input).unwrap();
+        let expected = quote!(
+            unsafe impl ::comet::api::Finalize for Plain {}
+        );
+        assert_eq!(expanded.to_string(), expected.to_string());
+    }
+
+    #[test]
+    fn unions_are_rejected() {
+        let input: DeriveInput = parse_quote! {
+            union Bits {
+                int: u64,
+                float: f64,
+            }
+        };
+        assert!(expand_trace(&input).is_err());
+        assert!(expand_collectable(&input).is_err());
+    }
+}
@@ -0,0 +1,158 @@
+//! Tests for `Trace`, `Finalize` and `Collectable` derive macros.
+use comet::{
+    api::{Collectable, Finalize, Gc, HeapObjectHeader, Trace, Visitor},
+    gc_base::GcBase,
+    immix::Immix,
+};
+use std::{cell::Cell, ptr::NonNull};
+
+thread_local! {
+    static TRACED: Cell<usize> = const { Cell::new(0) };
+    static FINALIZED: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Leaf that counts how many times it was traced.
+struct Counted;
+
+unsafe impl Trace for Counted {
+    fn trace(&mut self, _vis: &mut dyn Visitor) {
+        TRACED.with(|traced| traced.set(traced.get() + 1));
+    }
+}
+
+/// Leaf without drop glue that needs finalization anyway.
+struct Flagged;
+
+unsafe impl Finalize for Flagged {
+    unsafe fn finalize(&mut self) {
+        FINALIZED.with(|finalized| finalized.set(finalized.get() + 1));
+    }
+
+    fn needs_finalization() -> bool {
+        true
+    }
+}
+
+struct NoVisitor;
+
+impl Visitor for NoVisitor {
+    fn mark_object(&mut self, _root: &mut NonNull<HeapObjectHeader>) {}
+}
+
+fn traced(value: &mut impl Trace) -> usize {
+    TRACED.with(|traced| traced.set(0));
+    value.trace(&mut NoVisitor);
+    TRACED.with(|traced| traced.get())
+}
+
+#[derive(Trace, Finalize, Collectable)]
+struct Named {
+    first: Counted,
+    second: Counted,
+    #[unsafe_ignore_trace]
+    _ignored: Counted,
+}
+
+#[derive(Trace, Finalize, Collectable)]
+struct Tuple(Counted, #[unsafe_ignore_trace] Counted, Counted);
+
+#[derive(Trace, Finalize, Collectable)]
+enum Node {
+    Empty,
+    Leaf(Counted),
+    Branch {
+        left: Counted,
+        right: Counted,
+        #[unsafe_ignore_trace]
+        _ignored: Counted,
+    },
+}
+
+#[derive(Trace, Finalize, Collectable)]
+struct Generic<T> {
+    value: T,
+    #[unsafe_ignore_trace]
+    _untraced: Vec<u8>,
+}
+
+#[derive(Trace, Finalize, Collectable)]
+struct List<H: GcBase> {
+    value: Counted,
+    next: Option<Gc<List<H>, H>>,
+}
+
+#[derive(Trace, Finalize, Collectable)]
+struct Plain {
+    value: u32,
+}
+
+#[derive(Trace, Finalize, Collectable)]
+struct Owning {
+    value: String,
+}
+
+#[derive(Trace, Finalize, Collectable)]
+struct Custom {
+    #[custom_finalize]
+    #[unsafe_ignore_trace]
+    flagged: Flagged,
+    value: u32,
+}
+
+fn assert_collectable<T: Collectable>() {}
+
+#[test]
+fn structs_trace_every_field_but_ignored() {
+    let mut named = Named {
+        first: Counted,
+        second: Counted,
+        _ignored: Counted,
+    };
+    assert_eq!(traced(&mut named), 2);
+    assert_eq!(traced(&mut Tuple(Counted, Counted, Counted)), 2);
+}
+
+#[test]
+fn enums_trace_fields_of_active_variant() {
+    assert_eq!(traced(&mut Node::Empty), 0);
+    assert_eq!(traced(&mut Node::Leaf(Counted)), 1);
+    let mut branch = Node::Branch {
+        left: Counted,
+        right: Counted,
+        _ignored: Counted,
+    };
+    assert_eq!(traced(&mut branch), 2);
+}
+
+#[test]
+fn generic_parameters_are_traced() {
+    let mut generic = Generic {
+        value: Counted,
+        _untraced: vec![],
+    };
+    assert_eq!(traced(&mut generic), 1);
+    let mut list = List::<Immix> {
+        value: Counted,
+        next: None,
+    };
+    assert_eq!(traced(&mut list), 1);
+    assert_collectable::<Generic<Counted>>();
+    assert_collectable::<List<Immix>>();
+}
+
+#[test]
+fn needs_finalization_follows_fields() {
+    assert!(!Plain::needs_finalization());
+    assert!(Owning::needs_finalization());
+    assert!(Custom::needs_finalization());
+    let mut custom = Custom {
+        flagged: Flagged,
+        value: 0,
+    };
+    FINALIZED.with(|finalized| finalized.set(0));
+    unsafe {
+        custom.finalize();
+    }
+    assert_eq!(FINALIZED.with(|finalized| finalized.get()), 1);
+    assert_eq!(custom.value, 0);
+}
@@ -12,6 +12,7 @@ use std::{
 };
 const THRESHOLD: f64 = 0.75;
 
+#[derive(Trace, Finalize, Collectable)]
 struct Entry<Key: Trace + 'static, Value: Trace + 'static, H: GcBase> {
     key: Key,
     value: Value,
@@ -19,18 +20,6 @@ struct Entry<Key: Trace + 'static, Value: Trace + 'static, H: GcBase> {
     next: Option<Gc<Self, H>>,
 }
 
-unsafe impl<Key: Trace, Value: Trace, H: GcBase> Trace for Entry<Key, Value, H> {
-    fn trace(&mut self, vis: &mut dyn comet::api::Visitor) {
-        self.key.trace(vis);
-        self.value.trace(vis);
-        self.next.trace(vis);
-    }
-}
-
-unsafe impl<Key: Trace, Value: Trace, H: GcBase> Finalize for Entry<Key, Value, H> {}
-
-impl<Key: Trace + 'static, Value: Trace + 'static, H: GcBase> Collectable for Entry<Key, Value, H> {}
-
 pub struct HashMap<Key: Trace + 'static, Value: Trace + 'static, H: GcBase, S = RandomState> {
     hash_builder: S,
     len: usize,
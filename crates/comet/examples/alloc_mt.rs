@@ -5,25 +5,12 @@ use comet::{
     letroot,
 };
 
+#[derive(Trace, Finalize, Collectable)]
 pub enum Node<H: GcBase> {
     None,
     Some { value: i64, next: Gc<Node<H>, H> },
 }
 
-unsafe impl<H: GcBase> Trace for Node<H> {
-    fn trace(&mut self, vis: &mut dyn comet::api::Visitor) {
-        match self {
-            Self::Some { next, .. } => {
-                next.trace(vis);
-            }
-            _ => (),
-        }
-    }
-}
-
-unsafe impl<H: GcBase> Finalize for Node<H> {}
-impl<H: GcBase> Collectable for Node<H> {}
-
 fn main() {
     let mutator = instantiate_immix(
         ImmixOptions::default()
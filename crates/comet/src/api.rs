@@ -6,19 +6,28 @@ use std::{
     mem::{size_of, MaybeUninit},
     ops::{Deref, DerefMut, Range},
     ptr::{null_mut, DynMetadata, NonNull},
-    sync::atomic::AtomicU16,
+    sync::atomic::{AtomicU16, AtomicU64},
 };
 
 use crate::{
-    gc_base::{GcBase, ReadBarrier},
+    epoch::GcEpoch,
+    gc_base::{AllocError, GcBase, ReadBarrier},
     large_space::PreciseAllocation,
     mutator::MutatorRef,
+    poison::POISON_WORD,
     small_type_id,
     utils::*,
 };
+
+#[cfg(feature = "poison-check")]
+use crate::poison::report_use_after_free;
 use atomic::Ordering;
 use mopa::mopafy;
 
+/// Derive macros for [Trace], [Finalize] and [Collectable]. See `comet-derive` crate for supported attributes.
+#[cfg(feature = "derive")]
+pub use comet_derive::{Collectable, Finalize, Trace};
+
 /// Indicates that a type can be traced by a garbage collector.
 ///
 /// This doesn't necessarily mean that the type is safe to allocate in a garbage collector ([Collectable]).
@@ -79,17 +88,55 @@ mopafy!(Collectable);
 ///
 /// # Notes
 ///
-/// - Finalizers are not guaranteed to run in the GC cycle, they might run in background threads
+/// - Finalizers are not guaranteed to run in the GC cycle, they might run in background threads. Only types that
+///   opt in with [Finalize::finalize_on_thread] are finalized on [finalizer thread](crate::finalizer_thread), memory of
+///   such objects is kept until finalizer has run
 /// - Finalizers are not guaranteed to run at all if some collector does not support them
 /// - Finalizers make GC performance worser because the more finalizable objects you have in GC heap
 ///   the more checks in GC cycle might be performed
-/// - Finalizers that revive objects are UB
+/// - Finalizers that revive objects are UB, use [FinalizationRegistry](crate::finalization_registry::FinalizationRegistry) if cleanup needs access to GC objects
 /// - There is no strict ordering for execution of finalizers
 pub unsafe trait Finalize {
     /// Finalization method, invoked when object is dead.
     unsafe fn finalize(&mut self) {
         std::ptr::drop_in_place(self)
     }
+    /// Returns `false` if [Finalize::finalize] does nothing for this type. Collectors record it at allocation time
+    /// and skip dead objects that do not need finalization. By default true only for types with drop glue, types that
+    /// override [Finalize::finalize] without having drop glue must override this method too.
+    fn needs_finalization() -> bool
+    where
+        Self: Sized,
+    {
+        std::mem::needs_drop::<Self>()
+    }
+    /// Returns `Some` if finalizer of this type may run on [finalizer thread](crate::finalizer_thread) when it is
+    /// enabled. Objects of other types are still finalized in GC pause. Implement [FinalizeSend] and return
+    /// `Some(FinalizeOnThread::new())` to opt in.
+    fn finalize_on_thread() -> Option<FinalizeOnThread<Self>>
+    where
+        Self: Sized,
+    {
+        None
+    }
+}
+
+/// Indicates a type whose finalizer may run on any thread, see [Finalize::finalize_on_thread].
+pub trait FinalizeSend: Finalize + Send {}
+
+/// Proof that `T` implements [FinalizeSend]. Returned by [Finalize::finalize_on_thread].
+pub struct FinalizeOnThread<T: ?Sized>(PhantomData<fn() -> *const T>);
+
+impl<T: FinalizeSend> FinalizeOnThread<T> {
+    pub fn new() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<T: FinalizeSend> Default for FinalizeOnThread<T> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 pub const GC_WHITE: u8 = 0;
@@ -132,8 +179,17 @@ impl HeapObjectHeader {
     }
     #[inline(always)]
     pub fn get_dyn(&mut self) -> &mut dyn Collectable {
+        #[cfg(feature = "poison-check")]
+        if self.is_poisoned() {
+            report_use_after_free(self);
+        }
         unsafe { &mut *std::ptr::from_raw_parts_mut(self.data() as *mut (), self.value.metadata) }
     }
+    /// Returns true if object was freed by GC and its memory is poisoned, see [poison](crate::poison).
+    #[inline(always)]
+    pub fn is_poisoned(&self) -> bool {
+        unsafe { self.value.raw == POISON_WORD }
+    }
     #[inline(always)]
     pub fn set_forwarded(&mut self, fwdptr: usize) {
         self.value.raw = fwdptr as _;
@@ -200,22 +256,78 @@ impl HeapObjectHeader {
         self.type_id
     }
 
-    #[inline]
-    pub fn set_color(&self, current: u8, new: u8) -> bool {
-        unsafe {
-            let atomic = &*(&self.padding as *const u16 as *const AtomicU16);
-            let word = atomic.load(atomic::Ordering::Relaxed);
+    #[inline(always)]
+    fn padding_atomic(&self) -> &AtomicU16 {
+        unsafe { &*(&self.padding as *const u16 as *const AtomicU16) }
+    }
+
+    #[inline(always)]
+    fn cas_color(&self, current: u8, new: u8, set: u16) -> bool {
+        let atomic = self.padding_atomic();
+        let mut word = atomic.load(atomic::Ordering::Relaxed);
+        loop {
+            if ColourBit::decode(word as _) != current as u64 {
+                return true;
+            }
+            // retry when other bits of padding were changed concurrently.
             match atomic.compare_exchange_weak(
-                ColourBit::update(word as _, current as _) as _,
-                ColourBit::update(word as _, new as _) as _,
+                word,
+                ColourBit::update(word as _, new as _) as u16 | set,
                 atomic::Ordering::AcqRel,
                 atomic::Ordering::Relaxed,
             ) {
-                Ok(_) => false,
-                Err(_) => true,
+                Ok(_) => return false,
+                Err(x) => word = x,
             }
         }
     }
+
+    /// Atomically change object color from `current` to `new`. Returns `true` if object color is not `current`.
+    #[inline]
+    pub fn set_color(&self, current: u8, new: u8) -> bool {
+        self.cas_color(current, new, 0)
+    }
+
+    /// Same as [HeapObjectHeader::set_color] but object is also marked as being forwarded. Thread that changed
+    /// color must either invoke [HeapObjectHeader::set_forwarded_sync] or [HeapObjectHeader::cancel_forwarding].
+    #[inline]
+    pub fn set_color_forwarding(&self, current: u8, new: u8) -> bool {
+        self.cas_color(current, new, BeingForwarded::encode(1) as _)
+    }
+
+    /// Publish forwarding pointer of object that was marked by [HeapObjectHeader::set_color_forwarding]. Color of
+    /// object is preserved.
+    #[inline]
+    pub fn set_forwarded_sync(&self, fwdptr: usize) {
+        unsafe {
+            let raw = &*(&self.value as *const VTable as *const AtomicU64);
+            raw.store(fwdptr as _, atomic::Ordering::Relaxed);
+        }
+        let atomic = self.padding_atomic();
+        let word = atomic.load(atomic::Ordering::Relaxed);
+        let word = ForwardedBit::update(BeingForwarded::update(word as _, 0), 1);
+        atomic.store(word as _, atomic::Ordering::Release);
+    }
+
+    /// Object that was marked by [HeapObjectHeader::set_color_forwarding] is not going to be moved.
+    #[inline]
+    pub fn cancel_forwarding(&self) {
+        self.padding_atomic()
+            .fetch_and(!(BeingForwarded::MASK as u16), atomic::Ordering::Release);
+    }
+
+    /// Wait until other thread finishes forwarding object. Returns `true` if object was forwarded.
+    pub fn wait_for_forwarding(&self) -> bool {
+        let atomic = self.padding_atomic();
+        let backoff = crossbeam::utils::Backoff::new();
+        loop {
+            let word = atomic.load(atomic::Ordering::Acquire);
+            if BeingForwarded::decode(word as _) == 0 {
+                return ForwardedBit::decode(word as _) != 0;
+            }
+            backoff.snooze();
+        }
+    }
     #[inline]
     pub fn force_set_color(&mut self, color: u8) {
         self.padding = ColourBit::update(self.padding as _, color as _) as _;
@@ -237,6 +349,26 @@ impl HeapObjectHeader {
         self.padding = ParentKnown::update(self.padding as _, bit as u64) as _;
     }
 
+    #[inline(always)]
+    pub fn finalizable_bit(&self) -> bool {
+        FinalizableBit::decode(self.padding as _) != 0
+    }
+
+    #[inline(always)]
+    pub fn set_finalizable_bit(&mut self, bit: bool) {
+        self.padding = FinalizableBit::update(self.padding as _, bit as _) as _;
+    }
+
+    #[inline(always)]
+    pub fn finalize_on_thread_bit(&self) -> bool {
+        FinalizeOnThreadBit::decode(self.padding as _) != 0
+    }
+
+    #[inline(always)]
+    pub fn set_finalize_on_thread_bit(&mut self, bit: bool) {
+        self.padding = FinalizeOnThreadBit::update(self.padding as _, bit as _) as _;
+    }
+
     #[inline(always)]
     pub fn pinned_bit(&self) -> bool {
         Pinned::decode(self.padding as _) != 0
@@ -268,22 +400,39 @@ pub(crate) fn vtable_of<T: Collectable>() -> DynMetadata<dyn Collectable> {
 pub struct Gc<T: Collectable + ?Sized, H: GcBase> {
     pub(crate) base: NonNull<HeapObjectHeader>,
     pub(crate) marker: PhantomData<(NonNull<T>, NonNull<H>)>,
+    /// GC epoch of this pointer, see [epoch](crate::epoch).
+    pub(crate) epoch: GcEpoch,
 }
 impl<T: Collectable + Sized, H: GcBase> Gc<MaybeUninit<T>, H> {
     pub unsafe fn assume_init(self) -> Gc<T, H> {
         Gc {
             base: self.base,
             marker: Default::default(),
+            epoch: self.epoch,
         }
     }
 }
 impl<T: Collectable + ?Sized, H: GcBase> Gc<T, H> {
+    /// Create GC pointer from object header. Created pointer is never reported as stale, see [epoch](crate::epoch).
+    ///
+    /// # Safety
+    ///
+    /// `base` must point to object of type `T` allocated in heap `H`.
+    #[inline(always)]
+    pub unsafe fn from_raw(base: *mut HeapObjectHeader) -> Self {
+        Gc {
+            base: NonNull::new_unchecked(base),
+            marker: PhantomData,
+            epoch: GcEpoch::unchecked(),
+        }
+    }
+
     pub fn get_dyn(&self) -> &dyn Collectable {
-        unsafe { (*self.base.as_ptr()).get_dyn() }
+        unsafe { (*H::ReadBarrier::read_barrier(*self).base.as_ptr()).get_dyn() }
     }
 
     pub fn get_dyn_mut(&mut self) -> &mut dyn Collectable {
-        unsafe { (*self.base.as_ptr()).get_dyn() }
+        unsafe { (*H::ReadBarrier::resolve_for_write(*self).base.as_ptr()).get_dyn() }
     }
     /// Coerce this GC pointer to dyn Collectable.
     #[inline]
@@ -291,6 +440,7 @@ impl<T: Collectable + ?Sized, H: GcBase> Gc<T, H> {
         Gc {
             base: self.base,
             marker: PhantomData,
+            epoch: self.epoch,
         }
     }
     /// Check if this GC pointer is of type `U`
@@ -311,6 +461,7 @@ impl<T: Collectable + ?Sized, H: GcBase> Gc<T, H> {
             Some(Gc {
                 base: self.base,
                 marker: PhantomData,
+                epoch: self.epoch,
             })
         } else {
             None
@@ -370,6 +521,7 @@ impl Collectable for () {}
 unsafe impl<T: Collectable + ?Sized, H: GcBase> Trace for Gc<T, H> {
     fn trace(&mut self, vis: &mut dyn Visitor) {
         vis.mark_object(&mut self.base);
+        self.epoch.refresh();
     }
 }
 
@@ -380,6 +532,14 @@ pub trait Visitor {
     fn mark_weak(&mut self, root: &mut NonNull<HeapObjectHeader>) {
         self.mark_object(root);
     }
+    /// Callback to invoke when marking ephemerons. Collectors that support ephemerons mark value only once key is found
+    /// to be reachable and clear ephemeron if key is dead after marking. Default implementation keeps both key and value alive.
+    fn mark_ephemeron(&mut self, ephemeron: &mut EphemeronEntry) {
+        if let Some(key) = ephemeron.key.as_mut() {
+            self.mark_object(key);
+        }
+        ephemeron.mark_value(self);
+    }
 }
 
 impl<T: Collectable + ?Sized, H: GcBase> std::fmt::Pointer for Gc<T, H> {
@@ -410,9 +570,14 @@ impl<T: Collectable> Collectable for Option<T> {}
 impl<T: Collectable, H: GcBase> Deref for Gc<T, H> {
     type Target = T;
     fn deref(&self) -> &Self::Target {
+        self.epoch.check(self.base);
         unsafe {
             let this: Gc<T, H> = H::ReadBarrier::read_barrier::<T>(*self);
             let base = this.base.as_ptr();
+            #[cfg(feature = "poison-check")]
+            if (*base).is_poisoned() {
+                report_use_after_free(base);
+            }
             &*(*base).data().cast::<T>()
         }
     }
@@ -420,9 +585,14 @@ impl<T: Collectable, H: GcBase> Deref for Gc<T, H> {
 
 impl<T: Collectable, H: GcBase> DerefMut for Gc<T, H> {
     fn deref_mut(&mut self) -> &mut Self::Target {
+        self.epoch.check(self.base);
         unsafe {
-            let this: Gc<T, H> = H::ReadBarrier::read_barrier::<T>(*self);
+            let this: Gc<T, H> = H::ReadBarrier::resolve_for_write::<T>(*self);
             let base = this.base.as_ptr();
+            #[cfg(feature = "poison-check")]
+            if (*base).is_poisoned() {
+                report_use_after_free(base);
+            }
             &mut *((*base).data().cast::<T>() as *mut T)
         }
     }
@@ -525,6 +695,7 @@ impl<H: GcBase> Collectable for WeakInner<H> {}
 unsafe impl<T: Collectable + ?Sized, H: GcBase> Trace for Weak<T, H> {
     fn trace(&mut self, vis: &mut dyn Visitor) {
         vis.mark_weak(&mut self.value.base);
+        self.value.epoch.refresh();
     }
 }
 
@@ -537,18 +708,31 @@ impl<T: Collectable + ?Sized, H: GcBase> Weak<T, H> {
     }
     /// Creates a new weak reference that refers to the given object.
     pub unsafe fn create(mutator: &mut MutatorRef<H>, value: Gc<T, H>) -> Self {
+        match Self::try_create(mutator, value) {
+            Ok(weak_ref) => weak_ref,
+            Err(_) => crate::mutator::oom_abort(),
+        }
+    }
+    /// Same as [Weak::create] but returns referent back if heap is exhausted.
+    pub unsafe fn try_create(
+        mutator: &mut MutatorRef<H>,
+        value: Gc<T, H>,
+    ) -> Result<Self, AllocError<Gc<T, H>>> {
         let stack = mutator.shadow_stack();
         letroot!(value = stack, value);
-        let mut inner = mutator.allocate(
+        let mut inner = match mutator.try_allocate(
             WeakInner { value: None },
             crate::gc_base::AllocationSpace::New,
-        );
+        ) {
+            Ok(inner) => inner,
+            Err(_) => return Err(AllocError::new(*value)),
+        };
         inner.value = Some(value.to_dyn());
         mutator.write_barrier(inner.to_dyn());
-        Self {
+        Ok(Self {
             value: inner,
             marker: PhantomData,
-        }
+        })
     }
     /// Clears this reference object.
     ///
@@ -562,7 +746,11 @@ impl<T: Collectable + ?Sized, H: GcBase> Weak<T, H> {
     where
         T: Sized,
     {
-        self.value.value.map(|x| unsafe { x.downcast_unchecked() })
+        // referent is updated by GC without being traced.
+        self.value.value.map(|mut x| unsafe {
+            x.epoch.refresh();
+            x.downcast_unchecked()
+        })
     }
 
     /// # NOT FOR USE BY REGULAR CODE, ONLY FOR GC IMPLEMENTATIONS!
@@ -582,6 +770,7 @@ impl<T: Collectable + ?Sized, H: GcBase> Weak<T, H> {
                     self.value.value = Some(Gc {
                         base: NonNull::new_unchecked(new_header),
                         marker: PhantomData,
+                        epoch: value.epoch,
                     });
                 }
             }
@@ -595,6 +784,14 @@ impl<T: Collectable + ?Sized, H: GcBase> Weak<T, H> {
             marker: PhantomData,
         }
     }
+
+    /// Same as [Weak::to_dyn] but created reference is never reported as stale. Used for lists of references kept by
+    /// GC policies since these lists are not traced, see [epoch](crate::epoch).
+    pub(crate) fn to_dyn_untraced(self) -> Weak<dyn Collectable, H> {
+        let mut this = self.to_dyn();
+        this.value.epoch = GcEpoch::unchecked();
+        this
+    }
 }
 
 impl<T: Collectable + ?Sized, H: GcBase> Clone for Weak<T, H> {
@@ -605,6 +802,264 @@ impl<T: Collectable + ?Sized, H: GcBase> Clone for Weak<T, H> {
 
 impl<T: Collectable + ?Sized, H: GcBase> Copy for Weak<T, H> {}
 
+/// Returns time in milliseconds that is used for soft reference timestamps.
+pub fn soft_ref_clock() -> u64 {
+    static START: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+    START
+        .get_or_init(std::time::Instant::now)
+        .elapsed()
+        .as_millis() as u64
+}
+
+pub struct SoftInner<H: GcBase> {
+    pub value: Option<Gc<dyn Collectable, H>>,
+    /// Time of the last access, see [soft_ref_clock].
+    timestamp: AtomicU64,
+    /// When set referent is traced as strong reference.
+    keep: bool,
+}
+
+/// Soft reference objects, which are cleared at the discretion of the garbage collector in response to memory demand.
+/// Soft references are most often used to implement memory-sensitive caches.
+///
+/// Soft references are treated as strong references unless GC cycle is started because of allocation failure or heap is
+/// near its growth limit. In such cycles soft references that were not accessed for a long time are cleared, see
+/// [SoftRefPolicy](crate::gc_base::SoftRefPolicy).
+pub struct Soft<T: Collectable + ?Sized, H: GcBase> {
+    value: Gc<SoftInner<H>, H>,
+    marker: PhantomData<T>,
+}
+
+unsafe impl<H: GcBase> Trace for SoftInner<H> {
+    fn trace(&mut self, vis: &mut dyn Visitor) {
+        if self.keep {
+            self.value.trace(vis);
+        }
+    }
+}
+unsafe impl<H: GcBase> Finalize for SoftInner<H> {
+    unsafe fn finalize(&mut self) {}
+}
+
+impl<H: GcBase> Collectable for SoftInner<H> {}
+
+unsafe impl<T: Collectable + ?Sized, H: GcBase> Trace for Soft<T, H> {
+    fn trace(&mut self, vis: &mut dyn Visitor) {
+        vis.mark_object(&mut self.value.base);
+        self.value.epoch.refresh();
+    }
+}
+
+impl<T: Collectable + ?Sized, H: GcBase> Soft<T, H> {
+    pub unsafe fn base(self) -> *mut HeapObjectHeader {
+        self.value.base.as_ptr()
+    }
+    pub unsafe fn set_base(&mut self, hdr: *mut HeapObjectHeader) {
+        self.value.base = NonNull::new_unchecked(hdr);
+    }
+    /// Creates a new soft reference that refers to the given object.
+    pub unsafe fn create(mutator: &mut MutatorRef<H>, value: Gc<T, H>) -> Self {
+        let stack = mutator.shadow_stack();
+        letroot!(value = stack, value);
+        let mut inner = mutator.allocate(
+            SoftInner {
+                value: None,
+                timestamp: AtomicU64::new(soft_ref_clock()),
+                keep: true,
+            },
+            crate::gc_base::AllocationSpace::New,
+        );
+        inner.value = Some(value.to_dyn());
+        mutator.write_barrier(inner.to_dyn());
+        Self {
+            value: inner,
+            marker: PhantomData,
+        }
+    }
+    /// Clears this reference object.
+    pub fn clear(mut self) {
+        self.value.value = None;
+    }
+    /// Returns this soft reference object's referent and updates its access time. If this reference object has been
+    /// cleared, either by the program or by the garbage collector, then this method returns `None`.
+    pub fn get(self) -> Option<Gc<T, H>>
+    where
+        T: Sized,
+    {
+        self.value
+            .timestamp
+            .store(soft_ref_clock(), Ordering::Relaxed);
+        // referent is updated by GC without being traced.
+        self.value.value.map(|mut x| unsafe {
+            x.epoch.refresh();
+            x.downcast_unchecked()
+        })
+    }
+
+    /// Time of the last access to referent, see [soft_ref_clock].
+    pub fn timestamp(self) -> u64 {
+        self.value.timestamp.load(Ordering::Relaxed)
+    }
+
+    /// # NOT FOR USE BY REGULAR CODE, ONLY FOR GC IMPLEMENTATIONS!
+    ///
+    /// Must be invoked for each soft reference before marking. When `keep` is false referent is not marked through
+    /// this reference and reference is cleared if referent is dead after marking.
+    pub unsafe fn set_keep(mut self, keep: bool) {
+        self.value.keep = keep;
+    }
+
+    /// # NOT FOR USE BY REGULAR CODE, ONLY FOR GC IMPLEMENTATIONS!
+    ///
+    /// Must be invoked for each soft reference after marking cycle to update soft references.
+    pub unsafe fn after_mark(
+        &mut self,
+        process: impl FnOnce(*mut HeapObjectHeader) -> *mut HeapObjectHeader,
+    ) {
+        if let Some(value) = self.value.value {
+            let new_header = process(value.base.as_ptr());
+            if new_header.is_null() {
+                self.value.value = None;
+            } else {
+                self.value.value = Some(Gc {
+                    base: NonNull::new_unchecked(new_header),
+                    marker: PhantomData,
+                    epoch: value.epoch,
+                });
+            }
+        }
+    }
+
+    pub fn to_dyn(self) -> Soft<dyn Collectable, H> {
+        Soft {
+            value: H::ReadBarrier::read_barrier(self.value),
+            marker: PhantomData,
+        }
+    }
+
+    /// Same as [Soft::to_dyn] but created reference is never reported as stale. Used for lists of references kept by
+    /// GC policies since these lists are not traced, see [epoch](crate::epoch).
+    pub(crate) fn to_dyn_untraced(self) -> Soft<dyn Collectable, H> {
+        let mut this = self.to_dyn();
+        this.value.epoch = GcEpoch::unchecked();
+        this
+    }
+}
+
+impl<T: Collectable + ?Sized, H: GcBase> Clone for Soft<T, H> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: Collectable + ?Sized, H: GcBase> Copy for Soft<T, H> {}
+
+/// Type erased [Ephemeron] that is passed to [Visitor::mark_ephemeron].
+pub struct EphemeronEntry {
+    key: Option<NonNull<HeapObjectHeader>>,
+    value: Option<NonNull<HeapObjectHeader>>,
+}
+
+impl EphemeronEntry {
+    /// Returns key of ephemeron or `None` if ephemeron was cleared.
+    pub fn key(&self) -> Option<NonNull<HeapObjectHeader>> {
+        self.key
+    }
+
+    /// Mark value of ephemeron using `vis`.
+    pub fn mark_value(&mut self, vis: &mut (impl Visitor + ?Sized)) {
+        if let Some(value) = self.value.as_mut() {
+            vis.mark_object(value);
+        }
+    }
+
+    /// # NOT FOR USE BY REGULAR CODE, ONLY FOR GC IMPLEMENTATIONS!
+    ///
+    /// Update key of ephemeron with the address returned by `process`. If `process` returns null key is not marked (yet),
+    /// ephemeron is left untouched and `false` is returned.
+    pub unsafe fn resolve_key(
+        &mut self,
+        process: impl FnOnce(*mut HeapObjectHeader) -> *mut HeapObjectHeader,
+    ) -> bool {
+        match self.key {
+            Some(key) => match NonNull::new(process(key.as_ptr())) {
+                Some(key) => {
+                    self.key = Some(key);
+                    true
+                }
+                None => false,
+            },
+            None => false,
+        }
+    }
+
+    /// Clears both key and value of ephemeron.
+    pub fn clear(&mut self) {
+        self.key = None;
+        self.value = None;
+    }
+}
+
+/// Ephemeron is a key-value pair which keeps its value alive only while key is reachable. References from value to key
+/// do not keep key alive, so ephemerons can be used to implement `WeakMap` and `WeakSet` from JS.
+///
+/// Once GC finds that key is not reachable ephemeron is cleared i.e both [Ephemeron::key] and [Ephemeron::value]
+/// return `None`. Ephemerons are plain values that are stored inside of GC objects (e.g in hash table entries).
+///
+/// All collectors support ephemerons. Concurrent collectors (CMS and Shenandoah) treat ephemerons that are reachable
+/// directly from roots or constraints traced at the start of concurrent marking as strong references, ephemerons
+/// inside of GC objects are always processed.
+pub struct Ephemeron<K: Collectable + ?Sized, V: Collectable + ?Sized, H: GcBase> {
+    entry: EphemeronEntry,
+    marker: PhantomData<(Gc<K, H>, Gc<V, H>)>,
+}
+
+impl<K: Collectable + ?Sized, V: Collectable + ?Sized, H: GcBase> Ephemeron<K, V, H> {
+    /// Creates new ephemeron that keeps `value` alive while `key` is alive.
+    pub fn new(key: Gc<K, H>, value: Gc<V, H>) -> Self {
+        Self {
+            entry: EphemeronEntry {
+                key: Some(key.base),
+                value: Some(value.base),
+            },
+            marker: PhantomData,
+        }
+    }
+
+    /// Returns key of ephemeron or `None` if ephemeron was cleared.
+    pub fn key(&self) -> Option<Gc<K, H>> {
+        self.entry.key.map(|base| Gc {
+            base,
+            marker: PhantomData,
+            epoch: GcEpoch::unchecked(),
+        })
+    }
+
+    /// Returns value of ephemeron or `None` if ephemeron was cleared.
+    pub fn value(&self) -> Option<Gc<V, H>> {
+        self.entry.value.map(|base| Gc {
+            base,
+            marker: PhantomData,
+            epoch: GcEpoch::unchecked(),
+        })
+    }
+
+    /// Clears this ephemeron.
+    pub fn clear(&mut self) {
+        self.entry.clear();
+    }
+}
+
+unsafe impl<K: Collectable + ?Sized, V: Collectable + ?Sized, H: GcBase> Trace
+    for Ephemeron<K, V, H>
+{
+    fn trace(&mut self, vis: &mut dyn Visitor) {
+        if self.entry.key.is_some() {
+            vis.mark_ephemeron(&mut self.entry);
+        }
+    }
+}
+
 impl<T: PartialEq + Collectable, H: GcBase> PartialEq for Gc<T, H> {
     fn eq(&self, other: &Self) -> bool {
         **self == **other
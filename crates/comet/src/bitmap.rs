@@ -31,6 +31,10 @@ pub struct SpaceBitmap<const ALIGN: usize> {
     heap_limit: usize,
     name: &'static str,
 }
+// Bitmap words are atomics. Methods without `_sync` suffix might lose concurrent updates of the same word but never
+// race, so bitmap can be shared by marking and sweeping threads.
+unsafe impl<const ALIGN: usize> Send for SpaceBitmap<ALIGN> {}
+unsafe impl<const ALIGN: usize> Sync for SpaceBitmap<ALIGN> {}
 const BITS_PER_INTPTR: usize = size_of::<usize>() * 8;
 impl<const ALIGN: usize> SpaceBitmap<ALIGN> {
     pub fn is_null(&self) -> bool {
@@ -298,6 +302,11 @@ impl<const ALIGN: usize> SpaceBitmap<ALIGN> {
             end_offset -= ALIGN;
             self.clear((self.heap_begin + end_offset) as _);
         }
+        for index in Self::offset_to_index(begin_offset)..Self::offset_to_index(end_offset) {
+            unsafe {
+                (*self.bitmap_begin.add(index)).store(0, Ordering::Relaxed);
+            }
+        }
         // TODO: try to madvise unused pages.
     }
 
@@ -96,4 +96,8 @@ impl BumpPointerSpace {
     pub fn end(&self) -> *mut u8 {
         self.end
     }
+
+    pub fn cursor(&self) -> *mut u8 {
+        self.cursor.load(atomic::Ordering::Relaxed)
+    }
 }
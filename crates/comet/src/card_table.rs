@@ -6,11 +6,109 @@ pub const CARD_SIZE: usize = 512;
 pub const CARD_SIZE_BITS: usize = 9;
 pub const CARD_REFS: usize = CARD_SIZE / size_of::<usize>();
 
-#[allow(dead_code)]
+/// Card table that maps each [CARD_SIZE] bytes of heap to single byte. Card is "dirtied" by write barrier when object
+/// in that card is modified so GC knows which parts of old space must be scanned for references to young objects.
 pub struct CardTable {
     start: *mut u8,
     end: *mut u8,
+    /// Keeps card table memory mapped.
+    #[allow(dead_code)]
     map: Mmap,
     heap_begin: *mut u8,
     heap_size: usize,
 }
+
+impl CardTable {
+    pub const CARD_CLEAN: u8 = 0;
+    pub const CARD_DIRTY: u8 = 0x70;
+
+    /// Creates card table that covers `heap_size` bytes starting at `heap_begin`.
+    pub fn create(heap_begin: *mut u8, heap_size: usize) -> Self {
+        let capacity = heap_size.div_ceil(CARD_SIZE);
+        let map = Mmap::new(capacity, 0);
+        let start = map.start();
+        map.commit(start, capacity);
+        unsafe {
+            core::ptr::write_bytes(start, Self::CARD_CLEAN, capacity);
+        }
+        Self {
+            start,
+            end: unsafe { start.add(capacity) },
+            map,
+            heap_begin,
+            heap_size,
+        }
+    }
+
+    pub fn heap_begin(&self) -> *mut u8 {
+        self.heap_begin
+    }
+
+    pub fn heap_size(&self) -> usize {
+        self.heap_size
+    }
+
+    pub fn contains(&self, addr: *const u8) -> bool {
+        addr >= self.heap_begin && (addr as usize) < self.heap_begin as usize + self.heap_size
+    }
+    /// Returns card byte for the given address.
+    #[inline(always)]
+    pub fn card_from_addr(&self, addr: *const u8) -> *mut u8 {
+        debug_assert!(self.contains(addr));
+        unsafe {
+            self.start
+                .add((addr as usize - self.heap_begin as usize) >> CARD_SIZE_BITS)
+        }
+    }
+    /// Returns the first address that is covered by `card`.
+    #[inline(always)]
+    pub fn addr_from_card(&self, card: *const u8) -> *mut u8 {
+        debug_assert!(card >= self.start && card < self.end);
+        unsafe {
+            self.heap_begin
+                .add((card as usize - self.start as usize) << CARD_SIZE_BITS)
+        }
+    }
+    #[inline(always)]
+    pub fn mark_card(&self, addr: *const u8) {
+        unsafe {
+            self.card_from_addr(addr).write(Self::CARD_DIRTY);
+        }
+    }
+    #[inline(always)]
+    pub fn is_dirty(&self, addr: *const u8) -> bool {
+        unsafe { self.card_from_addr(addr).read() == Self::CARD_DIRTY }
+    }
+
+    pub fn clear_card_table(&self) {
+        unsafe {
+            core::ptr::write_bytes(
+                self.start,
+                Self::CARD_CLEAN,
+                self.end as usize - self.start as usize,
+            );
+        }
+    }
+
+    /// Visits all dirty cards and cleans them. `visitor` receives start address of each dirty card.
+    pub fn visit_dirty_and_clear(&self, mut visitor: impl FnMut(*mut u8)) {
+        unsafe {
+            let mut card = self.start;
+            // skip clean cards word by word.
+            while card < self.end {
+                if card as usize % size_of::<usize>() == 0
+                    && card.add(size_of::<usize>()) <= self.end
+                    && card.cast::<usize>().read() == 0
+                {
+                    card = card.add(size_of::<usize>());
+                    continue;
+                }
+                if card.read() == Self::CARD_DIRTY {
+                    card.write(Self::CARD_CLEAN);
+                    visitor(self.addr_from_card(card));
+                }
+                card = card.add(1);
+            }
+        }
+    }
+}
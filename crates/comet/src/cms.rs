@@ -1,8 +1,8 @@
 //! # Concurrent Mark-and-Sweep
 //!
-//! Simple CMS collector. Heap is divided into 128KB pages, small objects (<=64KB) are allocated
-//! into normal pages which contain free-list. Large objects go to large pages and large page contains only
-//! one object.
+//! Simple CMS collector. Heap is divided into 16KB blocks, small objects (smaller than [LARGE_CUTOFF](space::LARGE_CUTOFF)) are allocated
+//! into blocks which contain free-list of cells of the same size class. Large objects go to large object space and each
+//! large allocation contains only one object.
 //!
 //!
 //! ## GC cycle
@@ -22,27 +22,33 @@
 //!         object.color = GREY
 //!         marker.worklist.push(object)
 //! ```
+//! You ***must*** invoke [MutatorRef::write_barrier] after storing GC pointer into GC object.
 //!
 //! When marking worklist is empty it stops and executes final marking cycle.
 //!
 //! ## Final marking
 //!
-//! Final marking is executed in STW pause and in final marking phase we re-mark roots, process weak refs, execute finalizers
-//! and setup concurrent sweeper with currently allocated heap pages.
+//! Final marking is executed in STW pause and in final marking phase we re-mark roots, process ephemerons and weak refs,
+//! sweep large objects and setup concurrent sweeper with currently allocated heap blocks. Objects that contained
+//! ephemerons when they were traced concurrently are traced again in this pause since mutator might have moved the
+//! ephemerons. Objects allocated during concurrent marking are white
+//! and are kept alive only if they are reachable at final marking.
 //!
 //! ## Concurrent sweeping
 //!
-//! Sweeper works by draining heap pages and sweeping each object in them, after page is swept
-//! it is added to global page free-list so mutators can again allocate into them.
+//! Sweeper works by draining heap blocks and sweeping each object in them, after block is swept
+//! it is added to global block free-list so mutators can again allocate into them. Mutators that do not have
+//! available blocks sweep blocks by themselves. Note that finalizers of small objects are executed by the sweeper thread.
 //!
 //!
 //! # What if there is no enough memory and GC is running?
 //!
-//! If there is no enough memory to allocate object and GC is running, GC cycle "degrades" to Full STW cycle.
-//! In this cycle we stop all mutators and execute all stages of GC in STW pause.
+//! If there is no enough memory to allocate object and GC is running, GC cycle "degrades" to STW cycle.
+//! In this cycle we stop all mutators and finish marking in STW pause, heap is still swept concurrently. If no cycle
+//! is running or [MutatorRef::collect] is invoked full STW cycle is performed.
 //!
 //! # How GC decides when there is no enough memory?
-//! It does not, at the start of cycle we set GC threshold to 35% of current GC heap and when it is reached
+//! It does not, at the start of cycle we allow mutators to allocate 35% of current GC heap and when it is reached
 //! we simply switch to Degraded GC. At the end of the sweeping threshold is updated to be current_heap_size + 50% of current heap size.
 //! This allows us to perform concurrent cycles more often without going to degraded cycles.
 
@@ -52,9 +58,1106 @@ pub mod marking_worklist;
 pub mod space;
 pub mod write_barrier;
 
+use self::{
+    marker::Marker,
+    space::{CmsAllocator, Space, LARGE_CUTOFF},
+    write_barrier::write_barrier_impl,
+};
+use crate::{
+    api::{vtable_of, Collectable, Gc, HeapObjectHeader, Trace, Weak, GC_BLACK, GC_WHITE},
+    gc_base::{
+        AllocError, AllocationSpace, GcBase, MarkingConstraint, MarkingConstraintRuns, NoHelp,
+        NoReadBarrier, TLAB,
+    },
+    large_space::LargeObjectSpace,
+    make_small_type_id,
+    mutator::{JoinData, Mutator, MutatorRef, ThreadState},
+    poison::FreedMemory,
+    safepoint::{GlobalSafepoint, SafepointScope},
+    small_type_id,
+    stats::HeapStats,
+    stress::GcStress,
+    utils::{align_usize, formatted_size, stack_bounds::StackBounds},
+};
+use atomic::{Atomic, Ordering};
+use parking_lot::{
+    lock_api::{RawMutex, RawMutexFair},
+    RawMutex as Lock,
+};
+use std::{
+    any::TypeId,
+    cell::UnsafeCell,
+    mem::size_of,
+    ptr::null_mut,
+    sync::{atomic::AtomicUsize, Arc},
+    time::{Duration, Instant},
+};
+
+/// Phase of CMS cycle.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum CmsPhase {
+    Idle,
+    Marking,
+    Sweeping,
+}
+
+/// Number of objects concurrent marker processes before it lets mutators to take over the cycle.
+const MARKING_BATCH: usize = 512;
+
 /// Concurrent Mark&Sweep heap.
 ///
 ///
 ///
 /// `CONCURRENT` const determines if GC does concurrent marking&sweeping or always performs collection in STW, useful for debugging.
-pub struct ConcurrentMarkSweep<const CONCURRENT: bool = true> {}
+pub struct ConcurrentMarkSweep<const CONCURRENT: bool = true> {
+    space: &'static Space,
+    marker: Marker,
+    pub(crate) global_heap_lock: Lock,
+    pub(crate) large_space_lock: Lock,
+    pub(crate) large_space: LargeObjectSpace,
+    pub(crate) mutators: Vec<*mut Mutator<Self>>,
+    pub(crate) safepoint: GlobalSafepoint,
+    pub(crate) verbose: u8,
+    /// Serializes GC work between background collector thread and mutators that finish GC cycle in STW pause.
+    collector_lock: Lock,
+    phase: Atomic<CmsPhase>,
+    /// Incremented at the start of each GC cycle, background thread exits once the cycle it was started for is over.
+    epoch: AtomicUsize,
+    background_threads: AtomicUsize,
+    threshold: AtomicUsize,
+    degraded_limit: AtomicUsize,
+    cycle_start: Option<Instant>,
+    cycle_start_bytes: usize,
+    /// Set when marking of running cycle is finished.
+    sweep_start: Option<Instant>,
+    total_gcs: usize,
+    weak_refs: Vec<Weak<dyn Collectable, Self>>,
+    constraints: Vec<Box<dyn MarkingConstraint>>,
+    growth_multiplier: f64,
+    degraded_threshold: f64,
+    min_heap_size: usize,
+    max_heap_size: usize,
+    stats: HeapStats,
+}
+
+pub struct CmsOptions {
+    /// Determines by how much heap grows after GC cycle. By default set to 1.5
+    pub growth_multiplier: f64,
+    /// Fraction of heap size that mutators are allowed to allocate while GC cycle is running, once it is exceeded
+    /// cycle degrades to STW one. By default set to 0.35
+    pub degraded_threshold: f64,
+    /// Size of memory that is reserved for small objects. Set to 128MB by default
+    pub heap_size: usize,
+    /// Initial heap size before triggering GC cycle. By default set to 4MB
+    pub initial_size: usize,
+    /// Minimal heap size before triggering GC cycle. By default set to 1MB
+    pub min_heap_size: usize,
+    /// Maximal heap size before triggering GC cycle. By default set to 128MB
+    pub max_heap_size: usize,
+    /// Progression of size classes larger than 80 bytes. By default set to 1.4
+    pub size_class_progression: f64,
+    /// Enables verbose loggig to stdout.
+    pub verbose: u8,
+    /// GC stress mode, see [stress](crate::stress). Overridden by `COMET_GC_STRESS` environment variable. Disabled
+    /// by default.
+    pub stress: GcStress,
+    /// What happens to memory of dead objects, see [poison](crate::poison). Overridden by `COMET_FREED_MEMORY`
+    /// environment variable. Memory is reused by default.
+    pub freed_memory: FreedMemory,
+}
+
+impl CmsOptions {
+    /// Set growth multiplier. Panics if x <= 1
+    pub fn with_growth_multiplier(mut self, x: f64) -> Self {
+        if x <= 1.0 {
+            panic!("Growth multiplier is too small")
+        }
+        self.growth_multiplier = x;
+        self
+    }
+    /// Set degraded GC threshold. Panics if x is not in `(0, 1]` range.
+    pub fn with_degraded_threshold(mut self, x: f64) -> Self {
+        if x <= 0.0 || x > 1.0 {
+            panic!("Degraded GC threshold must be in (0, 1] range");
+        }
+        self.degraded_threshold = x;
+        self
+    }
+
+    pub fn with_heap_size(mut self, x: usize) -> Self {
+        if x < 256 * 1024 {
+            panic!("Heap size too small; Minimal heap size is 256KB");
+        }
+        self.heap_size = x;
+        self
+    }
+
+    pub fn with_initial_size(mut self, x: usize) -> Self {
+        self.initial_size = x;
+        self
+    }
+
+    pub fn with_min_heap_size(mut self, x: usize) -> Self {
+        self.min_heap_size = x;
+        self
+    }
+
+    pub fn with_max_heap_size(mut self, x: usize) -> Self {
+        self.max_heap_size = x;
+        self
+    }
+    /// Set size class progression. Panics if x <= 1
+    pub fn with_size_class_progression(mut self, x: f64) -> Self {
+        if x <= 1.0 {
+            panic!("Size class progression is too small");
+        }
+        self.size_class_progression = x;
+        self
+    }
+    pub fn with_verbose(mut self, x: u8) -> Self {
+        self.verbose = x;
+        self
+    }
+
+    /// Set GC stress mode.
+    pub fn with_stress(mut self, x: GcStress) -> Self {
+        self.stress = x;
+        self
+    }
+
+    /// Set what happens to memory of dead objects.
+    pub fn with_freed_memory(mut self, x: FreedMemory) -> Self {
+        self.freed_memory = x;
+        self
+    }
+}
+
+impl Default for CmsOptions {
+    fn default() -> Self {
+        Self {
+            growth_multiplier: 1.5,
+            degraded_threshold: 0.35,
+            heap_size: 128 * 1024 * 1024,
+            initial_size: 4 * 1024 * 1024,
+            min_heap_size: 1024 * 1024,
+            max_heap_size: 128 * 1024 * 1024,
+            size_class_progression: 1.4,
+            verbose: 0,
+            stress: GcStress::Disabled,
+            freed_memory: FreedMemory::Reuse,
+        }
+    }
+}
+
+pub fn instantiate_cms<const CONCURRENT: bool>(
+    options: CmsOptions,
+) -> MutatorRef<ConcurrentMarkSweep<CONCURRENT>> {
+    let space = Box::leak(Box::new(Space::new(
+        options.heap_size,
+        options.size_class_progression,
+        options.verbose > 1,
+    )));
+    space.freed_memory = FreedMemory::from_env_or(options.freed_memory);
+    let cms = Arc::new(UnsafeCell::new(ConcurrentMarkSweep::<CONCURRENT> {
+        space,
+        marker: Marker::new(),
+        large_space: LargeObjectSpace::new(),
+        large_space_lock: Lock::INIT,
+        global_heap_lock: Lock::INIT,
+        mutators: vec![],
+        safepoint: GlobalSafepoint::new(),
+        verbose: options.verbose,
+        collector_lock: Lock::INIT,
+        phase: Atomic::new(CmsPhase::Idle),
+        epoch: AtomicUsize::new(0),
+        background_threads: AtomicUsize::new(0),
+        threshold: AtomicUsize::new(options.initial_size.max(options.min_heap_size)),
+        degraded_limit: AtomicUsize::new(usize::MAX),
+        cycle_start: None,
+        cycle_start_bytes: 0,
+        sweep_start: None,
+        total_gcs: 0,
+        weak_refs: vec![],
+        constraints: vec![],
+        growth_multiplier: options.growth_multiplier,
+        degraded_threshold: options.degraded_threshold,
+        min_heap_size: options.min_heap_size,
+        max_heap_size: options.max_heap_size,
+        stats: HeapStats::default(),
+    }));
+    let href = unsafe { &mut *cms.get() };
+    href.large_space.freed_memory = href.space.freed_memory;
+    let join_data = JoinData::new();
+    let mut mutator = MutatorRef::new(Mutator::new(
+        cms.clone(),
+        &href.safepoint,
+        join_data.internal.clone(),
+    ));
+    href.mutators.push(&mut *mutator);
+    href.safepoint
+        .n_mutators
+        .fetch_add(1, atomic::Ordering::Relaxed);
+    mutator.stack_bounds = StackBounds::current_thread_stack_bounds();
+    mutator.state_set(ThreadState::Safe, ThreadState::Unsafe);
+
+    mutator.set_gc_stress(GcStress::from_env_or(options.stress));
+    mutator
+}
+
+impl<const CONCURRENT: bool> ConcurrentMarkSweep<CONCURRENT> {
+    /// Current phase of GC cycle.
+    pub fn phase(&self) -> CmsPhase {
+        self.phase.load(Ordering::Acquire)
+    }
+
+    unsafe fn run_constraints(&mut self, runs_at: MarkingConstraintRuns) {
+        let mut visitor = self.marker.visitor();
+        self.constraints.retain_mut(|constraint| {
+            if constraint.is_over() {
+                false
+            } else {
+                if constraint.runs_at() == runs_at {
+                    constraint.run(&mut visitor);
+                }
+                true
+            }
+        });
+    }
+
+    /// Number of bytes allocated in heap. Large space lock must be held.
+    fn heap_size(&self) -> usize {
+        self.space.num_bytes_allocated.load(Ordering::Relaxed) + self.large_space.bytes
+    }
+
+    unsafe fn walk_stack(&self, mut start: *mut *mut u8, mut end: *mut *mut u8) {
+        if end < start {
+            std::mem::swap(&mut start, &mut end);
+        }
+        let mut cursor = start;
+        while cursor < end {
+            let pointer = cursor.read();
+            cursor = cursor.add(1);
+            if pointer.is_null() {
+                continue;
+            }
+            let mut object = self.space.find_object(pointer);
+            if object.is_null() {
+                object = self.large_space.contains(pointer);
+            }
+            if !object.is_null() {
+                if self.verbose > 2 {
+                    eprintln!(
+                        "[GC] Found CMS object {:p} at {:p}",
+                        object,
+                        cursor.sub(1)
+                    );
+                }
+                self.marker.mark(object);
+            }
+        }
+    }
+
+    /// Grey all objects that are reachable from roots. Must be invoked only in STW pause.
+    unsafe fn mark_roots(&mut self, keep: &mut [&mut dyn Trace]) {
+        self.large_space.prepare_for_marking(false);
+        self.large_space.prepare_for_conservative_scan();
+        let mut visitor = self.marker.visitor();
+        for i in 0..self.mutators.len() {
+            let mutator = self.mutators[i];
+            self.walk_stack(
+                (*mutator).stack_bounds.origin.cast(),
+                (*mutator).last_sp.get().cast(),
+            );
+            let (start, end) = (*mutator).saved_registers();
+            self.walk_stack(start, end);
+            (*mutator).shadow_stack().walk(|entry| {
+                entry.trace(&mut visitor);
+            });
+        }
+        for object in keep {
+            object.trace(&mut visitor);
+        }
+    }
+
+    /// Start GC cycle. Must be invoked only in STW pause.
+    unsafe fn initial_mark(&mut self, keep: &mut [&mut dyn Trace]) {
+        self.epoch.fetch_add(1, Ordering::AcqRel);
+        self.cycle_start = Some(Instant::now());
+        self.cycle_start_bytes = self.heap_size();
+        let heap_size = self
+            .cycle_start_bytes
+            .max(self.threshold.load(Ordering::Relaxed));
+        self.degraded_limit.store(
+            heap_size + (heap_size as f64 * self.degraded_threshold) as usize,
+            Ordering::Relaxed,
+        );
+        self.large_space.begin_marking(true);
+        self.marker.set_marking(true);
+        self.mark_roots(keep);
+        self.run_constraints(MarkingConstraintRuns::BeforeMark);
+        self.phase.store(CmsPhase::Marking, Ordering::Release);
+    }
+
+    /// Finish marking and prepare heap for sweeping. Must be invoked only in STW pause.
+    unsafe fn final_mark(&mut self, keep: &mut [&mut dyn Trace]) {
+        for i in 0..self.mutators.len() {
+            // allocators must not hold blocks that are going to be swept.
+            (*self.mutators[i]).reset_tlab();
+        }
+        self.marker.set_collect_ephemerons(true);
+        self.mark_roots(keep);
+        self.marker.drain(usize::MAX);
+        self.run_constraints(MarkingConstraintRuns::AfterMark);
+        self.marker.drain(usize::MAX);
+        self.marker.process_ephemerons();
+        self.marker.set_collect_ephemerons(false);
+        self.marker.set_marking(false);
+
+        self.weak_refs.retain_mut(|object| {
+            let header = object.base();
+            if (*header).get_color() == GC_BLACK {
+                object.after_mark(|header| {
+                    if (*header).get_color() == GC_BLACK {
+                        header
+                    } else {
+                        null_mut()
+                    }
+                });
+                true
+            } else {
+                false
+            }
+        });
+        self.large_space.prepare_for_allocation(false);
+        self.large_space.sweep();
+        for allocation in self.large_space.allocations.iter() {
+            (*(**allocation).cell()).force_set_color(GC_WHITE);
+        }
+        self.space.prepare_for_sweep();
+        self.sweep_start = Some(Instant::now());
+        self.phase.store(CmsPhase::Sweeping, Ordering::Release);
+    }
+
+    /// Update GC threshold after sweeping. Large space lock must be held.
+    fn finish_cycle(&mut self, kind: &str) {
+        let bytes = self.heap_size();
+        let threshold = self
+            .min_heap_size
+            .max((bytes as f64 * self.growth_multiplier) as usize)
+            .min(self.max_heap_size);
+        self.threshold.store(threshold, Ordering::Relaxed);
+        self.degraded_limit.store(usize::MAX, Ordering::Relaxed);
+        let now = Instant::now();
+        let sweep_start = self.sweep_start.take().unwrap_or(now);
+        let mark = self.cycle_start.map_or(Duration::ZERO, |start| {
+            sweep_start.saturating_duration_since(start)
+        });
+        self.stats.record_cycle(
+            false,
+            mark,
+            now - sweep_start,
+            self.cycle_start_bytes,
+            bytes,
+        );
+        if self.verbose > 0 {
+            eprintln!(
+                "[gc] GC({}) {} CMS cycle {}->{}({}) {:.4}ms",
+                self.total_gcs,
+                kind,
+                formatted_size(self.cycle_start_bytes),
+                formatted_size(bytes),
+                formatted_size(threshold),
+                self.cycle_start
+                    .map(|start| start.elapsed().as_micros() as f64 / 1000.0)
+                    .unwrap_or(0.0)
+            );
+        }
+        self.total_gcs += 1;
+        self.phase.store(CmsPhase::Idle, Ordering::Release);
+    }
+
+    /// Execute all remaining stages of GC cycle or entire GC cycle if no cycle is running. Must be invoked only in
+    /// STW pause with collector lock held.
+    ///
+    /// When `concurrent_sweep` is true and cycle is running only marking is finished in STW pause, sweeping is left
+    /// to background collector thread and to mutators.
+    unsafe fn complete_cycle(&mut self, keep: &mut [&mut dyn Trace], concurrent_sweep: bool) {
+        if concurrent_sweep && self.phase() == CmsPhase::Sweeping {
+            return;
+        }
+        let pause = Instant::now();
+        let kind = if self.phase() == CmsPhase::Idle {
+            // whole heap is traced in this pause.
+            self.safepoint.next_epoch();
+            self.initial_mark(keep);
+            "Full"
+        } else {
+            "Degraded"
+        };
+        if self.phase() == CmsPhase::Marking {
+            self.marker.drain(usize::MAX);
+            self.final_mark(keep);
+        }
+        if !concurrent_sweep {
+            while self.space.sweep_next() {}
+        }
+        if self.verbose > 0 {
+            eprintln!(
+                "[gc] GC({}) Pause {} {:.4}ms",
+                self.total_gcs,
+                kind,
+                pause.elapsed().as_micros() as f64 / 1000.0
+            );
+        }
+        if !concurrent_sweep {
+            self.finish_cycle(kind);
+        }
+    }
+
+    fn collect_with(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        full: bool,
+        keep: &mut [&mut dyn Trace],
+    ) {
+        match SafepointScope::new(mutator.clone()) {
+            Some(safepoint) => unsafe {
+                mutator.save_conservative_roots();
+                let pause = Instant::now();
+                self.global_heap_lock.lock();
+                self.large_space_lock.lock();
+                self.collector_lock.lock();
+                let running = self.phase() != CmsPhase::Idle;
+                if full {
+                    self.complete_cycle(keep, false);
+                    if running {
+                        // objects that died after running cycle has started are not collected by it.
+                        self.complete_cycle(keep, false);
+                    }
+                } else {
+                    // background collector thread is alive while cycle is running and it will sweep the heap.
+                    self.complete_cycle(keep, CONCURRENT && running);
+                }
+                self.stats
+                    .record_pause(pause.elapsed(), safepoint.time_to_safepoint());
+                self.collector_lock.unlock();
+                drop(safepoint);
+                self.global_heap_lock.unlock();
+                self.large_space_lock.unlock();
+            },
+            None => (),
+        }
+    }
+
+    /// Perform initial marking and start background collector thread.
+    fn start_concurrent_cycle(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
+        match SafepointScope::new(mutator.clone()) {
+            Some(safepoint) => unsafe {
+                mutator.save_conservative_roots();
+                let pause = Instant::now();
+                self.global_heap_lock.lock();
+                self.large_space_lock.lock();
+                self.collector_lock.lock();
+                if self.phase() == CmsPhase::Idle {
+                    self.initial_mark(keep);
+                    self.spawn_collector();
+                    if self.verbose > 0 {
+                        eprintln!(
+                            "[gc] GC({}) Pause Initial Mark {:.4}ms",
+                            self.total_gcs,
+                            pause.elapsed().as_micros() as f64 / 1000.0
+                        );
+                    }
+                }
+                self.stats
+                    .record_pause(pause.elapsed(), safepoint.time_to_safepoint());
+                self.collector_lock.unlock();
+                drop(safepoint);
+                self.global_heap_lock.unlock();
+                self.large_space_lock.unlock();
+            },
+            None => (),
+        }
+    }
+
+    fn spawn_collector(&mut self) {
+        let epoch = self.epoch.load(Ordering::Acquire);
+        self.background_threads.fetch_add(1, Ordering::AcqRel);
+        let heap = self as *mut Self as usize;
+        std::thread::Builder::new()
+            .name("comet-cms".to_string())
+            .spawn(move || unsafe {
+                let heap = heap as *mut Self;
+                (*heap).concurrent_cycle(epoch);
+                (*heap).background_threads.fetch_sub(1, Ordering::AcqRel);
+            })
+            .expect("failed to spawn CMS collector thread");
+    }
+
+    /// Returns `true` if GC cycle `epoch` is still in `phase` i.e it was not completed by mutator in STW pause.
+    fn is_current(&self, epoch: usize, phase: CmsPhase) -> bool {
+        self.epoch.load(Ordering::Acquire) == epoch && self.phase() == phase
+    }
+
+    /// Concurrent part of GC cycle that is executed by background collector thread.
+    unsafe fn concurrent_cycle(&mut self, epoch: usize) {
+        if !self.concurrent_mark(epoch) {
+            return;
+        }
+        loop {
+            self.collector_lock.lock();
+            if !self.is_current(epoch, CmsPhase::Sweeping) {
+                self.collector_lock.unlock_fair();
+                return;
+            }
+            if !self.space.sweep_next() {
+                // large space lock is always taken before collector lock, mutator might be waiting for it in STW pause.
+                self.collector_lock.unlock();
+                self.large_space_lock.lock();
+                self.collector_lock.lock();
+                if self.is_current(epoch, CmsPhase::Sweeping) {
+                    self.finish_cycle("Concurrent");
+                }
+                self.collector_lock.unlock();
+                self.large_space_lock.unlock();
+                return;
+            }
+            self.collector_lock.unlock_fair();
+        }
+    }
+
+    /// Perform concurrent marking and final marking. Returns `true` if heap should be swept by this thread, marking
+    /// might be finished by a mutator in degraded cycle.
+    unsafe fn concurrent_mark(&mut self, epoch: usize) -> bool {
+        let time = Instant::now();
+        loop {
+            self.collector_lock.lock();
+            if !self.is_current(epoch, CmsPhase::Marking) {
+                self.collector_lock.unlock_fair();
+                return self.is_current(epoch, CmsPhase::Sweeping);
+            }
+            let done = self.marker.drain(MARKING_BATCH);
+            self.collector_lock.unlock_fair();
+            if done {
+                break;
+            }
+        }
+        if self.verbose > 0 {
+            eprintln!(
+                "[gc] GC({}) Concurrent Mark {:.4}ms",
+                self.total_gcs,
+                time.elapsed().as_micros() as f64 / 1000.0
+            );
+        }
+        let safepoint = loop {
+            if let Some(safepoint) = SafepointScope::try_new_no_mutator(self as *mut Self) {
+                break safepoint;
+            }
+            if !self.is_current(epoch, CmsPhase::Marking) {
+                return self.is_current(epoch, CmsPhase::Sweeping);
+            }
+        };
+        let pause = Instant::now();
+        self.global_heap_lock.lock();
+        self.large_space_lock.lock();
+        self.collector_lock.lock();
+        let current = self.is_current(epoch, CmsPhase::Marking);
+        if current {
+            self.final_mark(&mut []);
+        }
+        self.stats
+            .record_pause(pause.elapsed(), safepoint.time_to_safepoint());
+        self.collector_lock.unlock();
+        drop(safepoint);
+        self.global_heap_lock.unlock();
+        self.large_space_lock.unlock();
+        if current && self.verbose > 0 {
+            eprintln!(
+                "[gc] GC({}) Pause Final Mark {:.4}ms",
+                self.total_gcs,
+                pause.elapsed().as_micros() as f64 / 1000.0
+            );
+        }
+        self.is_current(epoch, CmsPhase::Sweeping)
+    }
+
+    /// Start GC cycle if threshold is reached or degrade running cycle to STW one if mutators allocated too much.
+    unsafe fn collect_if_needed(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
+        self.large_space_lock.lock();
+        let bytes = self.heap_size();
+        self.large_space_lock.unlock();
+        match self.phase() {
+            CmsPhase::Idle if bytes >= self.threshold.load(Ordering::Relaxed) => {
+                if CONCURRENT {
+                    self.start_concurrent_cycle(mutator, keep);
+                } else {
+                    self.collect_with(mutator, false, keep);
+                }
+            }
+            // mutators sweep blocks by themselves when there is no free memory, no need to stop the world
+            // while sweeping.
+            CmsPhase::Marking if bytes >= self.degraded_limit.load(Ordering::Relaxed) => {
+                self.collect_with(mutator, false, keep);
+            }
+            _ => (),
+        }
+    }
+
+    #[cold]
+    unsafe fn allocate_slow(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        size: usize,
+        keep: &mut [&mut dyn Trace],
+    ) -> *mut HeapObjectHeader {
+        // allocation slow path is the place where mutators stop for final marking.
+        mutator.poll_safepoint();
+        self.collect_if_needed(mutator, keep);
+        for attempt in 0..3 {
+            if mutator.tlab.refill_block(size) {
+                return mutator.tlab.allocate_cell(size);
+            }
+            match attempt {
+                0 => self.collect_alloc_failure(mutator, keep),
+                1 => self.collect(mutator, keep),
+                _ => (),
+            }
+        }
+        null_mut()
+    }
+}
+
+impl<const CONCURRENT: bool> TLAB<ConcurrentMarkSweep<CONCURRENT>> for CmsAllocator {
+    fn can_thread_local_allocate(&self, size: usize) -> bool {
+        size < LARGE_CUTOFF
+    }
+
+    fn refill(
+        &mut self,
+        _mutator: &MutatorRef<ConcurrentMarkSweep<CONCURRENT>>,
+        _alloc_size: usize,
+    ) -> bool {
+        false
+    }
+    fn allocate<T: Collectable + 'static>(
+        &mut self,
+        _value: T,
+    ) -> Result<Gc<T, ConcurrentMarkSweep<CONCURRENT>>, T> {
+        unreachable!()
+    }
+    fn reset(&mut self) {
+        self.reset_blocks();
+    }
+    fn create(heap: Arc<UnsafeCell<ConcurrentMarkSweep<CONCURRENT>>>) -> Self {
+        Self::new(unsafe { (*heap.get()).space })
+    }
+}
+
+impl<const CONCURRENT: bool> GcBase for ConcurrentMarkSweep<CONCURRENT> {
+    type TLAB = CmsAllocator;
+    const SUPPORTS_TLAB: bool = false;
+    type ReadBarrier = NoReadBarrier;
+    const LARGE_ALLOCATION_SIZE: usize = LARGE_CUTOFF;
+
+    fn inline_allocation_helpers(&self) -> Self::InlineAllocationHelpers {
+        NoHelp
+    }
+
+    fn stats(&self) -> HeapStats {
+        self.large_space_lock.lock();
+        let stats = self.stats.snapshot(
+            self.heap_size(),
+            self.threshold.load(Ordering::Relaxed),
+            self.large_space.bytes,
+        );
+        unsafe {
+            self.large_space_lock.unlock();
+        }
+        stats
+    }
+
+    fn add_constraint<T: MarkingConstraint + 'static>(&mut self, constraint: T) {
+        self.global_lock();
+        self.constraints.push(Box::new(constraint));
+        self.global_unlock();
+    }
+
+    fn allocate_raw(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        size: usize,
+        type_id: TypeId,
+        vtable: usize,
+    ) -> *mut HeapObjectHeader {
+        let size = align_usize(size + size_of::<HeapObjectHeader>(), 8);
+        unsafe {
+            let object = if size >= Self::LARGE_ALLOCATION_SIZE {
+                mutator.poll_safepoint();
+                self.collect_if_needed(mutator, &mut []);
+                self.large_space_lock.lock();
+                let object = self.large_space.allocate(size);
+                self.large_space_lock.unlock();
+                object
+            } else {
+                let object = mutator.tlab.allocate_cell(size);
+                if object.is_null() {
+                    self.allocate_slow(mutator, size, &mut [])
+                } else {
+                    object
+                }
+            };
+            if object.is_null() {
+                return null_mut();
+            }
+            // cells might contain stale headers of dead objects.
+            (*object).padding = 0;
+            (*object).set_vtable(vtable);
+            (*object).type_id = make_small_type_id(type_id);
+            object
+        }
+    }
+
+    fn allocate_weak<T: Collectable + ?Sized>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        value: Gc<T, Self>,
+    ) -> Result<Weak<T, Self>, AllocError<Gc<T, Self>>> {
+        let weak_ref = unsafe { Weak::<T, Self>::try_create(mutator, value)? };
+        self.global_heap_lock.lock();
+        self.weak_refs.push(weak_ref.to_dyn_untraced());
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        Ok(weak_ref)
+    }
+
+    #[inline]
+    fn alloc_inline<T: Collectable + Sized + 'static>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        mut value: T,
+        _space: AllocationSpace,
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
+        let size = align_usize(value.allocation_size() + size_of::<HeapObjectHeader>(), 8);
+        unsafe {
+            let mut object = mutator.tlab.allocate_cell(size);
+            if object.is_null() {
+                object = self.allocate_slow(mutator, size, &mut [&mut value]);
+                if object.is_null() {
+                    return Err(AllocError::new(value));
+                }
+            }
+            // cells might contain stale headers of dead objects.
+            (*object).padding = 0;
+            (*object).set_metadata(vtable_of::<T>());
+            (*object).type_id = small_type_id::<T>();
+            ((*object).data() as *mut T).write(value);
+            Ok(Gc::from_raw(object))
+        }
+    }
+
+    fn allocate_large<T: Collectable + Sized + 'static>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        mut value: T,
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
+        unsafe {
+            mutator.poll_safepoint();
+            self.collect_if_needed(mutator, &mut [&mut value]);
+            let size = value.allocation_size() + size_of::<HeapObjectHeader>();
+            self.large_space_lock.lock();
+            let mut object = self.large_space.allocate(size);
+            if object.is_null() {
+                self.large_space_lock.unlock();
+                self.collect(mutator, &mut [&mut value]);
+                self.large_space_lock.lock();
+                object = self.large_space.allocate(size);
+                if object.is_null() {
+                    self.large_space_lock.unlock();
+                    return Err(AllocError::new(value));
+                }
+            }
+            (*object).padding = 0;
+            (*object).set_metadata(vtable_of::<T>());
+            (*object).type_id = small_type_id::<T>();
+            ((*object).data() as *mut T).write(value);
+            self.large_space_lock.unlock();
+            Ok(Gc::from_raw(object))
+        }
+    }
+
+    #[inline]
+    fn write_barrier(&mut self, _mutator: &mut MutatorRef<Self>, object: Gc<dyn Collectable, Self>) {
+        if self.marker.is_marking() {
+            unsafe {
+                write_barrier_impl(&self.marker, object.base.as_ptr());
+            }
+        }
+    }
+
+    fn collect_alloc_failure(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
+        self.collect_with(mutator, false, keep);
+    }
+
+    /// Perform full STW collection. If concurrent cycle is running it is completed first.
+    fn collect(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
+        self.collect_with(mutator, true, keep);
+    }
+
+    fn alloc_tlab_area(&mut self, _mutator: &MutatorRef<Self>, _size: usize) -> *mut u8 {
+        null_mut()
+    }
+    fn safepoint(&self) -> &GlobalSafepoint {
+        &self.safepoint
+    }
+
+    fn attach_current_thread(&mut self, mutator: *mut Mutator<Self>) {
+        self.global_heap_lock.lock();
+        self.safepoint.n_mutators.fetch_add(1, Ordering::Relaxed);
+        self.mutators.push(mutator);
+        unsafe { self.global_heap_lock.unlock() };
+    }
+
+    fn detach_current_thread(&mut self, mutator: *mut Mutator<Self>) {
+        self.global_heap_lock.lock();
+
+        let mut detached = false;
+        self.mutators.retain(|x| {
+            if *x == mutator {
+                detached = true;
+                false
+            } else {
+                true
+            }
+        });
+        self.safepoint.n_mutators.fetch_sub(1, Ordering::Relaxed);
+        assert!(detached, "mutator must be detached");
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+    }
+
+    fn global_lock(&self) {
+        self.global_heap_lock.lock();
+    }
+    fn global_unlock(&self) {
+        unsafe {
+            debug_assert!(self.global_heap_lock.is_locked());
+            self.global_heap_lock.unlock();
+        }
+    }
+
+    fn mutators(&self) -> &[*mut Mutator<Self>] {
+        assert!(self.global_heap_lock.is_locked());
+        &self.mutators
+    }
+}
+
+impl<const CONCURRENT: bool> Drop for ConcurrentMarkSweep<CONCURRENT> {
+    fn drop(&mut self) {
+        unsafe {
+            // stop background collector before heap memory is released.
+            self.collector_lock.lock();
+            self.epoch.fetch_add(1, Ordering::AcqRel);
+            self.collector_lock.unlock();
+            while self.background_threads.load(Ordering::Acquire) != 0 {
+                std::thread::yield_now();
+            }
+            if self.verbose > 0 {
+                eprintln!("Dispose CMS space at {:p}", self);
+            }
+            drop(Box::from_raw(self.space as *const Space as *mut Space));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::{Ephemeron, Finalize, Visitor},
+        letroot,
+    };
+
+    struct Node<H: GcBase> {
+        value: usize,
+        next: Option<Gc<Node<H>, H>>,
+    }
+
+    unsafe impl<H: GcBase> Trace for Node<H> {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.next.trace(vis);
+        }
+    }
+    unsafe impl<H: GcBase> Finalize for Node<H> {}
+    impl<H: GcBase> Collectable for Node<H> {}
+
+    type Stw = ConcurrentMarkSweep<false>;
+
+    struct Table {
+        entries: Vec<Ephemeron<Node<Stw>, Node<Stw>, Stw>>,
+    }
+
+    unsafe impl Trace for Table {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.entries.trace(vis);
+        }
+    }
+    unsafe impl Finalize for Table {}
+    impl Collectable for Table {}
+
+    fn node(
+        mutator: &mut MutatorRef<Stw>,
+        value: usize,
+        next: Option<Gc<Node<Stw>, Stw>>,
+    ) -> Gc<Node<Stw>, Stw> {
+        mutator.allocate(Node { value, next }, AllocationSpace::New)
+    }
+
+    /// Add entries whose values reference their own keys. Keys are not reachable from anywhere else.
+    #[inline(never)]
+    fn add_unreachable_entries(mutator: &mut MutatorRef<Stw>, table: &mut Gc<Table, Stw>) {
+        for i in 0..100 {
+            let key = node(mutator, i, None);
+            let value = node(mutator, i, Some(key));
+            table.entries.push(Ephemeron::new(key, value));
+        }
+    }
+
+    #[test]
+    fn concurrent_cycles_keep_reachable_objects() {
+        let mut mutator = instantiate_cms::<true>(
+            CmsOptions::default()
+                .with_initial_size(1024 * 1024)
+                .with_min_heap_size(1024 * 1024),
+        );
+        let stack = mutator.shadow_stack();
+        let node: Node<ConcurrentMarkSweep<true>> = Node {
+            value: 0,
+            next: None,
+        };
+        letroot!(head = stack, mutator.allocate(node, AllocationSpace::New));
+        letroot!(tail = stack, *head);
+        let mut count = 1;
+        for i in 0..500000 {
+            let node = mutator.allocate(
+                Node {
+                    value: i,
+                    next: None,
+                },
+                AllocationSpace::New,
+            );
+            if i % 128 == 0 {
+                // tail might be already black, write barrier must re-grey it.
+                tail.next = Some(node);
+                mutator.write_barrier(tail.to_dyn());
+                *tail = node;
+                count += 1;
+            }
+        }
+        assert!(mutator.heap_ref().total_gcs > 0);
+        mutator.collect(&mut []);
+        let mut cursor = head.next;
+        let mut expected = 0;
+        let mut len = 1;
+        while let Some(node) = cursor {
+            assert_eq!(node.value, expected);
+            expected += 128;
+            len += 1;
+            cursor = node.next;
+        }
+        assert_eq!(len, count);
+    }
+
+    #[test]
+    fn stw_collection_frees_garbage() {
+        let mut mutator = instantiate_cms::<false>(CmsOptions::default());
+        let stack = mutator.shadow_stack();
+        let node: Node<ConcurrentMarkSweep<false>> = Node {
+            value: 42,
+            next: None,
+        };
+        letroot!(live = stack, mutator.allocate(node, AllocationSpace::New));
+        for i in 0..10000 {
+            mutator.allocate(
+                Node::<ConcurrentMarkSweep<false>> {
+                    value: i,
+                    next: None,
+                },
+                AllocationSpace::New,
+            );
+        }
+        mutator.collect(&mut []);
+        let heap = mutator.heap_ref();
+        assert_eq!(heap.phase(), CmsPhase::Idle);
+        assert!(heap.space.num_bytes_allocated.load(Ordering::Relaxed) < 64 * 1024);
+        assert_eq!(live.value, 42);
+        assert_eq!(unsafe { live.base.as_ref().get_color() }, GC_WHITE);
+    }
+
+    fn allocate_addresses(
+        mutator: &mut MutatorRef<ConcurrentMarkSweep<false>>,
+        count: usize,
+    ) -> Vec<usize> {
+        (0..count)
+            .map(|i| {
+                let node: Node<ConcurrentMarkSweep<false>> = Node {
+                    value: i,
+                    next: None,
+                };
+                mutator.allocate(node, AllocationSpace::New).base.as_ptr() as usize
+            })
+            .collect()
+    }
+
+    #[test]
+    fn quarantined_cells_are_poisoned_and_not_reused() {
+        let mut mutator = instantiate_cms::<false>(
+            CmsOptions::default().with_freed_memory(FreedMemory::Quarantine),
+        );
+        let dead = allocate_addresses(&mut mutator, 1000);
+        mutator.collect(&mut []);
+        // few objects might be kept alive by conservative stack scanning.
+        let poisoned = dead
+            .iter()
+            .filter(|&&object| unsafe { (*(object as *const HeapObjectHeader)).is_poisoned() })
+            .count();
+        assert!(poisoned >= 990, "only {} objects are poisoned", poisoned);
+        let fresh = allocate_addresses(&mut mutator, 1000);
+        assert!(fresh.iter().all(|object| !dead.contains(object)));
+    }
+
+    #[test]
+    fn ephemerons_with_dead_keys_are_cleared() {
+        let mut mutator = instantiate_cms::<false>(CmsOptions::default());
+        let stack = mutator.shadow_stack();
+        letroot!(
+            table = stack,
+            mutator.allocate(Table { entries: vec![] }, AllocationSpace::New)
+        );
+        letroot!(root = stack, node(&mut mutator, 1, None));
+        // key of the first entry is reachable only from value of the second entry
+        let key = node(&mut mutator, 2, None);
+        let value = node(&mut mutator, 20, None);
+        table.entries.push(Ephemeron::new(key, value));
+        let value = node(&mut mutator, 10, Some(key));
+        table.entries.push(Ephemeron::new(*root, value));
+        add_unreachable_entries(&mut mutator, &mut table);
+
+        mutator.collect(&mut []);
+
+        let first = &table.entries[0];
+        assert_eq!(first.key().unwrap().value, 2);
+        assert_eq!(first.value().unwrap().value, 20);
+        let second = &table.entries[1];
+        assert_eq!(second.key().unwrap().value, 1);
+        assert_eq!(second.value().unwrap().next.unwrap().value, 2);
+        // few keys might be kept alive by conservative stack scanning.
+        let cleared = table.entries[2..]
+            .iter()
+            .filter(|entry| entry.key().is_none() && entry.value().is_none())
+            .count();
+        assert!(cleared >= 90);
+    }
+}
@@ -1,11 +1,18 @@
 use std::{mem::size_of, ptr::null_mut};
 
-use crate::api::HeapObjectHeader;
+use crate::{
+    api::{HeapObjectHeader, GC_WHITE},
+    poison::{poison, FreedMemory},
+};
 
 pub const BLOCK_SIZE: usize = 16 * 1024;
 pub const ATOM_SIZE: usize = 16;
-pub const BLOCK_PAYLOAD: usize = BLOCK_SIZE - size_of::<Block>();
+/// Offset of the first cell in a block. Block header is padded so cells are always [ATOM_SIZE] aligned.
+pub const BLOCK_HEADER_SIZE: usize = (size_of::<Block>() + ATOM_SIZE - 1) & !(ATOM_SIZE - 1);
+pub const BLOCK_PAYLOAD: usize = BLOCK_SIZE - BLOCK_HEADER_SIZE;
 
+/// Free-list of block cells. Link to the next cell is stored as its offset from block start in size bits of the header
+/// so that vtable word of free cell is left intact and poisoned cells are still detected.
 pub struct FreeList {
     head: *mut HeapObjectHeader,
 }
@@ -15,27 +22,167 @@ impl FreeList {
         Self { head: null_mut() }
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.head.is_null()
+    }
+
     pub fn add(&mut self, entry: *mut u8) {
         unsafe {
             let entry = entry.cast::<HeapObjectHeader>();
             (*entry).set_free();
-            (*entry).value.raw = self.head as u64;
+            (*entry).padding2 = if self.head.is_null() {
+                0
+            } else {
+                ((self.head as usize - Block::from_object(entry.cast()) as usize) / ATOM_SIZE) as _
+            };
             self.head = entry;
         }
     }
 
+    /// Take cell from free-list. Returns null if free-list is empty.
     pub fn take(&mut self) -> *mut HeapObjectHeader {
         unsafe {
             let prev = self.head;
-            self.head = (*prev).value.raw as *mut HeapObjectHeader;
+            if prev.is_null() {
+                return null_mut();
+            }
+            self.head = match (*prev).padding2 as usize {
+                // first cell is after block header so zero offset marks the end of list.
+                0 => null_mut(),
+                next => (*Block::from_object(prev.cast()))
+                    .start()
+                    .add(next * ATOM_SIZE)
+                    .cast(),
+            };
             prev
         }
     }
 }
 
+impl Default for FreeList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// CMS heap block. Block contains cells of the same size class, free cells are linked into free-list.
+///
+/// Block with `cell_size` set to zero is not used for allocation.
+#[repr(C)]
 pub struct Block {
     free_list: FreeList,
-    cell_size: u8,
+    cell_size: u32,
+    /// Number of free cells after block was formatted or swept.
+    free_cells: u32,
 }
 
-impl Block {}
+impl Block {
+    pub fn from_object(object: *const u8) -> *mut Self {
+        (object as usize & !(BLOCK_SIZE - 1)) as *mut Self
+    }
+
+    pub fn start(&self) -> *mut u8 {
+        self as *const Self as _
+    }
+
+    pub fn payload_start(&self) -> *mut u8 {
+        unsafe { self.start().add(BLOCK_HEADER_SIZE) }
+    }
+
+    pub fn cell_size(&self) -> usize {
+        self.cell_size as _
+    }
+
+    pub fn free_cells(&self) -> usize {
+        self.free_cells as _
+    }
+
+    pub fn cell_count(&self) -> usize {
+        BLOCK_PAYLOAD / self.cell_size as usize
+    }
+
+    pub fn is_used(&self) -> bool {
+        self.cell_size != 0
+    }
+
+    /// Format block for allocation of `cell_size` cells. All cells are put to free-list.
+    pub fn init(&mut self, cell_size: usize) {
+        self.cell_size = cell_size as _;
+        self.free_list = FreeList::new();
+        let count = self.cell_count();
+        // add cells in reverse order so allocation goes from lower to higher addresses.
+        for i in (0..count).rev() {
+            unsafe {
+                self.free_list.add(self.payload_start().add(i * cell_size));
+            }
+        }
+        self.free_cells = count as _;
+    }
+
+    /// Mark block as not used for allocation.
+    pub fn deinit(&mut self) {
+        self.cell_size = 0;
+        self.free_cells = 0;
+        self.free_list = FreeList::new();
+    }
+
+    #[inline(always)]
+    pub fn allocate(&mut self) -> *mut HeapObjectHeader {
+        self.free_list.take()
+    }
+
+    /// Returns object header if `pointer` points to the start of allocated cell in this block.
+    pub fn object_at(&self, pointer: *const u8) -> *mut HeapObjectHeader {
+        if !self.is_used() || (pointer as usize) < self.payload_start() as usize {
+            return null_mut();
+        }
+        let offset = pointer as usize - self.payload_start() as usize;
+        if offset % self.cell_size() != 0 || offset / self.cell_size() >= self.cell_count() {
+            return null_mut();
+        }
+        let object = pointer as *mut HeapObjectHeader;
+        unsafe {
+            if (*object).is_free() || (*object).is_poisoned() {
+                return null_mut();
+            }
+        }
+        object
+    }
+
+    /// Sweep block and rebuild its free-list. White objects are finalized and freed, live objects are coloured
+    /// white again for the next GC cycle. Memory of freed objects is handled according to `freed_memory`, quarantined
+    /// cells are never added to free-list again. Returns number of live and quarantined cells.
+    pub fn sweep(&mut self, freed_memory: FreedMemory) -> usize {
+        let mut free_list = FreeList::new();
+        let mut live = 0;
+        let cell_size = self.cell_size();
+        for i in (0..self.cell_count()).rev() {
+            unsafe {
+                let cell = self.payload_start().add(i * cell_size);
+                let object = cell.cast::<HeapObjectHeader>();
+                if (*object).is_free() {
+                    free_list.add(cell);
+                } else if (*object).is_poisoned() {
+                    // quarantined by previous cycle.
+                    live += 1;
+                } else if (*object).get_color() == GC_WHITE {
+                    (*object).get_dyn().finalize();
+                    if freed_memory.poisons() {
+                        poison(cell, cell_size);
+                    }
+                    if freed_memory.reuses() {
+                        free_list.add(cell);
+                    } else {
+                        live += 1;
+                    }
+                } else {
+                    (*object).force_set_color(GC_WHITE);
+                    live += 1;
+                }
+            }
+        }
+        self.free_list = free_list;
+        self.free_cells = (self.cell_count() - live) as _;
+        live
+    }
+}
@@ -1,12 +1,190 @@
+use std::{
+    ptr::{null_mut, NonNull},
+    sync::atomic::{fence, AtomicBool},
+};
+
+use atomic::Ordering;
+use parking_lot::Mutex;
+
 use super::marking_worklist::MarkingWorklists;
+use crate::{
+    api::{EphemeronEntry, HeapObjectHeader, Visitor, GC_BLACK, GC_GREY, GC_WHITE},
+    large_space::PreciseAllocation,
+};
 
 pub struct Marker {
     marking_worklists: MarkingWorklists,
-    is_marking: bool,
+    is_marking: AtomicBool,
+    /// Set in final marking pause, ephemerons found by tracing are recorded for [Marker::process_ephemerons].
+    collect_ephemerons: AtomicBool,
+    /// Ephemerons found in final marking pause whose keys are not known to be alive yet.
+    ephemerons: Mutex<Vec<usize>>,
+    /// Objects that contained ephemerons when they were traced concurrently. Mutator might move ephemerons inside of
+    /// them (e.g by growing a vector) so these objects are traced again in final marking pause.
+    ephemeron_holders: Mutex<Vec<usize>>,
 }
 
 impl Marker {
+    pub fn new() -> Self {
+        Self {
+            marking_worklists: MarkingWorklists::new(),
+            is_marking: AtomicBool::new(false),
+            collect_ephemerons: AtomicBool::new(false),
+            ephemerons: Mutex::new(vec![]),
+            ephemeron_holders: Mutex::new(vec![]),
+        }
+    }
     pub fn marking_worklists(&self) -> &MarkingWorklists {
         &self.marking_worklists
     }
+
+    /// Returns `true` if marking is in progress and write barrier should be executed.
+    #[inline(always)]
+    pub fn is_marking(&self) -> bool {
+        self.is_marking.load(Ordering::Relaxed)
+    }
+
+    /// Must be invoked only in STW pause.
+    pub fn set_marking(&self, marking: bool) {
+        self.is_marking.store(marking, Ordering::Release);
+    }
+
+    /// Start or stop recording ephemerons for [Marker::process_ephemerons]. Must be invoked only in STW pause.
+    pub fn set_collect_ephemerons(&self, collect: bool) {
+        self.collect_ephemerons.store(collect, Ordering::Release);
+    }
+
+    pub fn visitor(&self) -> MarkingVisitor<'_> {
+        MarkingVisitor {
+            marker: self,
+            holder: null_mut(),
+        }
+    }
+
+    /// Colour white object grey and push it to marking worklist.
+    pub unsafe fn mark(&self, object: *mut HeapObjectHeader) {
+        // `set_color` might fail spuriously so we retry until object is no longer white.
+        while (*object).get_color() == GC_WHITE {
+            if !(*object).set_color(GC_WHITE, GC_GREY) {
+                if (*object).is_precise() {
+                    (*PreciseAllocation::from_cell(object)).test_and_set_marked();
+                }
+                self.marking_worklists.marking_worklist().push(object as usize);
+                return;
+            }
+        }
+    }
+
+    /// Process grey objects. Stops after `budget` objects are processed, returns `true` if worklists are empty.
+    pub unsafe fn drain(&self, mut budget: usize) -> bool {
+        let mut visitor = self.visitor();
+        while budget != 0 {
+            let object = match self.marking_worklists.pop() {
+                Some(object) => object as *mut HeapObjectHeader,
+                None => return true,
+            };
+            while (*object).get_color() == GC_GREY {
+                if !(*object).set_color(GC_GREY, GC_BLACK) {
+                    // object must be black before its fields are read: store that happens after this point
+                    // will be caught by write barrier.
+                    fence(Ordering::SeqCst);
+                    visitor.holder = object;
+                    (*object).get_dyn().trace(&mut visitor);
+                    break;
+                }
+            }
+            budget -= 1;
+        }
+        self.marking_worklists.is_empty()
+    }
+
+    /// Mark values of ephemerons with live keys until no more keys are found to be alive. Ephemerons with dead keys are
+    /// cleared. Must be invoked only in final marking pause after worklists are drained and with
+    /// [Marker::set_collect_ephemerons] enabled.
+    pub unsafe fn process_ephemerons(&self) {
+        let mut visitor = self.visitor();
+        for holder in std::mem::take(&mut *self.ephemeron_holders.lock()) {
+            let holder = holder as *mut HeapObjectHeader;
+            // grey holders were re-greyed by write barrier and are traced again by `drain`.
+            if (*holder).get_color() == GC_BLACK {
+                visitor.holder = holder;
+                (*holder).get_dyn().trace(&mut visitor);
+            }
+        }
+        self.drain(usize::MAX);
+        loop {
+            let mut marked = false;
+            let ephemerons = std::mem::take(&mut *self.ephemerons.lock());
+            let mut pending = vec![];
+            for ephemeron in ephemerons {
+                let entry = ephemeron as *mut EphemeronEntry;
+                let alive = (*entry).resolve_key(|key| {
+                    if (*key).get_color() == GC_BLACK {
+                        key
+                    } else {
+                        null_mut()
+                    }
+                });
+                if alive {
+                    (*entry).mark_value(&mut visitor);
+                    marked = true;
+                } else {
+                    pending.push(ephemeron);
+                }
+            }
+            self.ephemerons.lock().extend(pending);
+            if !marked {
+                break;
+            }
+            // values might reference keys of other ephemerons
+            self.drain(usize::MAX);
+        }
+        for ephemeron in std::mem::take(&mut *self.ephemerons.lock()) {
+            (*(ephemeron as *mut EphemeronEntry)).clear();
+        }
+    }
+}
+
+impl Default for Marker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Visitor that greys objects and pushes them to marker worklist.
+pub struct MarkingVisitor<'a> {
+    marker: &'a Marker,
+    /// Object that is traced by this visitor or null when roots are traced.
+    holder: *mut HeapObjectHeader,
+}
+
+impl<'a> Visitor for MarkingVisitor<'a> {
+    fn mark_object(&mut self, root: &mut NonNull<HeapObjectHeader>) {
+        unsafe {
+            self.marker.mark(root.as_ptr());
+        }
+    }
+
+    fn mark_ephemeron(&mut self, ephemeron: &mut EphemeronEntry) {
+        if self.marker.collect_ephemerons.load(Ordering::Acquire) {
+            self.marker
+                .ephemerons
+                .lock()
+                .push(ephemeron as *mut EphemeronEntry as usize);
+        } else if !self.holder.is_null() {
+            self.marker
+                .ephemeron_holders
+                .lock()
+                .push(self.holder as usize);
+        } else {
+            // roots and constraints traced outside of final marking pause are not traced again, ephemeron is
+            // treated as strong reference.
+            if let Some(key) = ephemeron.key() {
+                unsafe {
+                    self.marker.mark(key.as_ptr());
+                }
+            }
+            ephemeron.mark_value(self);
+        }
+    }
 }
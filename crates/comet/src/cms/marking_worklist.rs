@@ -6,7 +6,33 @@ pub struct MarkingWorklists {
 }
 
 impl MarkingWorklists {
+    pub fn new() -> Self {
+        Self {
+            marking_worklists: SegQueue::new(),
+            write_barrier_worklist: SegQueue::new(),
+        }
+    }
+    pub fn marking_worklist(&self) -> &SegQueue<usize> {
+        &self.marking_worklists
+    }
     pub fn write_barrier_worklist(&self) -> &SegQueue<usize> {
         &self.write_barrier_worklist
     }
+
+    /// Pop grey object. Objects re-greyed by write barrier are processed first.
+    pub fn pop(&self) -> Option<usize> {
+        self.write_barrier_worklist
+            .pop()
+            .or_else(|| self.marking_worklists.pop())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.write_barrier_worklist.is_empty() && self.marking_worklists.is_empty()
+    }
+}
+
+impl Default for MarkingWorklists {
+    fn default() -> Self {
+        Self::new()
+    }
 }
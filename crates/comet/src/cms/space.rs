@@ -1,6 +1,18 @@
-use crate::bitmap::round_up;
+use std::{
+    ptr::null_mut,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
-use super::block::{ATOM_SIZE, BLOCK_PAYLOAD};
+use parking_lot::Mutex;
+
+use crate::{
+    api::HeapObjectHeader,
+    bitmap::round_up,
+    poison::FreedMemory,
+    utils::{formatted_size, mmap::Mmap},
+};
+
+use super::block::{Block, ATOM_SIZE, BLOCK_PAYLOAD, BLOCK_SIZE};
 /// The largest cell we're willing to allocate in a [Block] the "normal way" (i.e. using size
 /// classes, rather than a large allocation) is half the size of the payload, rounded down. This
 /// ensures that we only use the size class approach if it means being able to pack two things
@@ -36,7 +48,7 @@ pub fn size_classes(size_class_progression: f64, dump: bool) -> Vec<usize> {
         let cells_per_block = BLOCK_PAYLOAD / size_class;
         let possibly_better_size_class = (BLOCK_PAYLOAD / cells_per_block) & !(ATOM_SIZE - 1);
 
-        let original_wastage = BLOCK_PAYLOAD - cells_per_block * ATOM_SIZE;
+        let original_wastage = BLOCK_PAYLOAD - cells_per_block * size_class;
         let new_wastage = (possibly_better_size_class - size_class) * cells_per_block;
 
         let better_size_class = if new_wastage > original_wastage {
@@ -74,7 +86,7 @@ pub fn build_size_class_table(progression: f64, dump: bool) -> [usize; NUM_SIZE_
         for i in next_index..=index {
             table[i] = size_class;
         }
-        next_index += 1;
+        next_index = index + 1;
     }
 
     for i in next_index..NUM_SIZE_CLASSES {
@@ -83,11 +95,56 @@ pub fn build_size_class_table(progression: f64, dump: bool) -> [usize; NUM_SIZE_
     table
 }
 
+/// CMS heap space. Memory is reserved once and divided into [BLOCK_SIZE] blocks, each block
+/// is formatted for a single size class.
 pub struct Space {
     size_class_for_size_step: [usize; NUM_SIZE_CLASSES],
+    map: Mmap,
+    start: *mut u8,
+    end: *mut u8,
+    /// Start of never used memory. Protected by `free_blocks` lock.
+    cursor: AtomicUsize,
+    /// Empty blocks that can be formatted for any size class.
+    free_blocks: Mutex<Vec<*mut Block>>,
+    /// Blocks that have free cells indexed by size class index.
+    available_blocks: Vec<Mutex<Vec<*mut Block>>>,
+    /// All blocks that are formatted for allocation.
+    blocks: Mutex<Vec<*mut Block>>,
+    /// Blocks that are waiting for sweeping.
+    sweep_list: Mutex<Vec<*mut Block>>,
+    pub num_bytes_allocated: AtomicUsize,
+    /// What happens to cells of dead objects. Quarantined cells are accounted as allocated.
+    pub freed_memory: FreedMemory,
 }
 
 impl Space {
+    pub fn new(size: usize, size_class_progression: f64, verbose: bool) -> Self {
+        let map = Mmap::new(size, BLOCK_SIZE);
+        let start = map.aligned_start();
+        let end = (map.end() as usize & !(BLOCK_SIZE - 1)) as *mut u8;
+        if verbose {
+            eprintln!(
+                "[cms] Reserved {} at {:p}->{:p}",
+                formatted_size(end as usize - start as usize),
+                start,
+                end
+            );
+        }
+        Self {
+            size_class_for_size_step: build_size_class_table(size_class_progression, verbose),
+            map,
+            start,
+            end,
+            cursor: AtomicUsize::new(start as usize),
+            free_blocks: Mutex::new(vec![]),
+            available_blocks: (0..NUM_SIZE_CLASSES).map(|_| Mutex::new(vec![])).collect(),
+            blocks: Mutex::new(vec![]),
+            sweep_list: Mutex::new(vec![]),
+            num_bytes_allocated: AtomicUsize::new(0),
+            freed_memory: FreedMemory::Reuse,
+        }
+    }
+
     pub const fn size_class_to_index(size: usize) -> usize {
         (size + ATOM_SIZE - 1) / ATOM_SIZE
     }
@@ -96,4 +153,159 @@ impl Space {
         let result = index * ATOM_SIZE;
         result
     }
+
+    /// Get size class for allocation of `size` bytes. `size` must not be larger than [LARGE_CUTOFF].
+    #[inline(always)]
+    pub fn size_class_for(&self, size: usize) -> usize {
+        self.size_class_for_size_step[Self::size_class_to_index(size)]
+    }
+
+    pub fn has_address(&self, pointer: *const u8) -> bool {
+        pointer >= self.start as *const u8 && pointer < self.end as *const u8
+    }
+
+    /// Returns object header if `pointer` points to allocated object in this space. Used by conservative stack scanning.
+    pub fn find_object(&self, pointer: *const u8) -> *mut HeapObjectHeader {
+        if !self.has_address(pointer) || pointer as usize >= self.cursor.load(Ordering::Relaxed) {
+            return null_mut();
+        }
+        unsafe { (*Block::from_object(pointer)).object_at(pointer) }
+    }
+
+    fn allocate_block(&self) -> *mut Block {
+        let mut free_blocks = self.free_blocks.lock();
+        if let Some(block) = free_blocks.pop() {
+            return block;
+        }
+        let cursor = self.cursor.load(Ordering::Relaxed);
+        if cursor + BLOCK_SIZE > self.end as usize {
+            return null_mut();
+        }
+        self.cursor.store(cursor + BLOCK_SIZE, Ordering::Relaxed);
+        self.map.commit(cursor as _, BLOCK_SIZE);
+        cursor as *mut Block
+    }
+
+    /// Acquire block with free cells of `cell_size`. Blocks that are waiting for sweeping are swept
+    /// by the caller when there is no available blocks. Returns null if heap is exhausted.
+    pub fn acquire_block(&self, cell_size: usize) -> *mut Block {
+        let index = Self::size_class_to_index(cell_size);
+        loop {
+            let block = self.available_blocks[index].lock().pop();
+            if let Some(block) = block {
+                self.account_block(block);
+                return block;
+            }
+            let block = self.allocate_block();
+            if !block.is_null() {
+                unsafe {
+                    (*block).init(cell_size);
+                }
+                self.blocks.lock().push(block);
+                self.account_block(block);
+                return block;
+            }
+            if !self.sweep_next() {
+                return null_mut();
+            }
+        }
+    }
+
+    /// Free cells of acquired block are accounted as allocated.
+    fn account_block(&self, block: *mut Block) {
+        unsafe {
+            self.num_bytes_allocated.fetch_add(
+                (*block).free_cells() * (*block).cell_size(),
+                Ordering::Relaxed,
+            );
+        }
+    }
+
+    /// Move all blocks to sweep list. Must be invoked in STW pause after all allocators are reset.
+    pub fn prepare_for_sweep(&self) {
+        let mut sweep_list = self.sweep_list.lock();
+        debug_assert!(sweep_list.is_empty());
+        std::mem::swap(&mut *sweep_list, &mut *self.blocks.lock());
+        for list in self.available_blocks.iter() {
+            list.lock().clear();
+        }
+        self.num_bytes_allocated.store(0, Ordering::Relaxed);
+    }
+
+    /// Sweep single block from sweep list. Returns `false` if there is no more blocks to sweep.
+    pub fn sweep_next(&self) -> bool {
+        let block = self.sweep_list.lock().pop();
+        match block {
+            Some(block) => {
+                unsafe {
+                    self.sweep_block(block);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    unsafe fn sweep_block(&self, block: *mut Block) {
+        let live = (*block).sweep(self.freed_memory);
+        if live == 0 {
+            (*block).deinit();
+            self.map.dontneed(block.cast(), BLOCK_SIZE);
+            self.free_blocks.lock().push(block);
+            return;
+        }
+        self.num_bytes_allocated
+            .fetch_add(live * (*block).cell_size(), Ordering::Relaxed);
+        self.blocks.lock().push(block);
+        if (*block).free_cells() != 0 {
+            self.available_blocks[Self::size_class_to_index((*block).cell_size())]
+                .lock()
+                .push(block);
+        }
+    }
+}
+
+/// Thread local CMS allocator. Each mutator owns one block per size class and allocates from its free-list without locks.
+pub struct CmsAllocator {
+    space: &'static Space,
+    blocks: Box<[*mut Block]>,
+}
+
+impl CmsAllocator {
+    pub fn new(space: &'static Space) -> Self {
+        Self {
+            space,
+            blocks: vec![null_mut(); NUM_SIZE_CLASSES].into_boxed_slice(),
+        }
+    }
+
+    /// Allocate cell for `size` bytes from current block. Returns null if block is exhausted.
+    #[inline(always)]
+    pub fn allocate_cell(&mut self, size: usize) -> *mut HeapObjectHeader {
+        let cell_size = self.space.size_class_for(size);
+        let block = self.blocks[Space::size_class_to_index(cell_size)];
+        if block.is_null() {
+            return null_mut();
+        }
+        unsafe {
+            let object = (*block).allocate();
+            if !object.is_null() {
+                (*object).set_size(cell_size);
+            }
+            object
+        }
+    }
+
+    /// Acquire new block for `size` bytes allocation. Returns `false` if heap is exhausted.
+    pub fn refill_block(&mut self, size: usize) -> bool {
+        let cell_size = self.space.size_class_for(size);
+        let block = self.space.acquire_block(cell_size);
+        self.blocks[Space::size_class_to_index(cell_size)] = block;
+        !block.is_null()
+    }
+
+    /// Forget all blocks. Must be invoked before blocks are swept.
+    pub fn reset_blocks(&mut self) {
+        self.blocks.iter_mut().for_each(|block| *block = null_mut());
+    }
 }
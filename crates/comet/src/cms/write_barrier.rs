@@ -1,15 +1,24 @@
 //! Retreating wavefront write barrier implementation for CMS.
 
+use std::sync::atomic::fence;
+
 use super::marker::Marker;
 use crate::api::{HeapObjectHeader, GC_BLACK, GC_GREY};
+use atomic::Ordering;
 
 #[inline(always)]
 pub(super) unsafe fn write_barrier_impl(marker: &Marker, object: *mut HeapObjectHeader) {
+    // store to `object` must be visible to the marker before we read object colour, otherwise marker might
+    // read old field value while we see object as grey.
+    fence(Ordering::SeqCst);
     // if object color is black it was already visited, we set its color to grey and push
     // it to write barrier worklist so concurrent marker will eventually process it in
     // concurrent marking cycle or at final marking cycle.
-    if (*object).set_color(GC_BLACK, GC_GREY) {
-        write_barrier_slow(marker, object);
+    while (*object).get_color() == GC_BLACK {
+        if !(*object).set_color(GC_BLACK, GC_GREY) {
+            write_barrier_slow(marker, object);
+            return;
+        }
     }
 }
 #[cold]
@@ -0,0 +1,175 @@
+//! Stale GC pointer detection.
+//!
+//! With `stale-gc-check` feature every [Gc](crate::api::Gc) returned by [MutatorRef::allocate](crate::mutator::MutatorRef::allocate)
+//! remembers GC epoch of its heap and its allocation site. Heap epoch is advanced at the start of every GC cycle that
+//! traces the whole heap, pointers that are traced during the cycle (rooted with `letroot!`, passed in `keep` slice or
+//! stored in fields of live objects) are refreshed to the new epoch. Dereferencing a pointer from earlier epoch means
+//! it was held across GC cycle without being rooted and panics with allocation site of the object.
+//!
+//! Only full heap collections advance the epoch: minor collections do not trace old objects, so their fields can't be
+//! refreshed. Concurrent cycles of CMS and Shenandoah do not advance it either: objects that are allocated or modified
+//! while marking runs concurrently are not traced again, so valid pointers stored into them would be reported. With
+//! these policies only cycles that run entirely in stop-the-world pause are checked, e.g cycles started by
+//! [MutatorRef::collect](crate::mutator::MutatorRef::collect) or by stress mode (see [stress](crate::stress)).
+//! Policies that scan stacks conservatively (Immix, StickyImmix) keep unrooted objects on the stack alive but they are
+//! still reported since code like that is not portable to precise collectors. Pointers created by GC internals (e.g
+//! ephemeron keys and values, pointers passed to [GcBase::inspect](crate::gc_base::GcBase::inspect)) are never
+//! reported.
+//!
+//! Without the feature [GcEpoch] is zero sized and all checks are no-ops.
+#[cfg(feature = "stale-gc-check")]
+use std::panic::Location;
+use std::{ptr::NonNull, sync::atomic::AtomicUsize};
+
+use crate::api::HeapObjectHeader;
+
+/// GC epoch of a [Gc](crate::api::Gc) pointer.
+#[derive(Clone, Copy)]
+pub struct GcEpoch {
+    /// Epoch counter of the heap, null for pointers that are not checked.
+    #[cfg(feature = "stale-gc-check")]
+    heap: *const AtomicUsize,
+    #[cfg(feature = "stale-gc-check")]
+    epoch: usize,
+    #[cfg(feature = "stale-gc-check")]
+    site: &'static Location<'static>,
+}
+
+impl GcEpoch {
+    /// Epoch of pointers that are never reported as stale.
+    #[cfg(feature = "stale-gc-check")]
+    #[inline(always)]
+    pub const fn unchecked() -> Self {
+        Self {
+            heap: std::ptr::null(),
+            epoch: 0,
+            site: Location::caller(),
+        }
+    }
+
+    /// Epoch of pointers that are never reported as stale.
+    #[cfg(not(feature = "stale-gc-check"))]
+    #[inline(always)]
+    pub const fn unchecked() -> Self {
+        Self {}
+    }
+
+    /// Current epoch of heap whose epoch counter is `heap`. Allocation site is location of the caller.
+    #[track_caller]
+    #[inline(always)]
+    pub(crate) fn new(heap: &AtomicUsize) -> Self {
+        #[cfg(feature = "stale-gc-check")]
+        {
+            Self {
+                heap,
+                epoch: heap.load(std::sync::atomic::Ordering::Relaxed),
+                site: Location::caller(),
+            }
+        }
+        #[cfg(not(feature = "stale-gc-check"))]
+        {
+            let _ = heap;
+            Self {}
+        }
+    }
+
+    /// Move pointer to the current epoch of its heap. Invoked when pointer is traced.
+    #[inline(always)]
+    pub fn refresh(&mut self) {
+        #[cfg(feature = "stale-gc-check")]
+        unsafe {
+            if let Some(heap) = self.heap.as_ref() {
+                self.epoch = heap.load(std::sync::atomic::Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Panics if pointer to `object` is from earlier epoch than its heap.
+    #[inline(always)]
+    pub fn check(&self, object: NonNull<HeapObjectHeader>) {
+        #[cfg(feature = "stale-gc-check")]
+        unsafe {
+            if let Some(heap) = self.heap.as_ref() {
+                let current = heap.load(std::sync::atomic::Ordering::Relaxed);
+                if self.epoch != current {
+                    panic!(
+                        "Stale GC pointer {:p} to object allocated at {} is used after GC cycle (pointer epoch {}, heap epoch {}). Pointer must be rooted with `letroot!` or traced",
+                        object, self.site, self.epoch, current
+                    );
+                }
+            }
+        }
+        #[cfg(not(feature = "stale-gc-check"))]
+        let _ = object;
+    }
+}
+
+#[cfg(all(test, feature = "stale-gc-check"))]
+mod tests {
+    use crate::{
+        api::{Collectable, Finalize, Gc, Trace, Visitor},
+        gc_base::AllocationSpace,
+        letroot,
+        semispace::{instantiate_semispace, SemiSpace, SemiSpaceOptions},
+    };
+
+    struct Node {
+        value: usize,
+        next: Option<Gc<Node, SemiSpace>>,
+    }
+
+    unsafe impl Trace for Node {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.next.trace(vis);
+        }
+    }
+    unsafe impl Finalize for Node {}
+    impl Collectable for Node {}
+
+    #[test]
+    fn traced_pointers_are_refreshed() {
+        let mut mutator = instantiate_semispace(SemiSpaceOptions::default());
+        let stack = mutator.shadow_stack();
+        let next = mutator.allocate(
+            Node {
+                value: 1,
+                next: None,
+            },
+            AllocationSpace::New,
+        );
+        letroot!(
+            node = stack,
+            mutator.allocate(
+                Node {
+                    value: 2,
+                    next: Some(next),
+                },
+                AllocationSpace::New,
+            )
+        );
+        mutator.collect(&mut []);
+        assert_eq!(node.value, 2);
+        assert_eq!(node.next.unwrap().value, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Stale GC pointer")]
+    fn stale_pointer_is_reported() {
+        let mut mutator = instantiate_semispace(SemiSpaceOptions::default());
+        let stack = mutator.shadow_stack();
+        letroot!(
+            node = stack,
+            mutator.allocate(
+                Node {
+                    value: 1,
+                    next: None
+                },
+                AllocationSpace::New
+            )
+        );
+        let stale = *node;
+        mutator.collect(&mut []);
+        assert_eq!(node.value, 1);
+        let _ = stale.value;
+    }
+}
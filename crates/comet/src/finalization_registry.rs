@@ -0,0 +1,160 @@
+//! # Finalization registry
+//!
+//! Implements semantics of JS `FinalizationRegistry`: mutator registers `(target, held)` pair and once GC finds
+//! `target` dead `held` value is pushed to per-heap cleanup queue. Held values are kept alive by the registry so
+//! cleanup callbacks can use them. Callbacks are invoked by mutators outside of GC pause (see
+//! [MutatorRef::drain_cleanup_queue](crate::mutator::MutatorRef::drain_cleanup_queue)) and are allowed to allocate.
+use crate::{
+    api::{Collectable, Gc, HeapObjectHeader, Trace, Visitor},
+    gc_base::GcBase,
+};
+use std::{collections::VecDeque, ptr::NonNull};
+
+struct FinalizationEntry<H: GcBase> {
+    target: NonNull<HeapObjectHeader>,
+    held: Gc<dyn Collectable, H>,
+}
+
+/// Registered targets and cleanup queue of GC heap. Must be protected by heap lock.
+pub struct FinalizationRegistry<H: GcBase> {
+    entries: Vec<FinalizationEntry<H>>,
+    cleanup_queue: VecDeque<Gc<dyn Collectable, H>>,
+}
+
+impl<H: GcBase> FinalizationRegistry<H> {
+    pub fn new() -> Self {
+        Self {
+            entries: vec![],
+            cleanup_queue: VecDeque::new(),
+        }
+    }
+
+    /// Register `held` value that is pushed to cleanup queue once `target` is dead.
+    pub fn register<T: Collectable + ?Sized, U: Collectable + ?Sized>(
+        &mut self,
+        target: Gc<T, H>,
+        held: Gc<U, H>,
+    ) {
+        self.entries.push(FinalizationEntry {
+            target: target.base,
+            held: Gc {
+                base: held.base,
+                marker: Default::default(),
+                epoch: held.epoch,
+            },
+        });
+    }
+
+    /// Pop held value of dead target from cleanup queue.
+    pub fn pop_cleanup(&mut self) -> Option<Gc<dyn Collectable, H>> {
+        self.cleanup_queue.pop_front()
+    }
+
+    /// Number of held values in cleanup queue.
+    pub fn pending_cleanups(&self) -> usize {
+        self.cleanup_queue.len()
+    }
+
+    /// # NOT FOR USE BY REGULAR CODE, ONLY FOR GC IMPLEMENTATIONS!
+    ///
+    /// Must be invoked after marking to find dead targets. `process` returns new address of live target or null
+    /// if target is dead. Held values of dead targets are moved to cleanup queue.
+    pub unsafe fn after_mark(
+        &mut self,
+        mut process: impl FnMut(*mut HeapObjectHeader) -> *mut HeapObjectHeader,
+    ) {
+        let cleanup_queue = &mut self.cleanup_queue;
+        self.entries
+            .retain_mut(|entry| match NonNull::new(process(entry.target.as_ptr())) {
+                Some(target) => {
+                    entry.target = target;
+                    true
+                }
+                None => {
+                    cleanup_queue.push_back(entry.held);
+                    false
+                }
+            });
+    }
+}
+
+impl<H: GcBase> Default for FinalizationRegistry<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Held values and cleanup queue are roots, targets are weak.
+unsafe impl<H: GcBase> Trace for FinalizationRegistry<H> {
+    fn trace(&mut self, vis: &mut dyn Visitor) {
+        for entry in self.entries.iter_mut() {
+            entry.held.trace(vis);
+        }
+        for held in self.cleanup_queue.iter_mut() {
+            held.trace(vis);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        api::{Collectable, Finalize, Gc, Trace, Visitor},
+        gc_base::AllocationSpace,
+        immix::{instantiate_immix, Immix, ImmixOptions},
+        letroot,
+        mutator::MutatorRef,
+    };
+
+    struct Node {
+        value: usize,
+        next: Option<Gc<Node, Immix>>,
+    }
+
+    unsafe impl Trace for Node {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.next.trace(vis);
+        }
+    }
+    unsafe impl Finalize for Node {}
+    impl Collectable for Node {}
+
+    fn node(mutator: &mut MutatorRef<Immix>, value: usize) -> Gc<Node, Immix> {
+        mutator.allocate(Node { value, next: None }, AllocationSpace::New)
+    }
+
+    #[inline(never)]
+    fn register_dead_targets(mutator: &mut MutatorRef<Immix>) {
+        for i in 0..100 {
+            let target = node(mutator, i);
+            let held = node(mutator, i);
+            mutator.register_finalization(target, held);
+        }
+    }
+
+    #[test]
+    fn held_values_of_dead_targets_are_queued() {
+        let mut mutator = instantiate_immix::<crate::gc_base::NoOpStackDecoder>(
+            ImmixOptions::default().with_heap_size(16 * 1024 * 1024),
+        );
+        let stack = mutator.shadow_stack();
+        letroot!(target = stack, node(&mut mutator, 1000));
+        let held = node(&mut mutator, 1000);
+        mutator.register_finalization(*target, held);
+        register_dead_targets(&mut mutator);
+        mutator.collect(&mut []);
+
+        let mut values = vec![];
+        mutator.drain_cleanup_queue(|mutator, held| {
+            // callbacks are allowed to allocate
+            let mut copy = node(mutator, 0);
+            copy.next = Some(held.downcast::<Node>().unwrap());
+            values.push(copy.next.unwrap().value);
+        });
+        // few targets might be kept alive by conservative stack scanning.
+        assert!(values.len() >= 90);
+        assert!(values.iter().all(|value| *value < 100));
+        assert_eq!(mutator.drain_cleanup_queue(|_, _| ()), 0);
+        assert_eq!(target.value, 1000);
+    }
+}
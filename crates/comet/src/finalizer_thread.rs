@@ -0,0 +1,329 @@
+//! # Background finalizer thread
+//!
+//! When enabled, collectors do not invoke [Finalize::finalize](crate::api::Finalize::finalize) in the GC pause for
+//! types that opt in with [Finalize::finalize_on_thread](crate::api::Finalize::finalize_on_thread). Dead object is queued
+//! for finalization on finalizer thread and stays in place: collector treats its memory as live until finalizer has run
+//! and releases it in the next GC cycle.
+//!
+//! Opting in requires [FinalizeSend](crate::api::FinalizeSend), so only `Send` types are finalized on the finalizer
+//! thread. Objects of other types are finalized in the GC pause as usual.
+use crate::api::HeapObjectHeader;
+use parking_lot::{Condvar, Mutex};
+use std::{
+    collections::HashSet,
+    sync::{mpsc, Arc},
+    thread::JoinHandle,
+};
+
+/// Dead object queued for finalization.
+struct Finalizable(*mut HeapObjectHeader);
+
+// Object is dead and its type implements `FinalizeSend`, only finalizer thread accesses it until its finalizer has run.
+unsafe impl Send for Finalizable {}
+
+#[derive(Default)]
+struct Queue {
+    /// Objects whose finalizer has not run yet.
+    pending: HashSet<usize>,
+    /// Objects that were finalized but their memory is not released yet.
+    finished: Vec<usize>,
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    finished: Condvar,
+}
+
+/// Per-heap finalizer thread. Dropping it waits until all queued objects are finalized.
+pub struct FinalizerThread {
+    sender: Option<mpsc::Sender<Finalizable>>,
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FinalizerThread {
+    /// Spawn finalizer thread.
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel::<Finalizable>();
+        let shared = Arc::new(Shared::default());
+        let thread = {
+            let shared = shared.clone();
+            std::thread::Builder::new()
+                .name("comet-finalizer".to_string())
+                .spawn(move || {
+                    while let Ok(Finalizable(object)) = receiver.recv() {
+                        unsafe {
+                            (*object).get_dyn().finalize();
+                        }
+                        let mut queue = shared.queue.lock();
+                        queue.pending.remove(&(object as usize));
+                        queue.finished.push(object as usize);
+                        if queue.pending.is_empty() {
+                            shared.finished.notify_all();
+                        }
+                    }
+                })
+                .expect("failed to spawn finalizer thread")
+        };
+        Self {
+            sender: Some(sender),
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    /// Queue dead `object` for finalization. Memory of `object` must be kept until [FinalizerThread::process] reports
+    /// it as finalized.
+    ///
+    /// # Safety
+    ///
+    /// `object` must be dead, must not be finalized by GC and its type must implement [FinalizeSend](crate::api::FinalizeSend).
+    pub unsafe fn enqueue(&self, object: *mut HeapObjectHeader) {
+        self.shared.queue.lock().pending.insert(object as usize);
+        if let Some(sender) = self.sender.as_ref() {
+            let _ = sender.send(Finalizable(object));
+        }
+    }
+
+    /// Invoke `keep` on every object whose finalizer has not run yet and `release` on every object that was finalized
+    /// since the last call. Collectors call it in GC pause before sweeping: memory of kept objects must survive the cycle,
+    /// memory of released objects can be freed.
+    pub fn process(
+        &self,
+        mut keep: impl FnMut(*mut HeapObjectHeader),
+        mut release: impl FnMut(*mut HeapObjectHeader),
+    ) {
+        let mut queue = self.shared.queue.lock();
+        for &object in queue.pending.iter() {
+            keep(object as _);
+        }
+        for object in queue.finished.drain(..) {
+            release(object as _);
+        }
+    }
+
+    /// Number of objects that are queued for finalization.
+    pub fn pending(&self) -> usize {
+        self.shared.queue.lock().pending.len()
+    }
+
+    /// Block current thread until all queued finalizers have run.
+    pub fn wait(&self) {
+        let mut queue = self.shared.queue.lock();
+        while !queue.pending.is_empty() {
+            self.shared.finished.wait(&mut queue);
+        }
+    }
+}
+
+/// Finalize dead `object` in place or queue it for finalization on `finalizer` thread if there is one and object type
+/// opted in. Returns true if object was queued, its memory must then be kept until [FinalizerThread::process] releases it.
+///
+/// # Safety
+///
+/// `object` must be dead and must not be finalized yet.
+pub unsafe fn finalize(finalizer: Option<&FinalizerThread>, object: *mut HeapObjectHeader) -> bool {
+    match finalizer {
+        Some(finalizer) if (*object).finalize_on_thread_bit() => {
+            finalizer.enqueue(object);
+            true
+        }
+        _ => {
+            (*object).get_dyn().finalize();
+            false
+        }
+    }
+}
+
+impl Default for FinalizerThread {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for FinalizerThread {
+    fn drop(&mut self) {
+        // closing channel stops finalizer thread once all queued objects are finalized.
+        drop(self.sender.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        api::{Collectable, Finalize, FinalizeOnThread, FinalizeSend, Trace},
+        gc_base::AllocationSpace,
+        immix::{instantiate_immix, Immix, ImmixOptions},
+        mutator::MutatorRef,
+    };
+    use std::{
+        rc::Rc,
+        sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    };
+
+    static FINALIZED: AtomicUsize = AtomicUsize::new(0);
+    static FINALIZED_IN_PAUSE: AtomicUsize = AtomicUsize::new(0);
+
+    struct Resource(usize);
+
+    impl Drop for Resource {
+        fn drop(&mut self) {
+            assert_eq!(self.0, 42);
+            if std::thread::current().name() == Some("comet-finalizer") {
+                FINALIZED.fetch_add(1, Ordering::SeqCst);
+            } else {
+                FINALIZED_IN_PAUSE.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    unsafe impl Trace for Resource {}
+    unsafe impl Finalize for Resource {
+        fn finalize_on_thread() -> Option<FinalizeOnThread<Self>> {
+            Some(FinalizeOnThread::new())
+        }
+    }
+    impl Collectable for Resource {}
+    impl FinalizeSend for Resource {}
+
+    #[inline(never)]
+    fn allocate_garbage(mutator: &mut MutatorRef<Immix>) {
+        for _ in 0..100 {
+            mutator.allocate(Resource(42), AllocationSpace::New);
+        }
+    }
+
+    #[test]
+    fn dead_objects_are_finalized_by_finalizer_thread() {
+        let mut mutator = instantiate_immix::<crate::gc_base::NoOpStackDecoder>(
+            ImmixOptions::default()
+                .with_heap_size(16 * 1024 * 1024)
+                .with_finalizer_thread(true),
+        );
+        allocate_garbage(&mut mutator);
+        mutator.collect(&mut []);
+        mutator.wait_for_pending_finalizers();
+        // few objects might be kept alive by conservative stack scanning.
+        assert!(FINALIZED.load(Ordering::SeqCst) >= 90);
+        assert_eq!(FINALIZED_IN_PAUSE.load(Ordering::SeqCst), 0);
+    }
+
+    static GATE: AtomicBool = AtomicBool::new(false);
+    static INTACT: AtomicUsize = AtomicUsize::new(0);
+    static CORRUPTED: AtomicUsize = AtomicUsize::new(0);
+
+    /// Remembers its own address, finalizer blocks until `GATE` is open.
+    struct Registered {
+        value: usize,
+        address: usize,
+    }
+
+    impl Drop for Registered {
+        fn drop(&mut self) {
+            while !GATE.load(Ordering::Acquire) {
+                std::thread::yield_now();
+            }
+            if self.value == 42 && self.address == self as *const Self as usize {
+                INTACT.fetch_add(1, Ordering::SeqCst);
+            } else {
+                CORRUPTED.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    unsafe impl Trace for Registered {}
+    unsafe impl Finalize for Registered {
+        fn finalize_on_thread() -> Option<FinalizeOnThread<Self>> {
+            Some(FinalizeOnThread::new())
+        }
+    }
+    impl Collectable for Registered {}
+    impl FinalizeSend for Registered {}
+
+    #[inline(never)]
+    fn allocate_registered(mutator: &mut MutatorRef<Immix>) {
+        for _ in 0..100 {
+            let mut object = mutator.allocate(
+                Registered {
+                    value: 42,
+                    address: 0,
+                },
+                AllocationSpace::New,
+            );
+            object.address = &*object as *const Registered as usize;
+        }
+    }
+
+    struct Filler([usize; 4]);
+
+    unsafe impl Trace for Filler {}
+    unsafe impl Finalize for Filler {}
+    impl Collectable for Filler {}
+
+    #[inline(never)]
+    fn allocate_filler(mutator: &mut MutatorRef<Immix>) {
+        for _ in 0..10000 {
+            mutator.allocate(Filler([usize::MAX; 4]), AllocationSpace::New);
+        }
+    }
+
+    #[test]
+    fn memory_is_kept_until_finalizer_has_run() {
+        let mut mutator = instantiate_immix::<crate::gc_base::NoOpStackDecoder>(
+            ImmixOptions::default()
+                .with_heap_size(16 * 1024 * 1024)
+                .with_finalizer_thread(true),
+        );
+        allocate_registered(&mut mutator);
+        mutator.collect(&mut []);
+        // finalizers are blocked, memory of queued objects must not be handed to allocator meanwhile.
+        for _ in 0..3 {
+            allocate_filler(&mut mutator);
+            mutator.collect(&mut []);
+        }
+        GATE.store(true, Ordering::Release);
+        mutator.wait_for_pending_finalizers();
+        assert!(INTACT.load(Ordering::SeqCst) >= 90);
+        assert_eq!(CORRUPTED.load(Ordering::SeqCst), 0);
+    }
+
+    static LOCAL_FINALIZED_IN_PAUSE: AtomicUsize = AtomicUsize::new(0);
+
+    /// Not `Send` so it can't opt in to finalizer thread.
+    struct Local(Rc<()>);
+
+    impl Drop for Local {
+        fn drop(&mut self) {
+            if std::thread::current().name() != Some("comet-finalizer") {
+                LOCAL_FINALIZED_IN_PAUSE.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    unsafe impl Trace for Local {}
+    unsafe impl Finalize for Local {}
+    impl Collectable for Local {}
+
+    #[inline(never)]
+    fn allocate_local(mutator: &mut MutatorRef<Immix>) {
+        for _ in 0..100 {
+            mutator.allocate(Local(Rc::new(())), AllocationSpace::New);
+        }
+    }
+
+    #[test]
+    fn types_without_opt_in_are_finalized_in_pause() {
+        let mut mutator = instantiate_immix::<crate::gc_base::NoOpStackDecoder>(
+            ImmixOptions::default()
+                .with_heap_size(16 * 1024 * 1024)
+                .with_finalizer_thread(true),
+        );
+        allocate_local(&mut mutator);
+        mutator.collect(&mut []);
+        assert!(LOCAL_FINALIZED_IN_PAUSE.load(Ordering::SeqCst) >= 90);
+    }
+}
@@ -7,10 +7,14 @@ use std::{
 };
 
 use crate::{
-    api::{Collectable, Gc, HeapObjectHeader, Trace, Visitor, Weak},
+    api::{soft_ref_clock, Collectable, Gc, HeapObjectHeader, Soft, Trace, Visitor, Weak},
+    heap_limit::NearHeapLimitCallback,
+    heap_snapshot::{report_shadow_stacks, RootSource},
     mutator::{Mutator, MutatorRef},
+    observer::GcObserver,
     rosalloc_space::RosAllocSpace,
     safepoint::GlobalSafepoint,
+    stats::HeapStats,
 };
 
 #[repr(u8)]
@@ -23,6 +27,37 @@ pub enum AllocationSpace {
 
 pub struct NoHelp;
 
+/// Error returned by fallible allocation functions when heap is exhausted even after emergency GC cycle. Value
+/// that was not allocated is given back.
+pub struct AllocError<T> {
+    value: T,
+}
+
+impl<T> AllocError<T> {
+    pub fn new(value: T) -> Self {
+        Self { value }
+    }
+
+    /// Returns value that was not allocated.
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+impl<T> std::fmt::Debug for AllocError<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AllocError").finish_non_exhaustive()
+    }
+}
+
+impl<T> std::fmt::Display for AllocError<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "out of memory")
+    }
+}
+
+impl<T> std::error::Error for AllocError<T> {}
+
 /// Base trait for all GCs.
 pub trait GcBase: Sized + 'static {
     /// Default large object size. If allocation request exceeds this constant [GcBase::allocate_large] is invoked.
@@ -40,7 +75,20 @@ pub trait GcBase: Sized + 'static {
         let _ = f;
         false
     }
+    /// Report roots of heap grouped by their source. Used by [heap_snapshot](crate::heap_snapshot) and
+    /// [verify](crate::verify), default implementation reports shadow stacks of mutators only.
+    ///
+    /// # Safety
+    ///
+    /// Must be invoked only when all mutators are suspended.
+    unsafe fn snapshot_roots(
+        &mut self,
+        report: &mut dyn FnMut(&RootSource, *mut HeapObjectHeader),
+    ) {
+        report_shadow_stacks(self.mutators(), report);
+    }
     /// Allocates `size` bytes on heap and creates object header with `type_id` and `vtable`. This function can be used to allocate dyn sized arrays or strings.
+    /// Returns null if heap is exhausted even after emergency GC cycle.
     fn allocate_raw(
         &mut self,
         mutator: &mut MutatorRef<Self>,
@@ -54,17 +102,66 @@ pub trait GcBase: Sized + 'static {
         let _ = vtable;
         todo!()
     }
-    /// Allocates weak reference on GC heap
+    /// Allocates weak reference on GC heap. Returns referent back if heap is exhausted.
     fn allocate_weak<T: Collectable + ?Sized>(
         &mut self,
         _mutator: &mut MutatorRef<Self>,
         _value: Gc<T, Self>,
-    ) -> Weak<T, Self> {
+    ) -> Result<Weak<T, Self>, AllocError<Gc<T, Self>>> {
         panic!(
             "Weak references are not supported by `{}`",
             std::any::type_name::<Self>()
         );
     }
+    /// Allocates soft reference on GC heap
+    fn allocate_soft<T: Collectable + ?Sized>(
+        &mut self,
+        _mutator: &mut MutatorRef<Self>,
+        _value: Gc<T, Self>,
+    ) -> Soft<T, Self> {
+        panic!(
+            "Soft references are not supported by `{}`",
+            std::any::type_name::<Self>()
+        );
+    }
+    /// Register `held` value that is pushed to cleanup queue once `target` is found dead by GC.
+    /// See [FinalizationRegistry](crate::finalization_registry::FinalizationRegistry).
+    fn register_finalization<T: Collectable + ?Sized, U: Collectable + ?Sized>(
+        &mut self,
+        _mutator: &mut MutatorRef<Self>,
+        _target: Gc<T, Self>,
+        _held: Gc<U, Self>,
+    ) {
+        panic!(
+            "Finalization registry is not supported by `{}`",
+            std::any::type_name::<Self>()
+        );
+    }
+    /// Pop held value of dead target from cleanup queue.
+    fn pop_cleanup(&mut self) -> Option<Gc<dyn Collectable, Self>> {
+        None
+    }
+    /// Block current thread until all objects queued for finalization on finalizer thread are finalized. No-op if GC
+    /// does not use finalizer thread.
+    fn wait_for_finalizers(&self) {}
+    /// Returns GC statistics of heap, see [stats](crate::stats).
+    fn stats(&self) -> HeapStats {
+        HeapStats::default()
+    }
+    /// Set callback that is invoked when allocation can't be satisfied because heap reached its growth limit. `None`
+    /// removes callback. See [heap_limit](crate::heap_limit).
+    fn set_near_heap_limit_callback(&mut self, _callback: Option<NearHeapLimitCallback>) {
+        panic!(
+            "Near heap limit callbacks are not supported by `{}`",
+            std::any::type_name::<Self>()
+        );
+    }
+    /// Register observer that is notified at phase boundaries of GC cycles. See [observer](crate::observer). Returns
+    /// false if observers are not supported by the policy.
+    fn add_gc_observer(&mut self, observer: Box<dyn GcObserver>) -> bool {
+        let _ = observer;
+        false
+    }
     fn get_rosalloc_space(&self) -> *mut RosAllocSpace {
         null_mut()
     }
@@ -102,29 +199,31 @@ pub trait GcBase: Sized + 'static {
     /// - Atomic bump-pointer/thread-local bump pointer or atomic freelist/thread-local freelist.
     ///
     /// Bump pointer might be used in Immix or SemiSpace GCs. While freelists might be used in case of Mark&Sweep GC.
+    ///
+    /// Returns `value` back if heap is exhausted even after emergency GC cycle.
     fn alloc_inline<T: Collectable + Sized + 'static>(
         &mut self,
         mutator: &mut MutatorRef<Self>,
         value: T,
         space: AllocationSpace,
-    ) -> Gc<T, Self>;
+    ) -> Result<Gc<T, Self>, AllocError<T>>;
 
     /// Post allocation operation e.g set mark in bitmap that this object was allocated.
     ///
     /// Restrictions for this function:
-    /// - Must not acquire any mutex locks when `needs_drop::<T>()` returns false
+    /// - Must not acquire any mutex locks when `T::needs_finalization()` returns false
     /// - Must not do CPU heavy operations
-    /// - Must put `value` to finalizer list if `needs_drop::<T>()` returns true
+    /// - Must record `value` as finalizable (header bit or finalizer list) if `T::needs_finalization()` returns true
     #[inline(always)]
     fn post_alloc<T: Collectable + Sized + 'static>(&mut self, value: Gc<T, Self>) {
         let _ = value;
     }
-    /// Allocates large object in GC heap.
+    /// Allocates large object in GC heap. Returns `value` back if memory for it can't be allocated.
     fn allocate_large<T: Collectable + Sized + 'static>(
         &mut self,
         mutator: &mut MutatorRef<Self>,
         value: T,
-    ) -> Gc<T, Self>;
+    ) -> Result<Gc<T, Self>, AllocError<T>>;
 
     /// Perform minor GC cycle by stopping all threads and collecting unused memory.
     fn minor_collection(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
@@ -136,6 +235,11 @@ pub trait GcBase: Sized + 'static {
     }
     /// Perform garbage collection cycle by stopping all threads and collecting unused memory.
     fn collect(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]);
+    /// Perform GC cycle forced by stress mode, see [stress](crate::stress). Moving policies should move as many
+    /// objects as they can. By default performs [GcBase::collect].
+    fn stress_collection(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
+        self.collect(mutator, keep);
+    }
 
     /// Write barrier implementation. No-op by default.
     fn write_barrier(&mut self, mutator: &mut MutatorRef<Self>, object: Gc<dyn Collectable, Self>) {
@@ -148,6 +252,58 @@ pub trait GcBase: Sized + 'static {
     }
 }
 
+/// Decides which soft references are cleared in GC cycle, similar to `SoftRefLRUPolicyMSPerMB` of HotSpot.
+///
+/// Soft references are kept unless GC cycle is started because of allocation failure or heap usage is near its growth
+/// limit. In such cycles soft reference is cleared if it was not accessed for more than `ms_per_mb` milliseconds
+/// per megabyte of free heap.
+#[derive(Clone, Copy, Debug)]
+pub struct SoftRefPolicy {
+    pub ms_per_mb: u64,
+}
+
+impl SoftRefPolicy {
+    /// Heap is near its growth limit when it uses this fraction of limit.
+    pub const NEAR_GROWTH_LIMIT: f64 = 0.9;
+
+    pub const fn new(ms_per_mb: u64) -> Self {
+        Self { ms_per_mb }
+    }
+
+    /// Decide which soft references are kept alive in current GC cycle. `used` is number of bytes allocated in heap.
+    ///
+    /// # Safety
+    ///
+    /// Must be invoked before marking, `soft_refs` must point to live soft references.
+    pub unsafe fn prepare<H: GcBase>(
+        &self,
+        soft_refs: &[Soft<dyn Collectable, H>],
+        alloc_failure: bool,
+        used: usize,
+        growth_limit: usize,
+    ) {
+        let near_limit = used as f64 >= growth_limit as f64 * Self::NEAR_GROWTH_LIMIT;
+        let max_age = if alloc_failure || near_limit {
+            let free_mb = (growth_limit.saturating_sub(used) / (1024 * 1024)) as u64;
+            Some(free_mb * self.ms_per_mb)
+        } else {
+            None
+        };
+        let now = soft_ref_clock();
+        for soft in soft_refs {
+            soft.set_keep(max_age.map_or(true, |max_age| {
+                now.saturating_sub(soft.timestamp()) <= max_age
+            }));
+        }
+    }
+}
+
+impl Default for SoftRefPolicy {
+    fn default() -> Self {
+        Self::new(1000)
+    }
+}
+
 /// Thread local allocation buffer. Instances of TLAB usually store write barrier buffers and thread local allocators.
 pub trait TLAB<H: GcBase<TLAB = Self>> {
     /// Can we allocate `size` bytes in thread local buffer?
@@ -215,6 +371,11 @@ pub trait ReadBarrier<H: GcBase>: Sized + 'static {
     fn read_barrier<T: Collectable + ?Sized>(x: Gc<T, H>) -> Gc<T, H> {
         x
     }
+    /// Resolve object before it is mutated. GCs that move objects concurrently with mutators might copy object
+    /// there so that store is not lost. By default it is the same as [ReadBarrier::read_barrier].
+    fn resolve_for_write<T: Collectable + ?Sized>(x: Gc<T, H>) -> Gc<T, H> {
+        Self::read_barrier(x)
+    }
 }
 
 pub struct NoReadBarrier;
@@ -239,6 +400,7 @@ impl<H: GcBase> ReadBarrier<H> for BrooksPointer {
             Gc {
                 base: NonNull::new_unchecked((*base).load(atomic::Ordering::Acquire) as _),
                 marker: PhantomData,
+                epoch: x.epoch,
             }
         }
     }
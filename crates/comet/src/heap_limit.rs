@@ -0,0 +1,179 @@
+//! Near heap limit callbacks.
+//!
+//! Heap that supports growth limit invokes registered callback when allocation can't be satisfied even after GC
+//! cycle because heap footprint reached its growth limit. Callback decides what happens next: heap limit might be
+//! raised, embedder might release its caches and ask for one more GC cycle or allocation might be failed. Failed
+//! allocation is reported to mutator as [AllocError](crate::gc_base::AllocError) by fallible allocation functions
+//! (e.g [MutatorRef::try_allocate](crate::mutator::MutatorRef::try_allocate)), which allows to kill single script
+//! instead of aborting the whole process.
+//!
+//! When heap is created with near heap limit threshold callback is also invoked after every GC cycle whose target
+//! footprint exceeds given fraction of growth limit, so embedder can raise the limit or release its caches before
+//! allocations start to fail. [HeapLimit::request_size] is zero in that case, [HeapLimitAction::Raise] is applied
+//! while [HeapLimitAction::Collect] and [HeapLimitAction::Fail] have no effect: released objects are reclaimed by the
+//! next cycle.
+//!
+//! Callback is invoked by mutator thread outside of GC pause under global heap lock. It ***must not*** allocate on
+//! GC heap, trigger GC cycle or wait for other mutators.
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Heap usage at the moment when near heap limit callback is invoked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeapLimit {
+    /// Number of bytes allocated in heap.
+    pub bytes_allocated: usize,
+    /// Size of allocation request that can't be satisfied, zero if callback is invoked because target footprint
+    /// exceeds near heap limit threshold.
+    pub request_size: usize,
+    /// Target footprint chosen after the last GC cycle.
+    pub target_footprint: usize,
+    /// Current growth limit of heap.
+    pub current_limit: usize,
+    /// Growth limit heap was created with.
+    pub initial_limit: usize,
+    /// Maximal value growth limit can be raised to.
+    pub capacity: usize,
+}
+
+/// Action requested by near heap limit callback.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeapLimitAction {
+    /// Raise growth limit to the given number of bytes and retry allocation. Limit is clamped to heap capacity,
+    /// allocation fails if limit is not increased.
+    Raise(usize),
+    /// Perform one more GC cycle and retry allocation. Useful when callback released references to cached objects.
+    /// Callback is invoked again if allocation still fails.
+    Collect,
+    /// Fail allocation.
+    Fail,
+}
+
+pub type NearHeapLimitCallback = Box<dyn FnMut(&HeapLimit) -> HeapLimitAction + Send>;
+
+/// Storage for near heap limit callback of a heap.
+pub struct NearHeapLimit {
+    callback: Option<NearHeapLimitCallback>,
+    initial_limit: usize,
+    threshold: Option<f64>,
+}
+
+impl NearHeapLimit {
+    /// Create storage for heap with `initial_limit` growth limit. Callback is invoked after GC cycles whose target
+    /// footprint exceeds `threshold` fraction of growth limit, `None` disables it.
+    pub fn new(initial_limit: usize, threshold: Option<f64>) -> Self {
+        Self {
+            callback: None,
+            initial_limit,
+            threshold,
+        }
+    }
+
+    /// Set fraction of growth limit that target footprint must exceed after GC cycle for callback to be invoked.
+    /// `None` disables it.
+    pub fn set_threshold(&mut self, threshold: Option<f64>) {
+        self.threshold = threshold;
+    }
+
+    /// Set callback that is invoked when heap reaches its growth limit. `None` removes callback.
+    pub fn set_callback(&mut self, callback: Option<NearHeapLimitCallback>) {
+        self.callback = callback;
+    }
+
+    /// Invoke callback because allocation of `request_size` bytes can't be satisfied. [HeapLimitAction::Raise] is
+    /// applied to `growth_limit` and is turned into [HeapLimitAction::Fail] if limit can't be increased. Returns
+    /// [HeapLimitAction::Fail] if no callback is registered.
+    pub fn invoke(
+        &mut self,
+        bytes_allocated: usize,
+        request_size: usize,
+        target_footprint: usize,
+        growth_limit: &AtomicUsize,
+        capacity: usize,
+    ) -> HeapLimitAction {
+        let callback = match self.callback.as_mut() {
+            Some(callback) => callback,
+            None => return HeapLimitAction::Fail,
+        };
+        let current_limit = growth_limit.load(Ordering::Relaxed);
+        let action = callback(&HeapLimit {
+            bytes_allocated,
+            request_size,
+            target_footprint,
+            current_limit,
+            initial_limit: self.initial_limit,
+            capacity,
+        });
+        match action {
+            HeapLimitAction::Raise(limit) => {
+                let limit = limit.min(capacity);
+                if limit <= current_limit {
+                    return HeapLimitAction::Fail;
+                }
+                growth_limit.store(limit, Ordering::Relaxed);
+                HeapLimitAction::Raise(limit)
+            }
+            action => action,
+        }
+    }
+
+    /// Invoke callback if `target_footprint` chosen after GC cycle exceeds threshold fraction of `growth_limit`.
+    /// Only [HeapLimitAction::Raise] has effect.
+    pub fn check_target_footprint(
+        &mut self,
+        bytes_allocated: usize,
+        target_footprint: usize,
+        growth_limit: &AtomicUsize,
+        capacity: usize,
+    ) {
+        let threshold = match self.threshold {
+            Some(threshold) if self.callback.is_some() => threshold,
+            _ => return,
+        };
+        if (target_footprint as f64) < growth_limit.load(Ordering::Relaxed) as f64 * threshold {
+            return;
+        }
+        self.invoke(bytes_allocated, 0, target_footprint, growth_limit, capacity);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raised_limit_is_clamped_to_capacity() {
+        let mut near_heap_limit = NearHeapLimit::new(100, None);
+        let growth_limit = AtomicUsize::new(100);
+        assert_eq!(
+            near_heap_limit.invoke(100, 8, 100, &growth_limit, 150),
+            HeapLimitAction::Fail
+        );
+        near_heap_limit.set_callback(Some(Box::new(|limit| {
+            HeapLimitAction::Raise(limit.current_limit * 2)
+        })));
+        assert_eq!(
+            near_heap_limit.invoke(100, 8, 100, &growth_limit, 150),
+            HeapLimitAction::Raise(150)
+        );
+        assert_eq!(growth_limit.load(Ordering::Relaxed), 150);
+        // limit is already equal to capacity
+        assert_eq!(
+            near_heap_limit.invoke(150, 8, 150, &growth_limit, 150),
+            HeapLimitAction::Fail
+        );
+    }
+
+    #[test]
+    fn callback_is_invoked_when_target_exceeds_threshold() {
+        let mut near_heap_limit = NearHeapLimit::new(100, Some(0.8));
+        let growth_limit = AtomicUsize::new(100);
+        near_heap_limit.set_callback(Some(Box::new(|limit| {
+            assert_eq!(limit.request_size, 0);
+            HeapLimitAction::Raise(limit.current_limit + 50)
+        })));
+        near_heap_limit.check_target_footprint(50, 79, &growth_limit, 1000);
+        assert_eq!(growth_limit.load(Ordering::Relaxed), 100);
+        near_heap_limit.check_target_footprint(50, 80, &growth_limit, 1000);
+        assert_eq!(growth_limit.load(Ordering::Relaxed), 150);
+    }
+}
@@ -0,0 +1,446 @@
+//! Heap snapshots in Chrome DevTools `.heapsnapshot` format.
+//!
+//! [HeapSnapshot::take] walks every object reported by [GcBase::inspect], records edges of each object by tracing it
+//! with recording visitor and groups roots by their source ([GcBase::snapshot_roots]). Written snapshot can be loaded
+//! into Memory panel of Chrome DevTools. Node names come from [Collectable::type_name](crate::api::Collectable::type_name)
+//! and sizes from [Gc::allocation_size](crate::api::Gc::allocation_size). Use [MutatorRef::heap_snapshot](crate::mutator::MutatorRef::heap_snapshot) to take snapshot
+//! in STW pause.
+//!
+//! Only Immix reports native stacks, marking constraints and finalization registry as roots, other policies report
+//! shadow stacks only. Objects reachable only from these roots are still written but appear unreachable in DevTools.
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    ptr::NonNull,
+};
+
+use crate::{
+    api::{EphemeronEntry, HeapObjectHeader, Visitor},
+    gc_base::GcBase,
+    mutator::Mutator,
+};
+
+/// Source of GC roots in heap snapshot.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RootSource {
+    /// Shadow stack of n-th mutator.
+    ShadowStack(usize),
+    /// Conservatively scanned native stack of n-th mutator.
+    ConservativeStack(usize),
+    /// Marking constraint with the given name.
+    Constraint(String),
+    /// Held values of finalization registry.
+    FinalizationRegistry,
+}
+
+impl RootSource {
+    fn name(&self) -> String {
+        match self {
+            Self::ShadowStack(mutator) => format!("(Shadow stack of mutator {})", mutator),
+            Self::ConservativeStack(mutator) => format!("(Native stack of mutator {})", mutator),
+            Self::Constraint(name) => format!("(Marking constraint {})", name),
+            Self::FinalizationRegistry => "(Finalization registry)".to_string(),
+        }
+    }
+}
+
+/// Visitor that reports objects it is invoked on as roots of `source`. Weak references and ephemerons are not roots.
+pub struct RootVisitor<'a> {
+    source: RootSource,
+    report: &'a mut dyn FnMut(&RootSource, *mut HeapObjectHeader),
+}
+
+impl<'a> RootVisitor<'a> {
+    pub fn new(
+        source: RootSource,
+        report: &'a mut dyn FnMut(&RootSource, *mut HeapObjectHeader),
+    ) -> Self {
+        Self { source, report }
+    }
+}
+
+impl Visitor for RootVisitor<'_> {
+    fn mark_object(&mut self, root: &mut NonNull<HeapObjectHeader>) {
+        (self.report)(&self.source, root.as_ptr());
+    }
+    fn mark_weak(&mut self, _root: &mut NonNull<HeapObjectHeader>) {}
+    fn mark_ephemeron(&mut self, _ephemeron: &mut EphemeronEntry) {}
+}
+
+/// Report objects referenced from shadow stacks of `mutators`.
+///
+/// # Safety
+///
+/// Must be invoked only when all mutators are suspended.
+pub unsafe fn report_shadow_stacks<H: GcBase>(
+    mutators: &[*mut Mutator<H>],
+    report: &mut dyn FnMut(&RootSource, *mut HeapObjectHeader),
+) {
+    for (i, &mutator) in mutators.iter().enumerate() {
+        let mut visitor = RootVisitor::new(RootSource::ShadowStack(i), report);
+        (*mutator)
+            .shadow_stack()
+            .walk(|entry| entry.trace(&mut visitor));
+    }
+}
+
+// Indices into `node_types` and `edge_types` of snapshot meta.
+const NODE_OBJECT: usize = 3;
+const NODE_SYNTHETIC: usize = 9;
+const EDGE_ELEMENT: usize = 1;
+const EDGE_WEAK: usize = 6;
+
+const NODE_FIELDS: usize = 6;
+
+struct Node {
+    kind: usize,
+    name: usize,
+    id: usize,
+    self_size: usize,
+    edge_count: usize,
+}
+
+struct Edge {
+    kind: usize,
+    index: usize,
+    to: usize,
+}
+
+/// Records edges of traced object.
+struct EdgeRecorder<'a> {
+    edges: &'a mut Vec<(usize, *mut HeapObjectHeader)>,
+}
+
+impl Visitor for EdgeRecorder<'_> {
+    fn mark_object(&mut self, root: &mut NonNull<HeapObjectHeader>) {
+        self.edges.push((EDGE_ELEMENT, root.as_ptr()));
+    }
+    fn mark_weak(&mut self, root: &mut NonNull<HeapObjectHeader>) {
+        self.edges.push((EDGE_WEAK, root.as_ptr()));
+    }
+    fn mark_ephemeron(&mut self, ephemeron: &mut EphemeronEntry) {
+        if let Some(mut key) = ephemeron.key() {
+            self.mark_weak(&mut key);
+        }
+        ephemeron.mark_value(self);
+    }
+}
+
+/// Object graph of the heap.
+pub struct HeapSnapshot {
+    nodes: Vec<Node>,
+    edges: Vec<Edge>,
+    strings: Vec<String>,
+    string_ids: HashMap<String, usize>,
+}
+
+impl HeapSnapshot {
+    /// Take snapshot of `heap`. Panics if heap does not support [GcBase::inspect].
+    ///
+    /// # Safety
+    ///
+    /// Must be invoked only when all mutators are suspended.
+    pub unsafe fn take<H: GcBase>(heap: &mut H) -> Self {
+        let mut objects = vec![];
+        if !heap.inspect(|object| {
+            objects.push(object);
+            true
+        }) {
+            panic!(
+                "Heap snapshots are not supported by `{}`",
+                std::any::type_name::<H>()
+            );
+        }
+        let mut roots: Vec<(RootSource, Vec<*mut HeapObjectHeader>)> = vec![];
+        heap.snapshot_roots(&mut |source, object| {
+            if let Some((_, objects)) = roots.iter_mut().find(|(root, _)| root == source) {
+                objects.push(object);
+            } else {
+                roots.push((source.clone(), vec![object]));
+            }
+        });
+
+        let mut this = Self {
+            nodes: vec![],
+            edges: vec![],
+            strings: vec![],
+            string_ids: HashMap::new(),
+        };
+        // synthetic root and "(GC roots)" are followed by one node per root source and then by heap objects.
+        let first_object = 2 + roots.len();
+        let nodes = objects
+            .iter()
+            .enumerate()
+            .map(|(i, object)| (object.base.as_ptr(), first_object + i))
+            .collect::<HashMap<_, _>>();
+        this.add_node(NODE_SYNTHETIC, "", 0);
+        this.add_edges([1].into_iter());
+        this.add_node(NODE_SYNTHETIC, "(GC roots)", 0);
+        this.add_edges(2..first_object);
+        for (source, objects) in roots.iter_mut() {
+            // the same object is often found in many stack slots.
+            objects.sort_unstable();
+            objects.dedup();
+            this.add_node(NODE_SYNTHETIC, &source.name(), 0);
+            this.add_edges(
+                objects
+                    .iter()
+                    .filter_map(|object| nodes.get(object).copied()),
+            );
+        }
+        let mut edges = vec![];
+        for object in objects.iter() {
+            this.add_node(
+                NODE_OBJECT,
+                object.get_dyn().type_name(),
+                object.allocation_size(),
+            );
+            edges.clear();
+            (*object.base.as_ptr())
+                .get_dyn()
+                .trace(&mut EdgeRecorder { edges: &mut edges });
+            for (i, &(kind, to)) in edges.iter().enumerate() {
+                if let Some(&to) = nodes.get(&to) {
+                    // name of weak edge is an index into string table, element edges use plain index.
+                    let index = if kind == EDGE_WEAK {
+                        this.string(&i.to_string())
+                    } else {
+                        i
+                    };
+                    this.nodes.last_mut().unwrap().edge_count += 1;
+                    this.edges.push(Edge { kind, index, to });
+                }
+            }
+        }
+        this
+    }
+
+    fn string(&mut self, string: &str) -> usize {
+        if let Some(&id) = self.string_ids.get(string) {
+            return id;
+        }
+        let id = self.strings.len();
+        self.strings.push(string.to_string());
+        self.string_ids.insert(string.to_string(), id);
+        id
+    }
+
+    fn add_node(&mut self, kind: usize, name: &str, self_size: usize) {
+        let name = self.string(name);
+        self.nodes.push(Node {
+            kind,
+            name,
+            // DevTools expect odd ids for heap objects.
+            id: self.nodes.len() * 2 + 1,
+            self_size,
+            edge_count: 0,
+        });
+    }
+
+    /// Add element edges from last node to `nodes`.
+    fn add_edges(&mut self, nodes: impl Iterator<Item = usize>) {
+        let node = self.nodes.last_mut().unwrap();
+        for (i, to) in nodes.enumerate() {
+            node.edge_count += 1;
+            self.edges.push(Edge {
+                kind: EDGE_ELEMENT,
+                index: i,
+                to,
+            });
+        }
+    }
+
+    /// Number of nodes in snapshot including synthetic root nodes.
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Number of edges in snapshot.
+    pub fn edge_count(&self) -> usize {
+        self.edges.len()
+    }
+
+    /// Write snapshot in `.heapsnapshot` JSON format.
+    pub fn write(&self, mut out: impl Write) -> io::Result<()> {
+        write!(
+            out,
+            "{{\"snapshot\":{{\"meta\":{{\
+             \"node_fields\":[\"type\",\"name\",\"id\",\"self_size\",\"edge_count\",\"trace_node_id\"],\
+             \"node_types\":[[\"hidden\",\"array\",\"string\",\"object\",\"code\",\"closure\",\"regexp\",\"number\",\
+             \"native\",\"synthetic\",\"concatenated string\",\"sliced string\",\"symbol\",\"bigint\"],\
+             \"string\",\"number\",\"number\",\"number\",\"number\"],\
+             \"edge_fields\":[\"type\",\"name_or_index\",\"to_node\"],\
+             \"edge_types\":[[\"context\",\"element\",\"property\",\"internal\",\"hidden\",\"shortcut\",\"weak\"],\
+             \"string_or_number\",\"node\"]}},\
+             \"node_count\":{},\"edge_count\":{},\"trace_function_count\":0}},",
+            self.nodes.len(),
+            self.edges.len()
+        )?;
+        write!(out, "\"nodes\":[")?;
+        for (i, node) in self.nodes.iter().enumerate() {
+            if i != 0 {
+                writeln!(out, ",")?;
+            }
+            write!(
+                out,
+                "{},{},{},{},{},0",
+                node.kind, node.name, node.id, node.self_size, node.edge_count
+            )?;
+        }
+        write!(out, "],\n\"edges\":[")?;
+        for (i, edge) in self.edges.iter().enumerate() {
+            if i != 0 {
+                writeln!(out, ",")?;
+            }
+            write!(
+                out,
+                "{},{},{}",
+                edge.kind,
+                edge.index,
+                edge.to * NODE_FIELDS
+            )?;
+        }
+        write!(
+            out,
+            "],\n\"trace_function_infos\":[],\"trace_tree\":[],\"samples\":[],\"locations\":[],\n\"strings\":["
+        )?;
+        for (i, string) in self.strings.iter().enumerate() {
+            if i != 0 {
+                writeln!(out, ",")?;
+            }
+            write_json_string(&mut out, string)?;
+        }
+        write!(out, "]}}")
+    }
+}
+
+fn write_json_string(out: &mut impl Write, string: &str) -> io::Result<()> {
+    write!(out, "\"")?;
+    for c in string.chars() {
+        match c {
+            '"' => write!(out, "\\\"")?,
+            '\\' => write!(out, "\\\\")?,
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32)?,
+            c => write!(out, "{}", c)?,
+        }
+    }
+    write!(out, "\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::{Collectable, Finalize, Gc, Trace},
+        gc_base::{AllocationSpace, NoOpStackDecoder},
+        immix::{instantiate_immix, Immix, ImmixOptions},
+        letroot,
+        minimark::{instantiate_minimark, MiniMarkOptions},
+    };
+
+    struct Link {
+        next: Option<Gc<Link, Immix>>,
+    }
+
+    unsafe impl Trace for Link {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.next.trace(vis);
+        }
+    }
+    unsafe impl Finalize for Link {}
+    impl Collectable for Link {}
+
+    #[test]
+    fn snapshot_contains_objects_edges_and_roots() {
+        let mut mutator = instantiate_immix::<NoOpStackDecoder>(
+            ImmixOptions::default().with_heap_size(16 * 1024 * 1024),
+        );
+        let stack = mutator.shadow_stack();
+        let tail = mutator.allocate(Link { next: None }, AllocationSpace::New);
+        letroot!(
+            head = stack,
+            mutator.allocate(Link { next: Some(tail) }, AllocationSpace::New)
+        );
+        let snapshot = mutator.heap_snapshot();
+        let name = |node: &Node| snapshot.strings[node.name].as_str();
+        let edges = |index: usize| {
+            let start = snapshot.nodes[..index]
+                .iter()
+                .map(|node| node.edge_count)
+                .sum::<usize>();
+            &snapshot.edges[start..start + snapshot.nodes[index].edge_count]
+        };
+        assert_eq!(name(&snapshot.nodes[1]), "(GC roots)");
+        let shadow_stack = snapshot
+            .nodes
+            .iter()
+            .position(|node| name(node) == "(Shadow stack of mutator 0)")
+            .unwrap();
+        assert_eq!(edges(shadow_stack).len(), 1);
+        let head_node = edges(shadow_stack)[0].to;
+        assert!(name(&snapshot.nodes[head_node]).ends_with("Link"));
+        assert_eq!(snapshot.nodes[head_node].self_size, head.allocation_size());
+        let head_edges = edges(head_node);
+        assert_eq!(head_edges.len(), 1);
+        assert_eq!(head_edges[0].kind, EDGE_ELEMENT);
+        assert_eq!(edges(head_edges[0].to).len(), 0);
+
+        let mut json = vec![];
+        snapshot.write(&mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.starts_with("{\"snapshot\":{\"meta\":"));
+        assert!(json.contains(&format!("\"node_count\":{}", snapshot.node_count())));
+        assert!(json.contains("\"(Shadow stack of mutator 0)\""));
+        assert!(json.ends_with("]}"));
+    }
+
+    struct WeakLink {
+        next: Gc<Link, Immix>,
+        weak: Gc<Link, Immix>,
+    }
+
+    unsafe impl Trace for WeakLink {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.next.trace(vis);
+            vis.mark_weak(&mut self.weak.base);
+        }
+    }
+    unsafe impl Finalize for WeakLink {}
+    impl Collectable for WeakLink {}
+
+    #[test]
+    fn weak_edges_are_named_by_strings() {
+        let mut mutator = instantiate_immix::<NoOpStackDecoder>(
+            ImmixOptions::default().with_heap_size(16 * 1024 * 1024),
+        );
+        let stack = mutator.shadow_stack();
+        let next = mutator.allocate(Link { next: None }, AllocationSpace::New);
+        let weak = mutator.allocate(Link { next: None }, AllocationSpace::New);
+        letroot!(
+            _root = stack,
+            mutator.allocate(WeakLink { next, weak }, AllocationSpace::New)
+        );
+        let snapshot = mutator.heap_snapshot();
+        let weak_edges = snapshot
+            .edges
+            .iter()
+            .filter(|edge| edge.kind == EDGE_WEAK)
+            .collect::<Vec<_>>();
+        assert_eq!(weak_edges.len(), 1);
+        assert_eq!(snapshot.strings[weak_edges[0].index], "1");
+    }
+
+    #[test]
+    fn shadow_stacks_are_reported_by_default() {
+        let mut mutator = instantiate_minimark(MiniMarkOptions::default());
+        let stack = mutator.shadow_stack();
+        letroot!(
+            _root = stack,
+            mutator.allocate(Link { next: None }, AllocationSpace::New)
+        );
+        let snapshot = mutator.heap_snapshot();
+        let roots = &snapshot.nodes[2];
+        assert_eq!(snapshot.strings[roots.name], "(Shadow stack of mutator 0)");
+        assert_eq!(roots.edge_count, 1);
+    }
+}
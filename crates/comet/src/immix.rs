@@ -10,18 +10,31 @@
 //! You can find more information about Immix in this [paper](https://users.cecs.anu.edu.au/~steveb/pubs/papers/immix-pldi-2008.pdf)
 
 use crate::{
-    api::{vtable_of, Collectable, Gc, HeapObjectHeader, Trace, Visitor, Weak, GC_BLACK, GC_WHITE},
+    api::{
+        vtable_of, Collectable, EphemeronEntry, Gc, HeapObjectHeader, Soft, Trace, Visitor, Weak,
+        GC_BLACK, GC_WHITE,
+    },
     bitmap::SpaceBitmap,
+    finalization_registry::FinalizationRegistry,
+    finalizer_thread::FinalizerThread,
     gc_base::{
-        AllocationSpace, GcBase, MarkingConstraint, MarkingConstraintRuns, NoHelp,
-        NoOpStackDecoder, NoReadBarrier, StackValueDecoder,
+        AllocError, AllocationSpace, GcBase, MarkingConstraint, MarkingConstraintRuns, NoHelp,
+        NoOpStackDecoder, NoReadBarrier, SoftRefPolicy, StackValueDecoder,
     },
+    heap_limit::{HeapLimitAction, NearHeapLimit, NearHeapLimitCallback},
+    heap_snapshot::{report_shadow_stacks, RootSource, RootVisitor},
     large_space::{LargeObjectSpace, PreciseAllocation},
     make_small_type_id,
-    mutator::{approximate_stack_pointer, oom_abort, JoinData, Mutator, MutatorRef, ThreadState},
+    mutator::{JoinData, Mutator, MutatorRef, ThreadState},
+    observer::{GcObserver, GcObservers, GcPhase, GcReason},
+    parallel_marking::{mark_parallel, ParallelMarking},
+    poison::FreedMemory,
     safepoint::{GlobalSafepoint, SafepointScope},
     small_type_id,
+    stats::HeapStats,
+    stress::GcStress,
     utils::{align_usize, formatted_size, stack_bounds::StackBounds},
+    verify::{verify_heap, VerifyError},
     ConstantId,
 };
 use crate::{
@@ -33,7 +46,7 @@ use atomic::Ordering;
 use im::Vector;
 use rosalloc::defs::PAGE_SIZE;
 use std::{
-    any::TypeId, cell::UnsafeCell, marker::PhantomData, mem::size_of, ptr::NonNull, sync::Arc,
+    any::TypeId, cell::UnsafeCell, collections::HashSet, mem::size_of, ptr::NonNull, sync::Arc,
 };
 use std::{
     ptr::null_mut,
@@ -49,9 +62,11 @@ pub fn is_line_aligned(ptr: *const u8) -> bool {
 
 pub mod block;
 pub mod chunk;
+pub mod defrag;
 pub mod space;
 use block::*;
 use chunk::*;
+use defrag::*;
 use space::*;
 
 /// Thread local allocator for Immix. This allocator stores two different bump pointers:
@@ -70,6 +85,28 @@ pub struct ImmixAllocator {
 }
 
 impl ImmixAllocator {
+    /// Creates allocator that allocates memory in `space`.
+    pub fn new(space: &'static ImmixSpace) -> Self {
+        Self {
+            space,
+            line: None,
+            limit: null_mut(),
+            large_cursor: null_mut(),
+            large_limit: null_mut(),
+            cursor: null_mut(),
+            request_for_large: false,
+            emergency_collection: false,
+            bmap: &space.mark_bitmap,
+        }
+    }
+    /// When set allocator is allowed to grow heap up to its growth limit instead of failing allocation.
+    pub fn set_emergency_collection(&mut self, emergency_collection: bool) {
+        self.emergency_collection = emergency_collection;
+    }
+    /// Returns `true` while allocator retries allocation after emergency GC cycle.
+    pub fn is_emergency_collection(&self) -> bool {
+        self.emergency_collection
+    }
     /// Try to acquire recyclable block. Returns false if there is no recyclable blocks or GC threshold is reached.
     pub fn acquire_recyclable_block(&mut self) -> bool {
         if self.is_out_of_memory_on_allocation(IMMIX_BLOCK_SIZE, self.emergency_collection) {
@@ -181,17 +218,7 @@ where
         self.line = None;
     }
     fn create(heap: std::sync::Arc<std::cell::UnsafeCell<H>>) -> Self {
-        Self {
-            space: unsafe { (*heap.get()).immix_space() },
-            line: None,
-            limit: null_mut(),
-            large_cursor: null_mut(),
-            large_limit: null_mut(),
-            cursor: null_mut(),
-            request_for_large: false,
-            emergency_collection: false,
-            bmap: unsafe { &(*heap.get()).immix_space().mark_bitmap },
-        }
+        Self::new(unsafe { (*heap.get()).immix_space() })
     }
 }
 
@@ -204,7 +231,7 @@ impl ImmixAllocator {
             let new_footprint = old_allocated + alloc_size;
             if new_footprint <= old_target {
                 return false;
-            } else if new_footprint > self.space.growth_limit {
+            } else if new_footprint > self.space.growth_limit.load(Ordering::Relaxed) {
                 return true;
             }
 
@@ -234,15 +261,7 @@ impl ImmixAllocator {
     }
     #[inline(always)]
     pub unsafe fn alloc_slow_inline(&mut self, size: usize) -> *mut u8 {
-        let result = self.alloc_slow_once(size);
-        if !result.is_null() {
-            return result;
-        }
-
-        if self.emergency_collection {
-            oom_abort();
-        }
-        result
+        self.alloc_slow_once(size)
     }
     #[inline]
     pub unsafe fn alloc(&mut self, size: usize) -> *mut u8 {
@@ -304,8 +323,28 @@ pub struct Immix<Decoder: 'static + StackValueDecoder = NoOpStackDecoder> {
     pub(crate) mark_color: u8,
     total_gcs: usize,
     weak_refs: Vec<Weak<dyn Collectable, Self>>,
+    soft_refs: Vec<Soft<dyn Collectable, Self>>,
+    soft_ref_policy: SoftRefPolicy,
+    /// Set when GC cycle is started because of allocation failure.
+    alloc_failure: bool,
+    /// Set when GC cycle is forced by stress mode, such cycle evacuates as many objects as it can.
+    stress: bool,
+    /// Ephemerons found during marking whose keys are not known to be alive yet.
+    ephemerons: Vec<*mut EphemeronEntry>,
+    finalization_registry: FinalizationRegistry<Self>,
     constraints: Vec<Box<dyn MarkingConstraint>>,
     growth_multiplier: f64,
+    defrag: bool,
+    evacuator: EvacuationAllocator,
+    /// Objects that were found by conservative stack scan and pinned during defrag GC.
+    pinned: Vec<*mut HeapObjectHeader>,
+    /// Threads that perform marking. When `None` marking is done by GC thread.
+    marking_pool: Option<scoped_threadpool::Pool>,
+    lazy_sweep: bool,
+    near_heap_limit: NearHeapLimit,
+    stats: HeapStats,
+    observers: GcObservers,
+    verify: bool,
 }
 
 impl<Decoder: StackValueDecoder> GetImmixSpace for Immix<Decoder> {
@@ -323,10 +362,37 @@ pub struct ImmixOptions {
     pub initial_size: usize,
     /// Minimal heap size before triggering GC cycle. By default set to 4MB, if `initial_size` is lesser than min_heap_size then it is set to min_heap_size.
     pub min_heap_size: usize,
-    /// Maximal heap size before triggering GC cycle. By default set to 128MB
+    /// Maximal heap size before triggering GC cycle. By default set to 128MB. This is initial growth limit of heap that
+    /// might be raised up to `heap_size` by near heap limit callback, see [heap_limit](crate::heap_limit).
     pub max_heap_size: usize,
     /// Enables verbose loggig to stdout.
     pub verbose: u8,
+    /// Enables opportunistic defragmentation: live objects from fragmented blocks are evacuated to clean blocks. Enabled by default.
+    pub defrag: bool,
+    /// Number of threads that perform marking. By default set to 1 i.e marking is done by GC thread.
+    pub marking_threads: usize,
+    /// Enables lazy sweeping: GC pause only queues blocks for sweeping and blocks are swept (and dead objects are
+    /// finalized) by mutators when they need new block for allocation. Reduces pause time. Disabled by default.
+    pub lazy_sweep: bool,
+    /// Soft references that were not accessed for this number of milliseconds per free megabyte of heap are cleared
+    /// when heap is under memory pressure. By default set to 1000.
+    pub soft_ref_lru_policy_ms_per_mb: u64,
+    /// Enables background finalizer thread: dead objects of types that opt in with
+    /// [Finalize::finalize_on_thread](crate::api::Finalize::finalize_on_thread) are finalized by separate thread so that
+    /// slow finalizers do not lengthen pauses. Their memory is kept until finalizer has run. Disabled by default.
+    pub finalizer_thread: bool,
+    /// Enables heap verification before and after every GC cycle, see [verify](crate::verify). Corrupted reference
+    /// is reported to stderr and process is aborted. Disabled by default.
+    pub verify: bool,
+    /// GC stress mode, see [stress](crate::stress). Overridden by `COMET_GC_STRESS` environment variable. Disabled
+    /// by default.
+    pub stress: GcStress,
+    /// What happens to memory of dead objects, see [poison](crate::poison). Overridden by `COMET_FREED_MEMORY`
+    /// environment variable. Memory is reused by default.
+    pub freed_memory: FreedMemory,
+    /// Fraction of `max_heap_size` that target footprint must exceed after GC cycle for near heap limit callback to
+    /// be invoked, see [heap_limit](crate::heap_limit). Disabled by default.
+    pub near_heap_limit_threshold: Option<f64>,
 }
 
 impl ImmixOptions {
@@ -365,6 +431,66 @@ impl ImmixOptions {
         self.verbose = x;
         self
     }
+
+    /// Enable or disable opportunistic defragmentation. When disabled Immix never moves objects.
+    pub fn with_defrag(mut self, x: bool) -> ImmixOptions {
+        self.defrag = x;
+        self
+    }
+
+    /// Set number of marking threads. Panics if x is 0.
+    pub fn with_marking_threads(mut self, x: usize) -> ImmixOptions {
+        if x == 0 {
+            panic!("At least one marking thread is required");
+        }
+        self.marking_threads = x;
+        self
+    }
+
+    /// Enable or disable lazy sweeping.
+    pub fn with_lazy_sweep(mut self, x: bool) -> ImmixOptions {
+        self.lazy_sweep = x;
+        self
+    }
+
+    /// Enable or disable background finalizer thread.
+    pub fn with_finalizer_thread(mut self, x: bool) -> ImmixOptions {
+        self.finalizer_thread = x;
+        self
+    }
+
+    /// Enable or disable heap verification.
+    pub fn with_verify(mut self, x: bool) -> ImmixOptions {
+        self.verify = x;
+        self
+    }
+
+    /// Set GC stress mode.
+    pub fn with_stress(mut self, x: GcStress) -> ImmixOptions {
+        self.stress = x;
+        self
+    }
+
+    /// Set what happens to memory of dead objects.
+    pub fn with_freed_memory(mut self, x: FreedMemory) -> ImmixOptions {
+        self.freed_memory = x;
+        self
+    }
+
+    /// Set soft reference LRU policy, see [SoftRefPolicy].
+    pub fn with_soft_ref_lru_policy_ms_per_mb(mut self, x: u64) -> ImmixOptions {
+        self.soft_ref_lru_policy_ms_per_mb = x;
+        self
+    }
+
+    /// Set near heap limit threshold. Panics if x is not in (0, 1] range.
+    pub fn with_near_heap_limit_threshold(mut self, x: f64) -> ImmixOptions {
+        if x <= 0.0 || x > 1.0 {
+            panic!("Near heap limit threshold must be in (0, 1] range")
+        }
+        self.near_heap_limit_threshold = Some(x);
+        self
+    }
 }
 impl Default for ImmixOptions {
     fn default() -> Self {
@@ -375,6 +501,15 @@ impl Default for ImmixOptions {
             max_heap_size: 128 * 1024 * 1024,
             initial_size: 32 * 1024 * 1024,
             verbose: 0,
+            defrag: true,
+            marking_threads: 1,
+            lazy_sweep: false,
+            soft_ref_lru_policy_ms_per_mb: SoftRefPolicy::default().ms_per_mb,
+            finalizer_thread: false,
+            verify: false,
+            stress: GcStress::Disabled,
+            freed_memory: FreedMemory::Reuse,
+            near_heap_limit_threshold: None,
         }
     }
 }
@@ -390,8 +525,29 @@ pub fn instantiate_immix<Decoder: StackValueDecoder>(
         options.verbose > 0,
     )));
     space.init_bitmap();
+    space.freed_memory = FreedMemory::from_env_or(options.freed_memory);
+    if options.finalizer_thread {
+        space.finalizer = Some(FinalizerThread::new());
+    }
+    let space: &'static ImmixSpace = space;
     let immix = Arc::new(UnsafeCell::new(Immix {
         space,
+        evacuator: EvacuationAllocator::new(space),
+        pinned: vec![],
+        defrag: options.defrag,
+        marking_pool: if options.marking_threads > 1 {
+            Some(scoped_threadpool::Pool::new(options.marking_threads as _))
+        } else {
+            None
+        },
+        lazy_sweep: options.lazy_sweep,
+        near_heap_limit: NearHeapLimit::new(
+            space.growth_limit.load(Ordering::Relaxed),
+            options.near_heap_limit_threshold,
+        ),
+        stats: HeapStats::default(),
+        observers: GcObservers::new(),
+        verify: options.verify,
         large_space: LargeObjectSpace::new(),
         large_space_lock: Lock::INIT,
         verbose: options.verbose,
@@ -404,10 +560,17 @@ pub fn instantiate_immix<Decoder: StackValueDecoder>(
         mark_stack: Vec::new(),
         total_gcs: 0,
         weak_refs: vec![],
+        soft_refs: vec![],
+        soft_ref_policy: SoftRefPolicy::new(options.soft_ref_lru_policy_ms_per_mb),
+        alloc_failure: false,
+        stress: false,
+        ephemerons: vec![],
+        finalization_registry: FinalizationRegistry::new(),
         constraints: vec![],
         growth_multiplier: options.growth_multiplier,
     }));
     let href = unsafe { &mut *immix.get() };
+    href.large_space.freed_memory = href.space.freed_memory;
     let join_data = JoinData::new();
     let mut mutator = MutatorRef::new(Mutator::new(
         immix.clone(),
@@ -421,6 +584,7 @@ pub fn instantiate_immix<Decoder: StackValueDecoder>(
     mutator.stack_bounds = StackBounds::current_thread_stack_bounds();
     mutator.state_set(ThreadState::Safe, ThreadState::Unsafe);
 
+    mutator.set_gc_stress(GcStress::from_env_or(options.stress));
     mutator
 }
 
@@ -457,13 +621,23 @@ impl<Decoder: StackValueDecoder> Immix<Decoder> {
         mutator: &mut MutatorRef<Self>,
 
         mut value: T,
-    ) -> Gc<T, Self> {
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
         self.collect_alloc_failure(mutator, &mut [&mut value]);
-
-        mutator.tlab.emergency_collection = true;
-        let value = self.alloc_inline(mutator, value, AllocationSpace::New);
-        mutator.tlab.emergency_collection = false;
-        value
+        loop {
+            mutator.tlab.emergency_collection = true;
+            let result = self.alloc_inline(mutator, value, AllocationSpace::New);
+            mutator.tlab.emergency_collection = false;
+            value = match result {
+                Err(error) => error.into_inner(),
+                result => return result,
+            };
+            let size = value.allocation_size() + size_of::<HeapObjectHeader>();
+            match self.invoke_near_heap_limit_callback(size) {
+                HeapLimitAction::Raise(_) => (),
+                HeapLimitAction::Collect => self.collect_alloc_failure(mutator, &mut [&mut value]),
+                HeapLimitAction::Fail => return Err(AllocError::new(value)),
+            }
+        }
     }
 
     #[cold]
@@ -476,14 +650,119 @@ impl<Decoder: StackValueDecoder> Immix<Decoder> {
         type_id: TypeId,
     ) -> *mut HeapObjectHeader {
         self.collect_alloc_failure(mutator, &mut []);
+        loop {
+            mutator.tlab.emergency_collection = true;
+            let object = self.allocate_raw(mutator, size, type_id, vtable);
+            mutator.tlab.emergency_collection = false;
+            if !object.is_null() {
+                return object;
+            }
+            match self.invoke_near_heap_limit_callback(size) {
+                HeapLimitAction::Raise(_) => (),
+                HeapLimitAction::Collect => self.collect_alloc_failure(mutator, &mut []),
+                HeapLimitAction::Fail => return null_mut(),
+            }
+        }
+    }
 
-        mutator.tlab.emergency_collection = true;
-        let value = self.allocate_raw(mutator, size, type_id, vtable);
-        mutator.tlab.emergency_collection = false;
-        value
+    /// Invoke near heap limit callback because allocation of `size` bytes failed even after GC cycle.
+    fn invoke_near_heap_limit_callback(&mut self, size: usize) -> HeapLimitAction {
+        self.global_heap_lock.lock();
+        let action = self.near_heap_limit.invoke(
+            self.space.num_bytes_allocated.load(Ordering::Relaxed),
+            size,
+            self.space.target_footprint.load(Ordering::Relaxed),
+            &self.space.growth_limit,
+            self.space.capacity(),
+        );
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        action
     }
 
-    unsafe fn walk_stack(&mut self, mut start: *mut *mut u8, mut end: *mut *mut u8) {
+    unsafe fn walk_stack(&mut self, start: *mut *mut u8, end: *mut *mut u8) {
+        let this = self as *mut Self;
+        (*this).scan_stack(start, end, |mut header, in_space| {
+            if in_space && self.space.defrag.in_defrag() && !header.as_ref().pinned_bit() {
+                // we do not know whether `pointer` is a real reference so object must stay in place.
+                header.as_mut().set_pinned_bit(true);
+                self.pinned.push(header.as_ptr());
+            }
+            self.mark_object(&mut header);
+        });
+    }
+
+    /// Verify heap, see [verify](crate::verify). Line marks are checked only when `lines` is true since they are
+    /// not maintained for objects allocated after GC cycle. Must be invoked with heap locks held.
+    pub(crate) unsafe fn verify_heap(&mut self, lines: bool) -> Result<usize, VerifyError> {
+        let large_objects = self
+            .large_space
+            .allocations
+            .iter()
+            .map(|alloc| (**alloc).cell())
+            .collect::<HashSet<_>>();
+        let space = self.space;
+        let mark_color = self.mark_color;
+        verify_heap(self, |object| {
+            if space.has_address(object.cast()) {
+                if align_usize(object as usize, 8) != object as usize {
+                    return Err("misaligned pointer into Immix space".to_string());
+                }
+                if (*ImmixBlock::from_object(object.cast())).state == BlockState::Unallocated {
+                    return Err("pointer into unallocated Immix block".to_string());
+                }
+                if !space.mark_bitmap.test(object.cast()) {
+                    return Err(
+                        "pointer does not point at object start or object is swept".to_string()
+                    );
+                }
+                if (*object).is_forwarded() {
+                    return Err("reference to evacuated object was not updated".to_string());
+                }
+                if (*object).get_color() == mark_color {
+                    return Err("reference to dead object".to_string());
+                }
+                if lines && !space.lines_marked(object) {
+                    return Err("lines of live object are not marked".to_string());
+                }
+                Ok(())
+            } else if large_objects.contains(&object) {
+                Ok(())
+            } else {
+                Err("pointer is outside of GC heap".to_string())
+            }
+        })
+    }
+
+    unsafe fn verify(&mut self, when: &str, lines: bool) {
+        match self.verify_heap(lines) {
+            Ok(count) => {
+                if self.verbose > 0 {
+                    eprintln!(
+                        "[gc] GC({}) Verified {} objects {} GC",
+                        self.total_gcs, count, when
+                    );
+                }
+            }
+            Err(error) => {
+                eprintln!(
+                    "[gc] GC({}) Heap verification {} GC failed: {}",
+                    self.total_gcs, when, error
+                );
+                std::process::abort();
+            }
+        }
+    }
+
+    /// Scan stack range conservatively. `f` is invoked on every object found with `true` if object is in Immix space
+    /// and `false` if it is in large object space.
+    unsafe fn scan_stack(
+        &self,
+        mut start: *mut *mut u8,
+        mut end: *mut *mut u8,
+        mut f: impl FnMut(NonNull<HeapObjectHeader>, bool),
+    ) {
         if end < start {
             std::mem::swap(&mut start, &mut end);
         }
@@ -501,23 +780,22 @@ impl<Decoder: StackValueDecoder> Immix<Decoder> {
                     /*if let Some(mut header) =
                     NonNull::new(self.space.mark_bitmap.find_header(pointer))*/
                     if self.space.mark_bitmap.test(pointer) {
-                        let mut header = NonNull::new_unchecked(pointer.cast::<HeapObjectHeader>());
+                        let header = NonNull::new_unchecked(pointer.cast::<HeapObjectHeader>());
                         if self.verbose > 1 {
                             eprintln!(
                                 "[GC] Found Immix space object {:p} from {:p} at {:p}",
                                 header, pointer, cursor
                             );
                         }
-                        // TODO: Pin object when we have defrag collection
-                        self.mark_object(&mut header);
+                        f(header, true);
                         cursor = cursor.add(1);
                         continue;
                     }
                 }
             }
 
-            if let Some(mut header) = NonNull::new(self.large_space.contains(pointer)) {
-                self.mark_object(&mut header);
+            if let Some(header) = NonNull::new(self.large_space.contains(pointer)) {
+                f(header, false);
                 cursor = cursor.add(1);
                 continue;
             }
@@ -546,7 +824,7 @@ impl<Decoder: StackValueDecoder> GcBase for Immix<Decoder> {
         unsafe {
             self.large_space_lock.lock();
             self.large_space.allocations.iter().for_each(|alloc| {
-                f(std::mem::transmute((**alloc).cell()));
+                f(Gc::from_raw((**alloc).cell()));
             });
             self.large_space_lock.unlock();
             let start = self.immix_space().map.start();
@@ -554,11 +832,44 @@ impl<Decoder: StackValueDecoder> GcBase for Immix<Decoder> {
             self.immix_space()
                 .mark_bitmap
                 .visit_marked_range(start, end, |ptr| {
-                    f(std::mem::transmute(ptr));
+                    // blocks that are not swept yet still contain dead objects and stale copies of evacuated ones.
+                    if !(*ptr).is_forwarded() && (*ptr).get_color() != self.mark_color {
+                        f(Gc::from_raw(ptr));
+                    }
                 });
         }
         true
     }
+    unsafe fn snapshot_roots(
+        &mut self,
+        report: &mut dyn FnMut(&RootSource, *mut HeapObjectHeader),
+    ) {
+        report_shadow_stacks(&self.mutators, report);
+        // lookup table of large objects is stale outside of GC cycle.
+        self.large_space.prepare_for_conservative_scan();
+        for i in 0..self.mutators.len() {
+            let mutator = self.mutators[i];
+            let source = RootSource::ConservativeStack(i);
+            self.scan_stack(
+                (*mutator).stack_bounds.origin.cast(),
+                (*mutator).last_sp.get().cast(),
+                |header, _| report(&source, header.as_ptr()),
+            );
+            let (start, end) = (*mutator).saved_registers();
+            self.scan_stack(start, end, |header, _| report(&source, header.as_ptr()));
+        }
+        let this = self as *mut Self;
+        for constraint in (*this).constraints.iter_mut() {
+            if !constraint.is_over() {
+                let source = RootSource::Constraint(constraint.name().to_string());
+                constraint.run(&mut RootVisitor::new(source, report));
+            }
+        }
+        self.finalization_registry.trace(&mut RootVisitor::new(
+            RootSource::FinalizationRegistry,
+            report,
+        ));
+    }
     fn allocate_raw(
         &mut self,
         mutator: &mut MutatorRef<Self>,
@@ -566,27 +877,36 @@ impl<Decoder: StackValueDecoder> GcBase for Immix<Decoder> {
         type_id: std::any::TypeId,
         vtable: usize,
     ) -> *mut HeapObjectHeader {
-        let alloc = &mut mutator.tlab;
         let size = align_usize(size + size_of::<HeapObjectHeader>(), 8);
         unsafe {
-            let memory = if size >= Self::LARGE_ALLOCATION_SIZE {
-                alloc.alloc(size)
+            let large = size >= Self::LARGE_ALLOCATION_SIZE;
+            let memory = if large {
+                self.large_space_lock.lock();
+                let object = self.large_space.allocate(size);
+                self.large_space_lock.unlock();
+                object.cast::<u8>()
             } else {
-                self.large_space.allocate(size).cast::<u8>()
+                mutator.tlab.alloc(size)
             };
 
             if memory.is_null() {
+                if mutator.tlab.emergency_collection {
+                    return null_mut();
+                }
                 return self.collect_and_alloc_raw(mutator, size, vtable, type_id);
             }
             let object = memory.cast::<HeapObjectHeader>();
+            // holes might contain stale headers of dead or evacuated objects.
+            (*object).padding = 0;
             (*object).set_vtable(vtable);
             (*object).type_id = make_small_type_id(type_id);
-            (*object).set_size(size);
+            if !large {
+                (*object).set_size(size);
+            }
+            // type of raw object is unknown so it is always finalized.
+            self.space.add_finalizable(object);
 
-            let gced: Gc<(), Self> = Gc {
-                base: NonNull::new_unchecked(object),
-                marker: Default::default(),
-            };
+            let gced: Gc<(), Self> = Gc::from_raw(object);
             self.post_alloc(gced);
             object
         }
@@ -595,14 +915,93 @@ impl<Decoder: StackValueDecoder> GcBase for Immix<Decoder> {
         &mut self,
         mutator: &mut MutatorRef<Self>,
         value: Gc<T, Self>,
-    ) -> Weak<T, Self> {
-        let weak_ref = unsafe { Weak::<T, Self>::create(mutator, value) };
+    ) -> Result<Weak<T, Self>, AllocError<Gc<T, Self>>> {
+        let weak_ref = unsafe { Weak::<T, Self>::try_create(mutator, value)? };
+        self.global_heap_lock.lock();
+        self.weak_refs.push(weak_ref.to_dyn_untraced());
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        Ok(weak_ref)
+    }
+    fn allocate_soft<T: Collectable + ?Sized>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        value: Gc<T, Self>,
+    ) -> Soft<T, Self> {
+        let soft_ref = unsafe { Soft::<T, Self>::create(mutator, value) };
+        self.global_heap_lock.lock();
+        self.soft_refs.push(soft_ref.to_dyn_untraced());
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        soft_ref
+    }
+    fn collect_alloc_failure(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        keep: &mut [&mut dyn Trace],
+    ) {
+        self.alloc_failure = true;
+        self.collect(mutator, keep);
+        self.alloc_failure = false;
+    }
+    fn stress_collection(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
+        self.stress = true;
+        self.collect(mutator, keep);
+        self.stress = false;
+    }
+    fn register_finalization<T: Collectable + ?Sized, U: Collectable + ?Sized>(
+        &mut self,
+        _mutator: &mut MutatorRef<Self>,
+        target: Gc<T, Self>,
+        held: Gc<U, Self>,
+    ) {
+        self.global_heap_lock.lock();
+        self.finalization_registry.register(target, held);
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+    }
+    fn set_near_heap_limit_callback(&mut self, callback: Option<NearHeapLimitCallback>) {
+        self.global_heap_lock.lock();
+        self.near_heap_limit.set_callback(callback);
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+    }
+    fn add_gc_observer(&mut self, observer: Box<dyn GcObserver>) -> bool {
+        self.global_heap_lock.lock();
+        self.observers.add(observer);
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        true
+    }
+    fn wait_for_finalizers(&self) {
+        if let Some(ref finalizer) = self.space.finalizer {
+            finalizer.wait();
+        }
+    }
+    fn stats(&self) -> HeapStats {
         self.global_heap_lock.lock();
-        self.weak_refs.push(weak_ref.to_dyn());
+        let stats = self.stats.snapshot(
+            self.space.num_bytes_allocated.load(Ordering::Relaxed) + self.large_space.bytes,
+            self.space.target_footprint.load(Ordering::Relaxed),
+            self.large_space.bytes,
+        );
         unsafe {
             self.global_heap_lock.unlock();
         }
-        weak_ref
+        stats
+    }
+    fn pop_cleanup(&mut self) -> Option<Gc<dyn Collectable, Self>> {
+        self.global_heap_lock.lock();
+        let held = self.finalization_registry.pop_cleanup();
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        held
     }
     #[inline]
     fn alloc_inline<T: Collectable + Sized + 'static>(
@@ -610,45 +1009,71 @@ impl<Decoder: StackValueDecoder> GcBase for Immix<Decoder> {
         mutator: &mut MutatorRef<Self>,
         value: T,
         _space: AllocationSpace,
-    ) -> Gc<T, Self> {
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
         let alloc = &mut mutator.tlab;
         let size = align_usize(value.allocation_size() + size_of::<HeapObjectHeader>(), 8);
         unsafe {
             let memory = alloc.alloc(size);
 
             if memory.is_null() {
+                if alloc.emergency_collection {
+                    // heap is exhausted even after emergency GC.
+                    return Err(AllocError::new(value));
+                }
                 return self.collect_and_alloc(mutator, value);
             }
             let object = memory.cast::<HeapObjectHeader>();
+            // holes might contain stale headers of dead or evacuated objects.
+            (*object).padding = 0;
             (*object).set_metadata(vtable_of::<T>());
             (*object).type_id = ConstantId::<T>::ID;
             (*object).set_size(size);
             ((*object).data() as *mut T).write(value);
-            let gced = Gc {
-                base: NonNull::new_unchecked(object),
-                marker: Default::default(),
-            };
+            let gced = Gc::from_raw(object);
             self.post_alloc(gced);
-            gced
+            Ok(gced)
         }
     }
 
     fn collect(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
         match SafepointScope::new(mutator.clone()) {
             Some(safepoint) => unsafe {
-                mutator.last_sp.set(approximate_stack_pointer());
-                let time = if self.verbose > 0 {
-                    Some(std::time::Instant::now())
-                } else {
-                    None
-                };
+                mutator.save_conservative_roots();
+                let time = std::time::Instant::now();
 
                 self.global_heap_lock.lock();
                 self.large_space_lock.lock();
+                self.observers.start(if self.alloc_failure {
+                    GcReason::AllocationFailure
+                } else {
+                    GcReason::Requested
+                });
                 let mark_phase = std::time::Instant::now();
+                // blocks left unswept since previous cycle are swept before colours are flipped again.
+                self.space.finish_sweep();
+                if self.verify {
+                    self.verify("before", false);
+                }
+                self.safepoint.next_epoch();
                 self.large_space.prepare_for_marking(false);
                 self.large_space.prepare_for_conservative_scan();
+                if self.stress && self.defrag {
+                    self.space.defrag.force_defrag(self.space);
+                } else {
+                    self.space
+                        .defrag
+                        .decide_whether_to_defrag(self.defrag, self.space);
+                }
+                let in_defrag = self.space.defrag.in_defrag();
                 self.space.prepare(true);
+                self.soft_ref_policy.prepare(
+                    &self.soft_refs,
+                    self.alloc_failure,
+                    self.space.num_bytes_allocated.load(Ordering::Relaxed) + self.large_space.bytes,
+                    self.space.growth_limit.load(Ordering::Relaxed),
+                );
+                // Scan all stacks conservatively before any precise root is visited so that
+                // conservatively found objects are pinned before they could be evacuated.
                 for i in 0..self.mutators.len() {
                     let mutator = self.mutators[i];
                     (*mutator).reset_tlab();
@@ -656,6 +1081,11 @@ impl<Decoder: StackValueDecoder> GcBase for Immix<Decoder> {
                         (*mutator).stack_bounds.origin.cast(),
                         (*mutator).last_sp.get().cast(),
                     );
+                    let (start, end) = (*mutator).saved_registers();
+                    self.walk_stack(start, end);
+                }
+                for i in 0..self.mutators.len() {
+                    let mutator = self.mutators[i];
                     (*mutator).shadow_stack().walk(|entry| {
                         entry.trace(self);
                     });
@@ -664,11 +1094,22 @@ impl<Decoder: StackValueDecoder> GcBase for Immix<Decoder> {
                 for object in keep {
                     object.trace(self);
                 }
+                let this = self as *mut Self;
+                (*this).finalization_registry.trace(self);
+                self.observers.phase(GcPhase::RootsScanned);
 
-                while let Some(object) = self.mark_stack.pop() {
-                    (*object).get_dyn().trace(self);
-                }
+                let mut evacuated = self.trace_marked_objects();
                 self.after_mark_constraints();
+                evacuated += self.process_ephemerons();
+                let mark_color = self.mark_color;
+                self.finalization_registry
+                    .after_mark(|target| live_address(target, mark_color));
+                for object in self.pinned.drain(..) {
+                    (*object).set_pinned_bit(false);
+                }
+                evacuated += self.evacuator.evacuated_bytes();
+                self.evacuator.reset();
+                self.observers.phase(GcPhase::Marked);
                 let mark_phase = mark_phase.elapsed();
                 let prev =
                     self.space.num_bytes_allocated.load(Ordering::Relaxed) + self.large_space.bytes;
@@ -676,10 +1117,16 @@ impl<Decoder: StackValueDecoder> GcBase for Immix<Decoder> {
                 let mark_color = self.mark_color;
                 let sweep_phase = std::time::Instant::now();
                 self.weak_refs.retain_mut(|object| {
-                    let header = object.base();
+                    let mut header = object.base();
+                    if (*header).is_forwarded() {
+                        header = (*header).vtable() as _;
+                        object.set_base(header);
+                    }
                     if (*header).get_color() == mark_color {
                         object.after_mark(|header| {
-                            if (*header).get_color() == mark_color {
+                            if (*header).is_forwarded() {
+                                (*header).vtable() as _
+                            } else if (*header).get_color() == mark_color {
                                 header
                             } else {
                                 null_mut()
@@ -691,9 +1138,48 @@ impl<Decoder: StackValueDecoder> GcBase for Immix<Decoder> {
                     }
                 });
 
-                self.large_space.sweep();
+                self.soft_refs.retain_mut(|object| {
+                    let mut header = object.base();
+                    if (*header).is_forwarded() {
+                        header = (*header).vtable() as _;
+                        object.set_base(header);
+                    }
+                    if (*header).get_color() == mark_color {
+                        object.after_mark(|header| live_address(header, mark_color));
+                        true
+                    } else {
+                        false
+                    }
+                });
+                self.observers.phase(GcPhase::WeakProcessed);
+
+                let space = self.space;
+                if let Some(finalizer) = space.finalizer.as_ref() {
+                    // memory of objects queued for finalization is live until their finalizer has run.
+                    let large_space = &mut self.large_space;
+                    finalizer.process(
+                        |object| {
+                            if !(*object).is_precise() {
+                                space.mark_lines(object);
+                            }
+                        },
+                        |object| {
+                            if (*object).is_precise() {
+                                large_space.release_finalized(object);
+                            }
+                        },
+                    );
+                }
+                self.large_space.sweep_with_finalizer(|object| {
+                    (*object).finalizable_bit() && space.finalize(object)
+                });
                 self.large_space.prepare_for_allocation(false);
-                self.space.release(self.alloc_color);
+                if self.lazy_sweep {
+                    self.space.release_lazily(self.alloc_color);
+                } else {
+                    self.space.release(self.alloc_color);
+                }
+                self.observers.phase(GcPhase::Swept);
 
                 let bytes_allocated =
                     self.space.num_bytes_allocated.load(Ordering::Relaxed) + self.large_space.bytes;
@@ -701,31 +1187,53 @@ impl<Decoder: StackValueDecoder> GcBase for Immix<Decoder> {
                     .space
                     .min_heap_size
                     .max((bytes_allocated as f64 * self.growth_multiplier) as usize)
-                    .min(self.space.max_heap_size);
+                    .min(self.space.growth_limit.load(Ordering::Relaxed));
 
                 self.space
                     .target_footprint
                     .store(target_size, Ordering::Relaxed);
                 let sweep_phase = sweep_phase.elapsed();
-                if let Some(time) = time {
-                    let elapsed = time.elapsed();
+                let elapsed = time.elapsed();
+                self.stats
+                    .record_pause(elapsed, safepoint.time_to_safepoint());
+                self.stats
+                    .record_cycle(false, mark_phase, sweep_phase, prev, bytes_allocated);
+                if self.verbose > 0 {
                     eprintln!(
-                        "[gc] GC({}) Pause Immix collection {}->{}({}) {:.4}ms (mark {:.4}ms, sweep {:.4}ms)",
+                        "[gc] GC({}) Pause Immix {}collection {}->{}({}) {:.4}ms (mark {:.4}ms, sweep {:.4}ms{})",
                         self.total_gcs,
+                        if in_defrag { "defrag " } else { "" },
                         formatted_size(prev),
                         formatted_size(bytes_allocated),
                         formatted_size(target_size),
                         elapsed.as_micros() as f64 / 1000.0,
                         mark_phase.as_micros() as f64 / 1000.0,
-                        sweep_phase.as_micros() as f64 / 1000.0
+                        sweep_phase.as_micros() as f64 / 1000.0,
+                        if in_defrag {
+                            format!(", evacuated {}", formatted_size(evacuated))
+                        } else {
+                            String::new()
+                        }
                     );
                 }
-                self.total_gcs += 1;
                 std::mem::swap(&mut self.alloc_color, &mut self.mark_color);
+                if self.verify {
+                    self.verify("after", true);
+                }
+                self.total_gcs += 1;
+                self.observers.end(&self.stats);
+                let stats = self.stats;
                 drop(safepoint);
 
+                self.near_heap_limit.check_target_footprint(
+                    bytes_allocated,
+                    target_size,
+                    &self.space.growth_limit,
+                    self.space.capacity(),
+                );
                 self.global_heap_lock.unlock();
                 self.large_space_lock.unlock();
+                GcObservers::after_pause(&mut self.observers, &self.global_heap_lock, &stats);
             },
             None => return,
         }
@@ -783,23 +1291,31 @@ impl<Decoder: StackValueDecoder> GcBase for Immix<Decoder> {
 
     fn allocate_large<T: Collectable + Sized + 'static>(
         &mut self,
-        _mutator: &mut MutatorRef<Self>,
-        value: T,
-    ) -> crate::api::Gc<T, Self> {
+        mutator: &mut MutatorRef<Self>,
+        mut value: T,
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
         unsafe {
             let size = value.allocation_size() + size_of::<HeapObjectHeader>();
             self.large_space_lock.lock();
-            let object = self.large_space.allocate(size);
+            let mut object = self.large_space.allocate(size);
+            if object.is_null() {
+                // dead large objects might free enough memory.
+                self.large_space_lock.unlock();
+                self.collect_alloc_failure(mutator, &mut [&mut value]);
+                self.large_space_lock.lock();
+                object = self.large_space.allocate(size);
+                if object.is_null() {
+                    self.large_space_lock.unlock();
+                    return Err(AllocError::new(value));
+                }
+            }
             (*object).set_metadata(vtable_of::<T>());
             (*object).type_id = small_type_id::<T>();
-            let gc = Gc {
-                base: NonNull::new_unchecked(object),
-                marker: PhantomData,
-            };
+            let gc = Gc::from_raw(object);
             ((*object).data() as *mut T).write(value);
             self.large_space_lock.unlock();
             self.post_alloc(gc);
-            gc
+            Ok(gc)
         }
     }
     #[inline(always)]
@@ -807,224 +1323,731 @@ impl<Decoder: StackValueDecoder> GcBase for Immix<Decoder> {
         unsafe {
             let base = value.base.as_ptr();
             (*base).force_set_color(self.alloc_color);
+            if T::needs_finalization() {
+                self.space.add_finalizable(base);
+                if T::finalize_on_thread().is_some() {
+                    (*base).set_finalize_on_thread_bit(true);
+                }
+            }
             //self.space.mark_bitmap.set<true>(base as _);
         }
     }
 }
 
-impl<Decoder: StackValueDecoder> Visitor for Immix<Decoder> {
-    fn mark_object(&mut self, root: &mut NonNull<HeapObjectHeader>) {
-        let object = root.as_ptr();
-        unsafe {
-            // todo: opportunistic evacuation
+impl<Decoder: StackValueDecoder> Immix<Decoder> {
+    fn marking(&self) -> ImmixMarking {
+        ImmixMarking {
+            space: self.space,
+            alloc_color: self.alloc_color,
+            mark_color: self.mark_color,
+        }
+    }
 
-            if !(*object).set_color(self.alloc_color, self.mark_color) {
-                if self.space.has_address(object.cast()) {
-                    self.space.mark_lines(object);
+    /// Trace objects from mark stack. Returns number of bytes evacuated by marking workers.
+    unsafe fn trace_marked_objects(&mut self) -> usize {
+        let marking = self.marking();
+        let evacuators = mark_parallel(
+            self.marking_pool.as_mut(),
+            &marking,
+            std::mem::take(&mut self.mark_stack),
+            &mut self.ephemerons,
+        );
+        evacuators
+            .iter()
+            .map(|evacuator| evacuator.evacuated_bytes())
+            .sum()
+    }
+
+    /// Mark values of ephemerons with live keys until no more keys are found to be alive. Ephemerons with dead keys are
+    /// cleared. Returns number of bytes evacuated by marking workers.
+    unsafe fn process_ephemerons(&mut self) -> usize {
+        let mark_color = self.mark_color;
+        let mut evacuated = 0;
+        loop {
+            let mut marked = false;
+            for ephemeron in std::mem::take(&mut self.ephemerons) {
+                let alive = (*ephemeron).resolve_key(|key| live_address(key, mark_color));
+                if alive {
+                    (*ephemeron).mark_value(self);
+                    marked = true;
                 } else {
-                    (*PreciseAllocation::from_cell(object)).test_and_set_marked();
+                    self.ephemerons.push(ephemeron);
+                }
+            }
+            if !marked {
+                break;
+            }
+            // values might reference keys of other ephemerons
+            evacuated += self.trace_marked_objects();
+        }
+        for ephemeron in self.ephemerons.drain(..) {
+            (*ephemeron).clear();
+        }
+        evacuated
+    }
+}
+
+/// Returns address of `object` after marking or null if `object` is dead.
+unsafe fn live_address(object: *mut HeapObjectHeader, mark_color: u8) -> *mut HeapObjectHeader {
+    if (*object).is_forwarded() {
+        (*object).vtable() as _
+    } else if (*object).get_color() == mark_color {
+        object
+    } else {
+        null_mut()
+    }
+}
+
+/// Marking state of Immix that is shared between marking workers.
+pub(crate) struct ImmixMarking {
+    space: &'static ImmixSpace,
+    alloc_color: u8,
+    mark_color: u8,
+}
+
+// Line marks, mark bitmap and clean block lists are updated atomically.
+unsafe impl Sync for ImmixMarking {}
+
+impl ParallelMarking for ImmixMarking {
+    type Local = EvacuationAllocator;
+
+    fn create_local(&self) -> EvacuationAllocator {
+        EvacuationAllocator::new(self.space)
+    }
+
+    unsafe fn mark(
+        &self,
+        evacuator: &mut EvacuationAllocator,
+        root: &mut NonNull<HeapObjectHeader>,
+    ) -> Option<*mut HeapObjectHeader> {
+        let object = root.as_ptr();
+        if (*object).is_forwarded() {
+            *root = NonNull::new_unchecked((*object).vtable() as _);
+            return None;
+        }
+        if !self.space.has_address(object.cast()) {
+            if !(*object).set_color(self.alloc_color, self.mark_color) {
+                (*PreciseAllocation::from_cell(object)).test_and_set_marked();
+                return Some(object);
+            }
+            return None;
+        }
+        if self.space.defrag.in_defrag()
+            && (*ImmixBlock::from_object(object.cast())).is_fragmented()
+            && !(*object).pinned_bit()
+        {
+            if (*object).set_color_forwarding(self.alloc_color, self.mark_color) {
+                // object is marked by another worker that might be still copying it.
+                if (*object).wait_for_forwarding() {
+                    *root = NonNull::new_unchecked((*object).vtable() as _);
                 }
+                return None;
+            }
+            // opportunistic evacuation: object is copied only if there is still space for it in clean blocks.
+            let size = (*object).size();
+            let copy = evacuator.alloc(size);
+            if !copy.is_null() {
+                std::ptr::copy_nonoverlapping(object.cast::<u8>(), copy, size);
+                let copy = copy.cast::<HeapObjectHeader>();
+                (*copy).cancel_forwarding();
+                if (*copy).finalizable_bit() {
+                    (*ImmixBlock::from_object(copy.cast())).set_has_finalizable();
+                }
+                self.space.mark_lines(copy);
+                (*object).set_forwarded_sync(copy as usize);
+                *root = NonNull::new_unchecked(copy);
+                return Some(copy);
+            }
+            (*object).cancel_forwarding();
+        } else if (*object).set_color(self.alloc_color, self.mark_color) {
+            return None;
+        }
+        self.space.mark_lines(object);
+        Some(object)
+    }
+}
+
+impl<Decoder: StackValueDecoder> Visitor for Immix<Decoder> {
+    fn mark_object(&mut self, root: &mut NonNull<HeapObjectHeader>) {
+        unsafe {
+            if let Some(object) = self.marking().mark(&mut self.evacuator, root) {
                 self.mark_stack.push(object);
             }
         }
     }
+
+    fn mark_ephemeron(&mut self, ephemeron: &mut EphemeronEntry) {
+        self.ephemerons.push(ephemeron);
+    }
 }
 
-/*
-pub type Histogram = [usize; Defrag::NUM_BINS];
-
-pub struct Defrag {
-    in_defrag_collection: AtomicBool,
-    defrag_space_exhausted: AtomicBool,
-    pub mark_histograms: Mutex<Vec<Histogram>>,
-    /// A block with number of holes greater than this threshold will be defragmented.
-    pub defrag_spill_threshold: AtomicUsize,
-    /// The number of remaining clean pages in defrag space.
-    available_clean_pages_for_defrag: AtomicUsize,
+impl<Decoder: StackValueDecoder> Drop for Immix<Decoder> {
+    fn drop(&mut self) {
+        unsafe {
+            if self.verbose > 0 {
+                eprintln!("Dispose immix space at {:p}", self);
+            }
+            Box::from_raw(self.space as *const ImmixSpace as *mut ImmixSpace);
+        }
+    }
 }
-impl Defrag {
-    const NUM_BINS: usize = (IMMIX_LINES_PER_BLOCK >> 1) + 1;
-    const DEFRAG_LINE_REUSE_RATIO: f32 = 0.99;
-    const MIN_SPILL_THRESHOLD: usize = 2;
-    const DEFRAG_STRESS: bool = false;
-    const DEFRAG_HEADROOM_PERCENT: usize = 2;
 
-    /// Allocate a new local histogram.
-    pub const fn new_histogram(&self) -> Histogram {
-        [0; Self::NUM_BINS]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::{Ephemeron, Finalize, Soft},
+        letroot,
+    };
+
+    struct Node {
+        value: usize,
+        next: Option<Gc<Node, Immix>>,
     }
 
-    /// Report back a completed mark histogram
-    #[inline(always)]
-    pub fn add_completed_mark_histogram(&self, histogram: Histogram) {
-        self.mark_histograms.lock().push(histogram)
+    unsafe impl Trace for Node {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.next.trace(vis);
+        }
     }
+    unsafe impl Finalize for Node {}
+    impl Collectable for Node {}
 
-    /// Check if the current GC is a defrag GC.
-    #[inline(always)]
-    pub fn in_defrag(&self) -> bool {
-        self.in_defrag_collection.load(Ordering::Acquire)
+    struct Table {
+        entries: Vec<Ephemeron<Node, Node, Immix>>,
     }
 
-    /// Determine whether the current GC should do defragmentation.
-    pub fn decide_whether_to_defrag(
-        &self,
-        emergency_collection: bool,
-        collect_whole_heap: bool,
-        collection_attempts: usize,
-        user_triggered: bool,
-        exhausted_reusable_space: bool,
-        full_heap_system_gc: bool,
-    ) {
-        let in_defrag = true
-            && (emergency_collection
-                || (collection_attempts > 1)
-                || !exhausted_reusable_space
-                || Self::DEFRAG_STRESS
-                || (collect_whole_heap && user_triggered && full_heap_system_gc));
-        // println!("Defrag: {}", in_defrag);
-        self.in_defrag_collection
-            .store(in_defrag, Ordering::Release)
+    unsafe impl Trace for Table {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.entries.trace(vis);
+        }
+    }
+    unsafe impl Finalize for Table {}
+    impl Collectable for Table {}
+
+    fn node(
+        mutator: &mut MutatorRef<Immix>,
+        value: usize,
+        next: Option<Gc<Node, Immix>>,
+    ) -> Gc<Node, Immix> {
+        mutator.allocate(Node { value, next }, AllocationSpace::New)
     }
 
-    /// Get the number of defrag headroom pages.
-    pub fn defrag_headroom_pages(&self, space: &ImmixSpace) -> usize {
-        space.get_reserved_pages() * Self::DEFRAG_HEADROOM_PERCENT / 100
+    /// Add entries whose values reference their own keys. Keys are not reachable from anywhere else.
+    #[inline(never)]
+    fn add_unreachable_entries(mutator: &mut MutatorRef<Immix>, table: &mut Gc<Table, Immix>) {
+        for i in 0..100 {
+            let key = node(mutator, i, None);
+            let value = node(mutator, i, Some(key));
+            table.entries.push(Ephemeron::new(key, value));
+        }
     }
 
-    /// Check if the defrag space is exhausted.
-    #[inline(always)]
-    pub fn space_exhausted(&self) -> bool {
-        self.defrag_space_exhausted.load(Ordering::Acquire)
-    }
-
-    /// Update available_clean_pages_for_defrag counter when a clean block is allocated.
-    pub fn notify_new_clean_block(&self, copy: bool) {
-        if copy {
-            let available_clean_pages_for_defrag =
-                self.available_clean_pages_for_defrag.fetch_update(
-                    Ordering::SeqCst,
-                    Ordering::SeqCst,
-                    |available_clean_pages_for_defrag| {
-                        if available_clean_pages_for_defrag <= IMMIX_BLOCK_SIZE / PAGE_SIZE {
-                            Some(0)
-                        } else {
-                            Some(available_clean_pages_for_defrag - (IMMIX_BLOCK_SIZE / PAGE_SIZE))
-                        }
-                    },
-                );
-            if available_clean_pages_for_defrag.unwrap() <= IMMIX_BLOCK_SIZE / PAGE_SIZE {
-                self.defrag_space_exhausted.store(true, Ordering::SeqCst);
-            }
+    #[inline(never)]
+    fn allocate_soft_refs(mutator: &mut MutatorRef<Immix>, refs: &mut Vec<Soft<Node, Immix>>) {
+        for i in 0..10 {
+            let value = node(mutator, i, None);
+            refs.push(mutator.allocate_soft(value));
+        }
+    }
+
+    #[inline(never)]
+    fn soft_ref_values(refs: &[Soft<Node, Immix>]) -> Vec<Option<usize>> {
+        refs.iter()
+            .map(|soft| soft.get().map(|node| node.value))
+            .collect()
+    }
+
+    #[test]
+    fn soft_refs_are_cleared_only_under_memory_pressure() {
+        let mut mutator = instantiate_immix::<NoOpStackDecoder>(
+            ImmixOptions::default()
+                .with_heap_size(16 * 1024 * 1024)
+                .with_soft_ref_lru_policy_ms_per_mb(0),
+        );
+        let stack = mutator.shadow_stack();
+        letroot!(refs = stack, Vec::<Soft<Node, Immix>>::new());
+        allocate_soft_refs(&mut mutator, &mut refs);
+        mutator.collect(&mut []);
+        assert_eq!(
+            soft_ref_values(&refs),
+            (0..10).map(Some).collect::<Vec<_>>()
+        );
+
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        let mut mutator_ref = mutator.clone();
+        mutator
+            .heap_ref()
+            .collect_alloc_failure(&mut mutator_ref, &mut []);
+        // few referents might be kept alive by conservative stack scanning.
+        let values = soft_ref_values(&refs);
+        assert!(values.iter().filter(|value| value.is_none()).count() >= 8);
+    }
+
+    #[test]
+    fn ephemerons_with_dead_keys_are_cleared() {
+        let mut mutator = instantiate_immix::<NoOpStackDecoder>(
+            ImmixOptions::default().with_heap_size(16 * 1024 * 1024),
+        );
+        let stack = mutator.shadow_stack();
+        letroot!(
+            table = stack,
+            mutator.allocate(Table { entries: vec![] }, AllocationSpace::New)
+        );
+        letroot!(root = stack, node(&mut mutator, 1, None));
+        // key of the first entry is reachable only from value of the second entry
+        let key = node(&mut mutator, 2, None);
+        let value = node(&mut mutator, 20, None);
+        table.entries.push(Ephemeron::new(key, value));
+        let value = node(&mut mutator, 10, Some(key));
+        table.entries.push(Ephemeron::new(*root, value));
+        add_unreachable_entries(&mut mutator, &mut table);
+
+        mutator.collect(&mut []);
+
+        let first = &table.entries[0];
+        assert_eq!(first.key().unwrap().value, 2);
+        assert_eq!(first.value().unwrap().value, 20);
+        let second = &table.entries[1];
+        assert_eq!(second.key().unwrap().value, 1);
+        assert_eq!(second.value().unwrap().value, 10);
+        assert_eq!(second.value().unwrap().next.unwrap().value, 2);
+        assert!(table.entries[2..]
+            .iter()
+            .all(|entry| entry.key().is_none() && entry.value().is_none()));
+    }
+
+    static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+    struct Resource(usize);
+
+    impl Drop for Resource {
+        fn drop(&mut self) {
+            DROPPED.fetch_add(1, atomic::Ordering::Relaxed);
         }
     }
 
-    /// Prepare work. Should be called in ImmixSpace::prepare.
-    #[allow(clippy::assertions_on_constants)]
-    pub fn prepare(&self, space: &ImmixSpace<VM>) {
-        self.defrag_space_exhausted.store(false, Ordering::Release);
+    unsafe impl Trace for Resource {}
+    unsafe impl Finalize for Resource {}
+    impl Collectable for Resource {}
 
-        // Calculate available free space for defragmentation.
+    #[inline(never)]
+    fn allocate_resources(mutator: &mut MutatorRef<Immix>) {
+        for i in 0..100 {
+            mutator.allocate(Resource(i), AllocationSpace::New);
+            node(mutator, i, None);
+        }
+    }
 
-        let mut available_clean_pages_for_defrag = VM::VMActivePlan::global().get_total_pages()
-            as isize
-            - VM::VMActivePlan::global().get_pages_reserved() as isize
-            + self.defrag_headroom_pages(space) as isize;
-        if available_clean_pages_for_defrag < 0 {
-            available_clean_pages_for_defrag = 0
+    #[test]
+    fn only_objects_that_need_finalization_are_recorded() {
+        let mut mutator = instantiate_immix::<NoOpStackDecoder>(
+            ImmixOptions::default().with_heap_size(16 * 1024 * 1024),
+        );
+        let stack = mutator.shadow_stack();
+        letroot!(root = stack, node(&mut mutator, 1, None));
+        letroot!(
+            resource = stack,
+            mutator.allocate(Resource(1000), AllocationSpace::New)
+        );
+        unsafe {
+            assert!(!(*root.base.as_ptr()).finalizable_bit());
+            assert!((*resource.base.as_ptr()).finalizable_bit());
+            assert!((*ImmixBlock::from_object(resource.base.as_ptr().cast())).has_finalizable());
+        }
+        allocate_resources(&mut mutator);
+        mutator.collect(&mut []);
+        // few objects might be kept alive by conservative stack scanning.
+        let dropped = DROPPED.load(atomic::Ordering::Relaxed);
+        assert!((90..=100).contains(&dropped));
+        assert_eq!(resource.0, 1000);
+        assert_eq!(root.value, 1);
+        unsafe {
+            // block still contains live finalizable object.
+            assert!((*ImmixBlock::from_object(resource.base.as_ptr().cast())).has_finalizable());
+        }
+    }
+
+    struct Payload([usize; 128]);
+
+    unsafe impl Trace for Payload {}
+    unsafe impl Finalize for Payload {}
+    impl Collectable for Payload {}
+
+    #[test]
+    fn allocation_fails_when_heap_is_exhausted() {
+        let mut mutator = instantiate_immix::<NoOpStackDecoder>(
+            ImmixOptions::default().with_heap_size(4 * 1024 * 1024),
+        );
+        let stack = mutator.shadow_stack();
+        letroot!(chunks = stack, Vec::<Gc<Payload, Immix>>::new());
+        let error = loop {
+            match mutator.try_allocate(Payload([chunks.len(); 128]), AllocationSpace::New) {
+                Ok(chunk) => chunks.push(chunk),
+                Err(error) => break error,
+            }
         };
+        assert!(chunks.len() > 1000);
+        assert!(chunks
+            .iter()
+            .enumerate()
+            .all(|(i, chunk)| chunk.0[0] == i && chunk.0[127] == i));
+        // value is given back to the caller.
+        assert_eq!(error.into_inner().0[0], chunks.len());
+    }
 
-        self.available_clean_pages_for_defrag
-            .store(available_clean_pages_for_defrag as usize, Ordering::Release);
+    #[test]
+    fn near_heap_limit_callback_raises_limit() {
+        let mut mutator = instantiate_immix::<NoOpStackDecoder>(
+            ImmixOptions::default()
+                .with_heap_size(8 * 1024 * 1024)
+                .with_max_heap_size(4 * 1024 * 1024),
+        );
+        let limits = Arc::new(parking_lot::Mutex::new(vec![]));
+        let recorded = limits.clone();
+        mutator.set_near_heap_limit_callback(move |limit| {
+            let mut limits = recorded.lock();
+            limits.push(*limit);
+            if limits.len() == 1 {
+                HeapLimitAction::Raise(usize::MAX)
+            } else {
+                HeapLimitAction::Fail
+            }
+        });
+        let stack = mutator.shadow_stack();
+        letroot!(chunks = stack, Vec::<Gc<Payload, Immix>>::new());
+        while let Ok(chunk) = mutator.try_allocate(Payload([0; 128]), AllocationSpace::New) {
+            chunks.push(chunk);
+        }
+        let limits = limits.lock();
+        assert_eq!(limits.len(), 2);
+        assert_eq!(limits[0].current_limit, 4 * 1024 * 1024);
+        assert_eq!(limits[0].initial_limit, 4 * 1024 * 1024);
+        // limit was raised to capacity of heap.
+        assert_eq!(limits[1].current_limit, 8 * 1024 * 1024);
+        assert!(chunks.len() * size_of::<Payload>() > 4 * 1024 * 1024);
+    }
 
-        if self.in_defrag() {
-            self.establish_defrag_spill_threshold(space)
+    #[test]
+    fn near_heap_limit_callback_is_invoked_before_allocation_fails() {
+        let mut mutator = instantiate_immix::<NoOpStackDecoder>(
+            ImmixOptions::default()
+                .with_heap_size(8 * 1024 * 1024)
+                .with_max_heap_size(4 * 1024 * 1024)
+                .with_near_heap_limit_threshold(0.5),
+        );
+        let limits = Arc::new(parking_lot::Mutex::new(vec![]));
+        let recorded = limits.clone();
+        mutator.set_near_heap_limit_callback(move |limit| {
+            recorded.lock().push(*limit);
+            HeapLimitAction::Raise(usize::MAX)
+        });
+        let stack = mutator.shadow_stack();
+        letroot!(chunks = stack, Vec::<Gc<Payload, Immix>>::new());
+        for i in 0..2048 {
+            chunks.push(mutator.allocate(Payload([i; 128]), AllocationSpace::New));
         }
+        mutator.collect(&mut []);
+        let limits = limits.lock();
+        assert!(!limits.is_empty());
+        assert_eq!(limits[0].request_size, 0);
+        assert_eq!(limits[0].current_limit, 4 * 1024 * 1024);
+        assert!(limits[0].target_footprint >= 2 * 1024 * 1024);
+        // limit was raised to capacity of heap.
+        assert_eq!(
+            mutator
+                .heap_ref()
+                .space
+                .growth_limit
+                .load(Ordering::Relaxed),
+            limits[0].capacity
+        );
+    }
 
-        self.available_clean_pages_for_defrag.store(
-            available_clean_pages_for_defrag as usize
-                + VM::VMActivePlan::global().get_collection_reserve(),
-            Ordering::Release,
+    #[test]
+    fn heap_stats_report_finished_cycles() {
+        let mut mutator = instantiate_immix::<NoOpStackDecoder>(
+            ImmixOptions::default().with_heap_size(16 * 1024 * 1024),
         );
+        assert_eq!(mutator.heap_stats().collections, 0);
+        let stack = mutator.shadow_stack();
+        letroot!(chunks = stack, Vec::<Gc<Payload, Immix>>::new());
+        for i in 0..100 {
+            chunks.push(mutator.allocate(Payload([i; 128]), AllocationSpace::New));
+        }
+        mutator.collect(&mut []);
+        let stats = mutator.heap_stats();
+        assert_eq!(stats.collections, 1);
+        assert_eq!(stats.full_collections, 1);
+        assert_eq!(stats.minor_collections, 0);
+        assert_eq!(stats.pauses, 1);
+        assert_eq!(stats.total_pause_time, stats.last_pause_time);
+        assert!(stats.live_bytes >= 100 * size_of::<Payload>());
+        assert!(stats.bytes_allocated >= stats.live_bytes);
+        assert!(stats.target_footprint > 0);
     }
 
-    /// Get the numebr of all the recyclable lines in all the reusable blocks.
-    fn get_available_lines(
-        &self,
-        space: &ImmixSpace<VM>,
-        spill_avail_histograms: &mut Histogram,
-    ) -> usize {
-        let mut total_available_lines = 0;
-        for block in space.reusable_blocks.get_blocks().iter() {
-            let bucket = block.get_holes();
-            let unavailable_lines = match block.get_state() {
-                BlockState::Reusable { unavailable_lines } => unavailable_lines as usize,
-                s => unreachable!("{:?} {:?}", block, s),
-            };
-            let available_lines = Block::LINES - unavailable_lines;
-            spill_avail_histograms[bucket] += available_lines;
-            total_available_lines += available_lines;
-        }
-        total_available_lines
-    }
-
-    /// Calculate the defrag threshold.
-    fn establish_defrag_spill_threshold(&self, space: &ImmixSpace) {
-        let mut spill_avail_histograms = self.new_histogram();
-        let clean_lines = self.get_available_lines(space, &mut spill_avail_histograms);
-        let available_lines = clean_lines
-            + (self
-                .available_clean_pages_for_defrag
-                .load(Ordering::Acquire)
-                << (12 as usize - 8));
-
-        // Number of lines we will evacuate.
-        let mut required_lines = 0isize;
-        // Number of to-space free lines we can use for defragmentation.
-        let mut limit = (available_lines as f32 / Self::DEFRAG_LINE_REUSE_RATIO) as isize;
-        let mut threshold = IMMIX_LINES_PER_BLOCK >> 1;
-        let mark_histograms = self.mark_histograms.lock();
-        // Blocks are grouped by buckets, indexed by the number of holes in the block.
-        // `mark_histograms` remembers the number of live lines for each bucket.
-        // Here, reversely iterate all the bucket to find a threshold that all buckets above this
-        // threshold can be evacuated, without causing to-space overflow.
-        for index in (Self::MIN_SPILL_THRESHOLD..Self::NUM_BINS).rev() {
-            threshold = index;
-            // Calculate total number of live lines in this bucket.
-            let this_bucket_mark = mark_histograms
-                .iter()
-                .map(|v| v[threshold] as isize)
-                .sum::<isize>();
-            // Calculate the number of free lines in this bucket.
-            let this_bucket_avail = spill_avail_histograms[threshold] as isize;
-            // Update counters
-            limit -= this_bucket_avail as isize;
-            required_lines += this_bucket_mark;
-            // Stop scanning. Lines to evacuate exceeds the free to-space lines.
-            if limit < required_lines {
-                break;
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    enum Event {
+        Start(GcReason),
+        Phase(GcPhase),
+        End(usize),
+        AfterPause(usize),
+    }
+
+    struct Recorder(Arc<parking_lot::Mutex<Vec<Event>>>);
+
+    impl GcObserver for Recorder {
+        fn gc_start(&mut self, reason: GcReason) {
+            self.0.lock().push(Event::Start(reason));
+        }
+        fn gc_phase(&mut self, phase: GcPhase) {
+            self.0.lock().push(Event::Phase(phase));
+        }
+        fn gc_end(&mut self, stats: &HeapStats) {
+            self.0.lock().push(Event::End(stats.collections));
+        }
+        fn gc_after_pause(&mut self, stats: &HeapStats) {
+            self.0.lock().push(Event::AfterPause(stats.collections));
+        }
+    }
+
+    #[test]
+    fn gc_observers_are_notified_at_phase_boundaries() {
+        let mut mutator = instantiate_immix::<NoOpStackDecoder>(
+            ImmixOptions::default().with_heap_size(16 * 1024 * 1024),
+        );
+        let events = Arc::new(parking_lot::Mutex::new(vec![]));
+        assert!(mutator.add_gc_observer(Recorder(events.clone())));
+        mutator.collect(&mut []);
+        let mut mutator_ref = mutator.clone();
+        mutator
+            .heap_ref()
+            .collect_alloc_failure(&mut mutator_ref, &mut []);
+        let cycle = |reason, collections| {
+            vec![
+                Event::Start(reason),
+                Event::Phase(GcPhase::RootsScanned),
+                Event::Phase(GcPhase::Marked),
+                Event::Phase(GcPhase::WeakProcessed),
+                Event::Phase(GcPhase::Swept),
+                Event::End(collections),
+                Event::AfterPause(collections),
+            ]
+        };
+        let mut expected = cycle(GcReason::Requested, 1);
+        expected.extend(cycle(GcReason::AllocationFailure, 2));
+        assert_eq!(*events.lock(), expected);
+    }
+
+    struct Allocator {
+        mutator: *mut MutatorRef<Immix>,
+        cache: Arc<parking_lot::Mutex<Vec<usize>>>,
+    }
+
+    unsafe impl Send for Allocator {}
+
+    impl GcObserver for Allocator {
+        fn gc_after_pause(&mut self, stats: &HeapStats) {
+            let mutator = unsafe { &mut *self.mutator };
+            let node = node(mutator, stats.collections, None);
+            self.cache.lock().push(node.value);
+            if stats.collections == 1 {
+                // nested cycle must not notify observers that are running.
+                mutator.collect(&mut []);
             }
         }
-        // println!("threshold: {}", threshold);
-        debug_assert!(threshold >= Self::MIN_SPILL_THRESHOLD);
-        self.defrag_spill_threshold
-            .store(threshold, Ordering::Release);
     }
 
-    /// Release work. Should be called in ImmixSpace::release.
-    #[allow(clippy::assertions_on_constants)]
-    pub fn release(&self, _space: &ImmixSpace) {
-        self.in_defrag_collection.store(false, Ordering::Release);
+    #[test]
+    fn gc_observers_may_allocate_after_pause() {
+        let mut mutator = instantiate_immix::<NoOpStackDecoder>(
+            ImmixOptions::default().with_heap_size(16 * 1024 * 1024),
+        );
+        let cache = Arc::new(parking_lot::Mutex::new(vec![]));
+        let events = Arc::new(parking_lot::Mutex::new(vec![]));
+        node(&mut mutator, 0, None);
+        let pointer = &mut mutator as *mut _;
+        mutator.add_gc_observer(Allocator {
+            mutator: pointer,
+            cache: cache.clone(),
+        });
+        mutator.collect(&mut []);
+        mutator.add_gc_observer(Recorder(events.clone()));
+        mutator.collect(&mut []);
+        assert_eq!(*cache.lock(), vec![1, 3]);
+        assert_eq!(events.lock().last(), Some(&Event::AfterPause(3)));
     }
-}*/
 
-impl<Decoder: StackValueDecoder> Drop for Immix<Decoder> {
-    fn drop(&mut self) {
-        unsafe {
-            if self.verbose > 0 {
-                eprintln!("Dispose immix space at {:p}", self);
+    struct Large([usize; 4096]);
+
+    unsafe impl Trace for Large {}
+    unsafe impl Finalize for Large {}
+    impl Collectable for Large {}
+
+    #[test]
+    fn verifier_accepts_valid_heap() {
+        let mut mutator = instantiate_immix::<NoOpStackDecoder>(
+            ImmixOptions::default()
+                .with_heap_size(16 * 1024 * 1024)
+                .with_verify(true),
+        );
+        let stack = mutator.shadow_stack();
+        letroot!(list = stack, None::<Gc<Node, Immix>>);
+        for i in 0..1000 {
+            *list = Some(node(&mut mutator, i, *list));
+            node(&mut mutator, i, None);
+            if i % 100 == 0 {
+                mutator.allocate(Large([i; 4096]), AllocationSpace::New);
             }
-            Box::from_raw(self.space as *const ImmixSpace as *mut ImmixSpace);
         }
+        mutator.collect(&mut []);
+        mutator.collect(&mut []);
+        let mut count = 0;
+        let mut cursor = *list;
+        while let Some(node) = cursor {
+            count += 1;
+            cursor = node.next;
+        }
+        assert_eq!(count, 1000);
+    }
+
+    struct Corrupted {
+        field: NonNull<HeapObjectHeader>,
+    }
+
+    unsafe impl Trace for Corrupted {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            vis.mark_object(&mut self.field);
+        }
+    }
+    unsafe impl Finalize for Corrupted {}
+    impl Collectable for Corrupted {}
+
+    #[test]
+    fn verifier_reports_reference_outside_of_heap() {
+        let mut mutator = instantiate_immix::<NoOpStackDecoder>(
+            ImmixOptions::default().with_heap_size(16 * 1024 * 1024),
+        );
+        let bogus = Box::into_raw(Box::new([0u64; 4])).cast::<HeapObjectHeader>();
+        let stack = mutator.shadow_stack();
+        letroot!(
+            corrupted = stack,
+            mutator.allocate(
+                Corrupted {
+                    field: NonNull::new(bogus).unwrap()
+                },
+                AllocationSpace::New
+            )
+        );
+        mutator.save_conservative_roots();
+        let heap = mutator.heap_ref();
+        heap.global_lock();
+        let error = unsafe { heap.verify_heap(false) }.unwrap_err();
+        heap.global_unlock();
+        assert_eq!(error.object, bogus);
+        assert_eq!(error.parent.unwrap().0, corrupted.base.as_ptr());
+        assert_eq!(error.root, RootSource::ShadowStack(0));
+        assert_eq!(error.problem, "pointer is outside of GC heap");
+        drop(unsafe { Box::from_raw(bogus.cast::<[u64; 4]>()) });
+    }
+
+    /// Never inlined so that the saved stack pointer is below locals of the caller.
+    #[inline(never)]
+    fn verify_from_callee(
+        mutator: &MutatorRef<Immix<NoOpStackDecoder>>,
+    ) -> Result<usize, VerifyError> {
+        mutator.save_conservative_roots();
+        let heap = mutator.heap_ref();
+        heap.global_lock();
+        let result = unsafe { heap.verify_heap(false) };
+        heap.global_unlock();
+        result
+    }
+
+    #[test]
+    fn verifier_traces_conservative_roots() {
+        let mut mutator = instantiate_immix::<NoOpStackDecoder>(
+            ImmixOptions::default().with_heap_size(16 * 1024 * 1024),
+        );
+        let bogus = Box::into_raw(Box::new([0u64; 4])).cast::<HeapObjectHeader>();
+        let corrupted = mutator.allocate(
+            Corrupted {
+                field: NonNull::new(bogus).unwrap(),
+            },
+            AllocationSpace::New,
+        );
+        let corrupted = std::hint::black_box(&corrupted);
+        let error = verify_from_callee(&mutator).unwrap_err();
+        assert_eq!(error.object, bogus);
+        assert_eq!(error.parent.unwrap().0, corrupted.base.as_ptr());
+        assert!(matches!(error.root, RootSource::ConservativeStack(_)));
+        drop(unsafe { Box::from_raw(bogus.cast::<[u64; 4]>()) });
+    }
+
+    #[test]
+    fn stress_mode_collects_every_n_allocations() {
+        let mut mutator = instantiate_immix::<NoOpStackDecoder>(
+            ImmixOptions::default()
+                .with_heap_size(16 * 1024 * 1024)
+                .with_verify(true)
+                .with_stress(GcStress::Allocations(10)),
+        );
+        let stack = mutator.shadow_stack();
+        letroot!(list = stack, None::<Gc<Node, Immix>>);
+        for i in 0..100 {
+            *list = Some(node(&mut mutator, i, *list));
+        }
+        assert_eq!(mutator.heap_stats().collections, 10);
+        let mut cursor = *list;
+        for i in (0..100).rev() {
+            let node = cursor.unwrap();
+            assert_eq!(node.value, i);
+            cursor = node.next;
+        }
+    }
+
+    #[inline(never)]
+    fn list(mutator: &mut MutatorRef<Immix>, length: usize) -> Option<Gc<Node, Immix>> {
+        let mut list = None;
+        for i in 0..length {
+            list = Some(node(mutator, i, list));
+        }
+        list
+    }
+
+    fn addresses(list: Option<Gc<Node, Immix>>) -> Vec<usize> {
+        let mut addresses = vec![];
+        let mut cursor = list;
+        while let Some(node) = cursor {
+            addresses.push(node.base.as_ptr() as usize);
+            cursor = node.next;
+        }
+        addresses
+    }
+
+    #[test]
+    fn stress_mode_evacuates_objects_on_safepoint() {
+        let mut mutator = instantiate_immix::<NoOpStackDecoder>(
+            ImmixOptions::default()
+                .with_heap_size(16 * 1024 * 1024)
+                .with_verify(true),
+        );
+        let stack = mutator.shadow_stack();
+        letroot!(head = stack, list(&mut mutator, 100));
+        let before = addresses(*head);
+        mutator.set_gc_stress(GcStress::Safepoints);
+        assert!(mutator.safepoint());
+        mutator.set_gc_stress(GcStress::Disabled);
+        assert_eq!(mutator.heap_stats().collections, 1);
+        let after = addresses(*head);
+        assert_eq!(after.len(), 100);
+        // objects found by conservative stack scan are pinned, everything else is evacuated.
+        let moved = before
+            .iter()
+            .zip(after.iter())
+            .filter(|(a, b)| a != b)
+            .count();
+        assert!(moved > 90);
     }
 }
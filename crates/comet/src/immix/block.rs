@@ -1,4 +1,5 @@
 use super::*;
+use crate::poison::poison;
 pub const IMMIX_BLOCK_SIZE: usize = 32 * 1024;
 pub const IMMIX_LINE_SIZE: usize = 256;
 pub const IMMIX_LINES_PER_BLOCK: usize = IMMIX_BLOCK_SIZE / IMMIX_LINE_SIZE;
@@ -20,6 +21,8 @@ pub struct ImmixBlock {
     pub state: BlockState,
     hole_count: u32,
     fragmented: bool,
+    /// Set when block might contain objects that need finalization.
+    finalizable: bool,
 }
 
 impl ImmixBlock {
@@ -35,6 +38,7 @@ impl ImmixBlock {
 
     pub fn deinit(&mut self) {
         self.state = BlockState::Unallocated;
+        self.finalizable = false;
     }
     pub fn init(&mut self, copy: bool) {
         self.state = if copy {
@@ -44,6 +48,7 @@ impl ImmixBlock {
         };
         self.hole_count = 0;
         self.fragmented = false;
+        self.finalizable = false;
         self.next = null_mut();
     }
     pub fn next_atomic(&self) -> &AtomicPtr<ImmixBlock> {
@@ -77,6 +82,21 @@ impl ImmixBlock {
         self.fragmented
     }
 
+    /// Mark block as evacuation candidate for current GC cycle.
+    pub fn set_fragmented(&mut self, fragmented: bool) {
+        self.fragmented = fragmented;
+    }
+
+    /// Returns `true` if block might contain objects that need finalization.
+    pub fn has_finalizable(&self) -> bool {
+        self.finalizable
+    }
+
+    /// Record that object that needs finalization was allocated in block.
+    pub fn set_has_finalizable(&mut self) {
+        self.finalizable = true;
+    }
+
     pub fn holes(&self) -> usize {
         self.hole_count as _
     }
@@ -130,35 +150,129 @@ impl ImmixBlock {
         align_down(addr as _, IMMIX_BLOCK_SIZE) as _
     }
 
-    /// Sweep Immix block. Returns `true` if block is dead.
-    pub fn sweep(&mut self, space: &ImmixSpace, sweep_color: u8) -> bool {
+    /// Sweep Immix block. Objects for which colour `is_dead` returns true are finalized. Returns `true` if block is dead.
+    pub fn sweep(&mut self, space: &ImmixSpace, is_dead: impl Fn(u8) -> bool) -> bool {
         if self.state == BlockState::Unallocated {
             // unallocated blocks go to free list instantly
             space.free_blocks.push(self as *mut Self);
             return true;
         }
-        let chunk = self.chunk();
-        let line_mark_table = unsafe { (&*chunk).line_mark_table() };
-        let mut marked_lines = 0;
+        let mut marked_lines = self.count_lines();
+        if self.sweep_objects(space, marked_lines, is_dead) {
+            marked_lines = self.count_lines();
+        }
+        self.account_marked_lines(space, marked_lines);
+        self.release(space, marked_lines)
+    }
+
+    /// Account live lines of block and queue it for lazy sweeping. Objects are not visited. Returns `true` if block is
+    /// unallocated.
+    pub fn queue_for_sweep(&mut self, space: &ImmixSpace) -> bool {
+        if self.state == BlockState::Unallocated {
+            space.free_blocks.push(self as *mut Self);
+            return true;
+        }
+        let marked_lines = self.count_lines();
+        self.account_marked_lines(space, marked_lines);
+        space.unswept_blocks.push(self as *mut Self);
+        false
+    }
+
+    /// Sweep block that was queued by [ImmixBlock::queue_for_sweep]. Returns `true` if block is dead.
+    pub fn sweep_lazily(&mut self, space: &ImmixSpace, is_dead: impl Fn(u8) -> bool) -> bool {
+        // line marks are not changed until next GC so number of marked lines is the same as at queueing time.
+        let mut marked_lines = self.count_lines();
+        if self.sweep_objects(space, marked_lines, is_dead) {
+            // lines of objects queued for finalization were not accounted at queueing time.
+            let queued_lines = self.count_lines();
+            space.num_bytes_allocated.fetch_add(
+                (queued_lines - marked_lines) * IMMIX_LINE_SIZE,
+                Ordering::Relaxed,
+            );
+            marked_lines = queued_lines;
+        }
+        self.release(space, marked_lines)
+    }
+
+    /// Finalize dead objects that need finalization and remove dead objects from mark bitmap. Dead block without
+    /// finalizable objects is cleared from mark bitmap without visiting its objects. Returns `true` if some object was
+    /// queued for finalization on finalizer thread, lines of such objects are marked so their memory is kept.
+    fn sweep_objects(
+        &mut self,
+        space: &ImmixSpace,
+        marked_lines: usize,
+        is_dead: impl Fn(u8) -> bool,
+    ) -> bool {
         let start = self.line(1);
-        let end = self.line(IMMIX_LINES_PER_BLOCK as u8 - 1);
+        let end = self.end();
+        if marked_lines == 0 && !self.finalizable {
+            space.mark_bitmap.clear_range(start, end);
+            return false;
+        }
+        let mut finalizable = false;
+        let mut queued = false;
         space
             .mark_bitmap
             .visit_marked_range(start, end, |object| unsafe {
-                if (*object).get_color() == sweep_color {
+                if (*object).is_forwarded() {
+                    // object was evacuated and its copy is alive in another block.
                     space.mark_bitmap.clear(object as _);
-                    (*object).get_dyn().finalize();
+                } else if is_dead((*object).get_color()) {
+                    space.mark_bitmap.clear(object as _);
+                    if (*object).finalizable_bit() && space.finalize(object) {
+                        space.mark_lines(object);
+                        queued = true;
+                    }
                     debug_assert!(!space.mark_bitmap.test(object as _));
                 } else {
+                    finalizable |= (*object).finalizable_bit();
                     debug_assert!(space.mark_bitmap.test(object as _));
                 }
             });
+        self.finalizable = finalizable;
+        queued
+    }
 
+    /// Count marked lines and holes in block. Returns number of marked lines.
+    fn count_lines(&mut self) -> usize {
+        let line_mark_table = unsafe { (*self.chunk()).line_mark_table() };
+        let mut marked_lines = 0;
+        let mut holes = 0;
+        let mut prev_line_marked = true;
         for i in 1..IMMIX_LINES_PER_BLOCK {
             // count number of marked lines so we can update num_bytes_allocated
-            if line_mark_table.test(self.line(i as _)) {
+            let marked = line_mark_table.test(self.line(i as _));
+            if marked {
                 marked_lines += 1;
+            } else if prev_line_marked {
+                // first unmarked line after marked one starts new hole
+                holes += 1;
             }
+            prev_line_marked = marked;
+        }
+        self.hole_count = holes;
+        marked_lines
+    }
+
+    /// Increase num_bytes_allocated with memory that is occupied by marked lines.
+    fn account_marked_lines(&self, space: &ImmixSpace, marked_lines: usize) {
+        if marked_lines != 0 {
+            space
+                .num_bytes_allocated
+                .fetch_add(marked_lines * IMMIX_LINE_SIZE, Ordering::Relaxed);
+            space.defrag.add_marked_lines(self.holes(), marked_lines);
+        }
+    }
+
+    /// Add swept block to free or reusable list. Returns `true` if block is dead.
+    fn release(&mut self, space: &ImmixSpace, marked_lines: usize) -> bool {
+        if space.freed_memory.poisons() {
+            self.poison_holes();
+        }
+        if !space.freed_memory.reuses() {
+            // quarantined block is kept allocated and never handed to allocator again
+            self.state = BlockState::Unmarked;
+            return false;
         }
         if marked_lines == 0 {
             // zero marked lines means object does not have live object. Release it and add to free list
@@ -166,12 +280,6 @@ impl ImmixBlock {
 
             true
         } else {
-            // Block has at least 1 marked line. Keep it and increase num_bytes_allocated with memory that
-            // is occupied by marked lines
-            space
-                .num_bytes_allocated
-                .fetch_add(marked_lines * IMMIX_LINE_SIZE, Ordering::Relaxed);
-
             if marked_lines != IMMIX_LINES_PER_BLOCK - 1 {
                 // block has unmarked lines that are available for allocation, mark it as reusable
                 // and push to reusable list
@@ -189,6 +297,20 @@ impl ImmixBlock {
 }
 
 impl ImmixBlock {
+    /// Fill unmarked lines with poison pattern. Every line occupied by live object is marked so only dead memory
+    /// is overwritten.
+    fn poison_holes(&self) {
+        unsafe {
+            let line_mark_table = (*self.chunk()).line_mark_table();
+            for i in 1..IMMIX_LINES_PER_BLOCK {
+                let line = self.line(i as _);
+                if !line_mark_table.test(line) {
+                    poison(line, IMMIX_LINE_SIZE);
+                }
+            }
+        }
+    }
+
     pub fn from_object(object: *const u8) -> *mut Self {
         unsafe {
             let offset = object as usize % IMMIX_BLOCK_SIZE;
@@ -273,6 +395,7 @@ impl BlockList {
     #[inline]
     pub fn reset(&self) {
         self.head.store(null_mut(), Ordering::Relaxed);
+        self.count.store(0, Ordering::Relaxed);
     }
 
     /// Get an array of all reusable blocks stored in this BlockList.
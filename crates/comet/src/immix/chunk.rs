@@ -52,13 +52,13 @@ impl Chunk {
     }
 
     /// Sweep single chunk. If chunk is empty it's entry in chunk map is cleared
-    pub fn sweep(&mut self, space: &ImmixSpace, sweep_color: u8) {
+    pub fn sweep(&mut self, space: &ImmixSpace, is_dead: impl Fn(u8) -> bool + Copy) {
         let mut cursor = 1;
         let mut allocated_blocks = 0;
         while cursor < CHUNK_BLOCKS {
             let block = self.block(cursor);
             unsafe {
-                if !(*block).sweep(space, sweep_color) {
+                if !(*block).sweep(space, is_dead) {
                     allocated_blocks += 1;
                 }
             }
@@ -69,6 +69,17 @@ impl Chunk {
         }
     }
 
+    /// Queue allocated blocks of chunk for lazy sweeping. Chunk map entry is kept because queued blocks are not
+    /// released until they are swept.
+    pub fn queue_for_sweep(&mut self, space: &ImmixSpace) {
+        for cursor in 1..CHUNK_BLOCKS {
+            let block = self.block(cursor);
+            unsafe {
+                (*block).queue_for_sweep(space);
+            }
+        }
+    }
+
     pub fn align(addr: *const u8) -> *mut u8 {
         align_down(addr as _, CHUNK_SIZE) as _
     }
@@ -0,0 +1,267 @@
+//! Opportunistic defragmentation for Immix space.
+//!
+//! Every block sweep records number of holes in block and number of marked lines in it. Blocks are grouped into buckets
+//! (indexed by number of holes) and when next GC cycle starts spill threshold is chosen: all blocks with at least that many holes
+//! are evacuation candidates and their live objects can be copied into clean blocks during marking. Threshold is chosen so that
+//! live lines from all candidate blocks fit into clean blocks that are available, evacuation is still opportunistic: once clean blocks
+//! are exhausted objects are simply marked in place.
+//!
+//! Objects that are found by conservative stack scanning are pinned and never evacuated.
+use super::*;
+use parking_lot::Mutex;
+use std::sync::atomic::AtomicBool;
+
+pub type Histogram = [usize; Defrag::NUM_BINS];
+
+pub struct Defrag {
+    in_defrag_collection: AtomicBool,
+    /// Number of marked lines in blocks grouped by number of holes in block. Collected during the last sweep.
+    mark_histogram: Mutex<Histogram>,
+    /// A block with number of holes greater than or equal to this threshold will be defragmented.
+    defrag_spill_threshold: AtomicUsize,
+    /// The number of remaining clean blocks that can be used for evacuation.
+    available_clean_blocks: AtomicUsize,
+}
+
+impl Defrag {
+    pub const NUM_BINS: usize = (IMMIX_LINES_PER_BLOCK >> 1) + 1;
+    const DEFRAG_LINE_REUSE_RATIO: f32 = 0.99;
+    const MIN_SPILL_THRESHOLD: usize = 2;
+
+    pub fn new() -> Self {
+        Self {
+            in_defrag_collection: AtomicBool::new(false),
+            mark_histogram: Mutex::new([0; Self::NUM_BINS]),
+            defrag_spill_threshold: AtomicUsize::new(Self::NUM_BINS),
+            available_clean_blocks: AtomicUsize::new(0),
+        }
+    }
+
+    /// Check if the current GC is a defrag GC.
+    #[inline(always)]
+    pub fn in_defrag(&self) -> bool {
+        self.in_defrag_collection.load(Ordering::Acquire)
+    }
+
+    #[inline(always)]
+    pub fn spill_threshold(&self) -> usize {
+        self.defrag_spill_threshold.load(Ordering::Acquire)
+    }
+
+    /// Report marked lines of swept block.
+    pub fn add_marked_lines(&self, holes: usize, marked_lines: usize) {
+        self.mark_histogram.lock()[holes] += marked_lines;
+    }
+
+    /// Determine whether the current GC should do defragmentation. Defragmentation is triggered when allocator did not
+    /// manage to reuse all recyclable blocks before GC (i.e holes are too small for allocations) and there is enough clean blocks
+    /// to evacuate at least one bucket of fragmented blocks.
+    pub fn decide_whether_to_defrag(&self, enabled: bool, space: &ImmixSpace) {
+        let exhausted_reusable_space = space.reusable_blocks.len() == 0;
+        let in_defrag = enabled
+            && !exhausted_reusable_space
+            && self.establish_defrag_spill_threshold(space) < Self::NUM_BINS;
+        self.in_defrag_collection
+            .store(in_defrag, Ordering::Release);
+    }
+
+    /// Make the current GC a defrag GC that evacuates objects from all blocks while there are clean blocks available.
+    /// Used by GC stress mode.
+    pub fn force_defrag(&self, space: &ImmixSpace) {
+        self.available_clean_blocks
+            .store(space.free_blocks.len(), Ordering::Release);
+        self.defrag_spill_threshold.store(0, Ordering::Release);
+        self.in_defrag_collection.store(true, Ordering::Release);
+    }
+
+    /// Calculate the defrag threshold. Returns [Defrag::NUM_BINS] if there is nothing to defragment.
+    fn establish_defrag_spill_threshold(&self, space: &ImmixSpace) -> usize {
+        let clean_blocks = space.free_blocks.len();
+        self.available_clean_blocks
+            .store(clean_blocks, Ordering::Release);
+        // Number of to-space free lines we can use for defragmentation.
+        let limit = (clean_blocks * (IMMIX_LINES_PER_BLOCK - 1)) as f32
+            * Self::DEFRAG_LINE_REUSE_RATIO;
+        let limit = limit as usize;
+        // Number of lines we will evacuate.
+        let mut required_lines = 0;
+        let mut threshold = Self::NUM_BINS;
+        let mark_histogram = self.mark_histogram.lock();
+        // Blocks are grouped by buckets, indexed by the number of holes in the block.
+        // Here, reversely iterate all the buckets to find a threshold that all buckets above this
+        // threshold can be evacuated, without causing to-space overflow.
+        for index in (Self::MIN_SPILL_THRESHOLD..Self::NUM_BINS).rev() {
+            required_lines += mark_histogram[index];
+            if required_lines > limit {
+                break;
+            }
+            threshold = index;
+        }
+        // do not defragment if there is no fragmented blocks at all.
+        if mark_histogram[threshold..].iter().all(|lines| *lines == 0) {
+            threshold = Self::NUM_BINS;
+        }
+        self.defrag_spill_threshold
+            .store(threshold, Ordering::Release);
+        threshold
+    }
+
+    /// Get clean block for evacuation. Returns null if there is no more blocks available for defragmentation.
+    pub fn acquire_clean_block(&self, space: &ImmixSpace) -> *mut ImmixBlock {
+        if self
+            .available_clean_blocks
+            .fetch_update(Ordering::AcqRel, Ordering::Relaxed, |blocks| {
+                blocks.checked_sub(1)
+            })
+            .is_err()
+        {
+            return null_mut();
+        }
+        let block = space.get_clean_block();
+        if !block.is_null() {
+            unsafe {
+                (*block).init(true);
+            }
+        }
+        block
+    }
+
+    /// Prepare for sweep. Should be called in ImmixSpace::release before blocks are swept.
+    pub fn prepare_for_sweep(&self) {
+        *self.mark_histogram.lock() = [0; Self::NUM_BINS];
+    }
+
+    /// Release work. Should be called in ImmixSpace::release.
+    pub fn release(&self) {
+        self.in_defrag_collection.store(false, Ordering::Release);
+        self.available_clean_blocks.store(0, Ordering::Release);
+    }
+}
+
+impl Default for Defrag {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Bump allocator that is used by GC to copy objects out of fragmented blocks. It allocates only in clean blocks.
+pub struct EvacuationAllocator {
+    cursor: *mut u8,
+    limit: *mut u8,
+    space: &'static ImmixSpace,
+    evacuated_bytes: usize,
+}
+
+// Each marking worker owns its allocator.
+unsafe impl Send for EvacuationAllocator {}
+
+impl EvacuationAllocator {
+    pub fn new(space: &'static ImmixSpace) -> Self {
+        Self {
+            cursor: null_mut(),
+            limit: null_mut(),
+            space,
+            evacuated_bytes: 0,
+        }
+    }
+
+    /// Allocate memory for evacuated object. Returns null if defragmentation space is exhausted.
+    pub unsafe fn alloc(&mut self, size: usize) -> *mut u8 {
+        if self.cursor.is_null() || self.cursor.add(size) > self.limit {
+            let block = self.space.defrag.acquire_clean_block(self.space);
+            if block.is_null() {
+                return null_mut();
+            }
+            self.cursor = (*block).start_address();
+            self.limit = (*block).end();
+        }
+        let result = self.cursor;
+        self.cursor = result.add(size);
+        self.evacuated_bytes += size;
+        self.space.mark_bitmap.set_sync(result);
+        result
+    }
+
+    /// Number of bytes evacuated since last reset.
+    pub fn evacuated_bytes(&self) -> usize {
+        self.evacuated_bytes
+    }
+
+    pub fn reset(&mut self) {
+        self.cursor = null_mut();
+        self.limit = null_mut();
+        self.evacuated_bytes = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        api::{Collectable, Finalize, Gc, Trace, Visitor},
+        gc_base::AllocationSpace,
+        immix::{instantiate_immix, Immix, ImmixOptions},
+        letroot,
+    };
+
+    struct Node {
+        value: usize,
+        next: Option<Gc<Node, Immix>>,
+        _padding: [usize; 4],
+    }
+
+    unsafe impl Trace for Node {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.next.trace(vis);
+        }
+    }
+    unsafe impl Finalize for Node {}
+    impl Collectable for Node {}
+
+    fn addresses(mut cursor: Option<Gc<Node, Immix>>) -> Vec<usize> {
+        let mut addresses = vec![];
+        while let Some(node) = cursor {
+            addresses.push(node.base.as_ptr() as usize);
+            cursor = node.next;
+        }
+        addresses
+    }
+
+    #[test]
+    fn fragmented_blocks_are_evacuated() {
+        let mut mutator = instantiate_immix::<crate::gc_base::NoOpStackDecoder>(
+            ImmixOptions::default().with_heap_size(16 * 1024 * 1024),
+        );
+        let stack = mutator.shadow_stack();
+        letroot!(list = stack, None::<Gc<Node, Immix>>);
+        for i in 0..50000 {
+            let mut node = mutator.allocate(
+                Node {
+                    value: i,
+                    next: None,
+                    _padding: [0; 4],
+                },
+                AllocationSpace::New,
+            );
+            if i % 16 == 0 {
+                node.next = *list;
+                *list = Some(node);
+            }
+        }
+        // first cycle leaves blocks fragmented and collects statistics for defragmentation
+        mutator.collect(&mut []);
+        let before = addresses(*list);
+        mutator.collect(&mut []);
+        let after = addresses(*list);
+        assert_eq!(before.len(), after.len());
+        assert!(before.iter().zip(after.iter()).any(|(x, y)| x != y));
+
+        let mut cursor = *list;
+        let mut expected = 49984;
+        while let Some(node) = cursor {
+            assert_eq!(node.value, expected);
+            expected = expected.wrapping_sub(16);
+            cursor = node.next;
+        }
+        assert_eq!(expected, usize::MAX - 15);
+    }
+}
@@ -1,9 +1,19 @@
 use super::*;
-use crate::{bitmap::SpaceBitmap, utils::mmap::Mmap};
+use crate::{
+    bitmap::SpaceBitmap,
+    finalizer_thread::{finalize, FinalizerThread},
+    poison::FreedMemory,
+    utils::mmap::Mmap,
+};
+use std::sync::atomic::AtomicU8;
 pub struct ImmixSpace {
     pub map: Mmap,
     pub free_blocks: BlockList,
     pub reusable_blocks: BlockList,
+    /// Blocks that were queued by [ImmixSpace::release_lazily] and are not swept yet.
+    pub unswept_blocks: BlockList,
+    /// Colour of dead objects in [ImmixSpace::unswept_blocks].
+    sweep_color: AtomicU8,
     pub n_chunks: usize,
     pub chunk_map: ChunkMap,
     pub target_footprint: AtomicUsize,
@@ -11,8 +21,15 @@ pub struct ImmixSpace {
     pub initial_size: usize,
     pub min_heap_size: usize,
     pub max_heap_size: usize,
-    pub growth_limit: usize,
+    /// Heap footprint can't exceed this limit. Initially set to `max_heap_size` and might be raised up to
+    /// [ImmixSpace::capacity] by near heap limit callback.
+    pub growth_limit: AtomicUsize,
     pub mark_bitmap: SpaceBitmap<8>,
+    pub defrag: Defrag,
+    /// When set dead objects are finalized by finalizer thread instead of sweeping thread.
+    pub finalizer: Option<FinalizerThread>,
+    /// What happens to memory of dead objects, see [poison](crate::poison).
+    pub freed_memory: FreedMemory,
 }
 
 impl ImmixSpace {
@@ -76,49 +93,96 @@ impl ImmixSpace {
             map: mmap,
             free_blocks: free_list,
             reusable_blocks: BlockList::new(),
+            unswept_blocks: BlockList::new(),
+            sweep_color: AtomicU8::new(0),
             chunk_map,
             num_bytes_allocated: AtomicUsize::new(0),
             target_footprint: AtomicUsize::new(initial_size),
             min_heap_size,
             max_heap_size,
             initial_size,
-            growth_limit: size as _,
+            growth_limit: AtomicUsize::new(max_heap_size.max(min_heap_size).min(size as _)),
+            defrag: Defrag::new(),
+            finalizer: None,
+            freed_memory: FreedMemory::Reuse,
         }
     }
     pub fn init_bitmap(&mut self) {
         self.mark_bitmap = SpaceBitmap::create("immix", self.map.start(), self.map.size());
     }
+    /// Size of memory reserved for Immix space.
+    pub fn capacity(&self) -> usize {
+        self.n_chunks * CHUNK_SIZE
+    }
     pub fn reserved_pages(&self) -> usize {
         self.free_blocks.len() * PAGE_SIZE
     }
 
-    /// Release block by adding it to free list. On Unix platforms it does `madvise` with `MADV_DONTNEED`.
+    /// Record that `object` needs finalization. Large objects only get header bit set, objects in Immix space also
+    /// mark their block as containing finalizable objects.
+    ///
+    /// # Safety
+    ///
+    /// `object` must be allocated in Immix space or large object space of this heap.
+    pub unsafe fn add_finalizable(&self, object: *mut HeapObjectHeader) {
+        (*object).set_finalizable_bit(true);
+        if self.has_address(object.cast()) {
+            (*ImmixBlock::from_object(object.cast())).set_has_finalizable();
+        }
+    }
+
+    /// Finalize dead object or queue it for finalization on finalizer thread. Returns true if object was queued, its
+    /// memory must be kept until finalizer has run.
+    ///
+    /// # Safety
+    ///
+    /// `object` must be dead and not finalized yet.
+    pub unsafe fn finalize(&self, object: *mut HeapObjectHeader) -> bool {
+        finalize(self.finalizer.as_ref(), object)
+    }
+
+    /// Release block by adding it to free list. On Unix platforms it does `madvise` with `MADV_DONTNEED` unless
+    /// freed memory is poisoned.
     pub fn release_block(&self, block: *mut ImmixBlock) {
         unsafe {
             (*block).deinit();
-            self.map.dontneed(block.cast(), IMMIX_BLOCK_SIZE);
+            if !self.freed_memory.poisons() {
+                self.map.dontneed(block.cast(), IMMIX_BLOCK_SIZE);
+            }
             self.free_blocks.push(block);
         }
     }
 
-    /// Get block from free list and initialize it.
+    /// Get block from free list and initialize it. If free list is empty unswept blocks are swept until
+    /// free block is found.
     pub fn get_clean_block(&self) -> *mut ImmixBlock {
-        let block = self.free_blocks.pop();
+        let mut block = self.free_blocks.pop();
+        while block.is_null() && self.sweep_next_block().is_some() {
+            block = self.free_blocks.pop();
+        }
         if block.is_null() {
             return null_mut();
         }
         unsafe {
             (*block).init(false);
 
-            self.chunk_map.set((*block).chunk().cast());
+            self.chunk_map.set_sync((*block).chunk().cast());
 
             block
         }
     }
 
-    /// Get first reusable block
+    /// Get first reusable block. If there are no reusable blocks unswept blocks are swept until either reusable
+    /// block is found or swept block is released. In latter case null is returned and allocator takes released block
+    /// from free list.
     pub fn get_reusable_block(&self) -> *mut ImmixBlock {
-        let block = self.reusable_blocks.pop();
+        let mut block = self.reusable_blocks.pop();
+        while block.is_null() {
+            match self.sweep_next_block() {
+                Some(false) => block = self.reusable_blocks.pop(),
+                _ => return null_mut(),
+            }
+        }
         if block.is_null() {
             return null_mut();
         }
@@ -153,14 +217,37 @@ impl ImmixSpace {
 
             let mut line = start_line;
             while line < end_line {
-                (*chunk).line_mark_table().set(line);
+                (*chunk).line_mark_table().set_sync(line);
                 line = line.add(IMMIX_LINE_SIZE);
             }
         }
     }
+    /// Returns true if every line occupied by object is marked.
+    ///
+    /// # Safety
+    ///
+    /// `object` must be allocated in this space.
+    pub unsafe fn lines_marked(&self, object: *const HeapObjectHeader) -> bool {
+        let block = ImmixBlock::align(object.cast()).cast::<ImmixBlock>();
+        let chunk = (*block).chunk();
+        let end = object.cast::<u8>().add((*object).size());
+        let mut line = line_align(object.cast()) as *const u8;
+        while line < end {
+            if !(*chunk).line_mark_table().test(line) {
+                return false;
+            }
+            line = line.add(IMMIX_LINE_SIZE);
+        }
+        true
+    }
     /// Prepare for marking phase by settings all blocks state to unamrked and possibly clearing
-    /// line mark table if `major_gc` is true.
+    /// line mark table if `major_gc` is true. If current GC is defrag GC fragmented blocks are selected for evacuation.
     pub fn prepare(&self, major_gc: bool) {
+        let spill_threshold = if self.defrag.in_defrag() {
+            self.defrag.spill_threshold()
+        } else {
+            usize::MAX
+        };
         self.chunk_map.visit_marked_range(
             self.map.aligned_start(),
             self.map.end(),
@@ -173,6 +260,7 @@ impl ImmixSpace {
                         continue;
                     }
                     (*block).set_state(BlockState::Unmarked);
+                    (*block).set_fragmented((*block).holes() >= spill_threshold);
                 }
                 if major_gc {
                     // Clear marked lines in order for GC to recycle lines properly after GC
@@ -183,21 +271,163 @@ impl ImmixSpace {
     }
 
     /// Release dead memory after GC cycle. This function will walk all alive chunks
-    /// and sweep allocated blocks in each chunk.
+    /// and sweep allocated blocks in each chunk. Objects with `sweep_color` are dead.
     pub fn release(&self, sweep_color: u8) {
+        self.sweep(move |color| color == sweep_color);
+    }
+
+    /// Same as [ImmixSpace::release] but all objects that do not have `mark_color` are dead.
+    pub fn release_unmarked(&self, mark_color: u8) {
+        self.sweep(move |color| color != mark_color);
+    }
+
+    /// Same as [ImmixSpace::release] but objects are not visited. Live lines are accounted and allocated blocks
+    /// are queued for sweeping, blocks are swept on demand when allocator runs out of free and reusable blocks or
+    /// by [ImmixSpace::finish_sweep].
+    pub fn release_lazily(&self, sweep_color: u8) {
+        debug_assert!(self.unswept_blocks.len() == 0);
         self.reusable_blocks.reset();
         self.free_blocks.reset();
+        self.defrag.prepare_for_sweep();
+        self.sweep_color.store(sweep_color, Ordering::Release);
         self.chunk_map.visit_marked_range(
             self.map.aligned_start(),
             self.map.end(),
             |chunk| unsafe {
                 let chunk = chunk.cast::<Chunk>();
-                (*chunk).sweep(self, sweep_color);
+                (*chunk).queue_for_sweep(self);
             },
         );
+        self.defrag.release();
+    }
+
+    /// Sweep next block queued by [ImmixSpace::release_lazily]. Returns `None` if there are no blocks left to sweep,
+    /// otherwise returns `Some(true)` if swept block was released to free list.
+    pub fn sweep_next_block(&self) -> Option<bool> {
+        let block = self.unswept_blocks.pop();
+        if block.is_null() {
+            return None;
+        }
+        let sweep_color = self.sweep_color.load(Ordering::Acquire);
+        unsafe { Some((*block).sweep_lazily(self, |color| color == sweep_color)) }
+    }
+
+    /// Sweep all blocks that are still queued. Must be invoked before marking since colours of unswept dead objects
+    /// become valid again once colours are flipped.
+    pub fn finish_sweep(&self) {
+        while self.sweep_next_block().is_some() {}
+    }
+
+    fn sweep(&self, is_dead: impl Fn(u8) -> bool + Copy) {
+        self.reusable_blocks.reset();
+        self.free_blocks.reset();
+        self.defrag.prepare_for_sweep();
+        self.chunk_map.visit_marked_range(
+            self.map.aligned_start(),
+            self.map.end(),
+            |chunk| unsafe {
+                let chunk = chunk.cast::<Chunk>();
+                (*chunk).sweep(self, is_dead);
+            },
+        );
+        self.defrag.release();
     }
 
     pub fn acquire_recyclable_lines(&self, line: *mut u8) -> (*mut u8, *mut u8) {
         ImmixBlock::find_hole(line)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        api::{Collectable, Finalize, HeapObjectHeader, Trace},
+        gc_base::AllocationSpace,
+        immix::{instantiate_immix, Immix, ImmixOptions},
+        mutator::MutatorRef,
+        poison::FreedMemory,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static FINALIZED: AtomicUsize = AtomicUsize::new(0);
+
+    struct Finalizable {
+        _padding: [usize; 8],
+    }
+
+    impl Drop for Finalizable {
+        fn drop(&mut self) {
+            FINALIZED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    unsafe impl Trace for Finalizable {}
+    unsafe impl Finalize for Finalizable {}
+    impl Collectable for Finalizable {}
+
+    #[inline(never)]
+    fn allocate_garbage(mutator: &mut MutatorRef<Immix>, count: usize) {
+        for _ in 0..count {
+            mutator.allocate(Finalizable { _padding: [0; 8] }, AllocationSpace::New);
+        }
+    }
+
+    #[test]
+    fn blocks_are_swept_lazily() {
+        let mut mutator = instantiate_immix::<crate::gc_base::NoOpStackDecoder>(
+            ImmixOptions::default()
+                .with_heap_size(16 * 1024 * 1024)
+                .with_lazy_sweep(true),
+        );
+        allocate_garbage(&mut mutator, 10000);
+        mutator.collect(&mut []);
+        // pause only queues blocks for sweeping
+        assert_eq!(FINALIZED.load(Ordering::Relaxed), 0);
+        // allocation sweeps blocks until it finds one it can allocate in
+        allocate_garbage(&mut mutator, 1);
+        let finalized = FINALIZED.load(Ordering::Relaxed);
+        assert!(finalized > 0 && finalized < 10000);
+        // next cycle finishes sweeping before marking. Few objects might be kept alive by conservative stack scanning.
+        mutator.collect(&mut []);
+        assert!(FINALIZED.load(Ordering::Relaxed) >= 9990);
+    }
+
+    struct Garbage {
+        _padding: [usize; 8],
+    }
+
+    unsafe impl Trace for Garbage {}
+    unsafe impl Finalize for Garbage {}
+    impl Collectable for Garbage {}
+
+    /// Allocate unreachable objects and return their addresses. Addresses are stored as integers in heap memory so
+    /// conservative stack scanning does not keep objects alive.
+    #[inline(never)]
+    fn allocate_addresses(mutator: &mut MutatorRef<Immix>, count: usize) -> Vec<usize> {
+        (0..count)
+            .map(|_| {
+                let object = mutator.allocate(Garbage { _padding: [0; 8] }, AllocationSpace::New);
+                object.base.as_ptr() as usize
+            })
+            .collect()
+    }
+
+    #[test]
+    fn quarantined_memory_is_poisoned_and_not_reused() {
+        let mut mutator = instantiate_immix::<crate::gc_base::NoOpStackDecoder>(
+            ImmixOptions::default()
+                .with_heap_size(16 * 1024 * 1024)
+                .with_freed_memory(FreedMemory::Quarantine),
+        );
+        let dead = allocate_addresses(&mut mutator, 1000);
+        mutator.collect(&mut []);
+        // few objects might be kept alive by conservative stack scanning.
+        let poisoned = dead
+            .iter()
+            .filter(|&&object| unsafe { (*(object as *const HeapObjectHeader)).is_poisoned() })
+            .count();
+        assert!(poisoned >= 990, "only {} objects are poisoned", poisoned);
+        let fresh = allocate_addresses(&mut mutator, 1000);
+        assert!(fresh.iter().all(|object| !dead.contains(object)));
+    }
+}
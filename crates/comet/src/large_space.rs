@@ -1,4 +1,5 @@
 use super::api::*;
+use crate::poison::{poison, FreedMemory};
 use std::{ptr::null_mut, sync::atomic::AtomicBool};
 /// Precise allocation used for large objects (>= LARGE_CUTOFF).
 /// Starlight uses mimalloc that already knows what to do for large allocations. The GC shouldn't
@@ -130,11 +131,14 @@ impl PreciseAllocation {
     pub fn sweep(&mut self) -> bool {
         true
     }
-    /// Try to create precise allocation (no way that it will return null for now).
+    /// Try to create precise allocation. Returns null if memory can't be allocated.
     pub fn try_create(size: usize, index_in_space: u32) -> *mut Self {
         let adjusted_alignment_allocation_size = Self::header_size() + size + Self::HALF_ALIGNMENT;
         unsafe {
             let mut space = libc::malloc(adjusted_alignment_allocation_size).cast::<u8>();
+            if space.is_null() {
+                return null_mut();
+            }
 
             let mut adjusted_alignment = false;
             if !is_aligned_for_precise_allocation(space) {
@@ -197,6 +201,8 @@ pub struct LargeObjectSpace {
     pub(crate) precise_allocations_for_this_collection_size: usize,
     pub(crate) precise_allocations_for_this_collection_begin: *mut *mut PreciseAllocation,
     pub(crate) precise_allocations_for_this_collection_end: *mut *mut PreciseAllocation,
+    /// What happens to memory of dead large objects, see [poison](crate::poison).
+    pub(crate) freed_memory: FreedMemory,
 }
 
 impl LargeObjectSpace {
@@ -216,6 +222,7 @@ impl LargeObjectSpace {
             precise_allocations_for_this_collection_end: null_mut(),
             precise_allocations_for_this_collection_begin: null_mut(),
             precise_allocations_for_this_collection_size: 0,
+            freed_memory: FreedMemory::Reuse,
         }
     }
     pub fn begin_marking(&mut self, full: bool) {
@@ -300,6 +307,19 @@ impl LargeObjectSpace {
     }
 
     pub fn sweep(&mut self) -> usize {
+        self.sweep_with_finalizer(|object| unsafe {
+            (*object).get_dyn().finalize();
+            false
+        })
+    }
+
+    /// Sweep space and pass dead objects to `finalize` before their memory is released. If `finalize` returns true
+    /// object was queued for finalization on finalizer thread: its allocation is removed from the space but memory is kept
+    /// and accounted until [LargeObjectSpace::release_finalized] is invoked.
+    pub fn sweep_with_finalizer(
+        &mut self,
+        mut finalize: impl FnMut(*mut HeapObjectHeader) -> bool,
+    ) -> usize {
         let mut src_index = self.precise_allocations_offset_nursery_for_sweep;
         let mut freed = 0;
         let mut dst_index = src_index;
@@ -309,11 +329,10 @@ impl LargeObjectSpace {
             unsafe {
                 (*allocation).sweep();
                 if (*allocation).is_empty() {
-                    self.bytes -= (*allocation).cell_size();
-                    freed += (*allocation).cell_size();
-                    (*(*allocation).cell()).get_dyn().finalize();
-                    (*allocation).destroy();
-
+                    if finalize((*allocation).cell()) {
+                        continue;
+                    }
+                    freed += self.release(allocation);
                     continue;
                 } else {
                     (*(*allocation).cell()).unmark();
@@ -329,17 +348,42 @@ impl LargeObjectSpace {
         freed
     }
 
+    /// Release memory of `object` that was kept by [LargeObjectSpace::sweep_with_finalizer] until its finalizer has
+    /// run. Returns number of freed bytes.
+    ///
+    /// # Safety
+    ///
+    /// `object` must be finalized and must not be released yet.
+    pub unsafe fn release_finalized(&mut self, object: *mut HeapObjectHeader) -> usize {
+        self.release(PreciseAllocation::from_cell(object))
+    }
+
+    unsafe fn release(&mut self, allocation: *mut PreciseAllocation) -> usize {
+        let size = (*allocation).cell_size();
+        self.bytes -= size;
+        if self.freed_memory.poisons() {
+            poison((*allocation).cell().cast(), size);
+        }
+        if self.freed_memory.reuses() {
+            (*allocation).destroy();
+        }
+        size
+    }
+
+    /// Allocate large object of `size` bytes. Returns null if memory can't be allocated.
     pub fn allocate(&mut self, size: usize) -> *mut HeapObjectHeader {
         unsafe {
             let index = self.allocations.len();
             let memory = PreciseAllocation::try_create(size, index as _);
             if memory.is_null() {
-                panic!("LargeObjectSpace: OOM");
+                return null_mut();
             }
 
             self.allocations.push(memory);
             self.bytes += (*memory).cell_size();
             let cell = (*memory).cell();
+            // malloc'ed memory might contain garbage, header bits must be cleared.
+            (*cell).padding = 0;
             (*cell).set_size(0); // size of 0 means object is large.
             (*memory).cell()
         }
@@ -15,11 +15,13 @@
 //!
 //! Comet includes a few GC policies implementations. Each GC policy has its own heap layout and allocation strategy.
 //! Here's the list of all GC policies with links to documentation for them:
+//! - [CMS](cms)
 //! - [Immix](immix)
 //! - [MarkSweep](marksweep)
 //! - [MiniMark](minimark)
 //! - [Semispace](semispace)
-//! - [Shenandoah](shenandoah) (NOTE: Very W.I.P & TBD)
+//! - [StickyImmix](sticky_immix)
+//! - [Shenandoah](shenandoah)
 
 #![feature(
     new_uninit,
@@ -41,21 +43,33 @@ pub mod api;
 pub mod bitmap;
 pub mod bump_pointer_space;
 pub mod card_table;
-#[allow(dead_code)]
 pub mod cms;
+pub mod epoch;
+pub mod finalization_registry;
+pub mod finalizer_thread;
 pub mod gc_base;
 pub mod global;
+pub mod heap_limit;
+pub mod heap_snapshot;
 pub mod immix;
 pub mod large_space;
 pub mod marksweep;
+pub mod minimark;
 pub mod mutator;
+pub mod observer;
+pub mod parallel_marking;
+pub mod poison;
 pub mod rosalloc_space;
 pub mod safepoint;
-#[allow(dead_code)]
+pub mod semispace;
 pub mod shenandoah;
 pub mod space;
+pub mod stats;
 pub mod sticky_immix;
+pub mod stress;
+pub mod sweeper;
 pub mod tlab;
+pub mod verify;
 pub mod waitlists;
 use std::{any::TypeId, marker::PhantomData};
 
@@ -129,7 +143,7 @@ pub(crate) const fn make_small_type_id(id: TypeId) -> u32 {
 macro_rules! gc_offsetof {
     ($name : path, $($field: ident).*) => {
         unsafe {
-            let uninit = std::mem::transmute::<_,$crate::api::Gc<$name,$crate::marksweep::MarkSweep>>(0x4000usize);
+            let uninit = $crate::api::Gc::<$name,$crate::marksweep::MarkSweep>::from_raw(0x4000usize as *mut _);
             let fref = &uninit.$($field).*;
             let faddr = fref as *const _ as usize;
             faddr - 0x4000
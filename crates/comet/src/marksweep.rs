@@ -1,17 +1,27 @@
-use crate::api::Weak;
+use crate::api::{Soft, Weak};
 use crate::bitmap::SpaceBitmap;
+use crate::finalization_registry::FinalizationRegistry;
+use crate::finalizer_thread::{finalize, FinalizerThread};
 use crate::gc_base::{
-    AllocationSpace, MarkingConstraint, MarkingConstraintRuns, NoHelp, NoReadBarrier,
+    AllocError, AllocationSpace, MarkingConstraint, MarkingConstraintRuns, NoHelp, NoReadBarrier,
+    SoftRefPolicy,
 };
+use crate::heap_limit::{HeapLimitAction, NearHeapLimit, NearHeapLimitCallback};
+use crate::observer::{GcObserver, GcObservers, GcPhase, GcReason};
+use crate::poison::FreedMemory;
 use crate::rosalloc_space::{RosAllocSpace, RosAllocTLAB};
+use crate::stats::HeapStats;
+use crate::stress::GcStress;
 use crate::utils::formatted_size;
 use crate::{
-    api::{vtable_of, Collectable, Gc, HeapObjectHeader, Trace, Visitor},
+    api::{vtable_of, Collectable, EphemeronEntry, Gc, HeapObjectHeader, Trace, Visitor},
     gc_base::GcBase,
     large_space::{LargeObjectSpace, PreciseAllocation},
-    mutator::{oom_abort, JoinData, Mutator, MutatorRef, ThreadState},
+    mutator::{JoinData, Mutator, MutatorRef, ThreadState},
+    parallel_marking::{mark_parallel, ParallelMarking},
     safepoint::{GlobalSafepoint, SafepointScope},
     small_type_id,
+    sweeper::rosalloc_parallel_sweep,
     utils::align_usize,
 };
 use atomic::Ordering;
@@ -20,7 +30,7 @@ use parking_lot::{lock_api::RawMutex, RawMutex as Lock};
 use rosalloc::{Rosalloc, NUM_OF_SLOTS};
 use std::ptr::null_mut;
 use std::sync::atomic::AtomicUsize;
-use std::{cell::UnsafeCell, marker::PhantomData, mem::size_of, ptr::NonNull, sync::Arc};
+use std::{cell::UnsafeCell, mem::size_of, ptr::NonNull, sync::Arc};
 
 #[repr(C)]
 pub struct MarkSweep {
@@ -34,7 +44,11 @@ pub struct MarkSweep {
     mark_stack: Vec<*mut HeapObjectHeader>,
     target_footprint: AtomicUsize,
     num_bytes_allocated: AtomicUsize,
-    growth_limit: usize,
+    /// Heap footprint can't exceed this limit. Might be raised up to capacity by near heap limit callback.
+    growth_limit: AtomicUsize,
+    near_heap_limit: NearHeapLimit,
+    stats: HeapStats,
+    observers: GcObservers,
     growth_multiplier: f64,
     max_free: usize,
     min_free: usize,
@@ -42,9 +56,18 @@ pub struct MarkSweep {
     verbose: bool,
     total_gcs: usize,
     weak_refs: Vec<Weak<dyn Collectable, Self>>,
+    soft_refs: Vec<Soft<dyn Collectable, Self>>,
+    soft_ref_policy: SoftRefPolicy,
+    /// Set when GC cycle is started because of allocation failure.
+    alloc_failure: bool,
+    /// Ephemerons found during marking whose keys are not known to be alive yet.
+    ephemerons: Vec<*mut EphemeronEntry>,
+    finalization_registry: FinalizationRegistry<Self>,
     constraints: Vec<Box<dyn MarkingConstraint>>,
     finalize_list: Vector<*mut HeapObjectHeader>,
     finalize_lock: Lock,
+    /// When set dead objects are finalized by finalizer thread instead of GC thread.
+    finalizer: Option<FinalizerThread>,
 }
 fn max_bytes_bulk_allocated_for(size: usize) -> usize {
     if !Rosalloc::is_size_for_thread_local(size) {
@@ -54,6 +77,131 @@ fn max_bytes_bulk_allocated_for(size: usize) -> usize {
     NUM_OF_SLOTS[idx] * bracket_size
 }
 
+/// Options of MarkSweep heap, see [instantiate_marksweep_with_options].
+pub struct MarkSweepOptions {
+    /// Footprint of heap before first GC cycle. By default set to 2MB.
+    pub initial_size: usize,
+    /// Heap footprint can't exceed this limit. By default set to 256MB.
+    pub growth_limit: usize,
+    /// Minimal amount of free memory after GC cycle. By default set to 512KB.
+    pub min_free: usize,
+    /// Maximal amount of free memory after GC cycle. By default set to 2MB.
+    pub max_free: usize,
+    /// Determines by how much heap grows after GC cycle. By default set to 1.75.
+    pub growth_multiplier: f64,
+    /// Size of reserved memory, growth limit might be raised up to it. By default set to 256MB.
+    pub capacity: usize,
+    /// Release free pages to OS eagerly.
+    pub low_memory_mode: bool,
+    /// Number of threads that perform marking and sweeping, must be at least 1.
+    pub num_threads: usize,
+    /// Enables verbose logging to stdout.
+    pub verbose: bool,
+    /// Finalize dead objects of types that opt in with [Finalize::finalize_on_thread](crate::api::Finalize::finalize_on_thread)
+    /// on background finalizer thread instead of GC pause, see [finalizer_thread](crate::finalizer_thread).
+    pub finalizer_thread: bool,
+    /// GC stress mode, see [stress](crate::stress). Overridden by `COMET_GC_STRESS` environment variable. Disabled
+    /// by default.
+    pub stress: GcStress,
+    /// What happens to memory of dead objects, see [poison](crate::poison). Overridden by `COMET_FREED_MEMORY`
+    /// environment variable. Memory is reused by default.
+    pub freed_memory: FreedMemory,
+    /// Fraction of `growth_limit` that target footprint must exceed after GC cycle for near heap limit callback to
+    /// be invoked, see [heap_limit](crate::heap_limit). Disabled by default.
+    pub near_heap_limit_threshold: Option<f64>,
+}
+
+impl MarkSweepOptions {
+    pub fn with_initial_size(mut self, x: usize) -> Self {
+        self.initial_size = x;
+        self
+    }
+    pub fn with_growth_limit(mut self, x: usize) -> Self {
+        self.growth_limit = x;
+        self
+    }
+    pub fn with_min_free(mut self, x: usize) -> Self {
+        self.min_free = x;
+        self
+    }
+    pub fn with_max_free(mut self, x: usize) -> Self {
+        self.max_free = x;
+        self
+    }
+    /// Set growth multiplier. Panics if x <= 1
+    pub fn with_growth_multiplier(mut self, x: f64) -> Self {
+        if x <= 1.0 {
+            panic!("Growth multiplier is too small")
+        }
+        self.growth_multiplier = x;
+        self
+    }
+    pub fn with_capacity(mut self, x: usize) -> Self {
+        self.capacity = x;
+        self
+    }
+    pub fn with_low_memory_mode(mut self, x: bool) -> Self {
+        self.low_memory_mode = x;
+        self
+    }
+    /// Set number of marking and sweeping threads. Panics if x is 0.
+    pub fn with_num_threads(mut self, x: usize) -> Self {
+        if x == 0 {
+            panic!("At least one GC thread is required");
+        }
+        self.num_threads = x;
+        self
+    }
+    pub fn with_verbose(mut self, x: bool) -> Self {
+        self.verbose = x;
+        self
+    }
+    pub fn with_finalizer_thread(mut self, x: bool) -> Self {
+        self.finalizer_thread = x;
+        self
+    }
+    /// Set GC stress mode.
+    pub fn with_stress(mut self, x: GcStress) -> Self {
+        self.stress = x;
+        self
+    }
+    /// Set what happens to memory of dead objects.
+    pub fn with_freed_memory(mut self, x: FreedMemory) -> Self {
+        self.freed_memory = x;
+        self
+    }
+    /// Set near heap limit threshold. Panics if x is not in (0, 1] range.
+    pub fn with_near_heap_limit_threshold(mut self, x: f64) -> Self {
+        if x <= 0.0 || x > 1.0 {
+            panic!("Near heap limit threshold must be in (0, 1] range")
+        }
+        self.near_heap_limit_threshold = Some(x);
+        self
+    }
+}
+
+impl Default for MarkSweepOptions {
+    fn default() -> Self {
+        Self {
+            initial_size: MS_DEFAULT_INITIAL_SIZE,
+            growth_limit: MS_DEFAULT_MAXIMUM_SIZE,
+            min_free: MS_DEFAULT_MIN_FREE,
+            max_free: MS_DEFAULT_MAX_FREE,
+            growth_multiplier: 1.75,
+            capacity: MS_DEFAULT_MAXIMUM_SIZE,
+            low_memory_mode: false,
+            num_threads: 1,
+            verbose: false,
+            finalizer_thread: false,
+            stress: GcStress::Disabled,
+            freed_memory: FreedMemory::Reuse,
+            near_heap_limit_threshold: None,
+        }
+    }
+}
+
+/// Create MarkSweep heap. `num_threads` is number of threads that perform marking and sweeping, must be at least 1.
+/// Use [instantiate_marksweep_with_options] to configure options that are not accepted by this function.
 pub fn instantiate_marksweep(
     initial_size: usize,
     growth_limit: usize,
@@ -65,18 +213,44 @@ pub fn instantiate_marksweep(
     num_threads: usize,
     verbose: bool,
 ) -> MutatorRef<MarkSweep> {
-    let heap = Arc::new(UnsafeCell::new(MarkSweep::new(
+    instantiate_marksweep_with_options(MarkSweepOptions {
         initial_size,
         growth_limit,
         min_free,
         max_free,
-        growht_multiplier,
+        growth_multiplier: growht_multiplier,
         capacity,
         low_memory_mode,
         num_threads,
         verbose,
+        ..Default::default()
+    })
+}
+
+/// Create MarkSweep heap configured by `options`.
+pub fn instantiate_marksweep_with_options(options: MarkSweepOptions) -> MutatorRef<MarkSweep> {
+    let heap = Arc::new(UnsafeCell::new(MarkSweep::new(
+        options.initial_size,
+        options.growth_limit,
+        options.min_free,
+        options.max_free,
+        options.growth_multiplier,
+        options.capacity,
+        options.low_memory_mode,
+        options.num_threads,
+        options.verbose,
     )));
     let href = unsafe { &mut *heap.get() };
+    if options.finalizer_thread {
+        href.finalizer = Some(FinalizerThread::new());
+    }
+    let freed_memory = FreedMemory::from_env_or(options.freed_memory);
+    href.large_space.freed_memory = freed_memory;
+    unsafe {
+        (*href.rosalloc).freed_memory = freed_memory;
+    }
+    href.near_heap_limit
+        .set_threshold(options.near_heap_limit_threshold);
     let join_data = JoinData::new();
     let mut mutator = MutatorRef::new(Mutator::new(
         heap.clone(),
@@ -88,6 +262,7 @@ pub fn instantiate_marksweep(
         .n_mutators
         .fetch_add(1, atomic::Ordering::Relaxed);
     mutator.state_set(ThreadState::Safe, ThreadState::Unsafe);
+    mutator.set_gc_stress(GcStress::from_env_or(options.stress));
     mutator
 }
 
@@ -159,12 +334,21 @@ impl MarkSweep {
             target_footprint: AtomicUsize::new(initial_size),
             max_free,
             min_free,
-            growth_limit,
+            growth_limit: AtomicUsize::new(growth_limit),
+            near_heap_limit: NearHeapLimit::new(growth_limit, None),
+            stats: HeapStats::default(),
+            observers: GcObservers::new(),
             num_bytes_allocated: AtomicUsize::new(0),
             growth_multiplier,
             pool: scoped_threadpool::Pool::new(num_threads as _),
             verbose,
             weak_refs: vec![],
+            soft_refs: vec![],
+            soft_ref_policy: SoftRefPolicy::default(),
+            alloc_failure: false,
+            ephemerons: vec![],
+            finalization_registry: FinalizationRegistry::new(),
+            finalizer: None,
         };
         unsafe {
             (*(*this.rosalloc).rosalloc()).set_footprint_limit((*this.rosalloc).capacity());
@@ -181,7 +365,7 @@ impl MarkSweep {
             let new_footprint = old_allocated + alloc_size;
             if new_footprint <= old_target {
                 return false;
-            } else if new_footprint > self.growth_limit {
+            } else if new_footprint > self.growth_limit.load(Ordering::Relaxed) {
                 return true;
             }
 
@@ -208,19 +392,50 @@ impl MarkSweep {
         &mut self,
         mutator: &mut MutatorRef<Self>,
         mut value: T,
-    ) -> Gc<T, Self> {
-        self.collect(mutator, &mut [&mut value]);
-        self.alloc_once::<T, true, false>(mutator, value)
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
+        self.collect_alloc_failure(mutator, &mut [&mut value]);
+        loop {
+            value = match self.alloc_once::<T, true, false>(mutator, value) {
+                Err(error) => error.into_inner(),
+                result => return result,
+            };
+            match self.invoke_near_heap_limit_callback(&value) {
+                HeapLimitAction::Raise(_) => (),
+                HeapLimitAction::Collect => self.collect_alloc_failure(mutator, &mut [&mut value]),
+                HeapLimitAction::Fail => return Err(AllocError::new(value)),
+            }
+        }
+    }
+
+    /// Invoke near heap limit callback because `value` can't be allocated even after GC cycle.
+    fn invoke_near_heap_limit_callback<T: Collectable>(&mut self, value: &T) -> HeapLimitAction {
+        let size = align_usize(value.allocation_size() + size_of::<HeapObjectHeader>(), 8);
+        self.global_heap_lock.lock();
+        let action = self.near_heap_limit.invoke(
+            self.num_bytes_allocated.load(Ordering::Relaxed),
+            size,
+            self.target_footprint.load(Ordering::Relaxed),
+            &self.growth_limit,
+            unsafe { (*self.rosalloc).capacity() },
+        );
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        action
     }
     #[inline(never)]
     pub fn alloc_once<T: Collectable + Sized + 'static, const GROW: bool, const GC: bool>(
         &mut self,
         mut mutator: &mut MutatorRef<Self>,
         value: T,
-    ) -> Gc<T, Self> {
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
         let size = align_usize(value.allocation_size() + size_of::<HeapObjectHeader>(), 8);
         let max_bytes_tl_bulk_allocated = max_bytes_bulk_allocated_for(size);
         if self.is_out_of_memory_on_allocation(max_bytes_tl_bulk_allocated, GROW) {
+            if !GC {
+                // heap can't grow even after GC
+                return Err(AllocError::new(value));
+            }
             // potentially run GC if we reached GC threshold
 
             return self.alloc_slow(mutator, value);
@@ -240,8 +455,8 @@ impl MarkSweep {
                 // trigger GC if no memory is available
                 return self.alloc_slow(mutator, value);
             } else if mem.is_null() && !GC {
-                // if GC hapenned and memory is still unavailbe just OOM
-                oom_abort();
+                // if GC hapenned and memory is still unavailbe heap is exhausted
+                return Err(AllocError::new(value));
             }
             if bytes_tl_bulk_allocated > 0 {
                 // update num_bytes_allocated so we can start GC when necessary
@@ -255,10 +470,7 @@ impl MarkSweep {
             (*header).type_id = small_type_id::<T>();
             ((*header).data() as *mut T).write(value);
             (*self.live_bitmap).set(header.cast());
-            Gc {
-                base: NonNull::new_unchecked(header),
-                marker: PhantomData,
-            }
+            Ok(Gc::from_raw(header))
         }
     }
 }
@@ -282,28 +494,110 @@ impl GcBase for MarkSweep {
         &mut self,
         mutator: &mut MutatorRef<Self>,
         value: Gc<T, Self>,
-    ) -> Weak<T, Self> {
-        let weak_ref = unsafe { Weak::create(mutator, value) };
+    ) -> Result<Weak<T, Self>, AllocError<Gc<T, Self>>> {
+        let weak_ref = unsafe { Weak::try_create(mutator, value)? };
+        self.global_heap_lock.lock();
+        self.weak_refs.push(weak_ref.to_dyn_untraced());
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        Ok(weak_ref)
+    }
+    fn allocate_soft<T: Collectable + ?Sized>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        value: Gc<T, Self>,
+    ) -> Soft<T, Self> {
+        let soft_ref = unsafe { Soft::create(mutator, value) };
+        self.global_heap_lock.lock();
+        self.soft_refs.push(soft_ref.to_dyn_untraced());
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        soft_ref
+    }
+    fn collect_alloc_failure(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        keep: &mut [&mut dyn Trace],
+    ) {
+        self.alloc_failure = true;
+        self.collect(mutator, keep);
+        self.alloc_failure = false;
+    }
+    fn register_finalization<T: Collectable + ?Sized, U: Collectable + ?Sized>(
+        &mut self,
+        _mutator: &mut MutatorRef<Self>,
+        target: Gc<T, Self>,
+        held: Gc<U, Self>,
+    ) {
+        self.global_heap_lock.lock();
+        self.finalization_registry.register(target, held);
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+    }
+    fn set_near_heap_limit_callback(&mut self, callback: Option<NearHeapLimitCallback>) {
+        self.global_heap_lock.lock();
+        self.near_heap_limit.set_callback(callback);
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+    }
+    fn add_gc_observer(&mut self, observer: Box<dyn GcObserver>) -> bool {
         self.global_heap_lock.lock();
-        self.weak_refs.push(weak_ref.to_dyn());
+        self.observers.add(observer);
         unsafe {
             self.global_heap_lock.unlock();
         }
-        weak_ref
+        true
+    }
+    fn wait_for_finalizers(&self) {
+        if let Some(ref finalizer) = self.finalizer {
+            finalizer.wait();
+        }
+    }
+    fn stats(&self) -> HeapStats {
+        self.global_heap_lock.lock();
+        let stats = self.stats.snapshot(
+            self.num_bytes_allocated.load(Ordering::Relaxed),
+            self.target_footprint.load(Ordering::Relaxed),
+            self.large_space.bytes,
+        );
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        stats
+    }
+    fn pop_cleanup(&mut self) -> Option<Gc<dyn Collectable, Self>> {
+        self.global_heap_lock.lock();
+        let held = self.finalization_registry.pop_cleanup();
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        held
     }
     fn collect(&mut self, mutator: &mut MutatorRef<MarkSweep>, mut keep: &mut [&mut dyn Trace]) {
         match SafepointScope::new(mutator.clone()) {
             Some(safepoint) => unsafe {
                 self.global_heap_lock.lock();
                 self.large_space_lock.lock();
-                let time = if self.verbose {
-                    Some(std::time::Instant::now())
+                let time = std::time::Instant::now();
+                self.observers.start(if self.alloc_failure {
+                    GcReason::AllocationFailure
                 } else {
-                    None
-                };
+                    GcReason::Requested
+                });
 
                 let prev = self.num_bytes_allocated.load(Ordering::Relaxed);
+                self.safepoint.next_epoch();
                 self.large_space.prepare_for_marking(false);
+                self.soft_ref_policy.prepare(
+                    &self.soft_refs,
+                    self.alloc_failure,
+                    prev,
+                    self.growth_limit.load(Ordering::Relaxed),
+                );
                 self.before_mark_constraints();
                 for i in 0..self.mutators.len() {
                     let mutator = self.mutators[i];
@@ -316,13 +610,27 @@ impl GcBase for MarkSweep {
                     });
                 }
                 keep.trace(self);
+                let this = self as *mut Self;
+                (*this).finalization_registry.trace(self);
+                self.observers.phase(GcPhase::RootsScanned);
 
-                while let Some(object) = self.mark_stack.pop() {
-                    (*object).get_dyn().trace(self);
-                }
+                self.trace_marked_objects();
                 self.after_mark_constraints();
+                self.process_ephemerons();
+                let marking = MarkSweepMarking {
+                    rosalloc: self.rosalloc,
+                };
+                self.finalization_registry.after_mark(|target| {
+                    if marking.is_marked(target) {
+                        target
+                    } else {
+                        null_mut()
+                    }
+                });
+                self.observers.phase(GcPhase::Marked);
                 let rosalloc = self.rosalloc;
                 let mark = &*(*rosalloc).get_mark_bitmap();
+                let finalizer = self.finalizer.as_ref();
                 self.finalize_list.retain(|x| {
                     let header = *x;
                     if (mark.has_address(header.cast()) && mark.test(header.cast()))
@@ -331,7 +639,11 @@ impl GcBase for MarkSweep {
                     {
                         true
                     } else {
-                        (*header).get_dyn().finalize();
+                        if finalize(finalizer, header) && !(*header).is_precise() {
+                            // rosalloc sweep skips objects that are not in live bitmap so slot is kept until
+                            // finalizer has run.
+                            (*(*rosalloc).get_live_bitmap()).clear(header.cast());
+                        }
                         false
                     }
                 });
@@ -355,6 +667,24 @@ impl GcBase for MarkSweep {
                     }
                 });
 
+                self.soft_refs.retain_mut(|object| {
+                    if marking.is_marked(object.base()) {
+                        object.after_mark(|header| {
+                            if marking.is_marked(header) {
+                                header
+                            } else {
+                                null_mut()
+                            }
+                        });
+                        true
+                    } else {
+                        false
+                    }
+                });
+                self.observers.phase(GcPhase::WeakProcessed);
+
+                let mark_phase = time.elapsed();
+                let sweep_phase = std::time::Instant::now();
                 let mut revoke_freed = 0;
                 for i in 0..self.mutators.len() {
                     let mutator = self.mutators[i];
@@ -363,21 +693,39 @@ impl GcBase for MarkSweep {
                 }
                 (*(*self.rosalloc).rosalloc()).revoke_thread_unsafe_current_runs();
 
-                let (freed, _) = (*self.rosalloc).sweep(false, |pointers, _| {
-                    (*self.rosalloc).sweep_callback(pointers, false)
-                });
-                /*let freed =
-                crate::sweeper::rosalloc_parallel_sweep(&mut self.pool, self.rosalloc);*/
+                let freed = rosalloc_parallel_sweep(&mut self.pool, self.rosalloc);
+
+                let mut finalized_freed = 0;
+                if let Some(finalizer) = self.finalizer.as_ref() {
+                    let mut finalized = vec![];
+                    let large_space = &mut self.large_space;
+                    finalizer.process(
+                        |_| {},
+                        |object| {
+                            if (*object).is_precise() {
+                                finalized_freed += large_space.release_finalized(object);
+                            } else {
+                                finalized.push(object.cast::<u8>());
+                            }
+                        },
+                    );
+                    finalized_freed += (*rosalloc).sweep_callback(&finalized, false);
+                }
 
-                let los_freed = self.large_space.sweep();
+                let finalizer = self.finalizer.as_ref();
+                let los_freed = self.large_space.sweep_with_finalizer(|object| {
+                    (*object).finalizable_bit() && finalize(finalizer, object)
+                });
 
-                let freed = freed + los_freed + revoke_freed;
+                let freed = freed + finalized_freed + los_freed + revoke_freed;
 
                 self.num_bytes_allocated.fetch_sub(freed, Ordering::Relaxed);
                 (*self.rosalloc).swap_bitmaps();
                 (*self.rosalloc).mark_bitmap.clear_all();
 
                 (*(*self.rosalloc).rosalloc()).trim();
+                self.observers.phase(GcPhase::Swept);
+                let sweep_phase = sweep_phase.elapsed();
 
                 let target_size;
                 let bytes_allocated = self.num_bytes_allocated.load(Ordering::Relaxed);
@@ -386,7 +734,11 @@ impl GcBase for MarkSweep {
                 grow_bytes = delta.min(self.max_free);
                 grow_bytes = grow_bytes.max(self.min_free);
                 target_size = bytes_allocated + (grow_bytes as f64 * 2.0) as usize;
-                if let Some(time) = time.map(|x| x.elapsed()) {
+                let time = time.elapsed();
+                self.stats.record_pause(time, safepoint.time_to_safepoint());
+                self.stats
+                    .record_cycle(false, mark_phase, sweep_phase, prev, bytes_allocated);
+                if self.verbose {
                     eprintln!(
                         "[gc] GC({}) Pause MarkSweep {}->{}({}) {:.4}ms",
                         self.total_gcs,
@@ -399,10 +751,19 @@ impl GcBase for MarkSweep {
                 }
                 self.large_space.prepare_for_allocation(false);
                 self.target_footprint.store(target_size, Ordering::Relaxed);
+                self.observers.end(&self.stats);
+                let stats = self.stats;
                 drop(safepoint);
 
+                self.near_heap_limit.check_target_footprint(
+                    bytes_allocated,
+                    target_size,
+                    &self.growth_limit,
+                    (*self.rosalloc).capacity(),
+                );
                 self.global_heap_lock.unlock();
                 self.large_space_lock.unlock();
+                GcObservers::after_pause(&mut self.observers, &self.global_heap_lock, &stats);
             },
             None => {
                 return;
@@ -415,7 +776,7 @@ impl GcBase for MarkSweep {
         mutator: &mut MutatorRef<Self>,
         value: T,
         _space: AllocationSpace,
-    ) -> Gc<T, Self> {
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
         let size = align_usize(value.allocation_size() + size_of::<HeapObjectHeader>(), 8);
         let val = if Rosalloc::is_size_for_thread_local(size) {
             let obj = unsafe { mutator.allocate_from_tlab(value) };
@@ -427,21 +788,28 @@ impl GcBase for MarkSweep {
 
                     value
                 }
-                Err(value) => self.alloc_once::<T, false, true>(mutator, value),
+                Err(value) => self.alloc_once::<T, false, true>(mutator, value)?,
             }
         } else {
-            self.alloc_once::<T, false, true>(mutator, value)
+            self.alloc_once::<T, false, true>(mutator, value)?
         };
 
         self.post_alloc(val);
-        val
+        Ok(val)
     }
 
     fn post_alloc<T: Collectable + Sized + 'static>(&mut self, value: Gc<T, Self>) {
-        if std::mem::needs_drop::<T>() {
+        if T::needs_finalization() {
             unsafe {
+                let base = value.base.as_ptr();
+                (*base).set_finalize_on_thread_bit(T::finalize_on_thread().is_some());
+                if (*base).is_precise() {
+                    // large objects are finalized by large object space sweep.
+                    (*base).set_finalizable_bit(true);
+                    return;
+                }
                 self.finalize_lock.lock();
-                self.finalize_list.push_back(value.base.as_ptr());
+                self.finalize_list.push_back(base);
                 self.finalize_lock.unlock();
             }
         }
@@ -499,19 +867,27 @@ impl GcBase for MarkSweep {
 
     fn allocate_large<T: Collectable + Sized + 'static>(
         &mut self,
-        _mutator: &mut MutatorRef<Self>,
-        value: T,
-    ) -> crate::api::Gc<T, Self> {
+        mutator: &mut MutatorRef<Self>,
+        mut value: T,
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
         unsafe {
             let size = value.allocation_size() + size_of::<HeapObjectHeader>();
             self.large_space_lock.lock();
-            let object = self.large_space.allocate(size);
+            let mut object = self.large_space.allocate(size);
+            if object.is_null() {
+                // dead large objects might free enough memory.
+                self.large_space_lock.unlock();
+                self.collect_alloc_failure(mutator, &mut [&mut value]);
+                self.large_space_lock.lock();
+                object = self.large_space.allocate(size);
+                if object.is_null() {
+                    self.large_space_lock.unlock();
+                    return Err(AllocError::new(value));
+                }
+            }
             (*object).set_metadata(vtable_of::<T>());
             (*object).type_id = small_type_id::<T>();
-            let gc = Gc {
-                base: NonNull::new_unchecked(object),
-                marker: PhantomData,
-            };
+            let gc = Gc::from_raw(object);
             ((*object).data() as *mut T).write(value);
             self.num_bytes_allocated.fetch_add(
                 (*PreciseAllocation::from_cell(object)).cell_size(),
@@ -519,7 +895,7 @@ impl GcBase for MarkSweep {
             );
             self.large_space_lock.unlock();
             self.post_alloc(gc);
-            gc
+            Ok(gc)
         }
     }
     fn init_tlab(&mut self, tlab: &mut Self::TLAB) {
@@ -527,23 +903,114 @@ impl GcBase for MarkSweep {
     }
 }
 
+/// Marking state of MarkSweep that is shared between marking workers.
+struct MarkSweepMarking {
+    rosalloc: *mut RosAllocSpace,
+}
+
+// Objects are marked in mark bitmap and LOS atomically.
+unsafe impl Sync for MarkSweepMarking {}
+
+impl ParallelMarking for MarkSweepMarking {
+    type Local = ();
+
+    fn create_local(&self) {}
+
+    unsafe fn mark(
+        &self,
+        _local: &mut (),
+        root: &mut NonNull<HeapObjectHeader>,
+    ) -> Option<*mut HeapObjectHeader> {
+        let object = root.as_ptr();
+        if (*object).is_precise() {
+            if !(*PreciseAllocation::from_cell(object)).test_and_set_marked() {
+                return Some(object);
+            }
+        } else {
+            // If object is not in LOS it must be in rosalloc space
+            debug_assert!((*self.rosalloc).has_address(object.cast()));
+            let bitmap = (*self.rosalloc).get_mark_bitmap();
+            debug_assert!((*(*self.rosalloc).get_live_bitmap()).test(object.cast()));
+            if !(*bitmap).set_sync(object.cast()) {
+                return Some(object);
+            }
+        }
+        None
+    }
+}
+
+impl MarkSweepMarking {
+    unsafe fn is_marked(&self, object: *mut HeapObjectHeader) -> bool {
+        if (*object).is_precise() {
+            (*PreciseAllocation::from_cell(object)).is_marked()
+        } else {
+            (*(*self.rosalloc).get_mark_bitmap()).test(object.cast())
+        }
+    }
+}
+
+impl MarkSweep {
+    /// Trace objects from mark stack using marking threads.
+    unsafe fn trace_marked_objects(&mut self) {
+        let marking = MarkSweepMarking {
+            rosalloc: self.rosalloc,
+        };
+        mark_parallel(
+            Some(&mut self.pool),
+            &marking,
+            std::mem::take(&mut self.mark_stack),
+            &mut self.ephemerons,
+        );
+    }
+
+    /// Mark values of ephemerons with live keys until no more keys are found to be alive. Ephemerons with dead keys are
+    /// cleared.
+    unsafe fn process_ephemerons(&mut self) {
+        let marking = MarkSweepMarking {
+            rosalloc: self.rosalloc,
+        };
+        loop {
+            let mut marked = false;
+            for ephemeron in std::mem::take(&mut self.ephemerons) {
+                let alive = (*ephemeron).resolve_key(|key| {
+                    if marking.is_marked(key) {
+                        key
+                    } else {
+                        null_mut()
+                    }
+                });
+                if alive {
+                    (*ephemeron).mark_value(self);
+                    marked = true;
+                } else {
+                    self.ephemerons.push(ephemeron);
+                }
+            }
+            if !marked {
+                break;
+            }
+            // values might reference keys of other ephemerons
+            self.trace_marked_objects();
+        }
+        for ephemeron in self.ephemerons.drain(..) {
+            (*ephemeron).clear();
+        }
+    }
+}
+
 impl Visitor for MarkSweep {
     fn mark_object(&mut self, root: &mut NonNull<HeapObjectHeader>) {
-        let object = root.as_ptr();
+        let marking = MarkSweepMarking {
+            rosalloc: self.rosalloc,
+        };
         unsafe {
-            if (*object).is_precise() {
-                if !(*PreciseAllocation::from_cell(object)).test_and_set_marked() {
-                    self.mark_stack.push(object);
-                }
-            } else {
-                // If object is not in LOS it must be in rosalloc space
-                debug_assert!((*self.rosalloc).has_address(object.cast()));
-                let bitmap = (*self.rosalloc).get_mark_bitmap();
-                debug_assert!((*(*self.rosalloc).get_live_bitmap()).test(object.cast()));
-                if !(*bitmap).set_sync(object.cast()) {
-                    self.mark_stack.push(object);
-                }
+            if let Some(object) = marking.mark(&mut (), root) {
+                self.mark_stack.push(object);
             }
         }
     }
+
+    fn mark_ephemeron(&mut self, ephemeron: &mut EphemeronEntry) {
+        self.ephemerons.push(ephemeron);
+    }
 }
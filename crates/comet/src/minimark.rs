@@ -0,0 +1,1102 @@
+//! # MiniMark: A generational garbage collector
+//!
+//! MiniMark has two generations: nursery and old space. Nursery is a [BumpPointerSpace] and all objects are allocated there
+//! unless [AllocationSpace::Old] is explicitly requested. Once nursery becomes full *minor* collection is performed: all surviving
+//! nursery objects are copied (promoted) to old space and nursery is reset. Old space is [Immix](crate::immix) space which is collected in
+//! mark-region fashion once it reaches its GC threshold, full collection always evacuates nursery first.
+//!
+//! Objects that are larger than [MiniMark::LARGE_ALLOCATION_SIZE] are allocated in [LargeObjectSpace] and are considered old.
+//!
+//! # Write barrier
+//!
+//! In order to find references from old objects to young ones without scanning entire old space MiniMark maintains card table
+//! for old space and remembered set for large objects. You ***must*** invoke [MutatorRef::write_barrier] after storing GC pointer into GC object
+//! otherwise young objects referenced only from old objects are not going to survive minor GC.
+//!
+//! # Rooting
+//!
+//! Nursery objects are moved so native stack is not scanned conservatively, all GC pointers on stack must be rooted using `letroot!()`.
+
+use std::{
+    any::TypeId,
+    cell::UnsafeCell,
+    mem::size_of,
+    ptr::{null_mut, NonNull},
+    sync::Arc,
+    time::Duration,
+};
+
+use atomic::Ordering;
+use parking_lot::{lock_api::RawMutex, RawMutex as Lock};
+
+use crate::{
+    api::{
+        vtable_of, Collectable, EphemeronEntry, Gc, HeapObjectHeader, Trace, VTable, Visitor, Weak,
+        GC_BLACK, GC_WHITE,
+    },
+    bump_pointer_space::BumpPointerSpace,
+    card_table::{CardTable, CARD_SIZE},
+    gc_base::{
+        AllocError, AllocationSpace, GcBase, MarkingConstraint, MarkingConstraintRuns, NoHelp,
+        NoReadBarrier, TLAB,
+    },
+    immix::{block::IMMIX_BLOCK_SIZE, space::ImmixSpace, GetImmixSpace, ImmixAllocator},
+    large_space::{LargeObjectSpace, PreciseAllocation},
+    make_small_type_id,
+    mutator::{JoinData, Mutator, MutatorRef, ThreadState},
+    poison::{poison, FreedMemory},
+    safepoint::{GlobalSafepoint, SafepointScope},
+    small_type_id,
+    stats::HeapStats,
+    stress::GcStress,
+    utils::{align_usize, formatted_size, stack_bounds::StackBounds},
+};
+
+/// MiniMark GC implementation. Read top level module documentation for more information
+pub struct MiniMark {
+    nursery: BumpPointerSpace,
+    space: &'static ImmixSpace,
+    /// Allocator that is used to promote nursery objects to old space.
+    promotion: ImmixAllocator,
+    card_table: CardTable,
+    pub(crate) global_heap_lock: Lock,
+    pub(crate) large_space_lock: Lock,
+    pub(crate) large_space: LargeObjectSpace,
+    /// Large objects that might contain references to young objects. Header mark bit is used as "remembered" bit.
+    remembered_set: Vec<*mut HeapObjectHeader>,
+    remembered_set_lock: Lock,
+    pub(crate) mutators: Vec<*mut Mutator<Self>>,
+    pub(crate) safepoint: GlobalSafepoint,
+    pub(crate) mark_stack: Vec<*mut HeapObjectHeader>,
+    pub(crate) verbose: u8,
+    pub(crate) alloc_color: u8,
+    pub(crate) mark_color: u8,
+    /// Set when minor GC is in progress, changes [Visitor] behaviour from marking to promoting.
+    in_minor_gc: bool,
+    /// Set when old space reached its GC threshold during promotion.
+    needs_major_gc: bool,
+    promoted_bytes: usize,
+    total_gcs: usize,
+    weak_refs: Vec<Weak<dyn Collectable, Self>>,
+    /// Ephemerons found by tracing whose keys are not known to be alive yet.
+    ephemerons: Vec<*mut EphemeronEntry>,
+    constraints: Vec<Box<dyn MarkingConstraint>>,
+    /// Finalizable objects that are allocated in nursery. Promoted and old objects are recorded by
+    /// [ImmixSpace::add_finalizable] and finalized by Immix sweep.
+    finalize_list: Vec<*mut HeapObjectHeader>,
+    finalize_lock: Lock,
+    growth_multiplier: f64,
+    stats: HeapStats,
+}
+
+impl GetImmixSpace for MiniMark {
+    fn immix_space(&self) -> &'static ImmixSpace {
+        self.space
+    }
+}
+
+pub struct MiniMarkOptions {
+    /// Nursery size. Set to 4MB by default.
+    pub nursery_size: usize,
+    /// Determines by how much old space grows after GC cycle. By default set to 1.75
+    pub growth_multiplier: f64,
+    /// Entire old space area size. Set to 128MB by default, minimal supported value is 4MB (single Immix chunk size)
+    pub heap_size: usize,
+    /// Initial old space size before triggering full GC cycle. By default set to 32MB
+    pub initial_size: usize,
+    /// Minimal old space size before triggering full GC cycle. By default set to 4MB.
+    pub min_heap_size: usize,
+    /// Maximal old space size before triggering full GC cycle. By default set to 128MB
+    pub max_heap_size: usize,
+    /// Enables verbose loggig to stdout.
+    pub verbose: u8,
+    /// GC stress mode, see [stress](crate::stress). Overridden by `COMET_GC_STRESS` environment variable. Disabled
+    /// by default.
+    pub stress: GcStress,
+    /// What happens to memory of dead objects, see [poison](crate::poison). Overridden by `COMET_FREED_MEMORY`
+    /// environment variable. Memory is reused by default.
+    pub freed_memory: FreedMemory,
+}
+
+impl MiniMarkOptions {
+    pub fn with_nursery_size(mut self, x: usize) -> Self {
+        if x < 128 * 1024 {
+            panic!("Nursery size too small; Minimal nursery size is 128KB");
+        }
+        self.nursery_size = x;
+        self
+    }
+    /// Set growth multiplier. Panics if x <= 1
+    pub fn with_growth_multiplier(mut self, x: f64) -> Self {
+        if x <= 1.0 {
+            panic!("Growth multiplier is too small")
+        }
+        self.growth_multiplier = x;
+        self
+    }
+
+    pub fn with_heap_size(mut self, x: usize) -> Self {
+        if x < 4 * 1024 * 1024 {
+            panic!("Heap size too small; Minimal heap size is 1 Immix chunk which is 4MB");
+        }
+        self.heap_size = x;
+        self
+    }
+
+    pub fn with_initial_size(mut self, x: usize) -> Self {
+        self.initial_size = x;
+        self
+    }
+
+    pub fn with_min_heap_size(mut self, x: usize) -> Self {
+        self.min_heap_size = x;
+        self
+    }
+
+    pub fn with_max_heap_size(mut self, x: usize) -> Self {
+        self.max_heap_size = x;
+        self
+    }
+    pub fn with_verbose(mut self, x: u8) -> Self {
+        self.verbose = x;
+        self
+    }
+
+    /// Set GC stress mode.
+    pub fn with_stress(mut self, x: GcStress) -> Self {
+        self.stress = x;
+        self
+    }
+
+    /// Set what happens to memory of dead objects.
+    pub fn with_freed_memory(mut self, x: FreedMemory) -> Self {
+        self.freed_memory = x;
+        self
+    }
+}
+
+impl Default for MiniMarkOptions {
+    fn default() -> Self {
+        Self {
+            nursery_size: 4 * 1024 * 1024,
+            growth_multiplier: 1.75,
+            heap_size: 128 * 1024 * 1024,
+            min_heap_size: 4 * 1024 * 1024,
+            max_heap_size: 128 * 1024 * 1024,
+            initial_size: 32 * 1024 * 1024,
+            verbose: 0,
+            stress: GcStress::Disabled,
+            freed_memory: FreedMemory::Reuse,
+        }
+    }
+}
+
+pub fn instantiate_minimark(options: MiniMarkOptions) -> MutatorRef<MiniMark> {
+    let space = Box::leak(Box::new(ImmixSpace::new(
+        options.heap_size,
+        options.initial_size,
+        options.min_heap_size,
+        options.max_heap_size,
+        options.verbose > 0,
+    )));
+    space.init_bitmap();
+    space.freed_memory = FreedMemory::from_env_or(options.freed_memory);
+    let space: &'static ImmixSpace = space;
+    let minimark = Arc::new(UnsafeCell::new(MiniMark {
+        nursery: BumpPointerSpace::new(options.nursery_size),
+        space,
+        promotion: ImmixAllocator::new(space),
+        card_table: CardTable::create(space.map.start(), space.map.size()),
+        global_heap_lock: Lock::INIT,
+        large_space_lock: Lock::INIT,
+        large_space: LargeObjectSpace::new(),
+        remembered_set: vec![],
+        remembered_set_lock: Lock::INIT,
+        mutators: vec![],
+        safepoint: GlobalSafepoint::new(),
+        mark_stack: vec![],
+        verbose: options.verbose,
+        alloc_color: GC_WHITE,
+        mark_color: GC_BLACK,
+        in_minor_gc: false,
+        needs_major_gc: false,
+        promoted_bytes: 0,
+        total_gcs: 0,
+        weak_refs: vec![],
+        ephemerons: vec![],
+        constraints: vec![],
+        finalize_list: vec![],
+        finalize_lock: Lock::INIT,
+        growth_multiplier: options.growth_multiplier,
+        stats: HeapStats::default(),
+    }));
+    let href = unsafe { &mut *minimark.get() };
+    href.large_space.freed_memory = href.space.freed_memory;
+    let join_data = JoinData::new();
+    let mut mutator = MutatorRef::new(Mutator::new(
+        minimark.clone(),
+        &href.safepoint,
+        join_data.internal.clone(),
+    ));
+    href.mutators.push(&mut *mutator);
+    href.safepoint
+        .n_mutators
+        .fetch_add(1, atomic::Ordering::Relaxed);
+    mutator.stack_bounds = StackBounds::current_thread_stack_bounds();
+    mutator.state_set(ThreadState::Safe, ThreadState::Unsafe);
+
+    mutator.set_gc_stress(GcStress::from_env_or(options.stress));
+    mutator
+}
+
+impl MiniMark {
+    unsafe fn after_mark_constraints(&mut self) {
+        let this = self as *mut Self;
+        (*this).constraints.retain_mut(|constraint| {
+            if constraint.is_over() {
+                false
+            } else {
+                if constraint.runs_at() == MarkingConstraintRuns::AfterMark {
+                    constraint.run(self);
+                }
+                true
+            }
+        });
+    }
+    unsafe fn before_mark_constraints(&mut self) {
+        let this = self as *mut Self;
+        (*this).constraints.retain_mut(|constraint| {
+            if constraint.is_over() {
+                false
+            } else {
+                if constraint.runs_at() == MarkingConstraintRuns::BeforeMark {
+                    constraint.run(self);
+                }
+                true
+            }
+        });
+    }
+
+    /// Put large object to remembered set if it is not there yet.
+    fn remember(&mut self, object: *mut HeapObjectHeader) {
+        unsafe {
+            self.remembered_set_lock.lock();
+            if !(*object).marked_bit() {
+                (*object).set_marked_bit();
+                self.remembered_set.push(object);
+            }
+            self.remembered_set_lock.unlock();
+        }
+    }
+
+    /// Returns new location of `object` after minor GC or null if it is dead.
+    unsafe fn minor_forwardee(&self, object: *mut HeapObjectHeader) -> *mut HeapObjectHeader {
+        if self.nursery.contains(object.cast()) {
+            if (*object).is_forwarded() {
+                (*object).vtable() as _
+            } else {
+                null_mut()
+            }
+        } else {
+            object
+        }
+    }
+
+    /// Returns `object` if it is marked by major GC or null if it is dead.
+    unsafe fn major_live_address(&self, object: *mut HeapObjectHeader) -> *mut HeapObjectHeader {
+        if (*object).get_color() == self.mark_color {
+            object
+        } else {
+            null_mut()
+        }
+    }
+
+    unsafe fn process_mark_stack(&mut self) {
+        while let Some(object) = self.mark_stack.pop() {
+            (*object).get_dyn().trace(self);
+        }
+    }
+
+    /// Mark values of ephemerons whose keys are alive according to `live_address` until no more keys are found to be
+    /// alive. Ephemerons with dead keys are cleared.
+    unsafe fn process_ephemerons(
+        &mut self,
+        live_address: unsafe fn(&Self, *mut HeapObjectHeader) -> *mut HeapObjectHeader,
+    ) {
+        loop {
+            let mut marked = false;
+            for ephemeron in std::mem::take(&mut self.ephemerons) {
+                let this = self as *const Self;
+                if (*ephemeron).resolve_key(|key| live_address(&*this, key)) {
+                    (*ephemeron).mark_value(self);
+                    marked = true;
+                } else {
+                    self.ephemerons.push(ephemeron);
+                }
+            }
+            if !marked {
+                break;
+            }
+            // values might reference keys of other ephemerons
+            self.process_mark_stack();
+        }
+        for ephemeron in self.ephemerons.drain(..) {
+            (*ephemeron).clear();
+        }
+    }
+
+    /// Promote all alive nursery objects to old space. Must be invoked only when all mutators are suspended.
+    unsafe fn minor_gc(&mut self, keep: &mut [&mut dyn Trace]) {
+        let time = std::time::Instant::now();
+        let prev = self.nursery.allocated();
+        let used = self.used_bytes();
+        self.in_minor_gc = true;
+        self.promoted_bytes = 0;
+        for i in 0..self.mutators.len() {
+            let mutator = self.mutators[i];
+            (*mutator).shadow_stack().walk(|entry| {
+                entry.trace(self);
+            });
+        }
+        self.before_mark_constraints();
+        for object in keep.iter_mut() {
+            object.trace(self);
+        }
+        // Old-to-young references: scan objects in dirty cards and remembered large objects.
+        let this = self as *mut Self;
+        self.card_table.visit_dirty_and_clear(|card| {
+            (*this)
+                .space
+                .mark_bitmap
+                .visit_marked_range(card, card.add(CARD_SIZE), |object| {
+                    (*object).get_dyn().trace(&mut *this);
+                });
+        });
+        for object in std::mem::take(&mut self.remembered_set) {
+            (*object).unmark();
+            (*object).get_dyn().trace(self);
+        }
+        self.process_mark_stack();
+        self.after_mark_constraints();
+        self.process_mark_stack();
+        // old keys are considered alive by minor GC.
+        self.process_ephemerons(Self::minor_forwardee);
+
+        let this = self as *const Self;
+        self.weak_refs.retain_mut(|weak| {
+            let inner = (*this).minor_forwardee(weak.base());
+            if inner.is_null() {
+                return false;
+            }
+            weak.set_base(inner);
+            weak.after_mark(|referent| (*this).minor_forwardee(referent));
+            true
+        });
+        // Promoted objects are finalized by old space sweep, dead ones are finalized right now.
+        for object in std::mem::take(&mut self.finalize_list) {
+            if !(*object).is_forwarded() {
+                (*object).get_dyn().finalize();
+            }
+        }
+        if self.space.freed_memory.poisons() {
+            // nursery is reused right away even in quarantine mode, stale pointers are caught until next allocation.
+            poison(self.nursery.start(), self.nursery.allocated());
+        }
+        self.nursery.reset();
+        self.in_minor_gc = false;
+        self.promotion.set_emergency_collection(false);
+        let elapsed = time.elapsed();
+        // nursery is evacuated so its whole collection time is reported as mark phase.
+        self.stats
+            .record_cycle(true, elapsed, Duration::ZERO, used, self.used_bytes());
+        if self.verbose > 0 {
+            eprintln!(
+                "[gc] GC({}) Pause Young MiniMark collection {}->{} (promoted {}) {:.4}ms",
+                self.total_gcs,
+                formatted_size(prev),
+                formatted_size(0),
+                formatted_size(self.promoted_bytes),
+                elapsed.as_micros() as f64 / 1000.0
+            );
+        }
+        self.total_gcs += 1;
+    }
+
+    /// Mark&sweep old space. Must be invoked only after [MiniMark::minor_gc] so nursery is empty.
+    unsafe fn major_gc(&mut self, keep: &mut [&mut dyn Trace]) {
+        let time = std::time::Instant::now();
+        debug_assert!(self.nursery.allocated() == 0);
+        self.needs_major_gc = false;
+        self.safepoint.next_epoch();
+        let mark_phase = std::time::Instant::now();
+        self.large_space.begin_marking(true);
+        self.large_space.prepare_for_marking(false);
+        self.space.prepare(true);
+        TLAB::<Self>::reset(&mut self.promotion);
+        for i in 0..self.mutators.len() {
+            let mutator = self.mutators[i];
+            (*mutator).reset_tlab();
+            (*mutator).shadow_stack().walk(|entry| {
+                entry.trace(self);
+            });
+        }
+        self.before_mark_constraints();
+        for object in keep.iter_mut() {
+            object.trace(self);
+        }
+        self.process_mark_stack();
+        self.after_mark_constraints();
+        self.process_mark_stack();
+        self.process_ephemerons(Self::major_live_address);
+        let mark_phase = mark_phase.elapsed();
+        let prev = self.space.num_bytes_allocated.load(Ordering::Relaxed) + self.large_space.bytes;
+        self.space.num_bytes_allocated.store(0, Ordering::Relaxed);
+        let mark_color = self.mark_color;
+        let sweep_phase = std::time::Instant::now();
+        self.weak_refs.retain_mut(|object| {
+            let header = object.base();
+            if (*header).get_color() == mark_color {
+                object.after_mark(|header| {
+                    if (*header).get_color() == mark_color {
+                        header
+                    } else {
+                        null_mut()
+                    }
+                });
+                true
+            } else {
+                false
+            }
+        });
+        // there is no young objects after major GC so all old-to-young references are gone.
+        self.card_table.clear_card_table();
+        self.remembered_set.clear();
+        self.large_space.sweep();
+        self.large_space.prepare_for_allocation(false);
+        self.space.release(self.alloc_color);
+
+        let bytes_allocated =
+            self.space.num_bytes_allocated.load(Ordering::Relaxed) + self.large_space.bytes;
+        let target_size = self
+            .space
+            .min_heap_size
+            .max((bytes_allocated as f64 * self.growth_multiplier) as usize)
+            .min(self.space.max_heap_size);
+        self.space
+            .target_footprint
+            .store(target_size, Ordering::Relaxed);
+        let sweep_phase = sweep_phase.elapsed();
+        self.stats
+            .record_cycle(false, mark_phase, sweep_phase, prev, bytes_allocated);
+        if self.verbose > 0 {
+            let elapsed = time.elapsed();
+            eprintln!(
+                "[gc] GC({}) Pause Full MiniMark collection {}->{}({}) {:.4}ms (mark {:.4}ms, sweep {:.4}ms)",
+                self.total_gcs,
+                formatted_size(prev),
+                formatted_size(bytes_allocated),
+                formatted_size(target_size),
+                elapsed.as_micros() as f64 / 1000.0,
+                mark_phase.as_micros() as f64 / 1000.0,
+                sweep_phase.as_micros() as f64 / 1000.0
+            );
+        }
+        self.total_gcs += 1;
+        std::mem::swap(&mut self.alloc_color, &mut self.mark_color);
+    }
+
+    /// Number of bytes allocated in nursery, old space and large object space.
+    fn used_bytes(&self) -> usize {
+        self.nursery.allocated()
+            + self.space.num_bytes_allocated.load(Ordering::Relaxed)
+            + self.large_space.bytes
+    }
+
+    /// Allocates memory for object in nursery or old space and initializes object header. Returns null on failure.
+    #[inline]
+    unsafe fn allocate_memory(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        size: usize,
+        space: AllocationSpace,
+    ) -> *mut HeapObjectHeader {
+        let memory = match space {
+            AllocationSpace::New => self.nursery.bump_alloc(size),
+            _ => mutator.tlab.alloc(size),
+        };
+        if memory.is_null() {
+            return null_mut();
+        }
+        let object = memory.cast::<HeapObjectHeader>();
+        // nursery and old space holes are reused so header might contain stale bits.
+        object.write(HeapObjectHeader {
+            value: VTable { raw: 0 },
+            padding: 0,
+            padding2: 0,
+            type_id: 0,
+        });
+        (*object).set_size(size);
+        if space != AllocationSpace::New {
+            // object might be initialized with references to young objects.
+            self.card_table.mark_card(object.cast());
+        }
+        object
+    }
+
+    #[cold]
+    unsafe fn collect_and_allocate_memory(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        size: usize,
+        space: AllocationSpace,
+        keep: &mut [&mut dyn Trace],
+    ) -> *mut HeapObjectHeader {
+        match space {
+            AllocationSpace::New => self.minor_collection(mutator, keep),
+            _ => self.collect(mutator, keep),
+        }
+        mutator.tlab.set_emergency_collection(true);
+        let object = self.allocate_memory(mutator, size, space);
+        mutator.tlab.set_emergency_collection(false);
+        // null means that heap is exhausted even after emergency GC.
+        object
+    }
+}
+
+impl GcBase for MiniMark {
+    type TLAB = ImmixAllocator;
+    const SUPPORTS_TLAB: bool = false;
+    type ReadBarrier = NoReadBarrier;
+    const LARGE_ALLOCATION_SIZE: usize = IMMIX_BLOCK_SIZE / 2;
+
+    fn inline_allocation_helpers(&self) -> Self::InlineAllocationHelpers {
+        NoHelp
+    }
+
+    fn add_constraint<T: MarkingConstraint + 'static>(&mut self, constraint: T) {
+        self.global_lock();
+        self.constraints.push(Box::new(constraint));
+        self.global_unlock();
+    }
+
+    fn stats(&self) -> HeapStats {
+        self.global_heap_lock.lock();
+        let stats = self.stats.snapshot(
+            self.used_bytes(),
+            self.space.target_footprint.load(Ordering::Relaxed),
+            self.large_space.bytes,
+        );
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        stats
+    }
+
+    fn inspect(&self, mut f: impl FnMut(Gc<dyn Collectable, Self>) -> bool) -> bool {
+        unsafe {
+            self.large_space_lock.lock();
+            self.large_space.allocations.iter().for_each(|alloc| {
+                f(Gc::from_raw((**alloc).cell()));
+            });
+            self.large_space_lock.unlock();
+            let start = self.space.map.start();
+            let end = self.space.map.end();
+            self.space.mark_bitmap.visit_marked_range(start, end, |ptr| {
+                f(Gc::from_raw(ptr));
+            });
+            let mut scan = self.nursery.start();
+            while scan < self.nursery.cursor() {
+                let object = scan.cast::<HeapObjectHeader>();
+                scan = scan.add((*object).size());
+                f(Gc::from_raw(object));
+            }
+        }
+        true
+    }
+
+    fn allocate_raw(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        size: usize,
+        type_id: TypeId,
+        vtable: usize,
+    ) -> *mut HeapObjectHeader {
+        let size = align_usize(size + size_of::<HeapObjectHeader>(), 8);
+        unsafe {
+            let object = if size >= Self::LARGE_ALLOCATION_SIZE {
+                self.large_space_lock.lock();
+                let object = self.large_space.allocate(size);
+                self.large_space_lock.unlock();
+                if object.is_null() {
+                    return null_mut();
+                }
+                self.remember(object);
+                object
+            } else {
+                let object = self.allocate_memory(mutator, size, AllocationSpace::New);
+                if object.is_null() {
+                    self.collect_and_allocate_memory(mutator, size, AllocationSpace::New, &mut [])
+                } else {
+                    object
+                }
+            };
+            if object.is_null() {
+                return null_mut();
+            }
+            (*object).set_vtable(vtable);
+            (*object).type_id = make_small_type_id(type_id);
+            (*object).force_set_color(self.alloc_color);
+            object
+        }
+    }
+
+    fn allocate_weak<T: Collectable + ?Sized>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        value: Gc<T, Self>,
+    ) -> Result<Weak<T, Self>, AllocError<Gc<T, Self>>> {
+        let weak_ref = unsafe { Weak::<T, Self>::try_create(mutator, value)? };
+        self.global_heap_lock.lock();
+        self.weak_refs.push(weak_ref.to_dyn_untraced());
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        Ok(weak_ref)
+    }
+
+    #[inline]
+    fn alloc_inline<T: Collectable + Sized + 'static>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        mut value: T,
+        space: AllocationSpace,
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
+        let size = align_usize(value.allocation_size() + size_of::<HeapObjectHeader>(), 8);
+        unsafe {
+            let mut object = self.allocate_memory(mutator, size, space);
+            if object.is_null() {
+                object = self.collect_and_allocate_memory(mutator, size, space, &mut [&mut value]);
+                if object.is_null() {
+                    return Err(AllocError::new(value));
+                }
+            }
+            (*object).set_metadata(vtable_of::<T>());
+            (*object).type_id = small_type_id::<T>();
+            ((*object).data() as *mut T).write(value);
+            let gced = Gc::from_raw(object);
+            self.post_alloc(gced);
+            Ok(gced)
+        }
+    }
+
+    fn allocate_large<T: Collectable + Sized + 'static>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        mut value: T,
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
+        unsafe {
+            let size = value.allocation_size() + size_of::<HeapObjectHeader>();
+            self.large_space_lock.lock();
+            let mut object = self.large_space.allocate(size);
+            if object.is_null() {
+                // dead large objects might free enough memory.
+                self.large_space_lock.unlock();
+                self.collect(mutator, &mut [&mut value]);
+                self.large_space_lock.lock();
+                object = self.large_space.allocate(size);
+                if object.is_null() {
+                    self.large_space_lock.unlock();
+                    return Err(AllocError::new(value));
+                }
+            }
+            (*object).set_metadata(vtable_of::<T>());
+            (*object).type_id = small_type_id::<T>();
+            let gc = Gc::from_raw(object);
+            ((*object).data() as *mut T).write(value);
+            self.large_space_lock.unlock();
+            // object might be initialized with references to young objects.
+            self.remember(object);
+            self.post_alloc(gc);
+            Ok(gc)
+        }
+    }
+
+    #[inline(always)]
+    fn post_alloc<T: Collectable + Sized + 'static>(&mut self, value: Gc<T, Self>) {
+        unsafe {
+            let base = value.base.as_ptr();
+            (*base).force_set_color(self.alloc_color);
+            if T::needs_finalization() {
+                if self.nursery.contains(base.cast()) {
+                    // bit is copied with the header, so promotion knows that copy needs finalization.
+                    (*base).set_finalizable_bit(true);
+                    self.finalize_lock.lock();
+                    self.finalize_list.push(base);
+                    self.finalize_lock.unlock();
+                } else {
+                    self.space.add_finalizable(base);
+                }
+            }
+        }
+    }
+
+    #[inline]
+    fn write_barrier(&mut self, _mutator: &mut MutatorRef<Self>, object: Gc<dyn Collectable, Self>) {
+        let base = object.base.as_ptr();
+        if self.nursery.contains(base.cast()) {
+            return;
+        }
+        if self.space.has_address(base.cast()) {
+            self.card_table.mark_card(base.cast());
+        } else {
+            self.remember(base);
+        }
+    }
+
+    fn collect_alloc_failure(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
+        self.minor_collection(mutator, keep);
+    }
+
+    fn minor_collection(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
+        match SafepointScope::new(mutator.clone()) {
+            Some(safepoint) => unsafe {
+                let time = std::time::Instant::now();
+                self.global_heap_lock.lock();
+                self.large_space_lock.lock();
+                self.minor_gc(keep);
+                if self.needs_major_gc {
+                    self.major_gc(keep);
+                }
+                self.stats
+                    .record_pause(time.elapsed(), safepoint.time_to_safepoint());
+                drop(safepoint);
+                self.global_heap_lock.unlock();
+                self.large_space_lock.unlock();
+            },
+            None => (),
+        }
+    }
+
+    fn collect(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
+        match SafepointScope::new(mutator.clone()) {
+            Some(safepoint) => unsafe {
+                let time = std::time::Instant::now();
+                self.global_heap_lock.lock();
+                self.large_space_lock.lock();
+                self.minor_gc(keep);
+                self.major_gc(keep);
+                self.stats
+                    .record_pause(time.elapsed(), safepoint.time_to_safepoint());
+                drop(safepoint);
+                self.global_heap_lock.unlock();
+                self.large_space_lock.unlock();
+            },
+            None => (),
+        }
+    }
+
+    fn alloc_tlab_area(&mut self, _mutator: &MutatorRef<Self>, _size: usize) -> *mut u8 {
+        null_mut()
+    }
+
+    fn safepoint(&self) -> &GlobalSafepoint {
+        &self.safepoint
+    }
+
+    fn attach_current_thread(&mut self, mutator: *mut Mutator<Self>) {
+        self.global_heap_lock.lock();
+        self.safepoint.n_mutators.fetch_add(1, Ordering::Relaxed);
+        self.mutators.push(mutator);
+        unsafe { self.global_heap_lock.unlock() };
+    }
+
+    fn detach_current_thread(&mut self, mutator: *mut Mutator<Self>) {
+        self.global_heap_lock.lock();
+
+        let mut detached = false;
+        self.mutators.retain(|x| {
+            if *x == mutator {
+                detached = true;
+                false
+            } else {
+                true
+            }
+        });
+        self.safepoint.n_mutators.fetch_sub(1, Ordering::Relaxed);
+        assert!(detached, "mutator must be detached");
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+    }
+
+    fn global_lock(&self) {
+        self.global_heap_lock.lock();
+    }
+
+    fn global_unlock(&self) {
+        unsafe {
+            debug_assert!(self.global_heap_lock.is_locked());
+            self.global_heap_lock.unlock();
+        }
+    }
+
+    fn mutators(&self) -> &[*mut Mutator<Self>] {
+        assert!(self.global_heap_lock.is_locked());
+        &self.mutators
+    }
+}
+
+impl Visitor for MiniMark {
+    fn mark_object(&mut self, root: &mut NonNull<HeapObjectHeader>) {
+        let object = root.as_ptr();
+        unsafe {
+            if self.in_minor_gc {
+                // minor GC: promote young objects, old objects are not traced.
+                if !self.nursery.contains(object.cast()) {
+                    return;
+                }
+                if (*object).is_forwarded() {
+                    *root = NonNull::new_unchecked((*object).vtable() as _);
+                    return;
+                }
+                let size = (*object).size();
+                let mut copy = self.promotion.alloc(size);
+                if copy.is_null() {
+                    // old space reached its threshold, grow it for now and collect it after minor GC.
+                    self.needs_major_gc = true;
+                    self.promotion.set_emergency_collection(true);
+                    copy = self.promotion.alloc(size);
+                    if copy.is_null() {
+                        // minor GC can't be stopped half-way, nursery would contain forwarded objects.
+                        crate::mutator::oom_abort();
+                    }
+                }
+                std::ptr::copy_nonoverlapping(object.cast::<u8>(), copy, size);
+                if (*object).finalizable_bit() {
+                    self.space.add_finalizable(copy.cast());
+                }
+                (*object).set_forwarded(copy as usize);
+                self.promoted_bytes += size;
+                self.mark_stack.push(copy.cast());
+                *root = NonNull::new_unchecked(copy.cast());
+            } else {
+                debug_assert!(!self.nursery.contains(object.cast()));
+                if !(*object).set_color(self.alloc_color, self.mark_color) {
+                    if self.space.has_address(object.cast()) {
+                        self.space.mark_lines(object);
+                    } else {
+                        (*PreciseAllocation::from_cell(object)).test_and_set_marked();
+                    }
+                    self.mark_stack.push(object);
+                }
+            }
+        }
+    }
+
+    fn mark_ephemeron(&mut self, ephemeron: &mut EphemeronEntry) {
+        // ephemerons are traced only in old space where objects are not moved.
+        self.ephemerons.push(ephemeron);
+    }
+}
+
+impl Drop for MiniMark {
+    fn drop(&mut self) {
+        unsafe {
+            if self.verbose > 0 {
+                eprintln!("Dispose MiniMark heap at {:p}", self);
+            }
+            drop(Box::from_raw(
+                self.space as *const ImmixSpace as *mut ImmixSpace,
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::{Ephemeron, Finalize},
+        letroot,
+    };
+    use std::sync::atomic::AtomicUsize;
+
+    struct Node {
+        value: usize,
+        next: Option<Gc<Node, MiniMark>>,
+    }
+
+    unsafe impl Trace for Node {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.next.trace(vis);
+        }
+    }
+    unsafe impl Finalize for Node {}
+    impl Collectable for Node {}
+
+    struct Table {
+        entries: Vec<Ephemeron<Node, Node, MiniMark>>,
+    }
+
+    unsafe impl Trace for Table {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.entries.trace(vis);
+        }
+    }
+    unsafe impl Finalize for Table {}
+    impl Collectable for Table {}
+
+    fn node(
+        mutator: &mut MutatorRef<MiniMark>,
+        value: usize,
+        next: Option<Gc<Node, MiniMark>>,
+        space: AllocationSpace,
+    ) -> Gc<Node, MiniMark> {
+        mutator.allocate(Node { value, next }, space)
+    }
+
+    /// Add entries whose values reference their own keys. Keys are not reachable from anywhere else.
+    fn add_unreachable_entries(
+        mutator: &mut MutatorRef<MiniMark>,
+        table: &mut Gc<Table, MiniMark>,
+        space: AllocationSpace,
+    ) {
+        for i in 0..100 {
+            let key = node(mutator, i, None, space);
+            let value = node(mutator, i, Some(key), space);
+            table.entries.push(Ephemeron::new(key, value));
+        }
+    }
+
+    static FINALIZED: AtomicUsize = AtomicUsize::new(0);
+
+    struct Resource;
+
+    impl Drop for Resource {
+        fn drop(&mut self) {
+            FINALIZED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    unsafe impl Trace for Resource {}
+    unsafe impl Finalize for Resource {}
+    impl Collectable for Resource {}
+
+    #[test]
+    fn survivors_are_promoted() {
+        let mut mutator = instantiate_minimark(MiniMarkOptions::default());
+        let stack = mutator.shadow_stack();
+        letroot!(list = stack, None::<Gc<Node, MiniMark>>);
+        for i in 0..1000 {
+            let node = mutator.allocate(
+                Node {
+                    value: i,
+                    next: *list,
+                },
+                AllocationSpace::New,
+            );
+            *list = Some(node);
+        }
+        assert!(mutator.heap_ref().nursery.contains(list.unwrap().base.as_ptr().cast()));
+        mutator.minor_collection(&mut []);
+        let heap = mutator.heap_ref();
+        assert!(heap.space.has_address(list.unwrap().base.as_ptr().cast()));
+        assert_eq!(heap.nursery.allocated(), 0);
+        let mut cursor = *list;
+        let mut expected = 1000;
+        while let Some(node) = cursor {
+            expected -= 1;
+            assert_eq!(node.value, expected);
+            cursor = node.next;
+        }
+        assert_eq!(expected, 0);
+    }
+
+    #[test]
+    fn write_barrier_keeps_young_objects() {
+        let mut mutator = instantiate_minimark(MiniMarkOptions::default());
+        let stack = mutator.shadow_stack();
+        letroot!(
+            old = stack,
+            mutator.allocate(
+                Node {
+                    value: 0,
+                    next: None
+                },
+                AllocationSpace::Old
+            )
+        );
+        mutator.minor_collection(&mut []);
+        let young = mutator.allocate(
+            Node {
+                value: 42,
+                next: None,
+            },
+            AllocationSpace::New,
+        );
+        old.next = Some(young);
+        mutator.write_barrier(old.to_dyn());
+        mutator.minor_collection(&mut []);
+        let young = old.next.unwrap();
+        assert!(!mutator.heap_ref().nursery.contains(young.base.as_ptr().cast()));
+        assert_eq!(young.value, 42);
+        mutator.full_collection(&mut []);
+        assert_eq!(old.next.unwrap().value, 42);
+    }
+
+    #[test]
+    fn promoted_and_old_objects_are_finalized() {
+        let mut mutator = instantiate_minimark(MiniMarkOptions::default());
+        let stack = mutator.shadow_stack();
+        {
+            letroot!(
+                resource = stack,
+                mutator.allocate(Resource, AllocationSpace::New)
+            );
+            mutator.minor_collection(&mut []);
+            let heap = mutator.heap_ref();
+            assert!(heap.space.has_address(resource.base.as_ptr().cast()));
+        }
+        mutator.allocate(Resource, AllocationSpace::Old);
+        assert_eq!(FINALIZED.load(Ordering::Relaxed), 0);
+        mutator.full_collection(&mut []);
+        assert_eq!(FINALIZED.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn ephemerons_with_dead_keys_are_cleared() {
+        let mut mutator = instantiate_minimark(MiniMarkOptions::default());
+        let stack = mutator.shadow_stack();
+        letroot!(
+            table = stack,
+            mutator.allocate(Table { entries: vec![] }, AllocationSpace::New)
+        );
+        letroot!(root = stack, node(&mut mutator, 1, None, AllocationSpace::New));
+        // key of the first entry is reachable only from value of the second entry
+        let key = node(&mut mutator, 2, None, AllocationSpace::New);
+        let value = node(&mut mutator, 20, None, AllocationSpace::New);
+        table.entries.push(Ephemeron::new(key, value));
+        let value = node(&mut mutator, 10, Some(key), AllocationSpace::New);
+        table.entries.push(Ephemeron::new(*root, value));
+        add_unreachable_entries(&mut mutator, &mut table, AllocationSpace::New);
+
+        mutator.minor_collection(&mut []);
+        let first = &table.entries[0];
+        assert_eq!(first.value().unwrap().value, 20);
+        let second = &table.entries[1];
+        assert!(second.key().unwrap().base == root.base);
+        assert!(second.value().unwrap().next.unwrap().base == first.key().unwrap().base);
+        assert!(table.entries[2..]
+            .iter()
+            .all(|entry| entry.key().is_none() && entry.value().is_none()));
+
+        // keys in old space are found dead by major GC only.
+        add_unreachable_entries(&mut mutator, &mut table, AllocationSpace::Old);
+        mutator.full_collection(&mut []);
+        assert_eq!(table.entries[0].key().unwrap().value, 2);
+        assert_eq!(table.entries[1].value().unwrap().value, 10);
+        assert!(table.entries[2..]
+            .iter()
+            .all(|entry| entry.key().is_none() && entry.value().is_none()));
+    }
+}
@@ -1,7 +1,9 @@
 //! Mutator thread local information for GC
 use std::{
+    any::TypeId,
     cell::{Cell, UnsafeCell},
-    mem::size_of,
+    io::{self, Write},
+    mem::{size_of, ManuallyDrop},
     ops::{Deref, DerefMut},
     ptr::{null_mut, NonNull},
     sync::{atomic::AtomicU32, Arc},
@@ -11,11 +13,17 @@ use atomic::{Atomic, Ordering};
 use parking_lot::{Condvar, Mutex};
 
 use crate::{
-    api::{Collectable, Finalize, Gc, HeapObjectHeader, Trace, Weak},
-    gc_base::{AllocationSpace, GcBase, MarkingConstraint, TLAB},
+    api::{Collectable, Finalize, Gc, HeapObjectHeader, Soft, Trace, Weak},
+    epoch::GcEpoch,
+    gc_base::{AllocError, AllocationSpace, GcBase, MarkingConstraint, TLAB},
+    heap_limit::{HeapLimit, HeapLimitAction},
+    heap_snapshot::HeapSnapshot,
+    observer::GcObserver,
     safepoint::{GlobalSafepoint, SafepointScope},
-    shadow_stack::ShadowStack,
-    utils::{align_usize, stack_bounds::StackBounds},
+    shadow_stack::{Rooted, ShadowStack},
+    stats::HeapStats,
+    stress::GcStress,
+    utils::{align_usize, registers::Registers, stack_bounds::StackBounds},
 };
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
@@ -49,10 +57,15 @@ pub struct Mutator<H: GcBase + 'static> {
     safepoint: *const GlobalSafepoint,
     safepoint_cond: *const AtomicU32,
     pub(crate) last_sp: Cell<*mut *mut u8>,
+    /// Callee-saved registers captured together with `last_sp`.
+    registers: Cell<Registers>,
     join_data: Arc<JoinDataInternal>,
     shadow_stack: ShadowStack,
     pub(crate) heap: Arc<UnsafeCell<H>>,
     rc: u32,
+    stress: GcStress,
+    /// Number of allocations left before next stress GC cycle.
+    stress_countdown: usize,
 }
 
 impl<H: 'static + GcBase> Mutator<H> {
@@ -90,6 +103,7 @@ impl<H: 'static + GcBase> Mutator<H> {
             join_data.internal.clone(),
         ));
 
+        mutator.set_gc_stress(self.stress);
         heap.attach_current_thread(&mut *mutator);
         drop(state);
         std::thread::spawn(move || {
@@ -121,9 +135,12 @@ impl<H: 'static + GcBase> Mutator<H> {
             state: Atomic::new(ThreadState::Unsafe),
             tlab: H::TLAB::create(heap),
             last_sp: Cell::new(null_mut()),
+            registers: Cell::new(Registers::default()),
             join_data,
             shadow_stack: ShadowStack::new(),
             rc: 1,
+            stress: GcStress::Disabled,
+            stress_countdown: 0,
         }
     }
     /// Get shadow stack reference for this thread.
@@ -142,11 +159,39 @@ impl<H: 'static + GcBase> Mutator<H> {
         self.get_safepoint().wait_gc();
         self.state.store(state, Ordering::Release);
     }
-    /// Check if safepoint is requested. If it is requested mutator will wait for safepoint to be released.
+    /// Returns GC stress mode of this mutator, see [stress](crate::stress).
+    pub fn gc_stress(&self) -> GcStress {
+        self.stress
+    }
+    /// Set GC stress mode of this mutator, see [stress](crate::stress). Mutators spawned by this mutator inherit its
+    /// stress mode.
+    pub fn set_gc_stress(&mut self, stress: GcStress) {
+        if let GcStress::Allocations(n) = stress {
+            assert!(
+                n > 0,
+                "Stress GC requires at least one allocation between cycles"
+            );
+            self.stress_countdown = n;
+        }
+        self.stress = stress;
+    }
+    /// Check if safepoint is requested. If it is requested mutator will wait for safepoint to be released. Returns
+    /// true if mutator was stopped for GC.
     ///
     /// This function should be quite cheap because it is simple conditional check if safepoint is requested and call to slow path if it is requested.
+    /// In [GcStress::Safepoints] mode every call performs GC cycle.
     #[inline(always)]
     pub fn safepoint(&self) -> bool {
+        if self.stress == GcStress::Safepoints {
+            self.stress_safepoint();
+            return true;
+        }
+        self.poll_safepoint()
+    }
+    /// Same as [Mutator::safepoint] but never performs stress GC cycle. Used by GC policies in allocation paths where
+    /// object being allocated is not rooted yet.
+    #[inline(always)]
+    pub(crate) fn poll_safepoint(&self) -> bool {
         unsafe {
             if (*self.safepoint_cond).load(Ordering::Relaxed) != 0 {
                 self.safepoint_slow();
@@ -157,17 +202,44 @@ impl<H: 'static + GcBase> Mutator<H> {
     }
     #[inline(never)]
     #[cold]
+    fn stress_safepoint(&self) {
+        // mutators are always allocated by `MutatorRef::new`, so new reference can be created from `self`.
+        let this = ManuallyDrop::new(MutatorRef {
+            mutator: NonNull::from(self),
+        });
+        let mut mutator = (*this).clone();
+        self.heap_ref().stress_collection(&mut mutator, &mut []);
+        self.poll_safepoint();
+    }
+    #[inline(never)]
+    #[cold]
     fn safepoint_slow(&self) {
-        self.last_sp.set(approximate_stack_pointer());
+        self.save_conservative_roots();
         self.set_gc_and_wait();
     }
 
-    pub(crate) fn state_set(&self, state: ThreadState, old_state: ThreadState) -> ThreadState {
+    /// Record approximate stack pointer and spill callee-saved registers so that GC can scan them conservatively.
+    /// Must be invoked by mutator thread before it stops at safepoint or starts GC cycle.
+    #[inline(always)]
+    pub(crate) fn save_conservative_roots(&self) {
+        unsafe {
+            (*self.registers.as_ptr()).capture();
+        }
         self.last_sp.set(approximate_stack_pointer());
+    }
+
+    /// Range of registers saved by [Mutator::save_conservative_roots] that should be scanned like the stack.
+    pub(crate) fn saved_registers(&self) -> (*mut *mut u8, *mut *mut u8) {
+        let start = self.registers.as_ptr().cast::<*mut u8>();
+        unsafe { (start, start.add(Registers::WORDS)) }
+    }
+
+    pub(crate) fn state_set(&self, state: ThreadState, old_state: ThreadState) -> ThreadState {
+        self.save_conservative_roots();
         self.state.store(state, Ordering::Release);
 
         if old_state.safe_for_safepoint() && !state.safe_for_safepoint() {
-            self.safepoint();
+            self.poll_safepoint();
         }
         old_state
     }
@@ -218,6 +290,28 @@ impl<H: GcBase> MutatorRef<H> {
             }
         }
     }
+    /// Take snapshot of the heap in STW pause. See [heap_snapshot](crate::heap_snapshot).
+    pub fn heap_snapshot(&self) -> HeapSnapshot {
+        loop {
+            match SafepointScope::new(self.clone()) {
+                Some(safepoint) => {
+                    self.save_conservative_roots();
+                    let heap = self.heap_ref();
+                    heap.global_lock();
+                    let snapshot = unsafe { HeapSnapshot::take(heap) };
+                    heap.global_unlock();
+                    drop(safepoint);
+                    break snapshot;
+                }
+                None => continue,
+            }
+        }
+    }
+    /// Take snapshot of the heap and write it to `out` in Chrome DevTools `.heapsnapshot` format. Snapshot is written
+    /// after mutators are resumed.
+    pub fn write_heap_snapshot(&self, out: impl Write) -> io::Result<()> {
+        self.heap_snapshot().write(out)
+    }
     pub fn write_barrier(&mut self, object: Gc<dyn Collectable, H>) {
         let heap = unsafe { &mut *self.heap.get() };
         heap.write_barrier(self, object);
@@ -244,16 +338,128 @@ impl<H: GcBase> MutatorRef<H> {
     }
     #[inline]
     pub fn allocate_weak<T: Collectable + ?Sized>(&mut self, value: Gc<T, H>) -> Weak<T, H> {
+        match self.try_allocate_weak(value) {
+            Ok(weak_ref) => weak_ref,
+            Err(_) => oom_abort(),
+        }
+    }
+    /// Same as [Mutator::allocate_weak] but returns referent back if heap is exhausted.
+    #[inline]
+    pub fn try_allocate_weak<T: Collectable + ?Sized>(
+        &mut self,
+        value: Gc<T, H>,
+    ) -> Result<Weak<T, H>, AllocError<Gc<T, H>>> {
         let href = unsafe { &mut *self.heap.get() };
         href.allocate_weak(self, value)
     }
-    /// Allocate `T` on GC heap
+    #[inline]
+    pub fn allocate_soft<T: Collectable + ?Sized>(&mut self, value: Gc<T, H>) -> Soft<T, H> {
+        let href = unsafe { &mut *self.heap.get() };
+        href.allocate_soft(self, value)
+    }
+    /// Register `held` value that is pushed to cleanup queue once `target` is found dead by GC.
+    pub fn register_finalization<T: Collectable + ?Sized, U: Collectable + ?Sized>(
+        &mut self,
+        target: Gc<T, H>,
+        held: Gc<U, H>,
+    ) {
+        let href = unsafe { &mut *self.heap.get() };
+        href.register_finalization(self, target, held)
+    }
+    /// Invoke `callback` for each held value in cleanup queue. Held value is rooted while `callback` runs and `callback`
+    /// is allowed to allocate. Returns number of invoked callbacks.
+    pub fn drain_cleanup_queue(
+        &mut self,
+        mut callback: impl FnMut(&mut Self, &mut Rooted<'_, Gc<dyn Collectable, H>>),
+    ) -> usize {
+        let stack = self.shadow_stack();
+        let mut count = 0;
+        loop {
+            let href = unsafe { &mut *self.heap.get() };
+            let held = match href.pop_cleanup() {
+                Some(held) => held,
+                None => break count,
+            };
+            letroot!(held = stack, held);
+            callback(self, &mut held);
+            count += 1;
+        }
+    }
+    /// Wait until finalizer thread finalizes all dead objects found so far. Mutator is in "unsafe" state while waiting
+    /// so other mutators can perform GC.
+    pub fn wait_for_pending_finalizers(&self) {
+        let _state = self.enter_unsafe();
+        self.heap_ref().wait_for_finalizers();
+    }
+    /// Returns GC statistics of heap, see [stats](crate::stats).
+    pub fn heap_stats(&self) -> HeapStats {
+        let _state = self.enter_unsafe();
+        self.heap_ref().stats()
+    }
+    /// Set `callback` that is invoked when allocation can't be satisfied because heap reached its growth limit.
+    /// See [heap_limit](crate::heap_limit).
+    pub fn set_near_heap_limit_callback(
+        &mut self,
+        callback: impl FnMut(&HeapLimit) -> HeapLimitAction + Send + 'static,
+    ) {
+        let href = unsafe { &mut *self.heap.get() };
+        href.set_near_heap_limit_callback(Some(Box::new(callback)));
+    }
+    /// Remove callback set by [MutatorRef::set_near_heap_limit_callback].
+    pub fn remove_near_heap_limit_callback(&mut self) {
+        let href = unsafe { &mut *self.heap.get() };
+        href.set_near_heap_limit_callback(None);
+    }
+    /// Register `observer` that is notified at phase boundaries of GC cycles. See [observer](crate::observer). Returns
+    /// false if observers are not supported by GC policy of the heap.
+    pub fn add_gc_observer(&mut self, observer: impl GcObserver + 'static) -> bool {
+        let href = unsafe { &mut *self.heap.get() };
+        href.add_gc_observer(Box::new(observer))
+    }
+    /// Allocate `T` on GC heap. Aborts the process if heap is exhausted even after emergency GC cycle, see
+    /// [Mutator::try_allocate] for fallible version.
     #[inline(always)]
+    #[cfg_attr(feature = "stale-gc-check", track_caller)]
     pub fn allocate<T: Collectable + Sized + 'static>(
         &mut self,
         value: T,
         space: AllocationSpace,
     ) -> Gc<T, H> {
+        // not implemented on top of `try_allocate` to not put one more copy of `value` on the stack in debug builds.
+        match self.allocate_object(value, space) {
+            Ok(mut value) => {
+                value.epoch = GcEpoch::new(&self.get_safepoint().epoch);
+                value
+            }
+            Err(_) => oom_abort(),
+        }
+    }
+
+    /// Allocate `T` on GC heap. Returns `value` back if heap is exhausted even after emergency GC cycle.
+    #[inline(always)]
+    #[cfg_attr(feature = "stale-gc-check", track_caller)]
+    pub fn try_allocate<T: Collectable + Sized + 'static>(
+        &mut self,
+        value: T,
+        space: AllocationSpace,
+    ) -> Result<Gc<T, H>, AllocError<T>> {
+        let mut result = self.allocate_object(value, space);
+        // pointer is stamped here and not in a closure so allocation site is the caller of `try_allocate`.
+        if let Ok(object) = &mut result {
+            object.epoch = GcEpoch::new(&self.get_safepoint().epoch);
+        }
+        result
+    }
+
+    #[inline(always)]
+    fn allocate_object<T: Collectable + Sized + 'static>(
+        &mut self,
+        mut value: T,
+        space: AllocationSpace,
+    ) -> Result<Gc<T, H>, AllocError<T>> {
+        if let GcStress::Allocations(_) = self.stress {
+            self.stress_allocation(&mut [&mut value]);
+        }
         let size = align_usize(value.allocation_size() + size_of::<HeapObjectHeader>(), 8);
         if (!self.tlab.can_thread_local_allocate(size) && size >= H::LARGE_ALLOCATION_SIZE)
             || space == AllocationSpace::Large
@@ -271,19 +477,65 @@ impl<H: GcBase> MutatorRef<H> {
         match result {
             Ok(value) => {
                 self.heap_ref().post_alloc(value);
-                value
+                Ok(value)
             }
             Err(value) => self.allocate_slow(value, size, space),
         }
     }
 
+    /// Allocates `size` bytes on heap and creates object header with `type_id` and `vtable`, see
+    /// [GcBase::allocate_raw]. Aborts the process if heap is exhausted.
+    pub fn allocate_raw(
+        &mut self,
+        size: usize,
+        type_id: TypeId,
+        vtable: usize,
+    ) -> *mut HeapObjectHeader {
+        match self.try_allocate_raw(size, type_id, vtable) {
+            Ok(object) => object,
+            Err(_) => oom_abort(),
+        }
+    }
+
+    /// Same as [Mutator::allocate_raw] but returns error if heap is exhausted even after emergency GC cycle.
+    pub fn try_allocate_raw(
+        &mut self,
+        size: usize,
+        type_id: TypeId,
+        vtable: usize,
+    ) -> Result<*mut HeapObjectHeader, AllocError<()>> {
+        if let GcStress::Allocations(_) = self.stress {
+            self.stress_allocation(&mut []);
+        }
+        let href = unsafe { &mut *self.heap.get() };
+        let object = href.allocate_raw(self, size, type_id, vtable);
+        if object.is_null() {
+            Err(AllocError::new(()))
+        } else {
+            Ok(object)
+        }
+    }
+
+    /// Count allocation and perform stress GC cycle if it is time to. `keep` is object being allocated.
+    #[cold]
+    fn stress_allocation(&mut self, keep: &mut [&mut dyn Trace]) {
+        self.stress_countdown -= 1;
+        if self.stress_countdown == 0 {
+            if let GcStress::Allocations(n) = self.stress {
+                self.stress_countdown = n;
+            }
+            let heap = unsafe { &mut *self.heap.get() };
+            heap.stress_collection(self, keep);
+        }
+    }
+
     #[cold]
     fn allocate_slow<T: Collectable + Sized + 'static>(
         &mut self,
         mut value: T,
         size: usize,
         space: AllocationSpace,
-    ) -> Gc<T, H> {
+    ) -> Result<Gc<T, H>, AllocError<T>> {
         let heap = unsafe { &mut *self.heap.get() };
         if size >= H::LARGE_ALLOCATION_SIZE || space == AllocationSpace::Large {
             heap.allocate_large(self, value)
@@ -294,12 +546,18 @@ impl<H: GcBase> MutatorRef<H> {
                 // if tlab failed to be refilled we request GC cycle and try to get some memory
                 heap.collect_alloc_failure(self, &mut [&mut value]);
                 if !this.tlab.refill(&self, size) {
-                    // if refilling again fails we just OOM
-                    oom_abort();
+                    // if refilling again fails heap is exhausted
+                    return Err(AllocError::new(value));
                 }
             }
             // must not fail
-            self.allocate(value, space)
+            match self.tlab.allocate(value) {
+                Ok(value) => {
+                    self.heap_ref().post_alloc(value);
+                    Ok(value)
+                }
+                Err(value) => Err(AllocError::new(value)),
+            }
         } else {
             // this path should be reached only when `H::SUPPORTS_TLAB` returns true and `size` is `>= H::TLAB::LARGE_OBJECT_SIZE`
             self.allocate_inline(value, size, space)
@@ -315,10 +573,9 @@ impl<H: GcBase> MutatorRef<H> {
         value: T,
         _size: usize,
         space: AllocationSpace,
-    ) -> Gc<T, H> {
+    ) -> Result<Gc<T, H>, AllocError<T>> {
         let href = unsafe { &mut *self.heap.get() };
-        let val = href.alloc_inline(self, value, space);
-        val
+        href.alloc_inline(self, value, space)
     }
 }
 
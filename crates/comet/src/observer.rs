@@ -0,0 +1,116 @@
+//! GC lifecycle observers.
+//!
+//! [GcObserver] registered with [MutatorRef::add_gc_observer](crate::mutator::MutatorRef::add_gc_observer) is notified
+//! at phase boundaries of every GC cycle. Unlike [MarkingConstraint](crate::gc_base::MarkingConstraint) observer
+//! does not take part in marking, it is a place to flush inline caches, clear code caches or record tracing spans.
+//!
+//! Observers are invoked by GC thread inside of STW pause while heap locks are held. They are free to use Rust heap
+//! but ***must not*** allocate on GC heap, trigger GC cycle or wait for mutators.
+//!
+//! [GcObserver::gc_after_pause] is the exception: it is invoked after the pause ends and heap locks are released by the
+//! mutator thread that ran the cycle, so it may allocate on GC heap, e.g to rebuild caches cleared during the pause.
+//! Observers are not notified of cycles started while `gc_after_pause` hooks run, including cycles triggered by their
+//! own allocations.
+use parking_lot::{lock_api::RawMutex, RawMutex as Lock};
+
+use crate::stats::HeapStats;
+
+/// Reason GC cycle was started.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GcReason {
+    /// Cycle was requested by mutator e.g by [MutatorRef::collect](crate::mutator::MutatorRef::collect).
+    Requested,
+    /// Allocation can't be satisfied without GC cycle.
+    AllocationFailure,
+}
+
+/// Phase boundary of GC cycle. Phases are reported in declaration order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GcPhase {
+    /// Stacks, shadow stacks and other roots are scanned, transitive closure is not computed yet.
+    RootsScanned,
+    /// All live objects are marked, ephemerons and finalization registry are processed.
+    Marked,
+    /// Weak and soft references to dead objects are cleared.
+    WeakProcessed,
+    /// Dead objects are swept and their memory is available for allocation.
+    Swept,
+}
+
+/// Observer of GC cycles. All methods have empty default implementations.
+pub trait GcObserver: Send {
+    /// Invoked at the start of GC pause before any GC work is done.
+    fn gc_start(&mut self, reason: GcReason) {
+        let _ = reason;
+    }
+    /// Invoked when GC cycle reaches `phase`.
+    fn gc_phase(&mut self, phase: GcPhase) {
+        let _ = phase;
+    }
+    /// Invoked at the end of GC pause. `stats` already include finished cycle.
+    fn gc_end(&mut self, stats: &HeapStats) {
+        let _ = stats;
+    }
+    /// Invoked after GC pause when mutators are resumed and heap locks are released. Unlike other methods it may
+    /// allocate on GC heap.
+    fn gc_after_pause(&mut self, stats: &HeapStats) {
+        let _ = stats;
+    }
+}
+
+/// List of observers registered on a heap.
+#[derive(Default)]
+pub struct GcObservers {
+    observers: Vec<Box<dyn GcObserver>>,
+}
+
+impl GcObservers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, observer: Box<dyn GcObserver>) {
+        self.observers.push(observer);
+    }
+
+    pub fn start(&mut self, reason: GcReason) {
+        for observer in self.observers.iter_mut() {
+            observer.gc_start(reason);
+        }
+    }
+
+    pub fn phase(&mut self, phase: GcPhase) {
+        for observer in self.observers.iter_mut() {
+            observer.gc_phase(phase);
+        }
+    }
+
+    pub fn end(&mut self, stats: &HeapStats) {
+        for observer in self.observers.iter_mut() {
+            observer.gc_end(stats);
+        }
+    }
+
+    /// Invoke [GcObserver::gc_after_pause] of observers without holding `lock` that protects them. Observers are moved
+    /// out while they run so that cycles started by their allocations do not alias them, observers registered in the
+    /// meantime are kept.
+    ///
+    /// # Safety
+    ///
+    /// `lock` must not be held by the calling thread.
+    pub unsafe fn after_pause(this: *mut Self, lock: &Lock, stats: &HeapStats) {
+        lock.lock();
+        let mut observers = std::mem::take(&mut (*this).observers);
+        lock.unlock();
+        if observers.is_empty() {
+            return;
+        }
+        for observer in observers.iter_mut() {
+            observer.gc_after_pause(stats);
+        }
+        lock.lock();
+        observers.append(&mut (*this).observers);
+        (*this).observers = observers;
+        lock.unlock();
+    }
+}
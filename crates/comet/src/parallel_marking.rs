@@ -0,0 +1,280 @@
+//! # Parallel marking
+//!
+//! Marking work is split between a number of workers. Each worker owns LIFO deque of objects it has marked and
+//! traces objects from it, once worker runs out of work it steals batches of objects from other workers. Marking
+//! is finished once all workers are idle and there is no work left to steal.
+//!
+//! GC specific part of marking (how object is marked and whether it has to be traced) is implemented by
+//! [ParallelMarking]. Roots are still marked by GC thread and objects marked from roots are used as initial work.
+//!
+//! Ephemerons found by workers are not processed, they are returned to GC thread which processes them once marking is finished.
+use crate::api::{EphemeronEntry, HeapObjectHeader, Visitor};
+use crossbeam::{
+    deque::{Injector, Steal, Stealer, Worker},
+    utils::Backoff,
+};
+use std::{
+    ptr::NonNull,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// GC specific part of parallel marking. It is shared between all marking workers.
+pub trait ParallelMarking: Sync {
+    /// State that is owned by each worker, i.e allocation buffer for evacuated objects.
+    type Local: Send;
+
+    fn create_local(&self) -> Self::Local;
+
+    /// Mark object that is referenced from `root`. Returns object that must be traced if object was not marked before.
+    /// `root` might be updated if object was moved.
+    ///
+    /// # Safety
+    ///
+    /// Might be invoked concurrently by many workers with the same object, marking must be atomic.
+    unsafe fn mark(
+        &self,
+        local: &mut Self::Local,
+        root: &mut NonNull<HeapObjectHeader>,
+    ) -> Option<*mut HeapObjectHeader>;
+}
+
+/// Visitor that is used by marking workers.
+pub struct MarkingWorker<'a, M: ParallelMarking> {
+    marking: &'a M,
+    local: M::Local,
+    worklist: Worker<usize>,
+    ephemerons: Vec<usize>,
+}
+
+impl<'a, M: ParallelMarking> Visitor for MarkingWorker<'a, M> {
+    fn mark_object(&mut self, root: &mut NonNull<HeapObjectHeader>) {
+        unsafe {
+            if let Some(object) = self.marking.mark(&mut self.local, root) {
+                self.worklist.push(object as usize);
+            }
+        }
+    }
+
+    fn mark_ephemeron(&mut self, ephemeron: &mut EphemeronEntry) {
+        self.ephemerons
+            .push(ephemeron as *mut EphemeronEntry as usize);
+    }
+}
+
+struct Shared<'a, M: ParallelMarking> {
+    marking: &'a M,
+    injector: Injector<usize>,
+    stealers: Vec<Stealer<usize>>,
+    idle: AtomicUsize,
+}
+
+impl<'a, M: ParallelMarking> Shared<'a, M> {
+    fn steal(&self, worklist: &Worker<usize>, index: usize) -> Option<usize> {
+        loop {
+            let mut retry = false;
+            match self.injector.steal_batch_and_pop(worklist) {
+                Steal::Success(object) => return Some(object),
+                Steal::Retry => retry = true,
+                Steal::Empty => (),
+            }
+            for (i, stealer) in self.stealers.iter().enumerate() {
+                if i == index {
+                    continue;
+                }
+                match stealer.steal_batch_and_pop(worklist) {
+                    Steal::Success(object) => return Some(object),
+                    Steal::Retry => retry = true,
+                    Steal::Empty => (),
+                }
+            }
+            if !retry {
+                return None;
+            }
+        }
+    }
+
+    /// Wait until there is work to steal. Returns `false` if marking is finished.
+    fn wait_for_work(&self) -> bool {
+        // Idle worker has empty worklist and does not push new objects so once all workers are idle no work is left.
+        let backoff = Backoff::new();
+        self.idle.fetch_add(1, Ordering::SeqCst);
+        loop {
+            if self.idle.load(Ordering::SeqCst) == self.stealers.len() {
+                return false;
+            }
+            if !self.injector.is_empty() || self.stealers.iter().any(|stealer| !stealer.is_empty())
+            {
+                self.idle.fetch_sub(1, Ordering::SeqCst);
+                return true;
+            }
+            if backoff.is_completed() {
+                std::thread::yield_now();
+            } else {
+                backoff.snooze();
+            }
+        }
+    }
+
+    unsafe fn run(&self, worklist: Worker<usize>, index: usize) -> (M::Local, Vec<usize>) {
+        let mut visitor = MarkingWorker {
+            marking: self.marking,
+            local: self.marking.create_local(),
+            worklist,
+            ephemerons: vec![],
+        };
+        loop {
+            while let Some(object) = visitor
+                .worklist
+                .pop()
+                .or_else(|| self.steal(&visitor.worklist, index))
+            {
+                (*(object as *mut HeapObjectHeader))
+                    .get_dyn()
+                    .trace(&mut visitor);
+            }
+            if !self.wait_for_work() {
+                break;
+            }
+        }
+        (visitor.local, visitor.ephemerons)
+    }
+}
+
+/// Trace all objects that are reachable from already marked `objects`. Marking is performed by `pool` threads or
+/// by current thread when there is no pool or pool has single thread. Ephemerons found during marking are pushed to
+/// `ephemerons`. Returns state of all workers.
+///
+/// # Safety
+///
+/// Must be invoked only in STW pause. `objects` must be marked by `marking`.
+pub unsafe fn mark_parallel<M: ParallelMarking>(
+    pool: Option<&mut scoped_threadpool::Pool>,
+    marking: &M,
+    objects: Vec<*mut HeapObjectHeader>,
+    ephemerons: &mut Vec<*mut EphemeronEntry>,
+) -> Vec<M::Local> {
+    let num_workers = pool
+        .as_ref()
+        .map(|pool| pool.thread_count() as usize)
+        .unwrap_or(1);
+    let worklists = (0..num_workers)
+        .map(|_| Worker::new_lifo())
+        .collect::<Vec<_>>();
+    let shared = Shared {
+        marking,
+        injector: Injector::new(),
+        stealers: worklists
+            .iter()
+            .map(|worklist| worklist.stealer())
+            .collect(),
+        idle: AtomicUsize::new(0),
+    };
+    for object in objects {
+        shared.injector.push(object as usize);
+    }
+    let results = match pool {
+        Some(pool) if num_workers > 1 => {
+            let mut locals = (0..num_workers).map(|_| None).collect::<Vec<_>>();
+            let shared = &shared;
+            pool.scoped(|scope| {
+                for (index, (worklist, local)) in
+                    worklists.into_iter().zip(locals.iter_mut()).enumerate()
+                {
+                    scope.execute(move || {
+                        *local = Some(shared.run(worklist, index));
+                    });
+                }
+            });
+            locals.into_iter().map(|local| local.unwrap()).collect()
+        }
+        _ => worklists
+            .into_iter()
+            .enumerate()
+            .map(|(index, worklist)| shared.run(worklist, index))
+            .collect::<Vec<_>>(),
+    };
+    results
+        .into_iter()
+        .map(|(local, found)| {
+            ephemerons.extend(
+                found
+                    .into_iter()
+                    .map(|ephemeron| ephemeron as *mut EphemeronEntry),
+            );
+            local
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        api::{Collectable, Finalize, Gc, Trace, Visitor},
+        gc_base::AllocationSpace,
+        immix::{instantiate_immix, Immix, ImmixOptions},
+        letroot,
+    };
+
+    struct Tree {
+        value: usize,
+        left: Option<Gc<Tree, Immix>>,
+        right: Option<Gc<Tree, Immix>>,
+    }
+
+    unsafe impl Trace for Tree {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.left.trace(vis);
+            self.right.trace(vis);
+        }
+    }
+    unsafe impl Finalize for Tree {}
+    impl Collectable for Tree {}
+
+    fn sum(tree: Option<Gc<Tree, Immix>>) -> usize {
+        match tree {
+            Some(tree) => tree.value + sum(tree.left) + sum(tree.right),
+            None => 0,
+        }
+    }
+
+    #[test]
+    fn parallel_marking_keeps_reachable_objects() {
+        let mut mutator = instantiate_immix::<crate::gc_base::NoOpStackDecoder>(
+            ImmixOptions::default()
+                .with_heap_size(16 * 1024 * 1024)
+                .with_marking_threads(4),
+        );
+        let stack = mutator.shadow_stack();
+        letroot!(trees = stack, Vec::<Gc<Tree, Immix>>::new());
+        let mut expected = 0;
+        for i in 0..50000 {
+            let tree = if i % 16 == 0 {
+                let left = trees.pop();
+                let right = if i % 3 == 0 { None } else { trees.pop() };
+                expected += i;
+                Tree {
+                    value: i,
+                    left,
+                    right,
+                }
+            } else {
+                Tree {
+                    value: 0,
+                    left: None,
+                    right: None,
+                }
+            };
+            let tree = mutator.allocate(tree, AllocationSpace::New);
+            if i % 16 == 0 {
+                trees.push(tree);
+            }
+        }
+        // first cycle leaves blocks fragmented, second one evacuates live objects in parallel
+        mutator.collect(&mut []);
+        mutator.collect(&mut []);
+        assert_eq!(
+            trees.iter().map(|tree| sum(Some(*tree))).sum::<usize>(),
+            expected
+        );
+    }
+}
@@ -0,0 +1,130 @@
+//! Freed memory poisoning.
+//!
+//! When poisoning is enabled memory of dead objects is overwritten with [POISON] pattern as soon as it is swept: holes
+//! in Immix blocks, CMS cells, rosalloc slots freed by MarkSweep and large objects. MiniMark nursery and SemiSpace
+//! from-space are poisoned after each cycle. Header of freed object then has [POISON_WORD] in place of its vtable. With
+//! `poison-check` cargo feature [HeapObjectHeader::get_dyn] and `Gc` deref abort the process with a diagnostic when
+//! dangling pointer is used instead of silently reading memory that might already be reused by another object. Without
+//! the feature these functions do not check the header, so default builds keep their fast path.
+//!
+//! Poisoned memory is still reused by allocator, so dangling pointer is detected only until its memory is allocated
+//! again. With [FreedMemory::Quarantine] freed memory is poisoned and never reused: Immix blocks are not returned to
+//! free and reusable lists, CMS cells are not added to free-lists, rosalloc slots and large objects are never freed.
+//! Heap is exhausted much faster in this mode. Nursery and from-space are reused even in this mode, copying policies
+//! can't work without them.
+//!
+//! Poisoning is configured by `with_freed_memory` method of policy options and can be overridden by
+//! `COMET_FREED_MEMORY` environment variable that accepts `reuse`, `poison` and `quarantine` values.
+//!
+//! Both modes are slow and are meant for debugging only.
+use std::{backtrace::Backtrace, mem::size_of};
+
+use crate::api::HeapObjectHeader;
+
+/// Name of environment variable that configures what happens to freed memory.
+pub const FREED_MEMORY_ENV: &str = "COMET_FREED_MEMORY";
+/// Pattern freed memory is filled with.
+pub const POISON: u32 = 0xDEADBEEF;
+/// Vtable word of header of freed object.
+pub const POISON_WORD: u64 = ((POISON as u64) << 32) | POISON as u64;
+
+/// What happens to memory of dead objects.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FreedMemory {
+    /// Memory is reused by allocator.
+    #[default]
+    Reuse,
+    /// Memory is filled with [POISON] pattern and reused by allocator.
+    Poison,
+    /// Memory is filled with [POISON] pattern and never reused.
+    Quarantine,
+}
+
+impl FreedMemory {
+    /// Parse mode from [FREED_MEMORY_ENV] environment variable. Returns `None` if variable is not set, panics if
+    /// value is invalid.
+    pub fn from_env() -> Option<Self> {
+        let value = std::env::var(FREED_MEMORY_ENV).ok()?;
+        match Self::parse(&value) {
+            Some(mode) => Some(mode),
+            None => panic!(
+                "Invalid {} value '{}', expected 'reuse', 'poison' or 'quarantine'",
+                FREED_MEMORY_ENV, value
+            ),
+        }
+    }
+
+    /// Mode from environment variable if it is set or `default` otherwise.
+    pub fn from_env_or(default: Self) -> Self {
+        Self::from_env().unwrap_or(default)
+    }
+
+    /// Returns true if freed memory is filled with [POISON] pattern.
+    #[inline]
+    pub fn poisons(self) -> bool {
+        self != Self::Reuse
+    }
+
+    /// Returns true if freed memory is returned to allocator.
+    #[inline]
+    pub fn reuses(self) -> bool {
+        self != Self::Quarantine
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "reuse" => Some(Self::Reuse),
+            "poison" => Some(Self::Poison),
+            "quarantine" => Some(Self::Quarantine),
+            _ => None,
+        }
+    }
+}
+
+/// Fill `size` bytes starting at `start` with [POISON] pattern.
+///
+/// # Safety
+///
+/// `start` must be valid for writes of `size` bytes and must be aligned to 4 bytes.
+pub unsafe fn poison(start: *mut u8, size: usize) {
+    let words = std::slice::from_raw_parts_mut(start.cast::<u32>(), size / size_of::<u32>());
+    words.fill(POISON);
+    let tail = size % size_of::<u32>();
+    start
+        .add(size - tail)
+        .copy_from_nonoverlapping(POISON.to_ne_bytes().as_ptr(), tail);
+}
+
+/// Report use of freed `object` and abort the process.
+#[cold]
+#[inline(never)]
+pub fn report_use_after_free(object: *const HeapObjectHeader) -> ! {
+    eprintln!(
+        "[gc] Use after free: object {:p} was freed by GC and its memory is poisoned with {:#x}. Pointer to it was not rooted or traced\n{}",
+        object,
+        POISON,
+        Backtrace::force_capture()
+    );
+    std::process::abort();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn poisoned_header_is_detected() {
+        let mut memory = [0u64; 5];
+        unsafe {
+            poison(memory.as_mut_ptr().cast(), 36);
+            assert!((*memory.as_ptr().cast::<HeapObjectHeader>()).is_poisoned());
+        }
+        assert_eq!(memory[..4], [POISON_WORD; 4]);
+        assert_eq!(memory[4], POISON as u64);
+        assert_eq!(
+            FreedMemory::parse("quarantine"),
+            Some(FreedMemory::Quarantine)
+        );
+        assert_eq!(FreedMemory::parse("never"), None);
+    }
+}
@@ -1,7 +1,4 @@
-use std::{
-    marker::PhantomData,
-    ptr::{null_mut, NonNull},
-};
+use std::{mem::size_of, ptr::null_mut};
 
 use rosalloc::{
     dedicated_full_run,
@@ -15,8 +12,10 @@ use crate::{
     api::{vtable_of, Gc, HeapObjectHeader, VTable},
     gc_base::{GcBase, TLAB},
     mutator::MutatorRef,
+    poison::{poison, FreedMemory},
     small_type_id,
     space::MallocSpace,
+    sweeper::FreeLocks,
     utils::{align_usize, mmap::Mmap},
 };
 use parking_lot::{lock_api::RawMutex, RawMutex as Lock};
@@ -26,6 +25,11 @@ pub struct RosAllocSpace {
     #[allow(dead_code)]
     low_memory_mode: bool,
     lock: Lock,
+    /// Serialize `Rosalloc::bulk_free` calls of the same size class made by parallel sweeping, see
+    /// [sweeper](crate::sweeper).
+    free_locks: FreeLocks,
+    /// What happens to memory of dead objects, see [poison](crate::poison).
+    pub(crate) freed_memory: FreedMemory,
 }
 
 deref_impl!(RosAllocSpace;MallocSpace where space);
@@ -179,6 +183,8 @@ impl RosAllocSpace {
             rosalloc,
             low_memory_mode,
             lock: Lock::INIT,
+            free_locks: FreeLocks::new(NUM_THREAD_LOCAL_SIZE_BRACKETS + 1),
+            freed_memory: FreedMemory::Reuse,
         }
     }
     pub fn create_rosalloc(
@@ -205,7 +211,9 @@ impl RosAllocSpace {
         }
     }
 
-    pub fn sweep_callback(&mut self, ptrs: &[*mut u8], swap_bitmaps: bool) -> usize {
+    /// Free dead objects `ptrs` found by sweeping. Might be invoked from multiple threads at once as long as `ptrs` of
+    /// concurrent calls do not share a word of live bitmap. Objects of different size classes are freed concurrently.
+    pub fn sweep_callback(&self, ptrs: &[*mut u8], swap_bitmaps: bool) -> usize {
         if !swap_bitmaps {
             let bitmap = self.get_live_bitmap();
 
@@ -215,7 +223,39 @@ impl RosAllocSpace {
                 }
             }
         }
-        unsafe { (*self.rosalloc).bulk_free(ptrs) }
+        if !self.freed_memory.reuses() {
+            // quarantined slots are never freed so they are still accounted as allocated.
+            self.poison(ptrs);
+            return 0;
+        }
+        // size class must be read before header is poisoned.
+        self.free_locks
+            .free_grouped(ptrs, Self::size_class, |objects| unsafe {
+                self.poison(objects);
+                (*self.rosalloc).bulk_free(objects)
+            })
+    }
+
+    /// Index of lock that serializes freeing of `object`: thread local size bracket of object or one shared class for
+    /// larger objects.
+    fn size_class(object: *mut u8) -> usize {
+        let size = unsafe { (*object.cast::<HeapObjectHeader>()).size() };
+        if Rosalloc::is_size_for_thread_local(size) {
+            Rosalloc::size_to_index_and_bracket_size(size).0
+        } else {
+            NUM_THREAD_LOCAL_SIZE_BRACKETS
+        }
+    }
+
+    fn poison(&self, ptrs: &[*mut u8]) {
+        if self.freed_memory.poisons() {
+            for &ptr in ptrs.iter() {
+                unsafe {
+                    let size = (*ptr.cast::<HeapObjectHeader>()).size();
+                    poison(ptr, size.max(size_of::<HeapObjectHeader>()));
+                }
+            }
+        }
     }
 }
 
@@ -274,10 +314,7 @@ impl<H: GcBase<TLAB = Self>> TLAB<H> for RosAllocTLAB {
             (*header).set_metadata(vtable_of::<T>());
             (*header).set_size(size);
             ((*header).data() as *mut T).write(value);
-            Ok(Gc {
-                base: NonNull::new_unchecked(header),
-                marker: PhantomData,
-            })
+            Ok(Gc::from_raw(header))
         }
     }
 
@@ -1,6 +1,7 @@
 use std::{
     cell::Cell,
-    sync::atomic::{AtomicBool, AtomicU32},
+    sync::atomic::{AtomicBool, AtomicU32, AtomicUsize},
+    time::{Duration, Instant},
 };
 
 use atomic::Ordering;
@@ -22,6 +23,8 @@ pub struct GlobalSafepoint {
     pub(crate) safepoint_enable_cnt: Cell<u8>,
     pub(crate) gc_running: AtomicU32,
     pub(crate) n_mutators: AtomicU32,
+    /// Number of full heap GC cycles, see [epoch](crate::epoch).
+    pub(crate) epoch: AtomicUsize,
 }
 
 impl GlobalSafepoint {
@@ -31,8 +34,13 @@ impl GlobalSafepoint {
             safepoint_lock: Lock::INIT,
             gc_running: AtomicU32::new(0),
             n_mutators: AtomicU32::new(0),
+            epoch: AtomicUsize::new(0),
         }
     }
+    /// Advance heap epoch. Must be invoked at the start of GC cycle that traces the whole heap.
+    pub(crate) fn next_epoch(&self) {
+        self.epoch.fetch_add(1, Ordering::Relaxed);
+    }
     fn enable(&self) {
         debug_assert!(self.safepoint_lock.is_locked());
         self.safepoint_enable_cnt
@@ -109,17 +117,27 @@ pub struct SafepointScope<H: 'static + GcBase> {
     heap: *mut H,
     old_state: ThreadState,
     mutator: Option<MutatorRef<H>>,
+    time_to_safepoint: Duration,
 }
 
 impl<H: 'static + GcBase> SafepointScope<H> {
     pub fn new_no_mutator(heap: *mut H) -> Self {
+        Self::try_new_no_mutator(heap).expect("Failed to create safepoint")
+    }
+    /// Create safepoint from a thread that is not a mutator (e.g background GC thread). Returns `None` if
+    /// some other thread is already running GC, in that case this function returns only after GC is finished.
+    pub fn try_new_no_mutator(heap: *mut H) -> Option<Self> {
+        let start = Instant::now();
         let href = unsafe { &*heap };
         let safepoint = href.safepoint();
-        assert!(safepoint.start(), "Failed to create safepoint");
-        let this = Self {
+        if !safepoint.start() {
+            return None;
+        }
+        let mut this = Self {
             heap,
             old_state: ThreadState::Unsafe,
             mutator: None,
+            time_to_safepoint: Duration::ZERO,
         };
         unsafe {
             let href = &mut *this.heap;
@@ -143,10 +161,12 @@ impl<H: 'static + GcBase> SafepointScope<H> {
 
             href.global_unlock();
         }
-        this
+        this.time_to_safepoint = start.elapsed();
+        Some(this)
     }
 
     pub fn new(mutator: MutatorRef<H>) -> Option<Self> {
+        let start = Instant::now();
         let href = unsafe { &*mutator.heap.get() };
         let safepoint = href.safepoint();
         let old_state = mutator.state.load(Ordering::Relaxed);
@@ -158,10 +178,11 @@ impl<H: 'static + GcBase> SafepointScope<H> {
             return None;
         }
 
-        let this = Self {
+        let mut this = Self {
             heap: mutator.heap.get(),
             old_state,
             mutator: Some(mutator),
+            time_to_safepoint: Duration::ZERO,
         };
 
         unsafe {
@@ -186,12 +207,18 @@ impl<H: 'static + GcBase> SafepointScope<H> {
 
             href.global_unlock();
         }
+        this.time_to_safepoint = start.elapsed();
         Some(this)
     }
 
     pub fn heap(&self) -> *mut H {
         return self.heap.clone();
     }
+
+    /// Time it took all mutators to reach safepoint.
+    pub fn time_to_safepoint(&self) -> Duration {
+        self.time_to_safepoint
+    }
 }
 
 impl<H: GcBase> Drop for SafepointScope<H> {
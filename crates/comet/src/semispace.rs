@@ -0,0 +1,809 @@
+//! # SemiSpace: A copying garbage collector
+//!
+//! Simple semi-space collector that divides heap into two [BumpPointerSpace]s: from-space and to-space. All allocations
+//! are bump-allocated in from-space and when it is exhausted garbage collection is performed by copying live objects from from-space to
+//! to-space using Cheney's algorithm, after that spaces are flipped and the entire old from-space is discarded in one piece.
+//!
+//! Objects that are larger than [SemiSpace::LARGE_ALLOCATION_SIZE] are allocated in [LargeObjectSpace] and they are never moved.
+//!
+//! # Rooting
+//!
+//! SemiSpace does not scan native stack conservatively: objects are moved on each GC cycle and it is impossible to update pointers on native stack.
+//! All GC pointers on stack ***must*** be rooted using `letroot!()` otherwise they are left dangling after GC cycle. This makes
+//! this policy useful to verify that your runtime does rooting properly.
+
+use std::{
+    any::TypeId,
+    cell::UnsafeCell,
+    mem::size_of,
+    ptr::{null_mut, NonNull},
+    sync::Arc,
+};
+
+use atomic::Ordering;
+use parking_lot::{lock_api::RawMutex, RawMutex as Lock};
+
+use crate::{
+    api::{
+        vtable_of, Collectable, EphemeronEntry, Gc, HeapObjectHeader, Trace, VTable, Visitor, Weak,
+    },
+    bump_pointer_space::BumpPointerSpace,
+    gc_base::{
+        AllocError, AllocationSpace, GcBase, MarkingConstraint, MarkingConstraintRuns, NoHelp,
+        NoReadBarrier,
+    },
+    large_space::{LargeObjectSpace, PreciseAllocation},
+    make_small_type_id,
+    mutator::{JoinData, Mutator, MutatorRef, ThreadState},
+    poison::{poison, FreedMemory},
+    safepoint::{GlobalSafepoint, SafepointScope},
+    small_type_id,
+    stats::HeapStats,
+    stress::GcStress,
+    tlab::SimpleTLAB,
+    utils::{align_usize, formatted_size, stack_bounds::StackBounds},
+};
+
+/// SemiSpace GC implementation. Read top level module documentation for more information
+pub struct SemiSpace {
+    from_space: BumpPointerSpace,
+    to_space: BumpPointerSpace,
+    pub(crate) global_heap_lock: Lock,
+    pub(crate) large_space_lock: Lock,
+    pub(crate) large_space: LargeObjectSpace,
+    pub(crate) mutators: Vec<*mut Mutator<Self>>,
+    pub(crate) safepoint: GlobalSafepoint,
+    /// Grey large objects. Objects in to-space do not need mark stack because they are scanned linearly.
+    pub(crate) mark_stack: Vec<*mut HeapObjectHeader>,
+    pub(crate) verbose: u8,
+    total_gcs: usize,
+    weak_refs: Vec<Weak<dyn Collectable, Self>>,
+    /// Ephemerons found in copied objects whose keys are not known to be alive yet.
+    ephemerons: Vec<*mut EphemeronEntry>,
+    constraints: Vec<Box<dyn MarkingConstraint>>,
+    finalize_list: Vec<*mut HeapObjectHeader>,
+    finalize_lock: Lock,
+    large_space_target: usize,
+    min_large_space_size: usize,
+    growth_multiplier: f64,
+    stats: HeapStats,
+}
+
+pub struct SemiSpaceOptions {
+    /// Size of each semispace. Heap reserves 2X of this size. Set to 16MB by default.
+    pub semispace_size: usize,
+    /// Number of bytes that can be allocated in large object space before triggering GC cycle. Set to 8MB by default.
+    pub large_space_size: usize,
+    /// Determines by how much large object space threshold grows after GC cycle. By default set to 1.75
+    pub growth_multiplier: f64,
+    /// Enables verbose loggig to stdout.
+    pub verbose: u8,
+    /// GC stress mode, see [stress](crate::stress). Overridden by `COMET_GC_STRESS` environment variable. Disabled
+    /// by default.
+    pub stress: GcStress,
+    /// What happens to memory of dead objects, see [poison](crate::poison). Overridden by `COMET_FREED_MEMORY`
+    /// environment variable. Memory is reused by default.
+    pub freed_memory: FreedMemory,
+}
+
+impl SemiSpaceOptions {
+    /// Set growth multiplier. Panics if x <= 1
+    pub fn with_growth_multiplier(mut self, x: f64) -> Self {
+        if x <= 1.0 {
+            panic!("Growth multiplier is too small")
+        }
+        self.growth_multiplier = x;
+        self
+    }
+
+    pub fn with_semispace_size(mut self, x: usize) -> Self {
+        if x < 256 * 1024 {
+            panic!("Semispace size too small; Minimal semispace size is 256KB");
+        }
+        self.semispace_size = x;
+        self
+    }
+
+    pub fn with_large_space_size(mut self, x: usize) -> Self {
+        self.large_space_size = x;
+        self
+    }
+
+    pub fn with_verbose(mut self, x: u8) -> Self {
+        self.verbose = x;
+        self
+    }
+
+    /// Set GC stress mode.
+    pub fn with_stress(mut self, x: GcStress) -> Self {
+        self.stress = x;
+        self
+    }
+
+    /// Set what happens to memory of dead objects.
+    pub fn with_freed_memory(mut self, x: FreedMemory) -> Self {
+        self.freed_memory = x;
+        self
+    }
+}
+
+impl Default for SemiSpaceOptions {
+    fn default() -> Self {
+        Self {
+            semispace_size: 16 * 1024 * 1024,
+            large_space_size: 8 * 1024 * 1024,
+            growth_multiplier: 1.75,
+            verbose: 0,
+            stress: GcStress::Disabled,
+            freed_memory: FreedMemory::Reuse,
+        }
+    }
+}
+
+pub fn instantiate_semispace(options: SemiSpaceOptions) -> MutatorRef<SemiSpace> {
+    let from_space = BumpPointerSpace::new(options.semispace_size);
+    let to_space = BumpPointerSpace::new(options.semispace_size);
+    to_space.decommit();
+    let semispace = Arc::new(UnsafeCell::new(SemiSpace {
+        from_space,
+        to_space,
+        global_heap_lock: Lock::INIT,
+        large_space_lock: Lock::INIT,
+        large_space: LargeObjectSpace::new(),
+        mutators: vec![],
+        safepoint: GlobalSafepoint::new(),
+        mark_stack: vec![],
+        verbose: options.verbose,
+        total_gcs: 0,
+        weak_refs: vec![],
+        ephemerons: vec![],
+        constraints: vec![],
+        finalize_list: vec![],
+        finalize_lock: Lock::INIT,
+        large_space_target: options.large_space_size,
+        min_large_space_size: options.large_space_size,
+        growth_multiplier: options.growth_multiplier,
+        stats: HeapStats::default(),
+    }));
+    let href = unsafe { &mut *semispace.get() };
+    href.large_space.freed_memory = FreedMemory::from_env_or(options.freed_memory);
+    let join_data = JoinData::new();
+    let mut mutator = MutatorRef::new(Mutator::new(
+        semispace.clone(),
+        &href.safepoint,
+        join_data.internal.clone(),
+    ));
+    href.mutators.push(&mut *mutator);
+    href.safepoint
+        .n_mutators
+        .fetch_add(1, atomic::Ordering::Relaxed);
+    mutator.stack_bounds = StackBounds::current_thread_stack_bounds();
+    mutator.state_set(ThreadState::Safe, ThreadState::Unsafe);
+
+    mutator.set_gc_stress(GcStress::from_env_or(options.stress));
+    mutator
+}
+
+impl SemiSpace {
+    unsafe fn after_mark_constraints(&mut self) {
+        let this = self as *mut Self;
+        (*this).constraints.retain_mut(|constraint| {
+            if constraint.is_over() {
+                false
+            } else {
+                if constraint.runs_at() == MarkingConstraintRuns::AfterMark {
+                    constraint.run(self);
+                }
+                true
+            }
+        });
+    }
+    unsafe fn before_mark_constraints(&mut self) {
+        let this = self as *mut Self;
+        (*this).constraints.retain_mut(|constraint| {
+            if constraint.is_over() {
+                false
+            } else {
+                if constraint.runs_at() == MarkingConstraintRuns::BeforeMark {
+                    constraint.run(self);
+                }
+                true
+            }
+        });
+    }
+
+    /// Returns new location of `object` if it survived GC cycle or null if it is dead. Must be invoked only after copying phase.
+    unsafe fn forwardee(&self, object: *mut HeapObjectHeader) -> *mut HeapObjectHeader {
+        if self.from_space.contains(object.cast()) {
+            if (*object).is_forwarded() {
+                (*object).vtable() as _
+            } else {
+                null_mut()
+            }
+        } else if self.to_space.contains(object.cast())
+            || (*PreciseAllocation::from_cell(object)).is_marked()
+        {
+            object
+        } else {
+            null_mut()
+        }
+    }
+
+    /// Cheney scan: linearly scan objects copied to to-space and trace grey large objects until there is no more work.
+    unsafe fn process_grey(&mut self, scan: &mut *mut u8) {
+        loop {
+            while *scan < self.to_space.cursor() {
+                let object = scan.cast::<HeapObjectHeader>();
+                *scan = scan.add((*object).size());
+                (*object).get_dyn().trace(self);
+            }
+            match self.mark_stack.pop() {
+                Some(object) => (*object).get_dyn().trace(self),
+                None => break,
+            }
+        }
+    }
+
+    /// Copy values of ephemerons with live keys until no more keys are found to be alive. Ephemerons with dead keys are
+    /// cleared.
+    unsafe fn process_ephemerons(&mut self, scan: &mut *mut u8) {
+        loop {
+            let mut marked = false;
+            for ephemeron in std::mem::take(&mut self.ephemerons) {
+                let this = self as *const Self;
+                if (*ephemeron).resolve_key(|key| (*this).forwardee(key)) {
+                    (*ephemeron).mark_value(self);
+                    marked = true;
+                } else {
+                    self.ephemerons.push(ephemeron);
+                }
+            }
+            if !marked {
+                break;
+            }
+            // values might reference keys of other ephemerons
+            self.process_grey(scan);
+        }
+        for ephemeron in self.ephemerons.drain(..) {
+            (*ephemeron).clear();
+        }
+    }
+
+    unsafe fn process_weak_refs(&mut self) {
+        let this = self as *const Self;
+        self.weak_refs.retain_mut(|weak| {
+            let inner = (*this).forwardee(weak.base());
+            if inner.is_null() {
+                return false;
+            }
+            weak.set_base(inner);
+            weak.after_mark(|referent| (*this).forwardee(referent));
+            true
+        });
+    }
+
+    unsafe fn process_finalizers(&mut self) {
+        let from_space = &self.from_space;
+        self.finalize_list.retain_mut(|object| {
+            debug_assert!(from_space.contains(object.cast()));
+            if (**object).is_forwarded() {
+                *object = (**object).vtable() as _;
+                true
+            } else {
+                (**object).get_dyn().finalize();
+                false
+            }
+        });
+    }
+
+    #[inline]
+    unsafe fn allocate_header(&self, size: usize) -> *mut HeapObjectHeader {
+        let memory = self.from_space.bump_alloc(size);
+        if memory.is_null() {
+            return null_mut();
+        }
+        let object = memory.cast::<HeapObjectHeader>();
+        // from-space is reused after each GC cycle so header might contain stale bits.
+        object.write(HeapObjectHeader {
+            value: VTable { raw: 0 },
+            padding: 0,
+            padding2: 0,
+            type_id: 0,
+        });
+        (*object).set_size(size);
+        object
+    }
+
+    #[cold]
+    unsafe fn collect_and_alloc<T: Collectable + Sized + 'static>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        mut value: T,
+        size: usize,
+    ) -> Result<*mut HeapObjectHeader, AllocError<T>> {
+        self.collect_alloc_failure(mutator, &mut [&mut value]);
+        let object = self.allocate_header(size);
+        if object.is_null() {
+            return Err(AllocError::new(value));
+        }
+        (*object).set_metadata(vtable_of::<T>());
+        (*object).type_id = small_type_id::<T>();
+        ((*object).data() as *mut T).write(value);
+        Ok(object)
+    }
+}
+
+impl GcBase for SemiSpace {
+    type TLAB = SimpleTLAB<Self>;
+    const SUPPORTS_TLAB: bool = false;
+    type ReadBarrier = NoReadBarrier;
+
+    fn inline_allocation_helpers(&self) -> Self::InlineAllocationHelpers {
+        NoHelp
+    }
+
+    fn add_constraint<T: MarkingConstraint + 'static>(&mut self, constraint: T) {
+        self.global_lock();
+        self.constraints.push(Box::new(constraint));
+        self.global_unlock();
+    }
+
+    fn stats(&self) -> HeapStats {
+        self.global_heap_lock.lock();
+        let stats = self.stats.snapshot(
+            self.from_space.allocated() + self.large_space.bytes,
+            self.from_space.size() + self.large_space_target,
+            self.large_space.bytes,
+        );
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        stats
+    }
+
+    fn inspect(&self, mut f: impl FnMut(Gc<dyn Collectable, Self>) -> bool) -> bool {
+        unsafe {
+            self.large_space_lock.lock();
+            self.large_space.allocations.iter().for_each(|alloc| {
+                f(Gc::from_raw((**alloc).cell()));
+            });
+            self.large_space_lock.unlock();
+            let mut scan = self.from_space.start();
+            while scan < self.from_space.cursor() {
+                let object = scan.cast::<HeapObjectHeader>();
+                scan = scan.add((*object).size());
+                f(Gc::from_raw(object));
+            }
+        }
+        true
+    }
+
+    fn allocate_raw(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        size: usize,
+        type_id: TypeId,
+        vtable: usize,
+    ) -> *mut HeapObjectHeader {
+        let size = align_usize(size + size_of::<HeapObjectHeader>(), 8);
+        unsafe {
+            let object = if size >= Self::LARGE_ALLOCATION_SIZE {
+                self.large_space_lock.lock();
+                let object = self.large_space.allocate(size);
+                self.large_space_lock.unlock();
+                object
+            } else {
+                let mut object = self.allocate_header(size);
+                if object.is_null() {
+                    self.collect_alloc_failure(mutator, &mut []);
+                    object = self.allocate_header(size);
+                }
+                object
+            };
+            if object.is_null() {
+                return null_mut();
+            }
+            (*object).set_vtable(vtable);
+            (*object).type_id = make_small_type_id(type_id);
+            object
+        }
+    }
+
+    fn allocate_weak<T: Collectable + ?Sized>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        value: Gc<T, Self>,
+    ) -> Result<Weak<T, Self>, AllocError<Gc<T, Self>>> {
+        let weak_ref = unsafe { Weak::<T, Self>::try_create(mutator, value)? };
+        self.global_heap_lock.lock();
+        self.weak_refs.push(weak_ref.to_dyn_untraced());
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        Ok(weak_ref)
+    }
+
+    #[inline]
+    fn alloc_inline<T: Collectable + Sized + 'static>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        value: T,
+        _space: AllocationSpace,
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
+        let size = align_usize(value.allocation_size() + size_of::<HeapObjectHeader>(), 8);
+        unsafe {
+            let object = self.allocate_header(size);
+            let object = if object.is_null() {
+                self.collect_and_alloc(mutator, value, size)?
+            } else {
+                (*object).set_metadata(vtable_of::<T>());
+                (*object).type_id = small_type_id::<T>();
+                ((*object).data() as *mut T).write(value);
+                object
+            };
+            let gced = Gc::from_raw(object);
+            self.post_alloc(gced);
+            Ok(gced)
+        }
+    }
+
+    fn allocate_large<T: Collectable + Sized + 'static>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        mut value: T,
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
+        unsafe {
+            let size = value.allocation_size() + size_of::<HeapObjectHeader>();
+            if self.large_space.bytes + size > self.large_space_target {
+                self.collect_alloc_failure(mutator, &mut [&mut value]);
+            }
+            self.large_space_lock.lock();
+            let mut object = self.large_space.allocate(size);
+            if object.is_null() {
+                self.large_space_lock.unlock();
+                self.collect_alloc_failure(mutator, &mut [&mut value]);
+                self.large_space_lock.lock();
+                object = self.large_space.allocate(size);
+                if object.is_null() {
+                    self.large_space_lock.unlock();
+                    return Err(AllocError::new(value));
+                }
+            }
+            (*object).set_metadata(vtable_of::<T>());
+            (*object).type_id = small_type_id::<T>();
+            let gc = Gc::from_raw(object);
+            ((*object).data() as *mut T).write(value);
+            self.large_space_lock.unlock();
+            self.post_alloc(gc);
+            Ok(gc)
+        }
+    }
+
+    #[inline(always)]
+    fn post_alloc<T: Collectable + Sized + 'static>(&mut self, value: Gc<T, Self>) {
+        unsafe {
+            let base = value.base.as_ptr();
+            // large objects are finalized by large object space sweep.
+            if T::needs_finalization() && !(*base).is_precise() {
+                self.finalize_lock.lock();
+                self.finalize_list.push(base);
+                self.finalize_lock.unlock();
+            }
+        }
+    }
+
+    fn collect(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
+        match SafepointScope::new(mutator.clone()) {
+            Some(safepoint) => unsafe {
+                let time = std::time::Instant::now();
+
+                self.global_heap_lock.lock();
+                self.large_space_lock.lock();
+                let prev = self.from_space.allocated() + self.large_space.bytes;
+                let copy_phase = std::time::Instant::now();
+                self.safepoint.next_epoch();
+                self.to_space.commit();
+                self.large_space.begin_marking(true);
+                self.large_space.prepare_for_marking(false);
+                for i in 0..self.mutators.len() {
+                    let mutator = self.mutators[i];
+                    (*mutator).reset_tlab();
+                    (*mutator).shadow_stack().walk(|entry| {
+                        entry.trace(self);
+                    });
+                }
+                self.before_mark_constraints();
+                for object in keep {
+                    object.trace(self);
+                }
+                let mut scan = self.to_space.start();
+                self.process_grey(&mut scan);
+                self.after_mark_constraints();
+                self.process_grey(&mut scan);
+                self.process_ephemerons(&mut scan);
+                let copy_phase = copy_phase.elapsed();
+                let sweep_phase = std::time::Instant::now();
+
+                self.process_weak_refs();
+                self.process_finalizers();
+                self.large_space.sweep();
+                self.large_space.prepare_for_allocation(false);
+
+                if self.large_space.freed_memory.poisons() {
+                    // from-space is poisoned instead of decommitted, it is reused by the next cycle even in
+                    // quarantine mode.
+                    poison(self.from_space.start(), self.from_space.allocated());
+                } else {
+                    self.from_space.decommit();
+                }
+                self.from_space.reset();
+                std::mem::swap(&mut self.from_space, &mut self.to_space);
+
+                let bytes_allocated = self.from_space.allocated() + self.large_space.bytes;
+                self.large_space_target = self
+                    .min_large_space_size
+                    .max((self.large_space.bytes as f64 * self.growth_multiplier) as usize);
+                let sweep_phase = sweep_phase.elapsed();
+                let elapsed = time.elapsed();
+                self.stats
+                    .record_pause(elapsed, safepoint.time_to_safepoint());
+                // copying is reported as mark phase, sweep phase processes weak references, finalizers and
+                // large objects.
+                self.stats
+                    .record_cycle(false, copy_phase, sweep_phase, prev, bytes_allocated);
+                if self.verbose > 0 {
+                    eprintln!(
+                        "[gc] GC({}) Pause SemiSpace collection {}->{}({}) {:.4}ms (copy {:.4}ms)",
+                        self.total_gcs,
+                        formatted_size(prev),
+                        formatted_size(bytes_allocated),
+                        formatted_size(self.from_space.size()),
+                        elapsed.as_micros() as f64 / 1000.0,
+                        copy_phase.as_micros() as f64 / 1000.0
+                    );
+                }
+                self.total_gcs += 1;
+                drop(safepoint);
+
+                self.global_heap_lock.unlock();
+                self.large_space_lock.unlock();
+            },
+            None => (),
+        }
+    }
+
+    fn alloc_tlab_area(&mut self, _mutator: &MutatorRef<Self>, _size: usize) -> *mut u8 {
+        null_mut()
+    }
+
+    fn safepoint(&self) -> &GlobalSafepoint {
+        &self.safepoint
+    }
+
+    fn attach_current_thread(&mut self, mutator: *mut Mutator<Self>) {
+        self.global_heap_lock.lock();
+        self.safepoint.n_mutators.fetch_add(1, Ordering::Relaxed);
+        self.mutators.push(mutator);
+        unsafe { self.global_heap_lock.unlock() };
+    }
+
+    fn detach_current_thread(&mut self, mutator: *mut Mutator<Self>) {
+        self.global_heap_lock.lock();
+
+        let mut detached = false;
+        self.mutators.retain(|x| {
+            if *x == mutator {
+                detached = true;
+                false
+            } else {
+                true
+            }
+        });
+        self.safepoint.n_mutators.fetch_sub(1, Ordering::Relaxed);
+        assert!(detached, "mutator must be detached");
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+    }
+
+    fn global_lock(&self) {
+        self.global_heap_lock.lock();
+    }
+
+    fn global_unlock(&self) {
+        unsafe {
+            debug_assert!(self.global_heap_lock.is_locked());
+            self.global_heap_lock.unlock();
+        }
+    }
+
+    fn mutators(&self) -> &[*mut Mutator<Self>] {
+        assert!(self.global_heap_lock.is_locked());
+        &self.mutators
+    }
+}
+
+impl Visitor for SemiSpace {
+    fn mark_object(&mut self, root: &mut NonNull<HeapObjectHeader>) {
+        let object = root.as_ptr();
+        unsafe {
+            if self.from_space.contains(object.cast()) {
+                if (*object).is_forwarded() {
+                    *root = NonNull::new_unchecked((*object).vtable() as _);
+                    return;
+                }
+                let size = (*object).size();
+                // GC is single threaded so there is no need in atomic bump allocation. To-space is
+                // as large as from-space so copying can't fail.
+                let copy = self.to_space.thread_bump_alloc_unsafe(size);
+                debug_assert!(!copy.is_null());
+                std::ptr::copy_nonoverlapping(object.cast::<u8>(), copy, size);
+                (*object).set_forwarded(copy as usize);
+                *root = NonNull::new_unchecked(copy.cast());
+            } else if !self.to_space.contains(object.cast()) {
+                if !(*PreciseAllocation::from_cell(object)).test_and_set_marked() {
+                    self.mark_stack.push(object);
+                }
+            }
+        }
+    }
+
+    fn mark_ephemeron(&mut self, ephemeron: &mut EphemeronEntry) {
+        // ephemerons are traced only in to-space copies and large objects so their address is stable.
+        self.ephemerons.push(ephemeron);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::{Ephemeron, Finalize},
+        letroot,
+    };
+
+    struct Node {
+        value: usize,
+        next: Option<Gc<Node, SemiSpace>>,
+    }
+
+    unsafe impl Trace for Node {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.next.trace(vis);
+        }
+    }
+    unsafe impl Finalize for Node {}
+    impl Collectable for Node {}
+
+    struct Table {
+        entries: Vec<Ephemeron<Node, Node, SemiSpace>>,
+    }
+
+    unsafe impl Trace for Table {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.entries.trace(vis);
+        }
+    }
+    unsafe impl Finalize for Table {}
+    impl Collectable for Table {}
+
+    fn node(
+        mutator: &mut MutatorRef<SemiSpace>,
+        value: usize,
+        next: Option<Gc<Node, SemiSpace>>,
+    ) -> Gc<Node, SemiSpace> {
+        mutator.allocate(Node { value, next }, AllocationSpace::New)
+    }
+
+    #[test]
+    fn objects_survive_relocation() {
+        let mut mutator = instantiate_semispace(SemiSpaceOptions::default());
+        let stack = mutator.shadow_stack();
+        letroot!(list = stack, None::<Gc<Node, SemiSpace>>);
+        for i in 0..1000 {
+            let node = mutator.allocate(
+                Node {
+                    value: i,
+                    next: *list,
+                },
+                AllocationSpace::New,
+            );
+            *list = Some(node);
+        }
+        let before = list.unwrap().base;
+        mutator.collect(&mut []);
+        assert_ne!(before, list.unwrap().base);
+        let mut cursor = *list;
+        let mut expected = 1000;
+        while let Some(node) = cursor {
+            expected -= 1;
+            assert_eq!(node.value, expected);
+            cursor = node.next;
+        }
+        assert_eq!(expected, 0);
+    }
+
+    #[test]
+    fn collects_when_semispace_is_exhausted() {
+        let mut mutator =
+            instantiate_semispace(SemiSpaceOptions::default().with_semispace_size(256 * 1024));
+        let stack = mutator.shadow_stack();
+        letroot!(list = stack, None::<Gc<Node, SemiSpace>>);
+        for i in 0..100000 {
+            let node = mutator.allocate(
+                Node {
+                    value: i,
+                    next: if i % 100 == 0 { None } else { *list },
+                },
+                AllocationSpace::New,
+            );
+            *list = Some(node);
+        }
+        assert_eq!(list.unwrap().value, 99999);
+        assert!(mutator.heap_ref().total_gcs > 0);
+    }
+
+    #[test]
+    fn weak_refs_are_updated_and_cleared() {
+        let mut mutator = instantiate_semispace(SemiSpaceOptions::default());
+        let stack = mutator.shadow_stack();
+        letroot!(
+            live = stack,
+            mutator.allocate(
+                Node {
+                    value: 42,
+                    next: None
+                },
+                AllocationSpace::New
+            )
+        );
+        let dead = mutator.allocate(
+            Node {
+                value: 0,
+                next: None,
+            },
+            AllocationSpace::New,
+        );
+        letroot!(live_weak = stack, mutator.allocate_weak(*live));
+        letroot!(dead_weak = stack, mutator.allocate_weak(dead));
+        mutator.collect(&mut []);
+        assert_eq!(live_weak.upgrade().map(|node| node.value), Some(42));
+        assert!(live_weak.upgrade().unwrap().base == live.base);
+        assert!(dead_weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn ephemerons_with_dead_keys_are_cleared() {
+        let mut mutator = instantiate_semispace(SemiSpaceOptions::default());
+        let stack = mutator.shadow_stack();
+        letroot!(
+            table = stack,
+            mutator.allocate(Table { entries: vec![] }, AllocationSpace::New)
+        );
+        letroot!(root = stack, node(&mut mutator, 1, None));
+        // key of the first entry is reachable only from value of the second entry
+        let key = node(&mut mutator, 2, None);
+        let value = node(&mut mutator, 20, None);
+        table.entries.push(Ephemeron::new(key, value));
+        let value = node(&mut mutator, 10, Some(key));
+        table.entries.push(Ephemeron::new(*root, value));
+        for i in 0..100 {
+            let key = node(&mut mutator, i, None);
+            let value = node(&mut mutator, i, Some(key));
+            table.entries.push(Ephemeron::new(key, value));
+        }
+
+        mutator.collect(&mut []);
+
+        let first = &table.entries[0];
+        assert_eq!(first.key().unwrap().value, 2);
+        assert_eq!(first.value().unwrap().value, 20);
+        let second = &table.entries[1];
+        assert!(second.key().unwrap().base == root.base);
+        assert_eq!(second.value().unwrap().value, 10);
+        assert!(second.value().unwrap().next.unwrap().base == first.key().unwrap().base);
+        assert!(table.entries[2..]
+            .iter()
+            .all(|entry| entry.key().is_none() && entry.value().is_none()));
+    }
+}
@@ -1,5 +1,1798 @@
+//! # Shenandoah
+//!
+//! Region based concurrent compacting collector inspired by Shenandoah GC from OpenJDK. Heap is divided into
+//! equally sized regions (see [ShenandoahHeapRegion::setup_sizes](region::ShenandoahHeapRegion::setup_sizes)), objects
+//! are bump allocated in regions and each object is prefixed with Brooks forwarding pointer that points to the object
+//! itself or to its to-space copy. Objects larger than [MAX_REGULAR_OBJECT_SIZE](heap::MAX_REGULAR_OBJECT_SIZE) are
+//! humongous: they occupy one or more contiguous regions and are never moved.
+//!
+//! ## GC cycle
+//!
+//! GC cycle is started when [ShenandoahHeuristics::should_start_gc] says so and it consists of these phases:
+//! - Init mark (STW): roots are marked, regions that are pointed to by conservative roots are pinned.
+//! - Concurrent mark: marked objects are traced by background thread. Write barrier re-traces mutated marked objects.
+//! - Final mark (STW): roots are re-marked and marking is finished. Ephemerons are processed: objects that contained
+//!   ephemerons when they were traced concurrently are traced again. Regions without live objects are reclaimed
+//!   immediately, collection set is chosen by heuristics from regions with the most garbage.
+//! - Concurrent cleanup: dead objects are finalized and reclaimed regions are made available for allocation.
+//! - Concurrent evacuation: live objects in collection set are copied to free regions.
+//! - Concurrent update refs: references to from-space copies are updated in the whole heap.
+//! - Final update refs (STW): roots are updated and collection set regions are reclaimed.
+//! - Concurrent cleanup of bitmaps.
+//!
+//! ## Barriers
+//!
+//! Reads go through Brooks pointer, before object is mutated it is resolved with [ReadBarrier::resolve_for_write]
+//! which evacuates object if it is in collection set, so stores always go to to-space copy. Both are done
+//! automatically by [Gc] `Deref` and `DerefMut` implementations. Note that mutating object through shared reference
+//! (e.g `Cell` fields) while evacuation is in progress might be lost, always use `DerefMut` to mutate GC objects.
+//!
+//! You ***must*** invoke [MutatorRef::write_barrier] after storing GC pointer into GC object: while marking it makes
+//! marker trace object again and while references are updated it replaces from-space references in object with
+//! to-space ones.
+//!
+//! ## Conservative roots
+//!
+//! Objects that are referenced from thread stacks can't be moved. Regions that contain such objects are pinned and never
+//! selected to collection set. When collection set region is still referenced from thread stack at the end of cycle it
+//! is retained until stacks no longer point to it.
+//!
+//! ## Degenerated and Full GC
+//!
+//! If mutators run out of memory while cycle is running, the rest of cycle is finished in STW pause (degenerated cycle).
+//! If evacuation runs out of memory, or there were too many degenerated cycles in a row, Full GC is performed: it is
+//! STW sliding mark-compact collection of the whole heap.
+
+pub mod barrier;
 pub mod collection_set;
 pub mod free_set;
 pub mod heap;
 pub mod heuristics;
+pub mod marker;
 pub mod region;
+
+use self::{
+    barrier::{
+        begin_evacuation, end_evacuation, heal_object, write_barrier_impl, ShenandoahBarrier,
+    },
+    heap::{
+        forwardee, is_forwarded, LocalAllocBuffer, ShenandoahHeap, BROOKS_POINTER_SIZE,
+        MAX_REGULAR_OBJECT_SIZE,
+    },
+    heuristics::{ShenandoahAdaptiveHeuristics, ShenandoahHeuristics},
+    marker::{ShenandoahMarker, UpdateRefsVisitor},
+    region::ShenandoahHeapRegion,
+};
+use crate::{
+    api::{vtable_of, Collectable, Gc, HeapObjectHeader, Trace, Visitor, Weak, WeakInner},
+    gc_base::{
+        AllocError, AllocationSpace, GcBase, MarkingConstraint, MarkingConstraintRuns, NoHelp, TLAB,
+    },
+    make_small_type_id,
+    mutator::{JoinData, Mutator, MutatorRef, ThreadState},
+    safepoint::{GlobalSafepoint, SafepointScope},
+    small_type_id,
+    stats::HeapStats,
+    stress::GcStress,
+    utils::{align_usize, formatted_size, stack_bounds::StackBounds},
+};
+use atomic::{Atomic, Ordering};
+use parking_lot::{
+    lock_api::{RawMutex, RawMutexFair},
+    RawMutex as Lock,
+};
+use std::{
+    any::TypeId,
+    cell::UnsafeCell,
+    mem::size_of,
+    ptr::{null_mut, NonNull},
+    sync::{atomic::AtomicUsize, Arc},
+    time::{Duration, Instant},
+};
+
+/// Phase of Shenandoah cycle.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum ShenandoahPhase {
+    Idle,
+    Marking,
+    /// Marking is finished, dead objects are finalized and immediate garbage is reclaimed.
+    CleanupEarly,
+    Evacuation,
+    UpdateRefs,
+    /// Collection set is reclaimed, bitmaps are prepared for the next cycle.
+    Cleanup,
+}
+
+/// Number of objects concurrent marker processes before it lets mutators to take over the cycle.
+const MARKING_BATCH: usize = 512;
+/// Number of regions that are cleaned up in one step of concurrent cleanup.
+const CLEANUP_BATCH: usize = 16;
+
+/// Allocations of this size or larger go to [GcBase::allocate_large].
+pub const LARGE_CUTOFF: usize = 16 * 1024;
+
+/// Shenandoah heap.
+pub struct Shenandoah<Heuristics: ShenandoahHeuristics = ShenandoahAdaptiveHeuristics> {
+    heap: &'static ShenandoahHeap,
+    heuristics: Heuristics,
+    heuristics_lock: Lock,
+    marker: ShenandoahMarker,
+    pub(crate) global_heap_lock: Lock,
+    pub(crate) mutators: Vec<*mut Mutator<Self>>,
+    pub(crate) safepoint: GlobalSafepoint,
+    pub(crate) verbose: u8,
+    /// Serializes GC work between background collector thread and mutators that finish GC cycle in STW pause.
+    collector_lock: Lock,
+    phase: Atomic<ShenandoahPhase>,
+    /// Incremented at the start of each GC cycle, background thread exits once the cycle it was started for is over.
+    epoch: AtomicUsize,
+    background_threads: AtomicUsize,
+    /// Next region or collection set entry processed by concurrent phase.
+    cursor: usize,
+    /// Allocation buffer used by GC to evacuate objects.
+    gclab: LocalAllocBuffer,
+    cycle_start: Option<Instant>,
+    cycle_start_bytes: usize,
+    /// Set when marking of running cycle is finished.
+    mark_end: Option<Instant>,
+    total_gcs: usize,
+    weak_refs: Vec<Weak<dyn Collectable, Self>>,
+    constraints: Vec<Box<dyn MarkingConstraint>>,
+    finalize_list: Vec<*mut HeapObjectHeader>,
+    finalize_lock: Lock,
+    full_gc_threshold: u32,
+    /// Set while GC cycle forced by stress mode is running, every region with live objects is evacuated.
+    stress: bool,
+    stats: HeapStats,
+}
+
+pub struct ShenandoahOptions {
+    /// Size of the heap. Set to 256MB by default
+    pub heap_size: usize,
+    /// Minimal region size. By default set to [ShenandoahHeapRegion::MIN_REGION_SIZE]
+    pub min_region_size: Option<usize>,
+    /// Maximal region size. By default set to [ShenandoahHeapRegion::MAX_REGION_SIZE]
+    pub max_region_size: Option<usize>,
+    /// Number of regions heap is divided to if region size constraints allow it. By default set to 2048
+    pub target_num_regions: Option<usize>,
+    /// Fraction of heap that is reserved for evacuation. By default set to 0.05
+    pub evac_reserve: f64,
+    /// GC cycle is always started when free heap is below this fraction of heap. By default set to 0.1
+    pub min_free_threshold: f64,
+    /// GC cycle is started when free heap is below this fraction of heap while heuristics learn GC cycle times.
+    /// By default set to 0.7
+    pub init_free_threshold: f64,
+    /// Regions with more garbage than this fraction of region size are added to collection set. By default set to 0.25
+    pub garbage_threshold: f64,
+    /// Number of GC cycles heuristics use to learn GC cycle times. By default set to 5
+    pub learning_steps: usize,
+    /// Number of degenerated cycles in a row after which Full GC is performed. By default set to 3
+    pub full_gc_threshold: u32,
+    /// Enables verbose loggig to stdout.
+    pub verbose: u8,
+    /// GC stress mode, see [stress](crate::stress). Overridden by `COMET_GC_STRESS` environment variable. Disabled
+    /// by default.
+    pub stress: GcStress,
+}
+
+impl ShenandoahOptions {
+    pub fn with_heap_size(mut self, x: usize) -> Self {
+        self.heap_size = x;
+        self
+    }
+
+    pub fn with_min_region_size(mut self, x: usize) -> Self {
+        self.min_region_size = Some(x);
+        self
+    }
+
+    pub fn with_max_region_size(mut self, x: usize) -> Self {
+        self.max_region_size = Some(x);
+        self
+    }
+
+    pub fn with_target_num_regions(mut self, x: usize) -> Self {
+        self.target_num_regions = Some(x);
+        self
+    }
+    /// Set evacuation reserve. Panics if x is not in `(0, 1)` range.
+    pub fn with_evac_reserve(mut self, x: f64) -> Self {
+        if x <= 0.0 || x >= 1.0 {
+            panic!("Evacuation reserve must be in (0, 1) range");
+        }
+        self.evac_reserve = x;
+        self
+    }
+    /// Set minimal free threshold. Panics if x is not in `[0, 1]` range.
+    pub fn with_min_free_threshold(mut self, x: f64) -> Self {
+        if !(0.0..=1.0).contains(&x) {
+            panic!("Min free threshold must be in [0, 1] range");
+        }
+        self.min_free_threshold = x;
+        self
+    }
+    /// Set initial free threshold. Panics if x is not in `[0, 1]` range.
+    pub fn with_init_free_threshold(mut self, x: f64) -> Self {
+        if !(0.0..=1.0).contains(&x) {
+            panic!("Init free threshold must be in [0, 1] range");
+        }
+        self.init_free_threshold = x;
+        self
+    }
+    /// Set garbage threshold. Panics if x is not in `[0, 1]` range.
+    pub fn with_garbage_threshold(mut self, x: f64) -> Self {
+        if !(0.0..=1.0).contains(&x) {
+            panic!("Garbage threshold must be in [0, 1] range");
+        }
+        self.garbage_threshold = x;
+        self
+    }
+
+    pub fn with_learning_steps(mut self, x: usize) -> Self {
+        self.learning_steps = x;
+        self
+    }
+
+    pub fn with_full_gc_threshold(mut self, x: u32) -> Self {
+        self.full_gc_threshold = x;
+        self
+    }
+
+    pub fn with_verbose(mut self, x: u8) -> Self {
+        self.verbose = x;
+        self
+    }
+
+    /// Set GC stress mode.
+    pub fn with_stress(mut self, x: GcStress) -> Self {
+        self.stress = x;
+        self
+    }
+}
+
+impl Default for ShenandoahOptions {
+    fn default() -> Self {
+        Self {
+            heap_size: 256 * 1024 * 1024,
+            min_region_size: None,
+            max_region_size: None,
+            target_num_regions: None,
+            evac_reserve: 0.05,
+            min_free_threshold: 0.1,
+            init_free_threshold: 0.7,
+            garbage_threshold: 0.25,
+            learning_steps: 5,
+            full_gc_threshold: 3,
+            verbose: 0,
+            stress: GcStress::Disabled,
+        }
+    }
+}
+
+pub fn instantiate_shenandoah<Heuristics: ShenandoahHeuristics>(
+    options: ShenandoahOptions,
+) -> MutatorRef<Shenandoah<Heuristics>> {
+    let sizes = ShenandoahHeapRegion::setup_sizes(
+        options.heap_size,
+        options.min_region_size,
+        options.target_num_regions,
+        options.max_region_size,
+    );
+    if options.verbose > 1 {
+        eprintln!("{:?}", sizes);
+    }
+    let heap: &'static ShenandoahHeap = Box::leak(Box::new(ShenandoahHeap::new(
+        sizes,
+        options.evac_reserve,
+        options.verbose,
+    )));
+    let gc = Arc::new(UnsafeCell::new(Shenandoah::<Heuristics> {
+        heap,
+        heuristics: Heuristics::new(&options, heap.capacity(), heap.region_size()),
+        heuristics_lock: Lock::INIT,
+        marker: ShenandoahMarker::new(heap),
+        global_heap_lock: Lock::INIT,
+        mutators: vec![],
+        safepoint: GlobalSafepoint::new(),
+        verbose: options.verbose,
+        collector_lock: Lock::INIT,
+        phase: Atomic::new(ShenandoahPhase::Idle),
+        epoch: AtomicUsize::new(0),
+        background_threads: AtomicUsize::new(0),
+        cursor: 0,
+        gclab: LocalAllocBuffer::new(),
+        cycle_start: None,
+        cycle_start_bytes: 0,
+        mark_end: None,
+        total_gcs: 0,
+        weak_refs: vec![],
+        constraints: vec![],
+        finalize_list: vec![],
+        finalize_lock: Lock::INIT,
+        full_gc_threshold: options.full_gc_threshold,
+        stress: false,
+        stats: HeapStats::default(),
+    }));
+    let href = unsafe { &mut *gc.get() };
+    let join_data = JoinData::new();
+    let mut mutator = MutatorRef::new(Mutator::new(
+        gc.clone(),
+        &href.safepoint,
+        join_data.internal.clone(),
+    ));
+    href.mutators.push(&mut *mutator);
+    href.safepoint
+        .n_mutators
+        .fetch_add(1, atomic::Ordering::Relaxed);
+    mutator.stack_bounds = StackBounds::current_thread_stack_bounds();
+    mutator.state_set(ThreadState::Safe, ThreadState::Unsafe);
+
+    mutator.set_gc_stress(GcStress::from_env_or(options.stress));
+    mutator
+}
+
+/// Thread local allocation buffer of Shenandoah mutator.
+pub struct ShenandoahTlab {
+    lab: LocalAllocBuffer,
+    heap: &'static ShenandoahHeap,
+}
+
+impl<Heuristics: ShenandoahHeuristics> TLAB<Shenandoah<Heuristics>> for ShenandoahTlab {
+    fn can_thread_local_allocate(&self, size: usize) -> bool {
+        size < LARGE_CUTOFF
+    }
+
+    fn refill(
+        &mut self,
+        _mutator: &MutatorRef<Shenandoah<Heuristics>>,
+        _alloc_size: usize,
+    ) -> bool {
+        false
+    }
+    fn allocate<T: Collectable + 'static>(
+        &mut self,
+        _value: T,
+    ) -> Result<Gc<T, Shenandoah<Heuristics>>, T> {
+        unreachable!()
+    }
+    fn reset(&mut self) {
+        self.heap.retire_lab(&mut self.lab, false);
+    }
+    fn create(heap: Arc<UnsafeCell<Shenandoah<Heuristics>>>) -> Self {
+        Self {
+            lab: LocalAllocBuffer::new(),
+            heap: unsafe { (*heap.get()).heap },
+        }
+    }
+}
+
+/// Visitor that replaces references with forwarding pointers computed by Full GC.
+struct ForwardingVisitor;
+
+impl Visitor for ForwardingVisitor {
+    fn mark_object(&mut self, root: &mut NonNull<HeapObjectHeader>) {
+        unsafe {
+            *root = NonNull::new_unchecked(forwardee(root.as_ptr()));
+        }
+    }
+}
+
+impl<Heuristics: ShenandoahHeuristics> Shenandoah<Heuristics> {
+    /// Current phase of GC cycle.
+    pub fn phase(&self) -> ShenandoahPhase {
+        self.phase.load(Ordering::Acquire)
+    }
+
+    pub fn heap(&self) -> &'static ShenandoahHeap {
+        self.heap
+    }
+
+    pub fn heuristics(&self) -> &Heuristics {
+        &self.heuristics
+    }
+
+    /// Number of finished GC cycles.
+    pub fn total_gcs(&self) -> usize {
+        self.total_gcs
+    }
+
+    fn set_phase(&self, phase: ShenandoahPhase) {
+        self.phase.store(phase, Ordering::Release);
+    }
+
+    unsafe fn run_constraints(&mut self, runs_at: MarkingConstraintRuns) {
+        let mut visitor = self.marker.visitor();
+        self.constraints.retain_mut(|constraint| {
+            if constraint.is_over() {
+                false
+            } else {
+                if constraint.runs_at() == runs_at {
+                    constraint.run(&mut visitor);
+                }
+                true
+            }
+        });
+    }
+
+    /// Visit conservative roots of all mutators: their stacks and saved registers.
+    unsafe fn walk_stacks(&self, mut f: impl FnMut(*mut HeapObjectHeader)) {
+        for i in 0..self.mutators.len() {
+            let mutator = self.mutators[i];
+            let stack = (
+                (*mutator).stack_bounds.origin.cast::<*mut u8>(),
+                (*mutator).last_sp.get(),
+            );
+            for (mut start, mut end) in [stack, (*mutator).saved_registers()] {
+                if end < start {
+                    std::mem::swap(&mut start, &mut end);
+                }
+                let mut cursor = start;
+                while cursor < end {
+                    let pointer = cursor.read();
+                    cursor = cursor.add(1);
+                    let object = self.heap.find_object(pointer);
+                    if !object.is_null() {
+                        if self.verbose > 2 {
+                            eprintln!(
+                                "[GC] Found Shenandoah object {:p} at {:p}",
+                                object,
+                                cursor.sub(1)
+                            );
+                        }
+                        f(object);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Mark all objects that are reachable from roots. Regions pointed to by conservative roots are pinned. Must be
+    /// invoked only in STW pause.
+    unsafe fn mark_roots(&mut self, keep: &mut [&mut dyn Trace]) {
+        let heap = self.heap;
+        let marker = &self.marker;
+        self.walk_stacks(|object| {
+            heap.region_of(object.cast()).set_pinned(true);
+            let resolved = forwardee(object);
+            if resolved != object {
+                // from-space copy is referenced from stack: it must stay in place and its copy must not move
+                // so that reads through forwarding pointer do not observe stale data.
+                marker.mark_stale(object);
+                heap.region_of(resolved.cast()).set_pinned(true);
+            }
+            marker.mark(resolved);
+        });
+        let mut visitor = self.marker.visitor();
+        for i in 0..self.mutators.len() {
+            let mutator = self.mutators[i];
+            (*mutator).shadow_stack().walk(|entry| {
+                entry.trace(&mut visitor);
+            });
+        }
+        for object in keep {
+            object.trace(&mut visitor);
+        }
+    }
+
+    /// Update roots to point to to-space copies. Must be invoked only in STW pause.
+    unsafe fn update_roots(&mut self, keep: &mut [&mut dyn Trace], visitor: &mut dyn Visitor) {
+        for i in 0..self.mutators.len() {
+            let mutator = self.mutators[i];
+            (*mutator).shadow_stack().walk(|entry| {
+                entry.trace(visitor);
+            });
+        }
+        for object in keep {
+            object.trace(visitor);
+        }
+        self.constraints.retain_mut(|constraint| {
+            if constraint.is_over() {
+                false
+            } else {
+                constraint.run(visitor);
+                true
+            }
+        });
+        for weak in self.weak_refs.iter_mut() {
+            // weak referent is not traced by `WeakInner`, update it in place without going through barriers.
+            let inner = weak.base();
+            let data = (*inner).data() as *mut WeakInner<Self>;
+            if let Some(value) = (*data).value.as_mut() {
+                visitor.mark_object(&mut value.base);
+            }
+            let mut base = NonNull::new_unchecked(inner);
+            visitor.mark_object(&mut base);
+            weak.set_base(base.as_ptr());
+        }
+        self.finalize_lock.lock();
+        for object in self.finalize_list.iter_mut() {
+            *object = forwardee(*object);
+        }
+        self.finalize_lock.unlock();
+    }
+
+    /// Clear weak references to objects that were not marked. Must be invoked only in STW pause after marking.
+    unsafe fn process_weak_refs(&mut self) {
+        let heap = self.heap;
+        self.weak_refs.retain_mut(|weak| {
+            let inner = forwardee(weak.base());
+            if !heap.is_marked(inner) {
+                return false;
+            }
+            weak.set_base(inner);
+            weak.after_mark(|referent| {
+                let referent = forwardee(referent);
+                if heap.is_marked(referent) {
+                    referent
+                } else {
+                    null_mut()
+                }
+            });
+            true
+        });
+    }
+
+    /// Finalize objects that did not survive marking. When `in_cycle` is true only objects below update watermark
+    /// are considered, objects above it were allocated after final mark.
+    unsafe fn process_finalizers(&mut self, in_cycle: bool) {
+        let heap = self.heap;
+        self.finalize_lock.lock();
+        self.finalize_list.retain_mut(|object| {
+            *object = forwardee(*object);
+            let region = heap.region_of(object.cast());
+            if (in_cycle && *object as *mut u8 >= region.update_watermark())
+                || heap.is_marked(*object)
+            {
+                true
+            } else {
+                (**object).get_dyn().finalize();
+                false
+            }
+        });
+        self.finalize_lock.unlock();
+    }
+
+    /// Trash humongous region `index` and its continuations.
+    unsafe fn trash_humongous(&self, index: usize) -> usize {
+        let mut reclaimed = 0;
+        let mut current = index;
+        loop {
+            let region = self.heap.region(current);
+            reclaimed += region.used();
+            region.make_trash();
+            current += 1;
+            if current == self.heap.num_regions()
+                || !self.heap.region(current).is_humongous_continuation()
+            {
+                break;
+            }
+        }
+        reclaimed
+    }
+
+    /// Trash regions that do not contain live objects. Must be invoked in STW pause after marking.
+    unsafe fn trash_immediate_garbage(&self) -> usize {
+        let heap = self.heap;
+        let mut reclaimed = 0;
+        for index in 0..heap.num_regions() {
+            let region = heap.region(index);
+            if region.is_pinned() {
+                continue;
+            }
+            if (region.is_regular() && region.live_data() == 0) || region.is_retained() {
+                reclaimed += region.used();
+                region.make_trash();
+            } else if region.is_humongous_start()
+                && !heap.is_marked(region.bottom().add(BROOKS_POINTER_SIZE).cast())
+            {
+                reclaimed += self.trash_humongous(index);
+            }
+        }
+        reclaimed
+    }
+
+    /// Start GC cycle. Must be invoked only in STW pause.
+    unsafe fn init_mark(&mut self, keep: &mut [&mut dyn Trace]) {
+        self.epoch.fetch_add(1, Ordering::AcqRel);
+        self.cycle_start = Some(Instant::now());
+        self.cycle_start_bytes = self.heap.used();
+        self.heuristics_lock.lock();
+        self.heuristics.record_cycle_start();
+        self.heuristics_lock.unlock();
+        self.heap.reset_bytes_allocated_since_gc();
+        for index in 0..self.heap.num_regions() {
+            let region = self.heap.region(index);
+            region.clear_live_data();
+            region.set_pinned(false);
+        }
+        self.marker.set_marking(true);
+        self.mark_roots(keep);
+        self.run_constraints(MarkingConstraintRuns::BeforeMark);
+        self.set_phase(ShenandoahPhase::Marking);
+    }
+
+    /// Finish marking and choose collection set. Must be invoked only in STW pause.
+    unsafe fn final_mark(&mut self, keep: &mut [&mut dyn Trace]) {
+        let heap = self.heap;
+        for i in 0..self.mutators.len() {
+            // allocation buffers must not point into regions that are reclaimed or evacuated.
+            (*self.mutators[i]).reset_tlab();
+        }
+        self.marker.set_collect_ephemerons(true);
+        self.mark_roots(keep);
+        self.marker.drain(usize::MAX);
+        self.run_constraints(MarkingConstraintRuns::AfterMark);
+        self.marker.drain(usize::MAX);
+        self.marker.process_ephemerons();
+        self.marker.set_collect_ephemerons(false);
+        self.marker.set_marking(false);
+        self.process_weak_refs();
+        self.mark_end = Some(Instant::now());
+
+        for index in 0..heap.num_regions() {
+            let region = heap.region(index);
+            region.set_update_watermark(region.top());
+        }
+        let reclaimed = self.trash_immediate_garbage();
+        let cset = heap.collection_set();
+        cset.clear();
+        let free = heap.reserve_regions() * heap.region_size();
+        self.heuristics_lock.lock();
+        if self.stress {
+            self.heuristics
+                .choose_stress_collection_set(heap, cset, free);
+        } else {
+            self.heuristics.choose_collection_set(heap, cset, free);
+        }
+        self.heuristics_lock.unlock();
+        for &index in cset.regions() {
+            heap.region(index).make_cset();
+        }
+        if self.verbose > 0 {
+            eprintln!(
+                "[gc] GC({}) Immediate garbage {}, collection set {} regions with {} live and {} garbage",
+                self.total_gcs,
+                formatted_size(reclaimed),
+                cset.count(),
+                formatted_size(cset.live()),
+                formatted_size(cset.garbage())
+            );
+        }
+        heap.rebuild_free_set();
+        if !cset.is_empty() {
+            heap.set_has_forwarded(true);
+            begin_evacuation(heap);
+        }
+        self.set_phase(ShenandoahPhase::CleanupEarly);
+    }
+
+    /// Finalize dead objects and reclaim immediate garbage.
+    unsafe fn cleanup_early(&mut self) {
+        let heap = self.heap;
+        self.process_finalizers(true);
+        heap.recycle_trash();
+        heap.rebuild_free_set();
+        self.cursor = 0;
+        if heap.collection_set().is_empty() {
+            self.set_phase(ShenandoahPhase::Cleanup);
+        } else {
+            self.set_phase(ShenandoahPhase::Evacuation);
+        }
+    }
+
+    /// Evacuate next collection set region. Returns `false` when evacuation is finished.
+    unsafe fn evacuate_step(&mut self) -> bool {
+        let heap = self.heap;
+        let cset = heap.collection_set();
+        if self.cursor < cset.count() && !heap.is_evac_oom() {
+            heap.evacuate_region(cset.regions()[self.cursor], &mut self.gclab);
+            self.cursor += 1;
+            return true;
+        }
+        heap.retire_lab(&mut self.gclab, true);
+        end_evacuation(heap);
+        self.cursor = 0;
+        if !heap.is_evac_oom() {
+            self.set_phase(ShenandoahPhase::UpdateRefs);
+        }
+        false
+    }
+
+    /// Update references in next region. Returns `false` when all regions are processed.
+    unsafe fn update_refs_step(&mut self) -> bool {
+        let heap = self.heap;
+        while self.cursor < heap.num_regions() {
+            let index = self.cursor;
+            self.cursor += 1;
+            let region = heap.region(index);
+            if region.is_cset() || region.is_retained() {
+                continue;
+            }
+            heap.update_region_refs(index, &mut UpdateRefsVisitor::new(heap, None));
+            return true;
+        }
+        false
+    }
+
+    /// Update roots and reclaim collection set. Must be invoked only in STW pause.
+    unsafe fn final_update_refs(&mut self, keep: &mut [&mut dyn Trace]) {
+        let heap = self.heap;
+        for i in 0..self.mutators.len() {
+            (*self.mutators[i]).reset_tlab();
+        }
+        self.update_roots(keep, &mut UpdateRefsVisitor::new(heap, None));
+        // stacks might still point to from-space copies, such regions are retained until stacks no longer reference them.
+        self.walk_stacks(|object| {
+            let region = heap.region_of(object.cast());
+            if region.is_cset() {
+                region.set_pinned(true);
+            }
+        });
+        let cset = heap.collection_set();
+        let mut retained = 0;
+        for &index in cset.regions() {
+            let region = heap.region(index);
+            if region.is_pinned() {
+                region.make_retained();
+                retained += 1;
+            } else {
+                region.make_trash();
+            }
+        }
+        if self.verbose > 0 && retained != 0 {
+            eprintln!(
+                "[gc] GC({}) Retained {} collection set regions referenced from stack",
+                self.total_gcs, retained
+            );
+        }
+        cset.clear();
+        heap.recycle_trash();
+        heap.set_has_forwarded(false);
+        heap.rebuild_free_set();
+        self.cursor = 0;
+        self.set_phase(ShenandoahPhase::Cleanup);
+    }
+
+    /// Clean bitmaps of next batch of regions. Returns `false` when all regions are processed.
+    unsafe fn cleanup_step(&mut self) -> bool {
+        let heap = self.heap;
+        let end = (self.cursor + CLEANUP_BATCH).min(heap.num_regions());
+        for index in self.cursor..end {
+            heap.clean_live_bitmap(index);
+            heap.clear_mark_bitmap(index);
+        }
+        self.cursor = end;
+        self.cursor < heap.num_regions()
+    }
+
+    fn finish_cycle(&mut self, kind: &str) {
+        let bytes = self.heap.used();
+        let now = Instant::now();
+        let mark_end = self.mark_end.take().unwrap_or(now);
+        let mark = self.cycle_start.map_or(Duration::ZERO, |start| {
+            mark_end.saturating_duration_since(start)
+        });
+        self.stats
+            .record_cycle(false, mark, now - mark_end, self.cycle_start_bytes, bytes);
+        if self.verbose > 0 {
+            eprintln!(
+                "[gc] GC({}) {} Shenandoah cycle {}->{}({}) {:.4}ms",
+                self.total_gcs,
+                kind,
+                formatted_size(self.cycle_start_bytes),
+                formatted_size(bytes),
+                formatted_size(self.heap.capacity()),
+                self.cycle_start
+                    .map(|start| start.elapsed().as_micros() as f64 / 1000.0)
+                    .unwrap_or(0.0)
+            );
+        }
+        self.total_gcs += 1;
+        self.cursor = 0;
+        self.set_phase(ShenandoahPhase::Idle);
+    }
+
+    /// Execute all remaining phases of GC cycle or entire GC cycle if no cycle is running. Returns `false` if evacuation
+    /// failed and Full GC is required. Must be invoked only in STW pause with collector lock held.
+    unsafe fn complete_cycle(&mut self, keep: &mut [&mut dyn Trace]) -> bool {
+        if self.phase() == ShenandoahPhase::Idle {
+            // whole heap is traced in this pause.
+            self.safepoint.next_epoch();
+            self.init_mark(keep);
+        }
+        if self.phase() == ShenandoahPhase::Marking {
+            self.marker.drain(usize::MAX);
+            self.final_mark(keep);
+        }
+        if self.phase() == ShenandoahPhase::CleanupEarly {
+            self.cleanup_early();
+        }
+        if self.phase() == ShenandoahPhase::Evacuation {
+            while self.evacuate_step() {}
+            if self.heap.is_evac_oom() {
+                return false;
+            }
+        }
+        if self.phase() == ShenandoahPhase::UpdateRefs {
+            while self.update_refs_step() {}
+            self.final_update_refs(keep);
+        }
+        if self.phase() == ShenandoahPhase::Cleanup {
+            while self.cleanup_step() {}
+        }
+        true
+    }
+
+    /// Finish GC cycle in STW pause. Must be invoked only in STW pause with collector lock held.
+    unsafe fn degenerated_gc(&mut self, keep: &mut [&mut dyn Trace]) {
+        if self.heuristics.degenerated_cycles_in_a_row() >= self.full_gc_threshold {
+            self.full_gc(keep, "Full (upgraded)");
+            return;
+        }
+        let pause = Instant::now();
+        let from = self.phase();
+        if !self.complete_cycle(keep) {
+            self.full_gc(keep, "Full (evacuation failure)");
+            return;
+        }
+        self.heuristics_lock.lock();
+        self.heuristics.record_success_degenerated();
+        self.heuristics_lock.unlock();
+        if self.verbose > 0 {
+            eprintln!(
+                "[gc] GC({}) Pause Degenerated GC ({:?}) {:.4}ms",
+                self.total_gcs,
+                from,
+                pause.elapsed().as_micros() as f64 / 1000.0
+            );
+        }
+        self.finish_cycle("Degenerated");
+    }
+
+    /// STW cycle forced by stress mode. Running cycle is finished first so that the whole heap is marked and evacuated
+    /// by the stress cycle. Must be invoked only in STW pause with collector lock held.
+    unsafe fn stress_gc(&mut self, keep: &mut [&mut dyn Trace]) {
+        if self.phase() != ShenandoahPhase::Idle {
+            self.degenerated_gc(keep);
+        }
+        let pause = Instant::now();
+        self.stress = true;
+        let evacuated = self.complete_cycle(keep);
+        self.stress = false;
+        if !evacuated {
+            self.full_gc(keep, "Full (evacuation failure)");
+            return;
+        }
+        if self.verbose > 0 {
+            eprintln!(
+                "[gc] GC({}) Pause Stress GC {:.4}ms",
+                self.total_gcs,
+                pause.elapsed().as_micros() as f64 / 1000.0
+            );
+        }
+        self.finish_cycle("Stress");
+    }
+
+    /// Abandon running GC cycle so Full GC can start from scratch. Must be invoked only in STW pause.
+    unsafe fn abandon_cycle(&mut self) {
+        let heap = self.heap;
+        self.marker.set_marking(false);
+        while self.marker.marking_worklists().pop().is_some() {}
+        self.marker.clear_ephemerons();
+        heap.retire_lab(&mut self.gclab, true);
+        end_evacuation(heap);
+        let cset = heap.collection_set();
+        for &index in cset.regions() {
+            // collection set might contain from-space copies, Full GC resolves references to them.
+            heap.region(index).make_regular_bypass();
+        }
+        cset.clear();
+        heap.clear_evac_oom();
+    }
+
+    /// Sliding mark-compact collection of the whole heap. Must be invoked only in STW pause with collector lock held.
+    unsafe fn full_gc(&mut self, keep: &mut [&mut dyn Trace], kind: &str) {
+        let heap = self.heap;
+        let pause = Instant::now();
+        if self.phase() == ShenandoahPhase::Idle {
+            self.cycle_start = Some(pause);
+            self.cycle_start_bytes = heap.used();
+            self.heuristics_lock.lock();
+            self.heuristics.record_cycle_start();
+            self.heuristics_lock.unlock();
+        }
+        self.abandon_cycle();
+        // stop background thread, it must not continue abandoned cycle.
+        self.epoch.fetch_add(1, Ordering::AcqRel);
+        self.safepoint.next_epoch();
+        for i in 0..self.mutators.len() {
+            (*self.mutators[i]).reset_tlab();
+        }
+
+        // 1. Mark live objects.
+        for index in 0..heap.num_regions() {
+            let region = heap.region(index);
+            heap.clear_mark_bitmap(index);
+            region.clear_live_data();
+            region.set_pinned(false);
+        }
+        self.marker.set_collect_ephemerons(true);
+        self.mark_roots(keep);
+        self.run_constraints(MarkingConstraintRuns::BeforeMark);
+        self.marker.drain(usize::MAX);
+        self.run_constraints(MarkingConstraintRuns::AfterMark);
+        self.marker.drain(usize::MAX);
+        self.marker.process_ephemerons();
+        self.marker.set_collect_ephemerons(false);
+        self.process_weak_refs();
+        self.process_finalizers(false);
+        self.mark_end = Some(Instant::now());
+        self.trash_immediate_garbage();
+        heap.recycle_trash();
+
+        // 2. Compute new locations of objects in compactable regions and store them in forwarding pointers.
+        let compactable = |region: &ShenandoahHeapRegion| {
+            (region.is_regular() && !region.is_pinned()) || region.is_empty()
+        };
+        let regions = (0..heap.num_regions())
+            .filter(|&index| compactable(heap.region(index)))
+            .collect::<Vec<_>>();
+        let mut new_tops = regions
+            .iter()
+            .map(|&index| heap.region(index).bottom())
+            .collect::<Vec<_>>();
+        let mut to = 0;
+        for &index in regions.iter() {
+            let region = heap.region(index);
+            heap.mark_bitmap()
+                .visit_marked_range(region.bottom(), region.top(), |object| {
+                    let size = (*object).size() + BROOKS_POINTER_SIZE;
+                    while new_tops[to] as usize + size > heap.region(regions[to]).end() as usize {
+                        to += 1;
+                    }
+                    let new_object = new_tops[to].add(BROOKS_POINTER_SIZE);
+                    new_tops[to] = new_tops[to].add(size);
+                    (*heap::brooks_pointer(object)).store(new_object as usize, Ordering::Relaxed);
+                });
+        }
+
+        // 3. Update references to point to new locations.
+        for index in 0..heap.num_regions() {
+            let region = heap.region(index);
+            if !region.is_active() {
+                continue;
+            }
+            let moving = compactable(region);
+            heap.mark_bitmap()
+                .visit_marked_range(region.bottom(), region.top(), |object| {
+                    // objects outside of compactable regions are forwarded only if they are stale from-space copies.
+                    if moving || !is_forwarded(object) {
+                        (*object).get_dyn().trace(&mut ForwardingVisitor);
+                    }
+                });
+        }
+        self.update_roots(keep, &mut ForwardingVisitor);
+
+        // 4. Move objects.
+        for &index in regions.iter() {
+            let region = heap.region(index);
+            heap.live_bitmap()
+                .clear_range(region.bottom(), region.end());
+        }
+        for &index in regions.iter() {
+            let region = heap.region(index);
+            heap.mark_bitmap()
+                .visit_marked_range(region.bottom(), region.top(), |object| {
+                    let size = (*object).size() + BROOKS_POINTER_SIZE;
+                    let new_object = forwardee(object);
+                    if new_object != object {
+                        std::ptr::copy(
+                            object.cast::<u8>().sub(BROOKS_POINTER_SIZE),
+                            new_object.cast::<u8>().sub(BROOKS_POINTER_SIZE),
+                            size,
+                        );
+                        (*heap::brooks_pointer(new_object))
+                            .store(new_object as usize, Ordering::Relaxed);
+                    }
+                    heap.live_bitmap().set(new_object.cast());
+                });
+        }
+        for (i, &index) in regions.iter().enumerate() {
+            let region = heap.region(index);
+            heap.clear_mark_bitmap(index);
+            if new_tops[i] == region.bottom() {
+                if region.is_regular() {
+                    region.make_trash();
+                    heap.recycle_region(index);
+                }
+            } else {
+                if region.is_empty() {
+                    region.make_regular_allocation();
+                }
+                region.set_top(new_tops[i]);
+                region.clear_live_data();
+                region.increase_live_data(region.used());
+            }
+            region.set_update_watermark(region.top());
+        }
+
+        // 5. Prepare pinned and humongous regions for the next cycle.
+        for index in 0..heap.num_regions() {
+            let region = heap.region(index);
+            if region.is_active() && !compactable(region) {
+                region.set_update_watermark(region.top());
+                heap.clean_live_bitmap(index);
+                heap.clear_mark_bitmap(index);
+            }
+        }
+        heap.set_has_forwarded(false);
+        heap.rebuild_free_set();
+        self.heuristics_lock.lock();
+        self.heuristics.record_success_full();
+        self.heuristics_lock.unlock();
+        if self.verbose > 0 {
+            eprintln!(
+                "[gc] GC({}) Pause {} {:.4}ms",
+                self.total_gcs,
+                kind,
+                pause.elapsed().as_micros() as f64 / 1000.0
+            );
+        }
+        self.finish_cycle(kind);
+    }
+
+    /// Stop the world and perform `cycle` with global heap lock and collector lock held. Returns `false` if some other
+    /// thread was stopping the world and `cycle` was not performed.
+    fn collect_with(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        cycle: unsafe fn(&mut Self, &mut [&mut dyn Trace]),
+        keep: &mut [&mut dyn Trace],
+    ) -> bool {
+        match SafepointScope::new(mutator.clone()) {
+            Some(safepoint) => unsafe {
+                mutator.save_conservative_roots();
+                let pause = Instant::now();
+                self.global_heap_lock.lock();
+                self.collector_lock.lock();
+                cycle(self, keep);
+                self.stats
+                    .record_pause(pause.elapsed(), safepoint.time_to_safepoint());
+                self.collector_lock.unlock();
+                drop(safepoint);
+                self.global_heap_lock.unlock();
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Perform initial marking and start background collector thread.
+    fn start_concurrent_cycle(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        keep: &mut [&mut dyn Trace],
+    ) {
+        match SafepointScope::new(mutator.clone()) {
+            Some(safepoint) => unsafe {
+                mutator.save_conservative_roots();
+                let pause = Instant::now();
+                self.global_heap_lock.lock();
+                self.collector_lock.lock();
+                if self.phase() == ShenandoahPhase::Idle {
+                    self.init_mark(keep);
+                    self.spawn_collector();
+                    if self.verbose > 0 {
+                        eprintln!(
+                            "[gc] GC({}) Pause Init Mark {:.4}ms",
+                            self.total_gcs,
+                            pause.elapsed().as_micros() as f64 / 1000.0
+                        );
+                    }
+                }
+                self.stats
+                    .record_pause(pause.elapsed(), safepoint.time_to_safepoint());
+                self.collector_lock.unlock();
+                drop(safepoint);
+                self.global_heap_lock.unlock();
+            },
+            None => (),
+        }
+    }
+
+    fn spawn_collector(&mut self) {
+        let epoch = self.epoch.load(Ordering::Acquire);
+        self.background_threads.fetch_add(1, Ordering::AcqRel);
+        let heap = self as *mut Self as usize;
+        std::thread::Builder::new()
+            .name("comet-shenandoah".to_string())
+            .spawn(move || unsafe {
+                let heap = heap as *mut Self;
+                (*heap).concurrent_cycle(epoch);
+                (*heap).background_threads.fetch_sub(1, Ordering::AcqRel);
+            })
+            .expect("failed to spawn Shenandoah collector thread");
+    }
+
+    /// Returns `true` if GC cycle `epoch` is still in `phase` i.e it was not completed by mutator in STW pause.
+    fn is_current(&self, epoch: usize, phase: ShenandoahPhase) -> bool {
+        self.epoch.load(Ordering::Acquire) == epoch && self.phase() == phase
+    }
+
+    /// Concurrent part of GC cycle that is executed by background collector thread.
+    unsafe fn concurrent_cycle(&mut self, epoch: usize) {
+        let mut time = Instant::now();
+        loop {
+            self.collector_lock.lock();
+            if self.epoch.load(Ordering::Acquire) != epoch {
+                self.collector_lock.unlock_fair();
+                return;
+            }
+            let phase = self.phase();
+            let pause = match phase {
+                ShenandoahPhase::Idle => {
+                    self.collector_lock.unlock_fair();
+                    return;
+                }
+                ShenandoahPhase::Marking => self.marker.drain(MARKING_BATCH),
+                ShenandoahPhase::CleanupEarly => {
+                    self.cleanup_early();
+                    false
+                }
+                ShenandoahPhase::Evacuation => !self.evacuate_step() && self.heap.is_evac_oom(),
+                ShenandoahPhase::UpdateRefs => !self.update_refs_step(),
+                ShenandoahPhase::Cleanup => {
+                    if !self.cleanup_step() {
+                        self.heuristics_lock.lock();
+                        self.heuristics.record_success_concurrent();
+                        self.heuristics_lock.unlock();
+                        self.finish_cycle("Concurrent");
+                        self.collector_lock.unlock();
+                        return;
+                    }
+                    false
+                }
+            };
+            self.collector_lock.unlock_fair();
+            if self.phase() != phase || pause {
+                if self.verbose > 0 {
+                    eprintln!(
+                        "[gc] GC({}) Concurrent {:?} {:.4}ms",
+                        self.total_gcs,
+                        phase,
+                        time.elapsed().as_micros() as f64 / 1000.0
+                    );
+                }
+                time = Instant::now();
+            }
+            if pause {
+                self.pause(epoch, phase);
+            }
+        }
+    }
+
+    /// Execute STW pause that follows concurrent `phase`.
+    unsafe fn pause(&mut self, epoch: usize, phase: ShenandoahPhase) {
+        let safepoint = loop {
+            if let Some(safepoint) = SafepointScope::try_new_no_mutator(self as *mut Self) {
+                break safepoint;
+            }
+            if !self.is_current(epoch, phase) {
+                return;
+            }
+        };
+        let pause = Instant::now();
+        self.global_heap_lock.lock();
+        self.collector_lock.lock();
+        let name = if self.is_current(epoch, phase) {
+            match phase {
+                ShenandoahPhase::Marking => {
+                    self.final_mark(&mut []);
+                    "Final Mark"
+                }
+                ShenandoahPhase::UpdateRefs => {
+                    self.final_update_refs(&mut []);
+                    "Final Update Refs"
+                }
+                _ => {
+                    self.full_gc(&mut [], "Full (evacuation failure)");
+                    "Full"
+                }
+            }
+        } else {
+            ""
+        };
+        self.stats
+            .record_pause(pause.elapsed(), safepoint.time_to_safepoint());
+        self.collector_lock.unlock();
+        drop(safepoint);
+        self.global_heap_lock.unlock();
+        if !name.is_empty() && self.verbose > 0 {
+            eprintln!(
+                "[gc] GC({}) Pause {} {:.4}ms",
+                self.total_gcs,
+                name,
+                pause.elapsed().as_micros() as f64 / 1000.0
+            );
+        }
+    }
+
+    /// Start GC cycle if heuristics say so.
+    unsafe fn collect_if_needed(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        keep: &mut [&mut dyn Trace],
+    ) {
+        if self.phase() != ShenandoahPhase::Idle {
+            return;
+        }
+        let available = self.heap.available();
+        self.heuristics_lock.lock();
+        let start = self
+            .heuristics
+            .should_start_gc(available, self.heap.bytes_allocated_since_gc());
+        self.heuristics_lock.unlock();
+        if start {
+            self.start_concurrent_cycle(mutator, keep);
+        }
+    }
+
+    /// Allocate `size` bytes. When `lab` is true allocation buffer of mutator is refilled, otherwise object is
+    /// allocated directly in heap.
+    #[cold]
+    unsafe fn allocate_slow(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        size: usize,
+        lab: bool,
+        keep: &mut [&mut dyn Trace],
+    ) -> *mut u8 {
+        // allocation slow path is the place where mutators stop for GC pauses.
+        mutator.poll_safepoint();
+        self.collect_if_needed(mutator, keep);
+        for attempt in 0..3 {
+            let memory = if lab {
+                if self.heap.refill_lab(&mut mutator.tlab.lab, size, false) {
+                    mutator.tlab.lab.allocate(size)
+                } else {
+                    null_mut()
+                }
+            } else if size - BROOKS_POINTER_SIZE <= MAX_REGULAR_OBJECT_SIZE {
+                self.heap.allocate_shared(size)
+            } else {
+                self.heap.allocate_humongous(size)
+            };
+            if !memory.is_null() {
+                return memory;
+            }
+            match attempt {
+                0 => self.collect_alloc_failure(mutator, keep),
+                1 => self.collect(mutator, keep),
+                _ => (),
+            }
+        }
+        null_mut()
+    }
+
+    /// Register allocated object: object that needs drop goes to finalizer list, references in object are updated
+    /// if heap contains from-space references.
+    #[inline(always)]
+    unsafe fn post_alloc_object<T: Collectable + Sized + 'static>(&mut self, object: Gc<T, Self>) {
+        self.post_alloc(object);
+        if self.heap.has_forwarded() {
+            heal_object(self.heap, object.base.as_ptr());
+        }
+    }
+}
+
+impl<Heuristics: ShenandoahHeuristics> GcBase for Shenandoah<Heuristics> {
+    type TLAB = ShenandoahTlab;
+    const SUPPORTS_TLAB: bool = false;
+    type ReadBarrier = ShenandoahBarrier;
+    const LARGE_ALLOCATION_SIZE: usize = LARGE_CUTOFF;
+
+    fn inline_allocation_helpers(&self) -> Self::InlineAllocationHelpers {
+        NoHelp
+    }
+
+    fn stats(&self) -> HeapStats {
+        // cycles are started by heuristics, the whole heap is reported as target footprint.
+        self.collector_lock.lock();
+        let stats = self.stats.snapshot(
+            self.heap.used(),
+            self.heap.capacity(),
+            self.heap.humongous_used(),
+        );
+        unsafe {
+            self.collector_lock.unlock();
+        }
+        stats
+    }
+
+    fn add_constraint<T: MarkingConstraint + 'static>(&mut self, constraint: T) {
+        self.global_lock();
+        self.constraints.push(Box::new(constraint));
+        self.global_unlock();
+    }
+
+    /// Allocate raw object. Since object contents are not known to GC at allocation time you ***must*** invoke
+    /// [MutatorRef::write_barrier] once object is initialized.
+    fn allocate_raw(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        size: usize,
+        type_id: TypeId,
+        vtable: usize,
+    ) -> *mut HeapObjectHeader {
+        let size = align_usize(size + size_of::<HeapObjectHeader>(), 8);
+        let alloc_size = size + BROOKS_POINTER_SIZE;
+        unsafe {
+            let memory = if size >= Self::LARGE_ALLOCATION_SIZE {
+                self.allocate_slow(mutator, alloc_size, false, &mut [])
+            } else {
+                let memory = mutator.tlab.lab.allocate(alloc_size);
+                if memory.is_null() {
+                    self.allocate_slow(mutator, alloc_size, true, &mut [])
+                } else {
+                    memory
+                }
+            };
+            if memory.is_null() {
+                return null_mut();
+            }
+            let object = self.heap.install_object(memory, size);
+            (*object).set_vtable(vtable);
+            (*object).type_id = make_small_type_id(type_id);
+            object
+        }
+    }
+
+    fn allocate_weak<T: Collectable + ?Sized>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        value: Gc<T, Self>,
+    ) -> Result<Weak<T, Self>, AllocError<Gc<T, Self>>> {
+        let weak_ref = unsafe { Weak::<T, Self>::try_create(mutator, value)? };
+        self.global_heap_lock.lock();
+        self.weak_refs.push(weak_ref.to_dyn_untraced());
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        Ok(weak_ref)
+    }
+
+    #[inline]
+    fn alloc_inline<T: Collectable + Sized + 'static>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        mut value: T,
+        _space: AllocationSpace,
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
+        let size = align_usize(value.allocation_size() + size_of::<HeapObjectHeader>(), 8);
+        let alloc_size = size + BROOKS_POINTER_SIZE;
+        unsafe {
+            let mut memory = mutator.tlab.lab.allocate(alloc_size);
+            if memory.is_null() {
+                memory = self.allocate_slow(mutator, alloc_size, true, &mut [&mut value]);
+                if memory.is_null() {
+                    return Err(AllocError::new(value));
+                }
+            }
+            let object = self.heap.install_object(memory, size);
+            (*object).set_metadata(vtable_of::<T>());
+            (*object).type_id = small_type_id::<T>();
+            ((*object).data() as *mut T).write(value);
+            let gc = Gc::from_raw(object);
+            self.post_alloc_object(gc);
+            Ok(gc)
+        }
+    }
+
+    fn allocate_large<T: Collectable + Sized + 'static>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        mut value: T,
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
+        let size = align_usize(value.allocation_size() + size_of::<HeapObjectHeader>(), 8);
+        unsafe {
+            let memory = self.allocate_slow(
+                mutator,
+                size + BROOKS_POINTER_SIZE,
+                false,
+                &mut [&mut value],
+            );
+            if memory.is_null() {
+                return Err(AllocError::new(value));
+            }
+            let object = self.heap.install_object(memory, size);
+            (*object).set_metadata(vtable_of::<T>());
+            (*object).type_id = small_type_id::<T>();
+            ((*object).data() as *mut T).write(value);
+            let gc = Gc::from_raw(object);
+            self.post_alloc_object(gc);
+            Ok(gc)
+        }
+    }
+
+    #[inline(always)]
+    fn post_alloc<T: Collectable + Sized + 'static>(&mut self, value: Gc<T, Self>) {
+        if std::mem::needs_drop::<T>() {
+            self.finalize_lock.lock();
+            self.finalize_list.push(value.base.as_ptr());
+            unsafe {
+                self.finalize_lock.unlock();
+            }
+        }
+    }
+
+    #[inline]
+    fn write_barrier(
+        &mut self,
+        _mutator: &mut MutatorRef<Self>,
+        object: Gc<dyn Collectable, Self>,
+    ) {
+        if self.marker.is_marking() || self.heap.has_forwarded() {
+            unsafe {
+                write_barrier_impl(self.heap, &self.marker, forwardee(object.base.as_ptr()));
+            }
+        }
+    }
+
+    /// Finish running GC cycle in STW pause or perform STW cycle if no cycle is running.
+    fn collect_alloc_failure(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        keep: &mut [&mut dyn Trace],
+    ) {
+        self.collect_with(mutator, Self::degenerated_gc, keep);
+    }
+
+    /// Perform Full GC. Running cycle is abandoned.
+    fn collect(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
+        self.collect_with(
+            mutator,
+            |this, keep| unsafe { this.full_gc(keep, "Full") },
+            keep,
+        );
+    }
+
+    /// Perform STW cycle that evacuates every region with live objects.
+    fn stress_collection(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
+        // background collector thread might be stopping the world for final mark, stress cycle is performed after it.
+        while !self.collect_with(mutator, Self::stress_gc, keep) {}
+    }
+
+    fn alloc_tlab_area(&mut self, _mutator: &MutatorRef<Self>, _size: usize) -> *mut u8 {
+        null_mut()
+    }
+    fn safepoint(&self) -> &GlobalSafepoint {
+        &self.safepoint
+    }
+
+    fn attach_current_thread(&mut self, mutator: *mut Mutator<Self>) {
+        self.global_heap_lock.lock();
+        self.safepoint.n_mutators.fetch_add(1, Ordering::Relaxed);
+        self.mutators.push(mutator);
+        unsafe { self.global_heap_lock.unlock() };
+    }
+
+    fn detach_current_thread(&mut self, mutator: *mut Mutator<Self>) {
+        self.global_heap_lock.lock();
+
+        let mut detached = false;
+        self.mutators.retain(|x| {
+            if *x == mutator {
+                detached = true;
+                false
+            } else {
+                true
+            }
+        });
+        self.safepoint.n_mutators.fetch_sub(1, Ordering::Relaxed);
+        assert!(detached, "mutator must be detached");
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+    }
+
+    fn global_lock(&self) {
+        self.global_heap_lock.lock();
+    }
+    fn global_unlock(&self) {
+        unsafe {
+            debug_assert!(self.global_heap_lock.is_locked());
+            self.global_heap_lock.unlock();
+        }
+    }
+
+    fn mutators(&self) -> &[*mut Mutator<Self>] {
+        assert!(self.global_heap_lock.is_locked());
+        &self.mutators
+    }
+}
+
+impl<Heuristics: ShenandoahHeuristics> Drop for Shenandoah<Heuristics> {
+    fn drop(&mut self) {
+        unsafe {
+            // stop background collector before heap memory is released.
+            self.collector_lock.lock();
+            self.epoch.fetch_add(1, Ordering::AcqRel);
+            self.collector_lock.unlock();
+            while self.background_threads.load(Ordering::Acquire) != 0 {
+                std::thread::yield_now();
+            }
+            end_evacuation(self.heap);
+            if self.verbose > 0 {
+                eprintln!("Dispose Shenandoah heap at {:p}", self.heap);
+            }
+            drop(Box::from_raw(
+                self.heap as *const ShenandoahHeap as *mut ShenandoahHeap,
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{heuristics::ShenandoahAggressiveHeuristics, *};
+    use crate::{
+        api::{Ephemeron, Finalize},
+        letroot,
+    };
+
+    struct Node<H: GcBase> {
+        value: usize,
+        next: Option<Gc<Node<H>, H>>,
+    }
+
+    unsafe impl<H: GcBase> Trace for Node<H> {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.next.trace(vis);
+        }
+    }
+    unsafe impl<H: GcBase> Finalize for Node<H> {}
+    impl<H: GcBase> Collectable for Node<H> {}
+
+    struct Large {
+        data: [u8; 100 * 1024],
+    }
+
+    unsafe impl Trace for Large {}
+    unsafe impl Finalize for Large {}
+    impl Collectable for Large {}
+
+    type Entry = Ephemeron<Node<Shenandoah>, Node<Shenandoah>, Shenandoah>;
+
+    struct Holder {
+        entries: Vec<Entry>,
+    }
+
+    unsafe impl Trace for Holder {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.entries.trace(vis);
+        }
+    }
+    unsafe impl Finalize for Holder {}
+    impl Collectable for Holder {}
+
+    fn node(
+        mutator: &mut MutatorRef<Shenandoah>,
+        value: usize,
+        next: Option<Gc<Node<Shenandoah>, Shenandoah>>,
+    ) -> Gc<Node<Shenandoah>, Shenandoah> {
+        mutator.allocate(Node { value, next }, AllocationSpace::New)
+    }
+
+    /// Add entries whose values reference their own keys. Keys are not reachable from anywhere else.
+    #[inline(never)]
+    fn add_unreachable_entries(
+        mutator: &mut MutatorRef<Shenandoah>,
+        holder: &mut Gc<Holder, Shenandoah>,
+    ) {
+        for i in 0..100 {
+            let key = node(mutator, i, None);
+            let value = node(mutator, i, Some(key));
+            holder.entries.push(Ephemeron::new(key, value));
+        }
+    }
+
+    fn count_cleared(entries: &[Entry]) -> usize {
+        entries
+            .iter()
+            .filter(|entry| entry.key().is_none() && entry.value().is_none())
+            .count()
+    }
+
+    #[test]
+    fn concurrent_cycles_keep_reachable_objects() {
+        type Heap = Shenandoah<ShenandoahAggressiveHeuristics>;
+        let mut mutator = instantiate_shenandoah::<ShenandoahAggressiveHeuristics>(
+            ShenandoahOptions::default().with_heap_size(8 * 1024 * 1024),
+        );
+        let stack = mutator.shadow_stack();
+        let node: Node<Heap> = Node {
+            value: 0,
+            next: None,
+        };
+        letroot!(head = stack, mutator.allocate(node, AllocationSpace::New));
+        letroot!(tail = stack, *head);
+        let mut count = 1;
+        for i in 0..300000 {
+            let node = mutator.allocate(
+                Node {
+                    value: i,
+                    next: None,
+                },
+                AllocationSpace::New,
+            );
+            if i % 128 == 0 {
+                tail.next = Some(node);
+                mutator.write_barrier(tail.to_dyn());
+                *tail = node;
+                count += 1;
+            }
+        }
+        assert!(mutator.heap_ref().total_gcs() > 0);
+        mutator.collect(&mut []);
+        let mut cursor = head.next;
+        let mut expected = 0;
+        let mut len = 1;
+        while let Some(node) = cursor {
+            assert_eq!(node.value, expected);
+            expected += 128;
+            len += 1;
+            cursor = node.next;
+        }
+        assert_eq!(len, count);
+    }
+
+    #[test]
+    fn full_gc_compacts_heap() {
+        type Heap = Shenandoah;
+        let mut mutator = instantiate_shenandoah::<ShenandoahAdaptiveHeuristics>(
+            ShenandoahOptions::default().with_heap_size(8 * 1024 * 1024),
+        );
+        let stack = mutator.shadow_stack();
+        let node: Node<Heap> = Node {
+            value: 42,
+            next: None,
+        };
+        letroot!(live = stack, mutator.allocate(node, AllocationSpace::New));
+        for i in 0..50000 {
+            mutator.allocate(
+                Node::<Heap> {
+                    value: i,
+                    next: None,
+                },
+                AllocationSpace::New,
+            );
+        }
+        let before = mutator.heap_ref().heap().used();
+        mutator.collect(&mut []);
+        let heap = mutator.heap_ref();
+        assert_eq!(heap.phase(), ShenandoahPhase::Idle);
+        // regions pinned by stale stack values are not compacted
+        assert!(heap.heap().used() < before / 2);
+        assert_eq!(live.value, 42);
+    }
+
+    #[test]
+    fn humongous_objects_are_reclaimed() {
+        let mut mutator = instantiate_shenandoah::<ShenandoahAdaptiveHeuristics>(
+            ShenandoahOptions::default().with_heap_size(8 * 1024 * 1024),
+        );
+        let stack = mutator.shadow_stack();
+        letroot!(
+            live = stack,
+            mutator.allocate(
+                Large {
+                    data: [7; 100 * 1024]
+                },
+                AllocationSpace::New
+            )
+        );
+        for _ in 0..100 {
+            mutator.allocate(
+                Large {
+                    data: [0; 100 * 1024],
+                },
+                AllocationSpace::New,
+            );
+        }
+        mutator.collect(&mut []);
+        let heap = mutator.heap_ref().heap();
+        let region = unsafe { heap.region_of(live.base.as_ptr().cast()) };
+        assert!(region.is_humongous_start());
+        assert!(heap.used() < 512 * 1024);
+        assert!(live.data.iter().all(|x| *x == 7));
+    }
+
+    #[test]
+    fn stress_cycle_evacuates_regions_with_little_garbage() {
+        type Heap = Shenandoah;
+        let mut mutator = instantiate_shenandoah::<ShenandoahAdaptiveHeuristics>(
+            ShenandoahOptions::default()
+                .with_heap_size(8 * 1024 * 1024)
+                .with_evac_reserve(0.5),
+        );
+        let stack = mutator.shadow_stack();
+        let node: Node<Heap> = Node {
+            value: 0,
+            next: None,
+        };
+        letroot!(head = stack, mutator.allocate(node, AllocationSpace::New));
+        for i in 1..60000 {
+            let node = mutator.allocate(
+                Node {
+                    value: i,
+                    next: head.next,
+                },
+                AllocationSpace::New,
+            );
+            head.next = Some(node);
+        }
+        let addresses = |head: Gc<Node<Heap>, Heap>| {
+            let mut result = vec![];
+            let mut cursor = head.next;
+            while let Some(node) = cursor {
+                result.push(node.base.as_ptr() as usize);
+                cursor = node.next;
+            }
+            result
+        };
+        let before = addresses(*head);
+        mutator.set_gc_stress(GcStress::Safepoints);
+        assert!(mutator.safepoint());
+        mutator.set_gc_stress(GcStress::Disabled);
+        let after = addresses(*head);
+        assert_eq!(after.len(), 59999);
+        // there is no garbage at all so only stress cycle evacuates these regions. Regions pinned by conservative stack
+        // scan, e.g region of `head`, are not evacuated.
+        let moved = before
+            .iter()
+            .zip(after.iter())
+            .filter(|(a, b)| a != b)
+            .count();
+        assert!(moved > 30000, "only {} objects are moved", moved);
+    }
+
+    #[test]
+    fn ephemerons_with_dead_keys_are_cleared() {
+        let mut mutator = instantiate_shenandoah::<ShenandoahAdaptiveHeuristics>(
+            ShenandoahOptions::default().with_heap_size(8 * 1024 * 1024),
+        );
+        let stack = mutator.shadow_stack();
+        letroot!(
+            holder = stack,
+            mutator.allocate(Holder { entries: vec![] }, AllocationSpace::New)
+        );
+        letroot!(root = stack, node(&mut mutator, 1, None));
+        // key of the first entry is reachable only from value of the second entry
+        let key = node(&mut mutator, 2, None);
+        let value = node(&mut mutator, 20, None);
+        holder.entries.push(Ephemeron::new(key, value));
+        let value = node(&mut mutator, 10, Some(key));
+        holder.entries.push(Ephemeron::new(*root, value));
+        add_unreachable_entries(&mut mutator, &mut holder);
+
+        // Full GC
+        mutator.collect(&mut []);
+        // few keys might be kept alive by conservative stack scanning.
+        assert!(count_cleared(&holder.entries[2..]) >= 90);
+
+        // stress cycle marks and evacuates heap the same way concurrent cycle does
+        add_unreachable_entries(&mut mutator, &mut holder);
+        mutator.set_gc_stress(GcStress::Safepoints);
+        assert!(mutator.safepoint());
+        mutator.set_gc_stress(GcStress::Disabled);
+        assert!(count_cleared(&holder.entries[102..]) >= 90);
+
+        let first = &holder.entries[0];
+        assert_eq!(first.key().unwrap().value, 2);
+        assert_eq!(first.value().unwrap().value, 20);
+        let second = &holder.entries[1];
+        assert_eq!(second.key().unwrap().value, 1);
+        assert_eq!(second.value().unwrap().next.unwrap().value, 2);
+    }
+}
@@ -0,0 +1,125 @@
+//! Barriers of Shenandoah GC.
+//!
+//! Reads go through Brooks forwarding pointer ([BrooksPointer]). Before object is mutated it is resolved with
+//! [ShenandoahBarrier::resolve_for_write] which copies object out of collection set if evacuation is running, so that
+//! stores never go to from-space copy. [ReadBarrier] is not able to access heap instance so heaps that are evacuating
+//! are registered in global table.
+
+use std::{
+    ptr::NonNull,
+    sync::atomic::{fence, AtomicUsize},
+};
+
+use atomic::Ordering;
+use parking_lot::{const_rwlock, RwLock};
+
+use super::{
+    heap::{forwardee, ShenandoahHeap},
+    marker::{ShenandoahMarker, UpdateRefsVisitor},
+};
+use crate::{
+    api::{Collectable, Gc, HeapObjectHeader},
+    gc_base::{BrooksPointer, GcBase, ReadBarrier},
+};
+
+/// Heaps that are currently evacuating: `(heap start, heap end, heap)`.
+static HEAPS: RwLock<Vec<(usize, usize, usize)>> = const_rwlock(Vec::new());
+/// Number of heaps with evacuation in progress. When zero write barrier does not need to look up heap.
+static EVACUATING_HEAPS: AtomicUsize = AtomicUsize::new(0);
+
+pub struct ShenandoahBarrier;
+
+impl<H: GcBase> ReadBarrier<H> for ShenandoahBarrier {
+    #[inline(always)]
+    fn read_barrier<T: Collectable + ?Sized>(x: Gc<T, H>) -> Gc<T, H> {
+        <BrooksPointer as ReadBarrier<H>>::read_barrier(x)
+    }
+
+    #[inline(always)]
+    fn resolve_for_write<T: Collectable + ?Sized>(x: Gc<T, H>) -> Gc<T, H> {
+        if EVACUATING_HEAPS.load(Ordering::Acquire) == 0 {
+            return <BrooksPointer as ReadBarrier<H>>::read_barrier(x);
+        }
+        unsafe {
+            let mut x = x;
+            x.base = NonNull::new_unchecked(resolve_for_write_slow(x.base.as_ptr()));
+            x
+        }
+    }
+}
+
+#[cold]
+#[inline(never)]
+unsafe fn resolve_for_write_slow(object: *mut HeapObjectHeader) -> *mut HeapObjectHeader {
+    let resolved = forwardee(object);
+    if resolved != object {
+        return resolved;
+    }
+    let heaps = HEAPS.read();
+    for &(start, end, heap) in heaps.iter() {
+        if (object as usize) >= start && (object as usize) < end {
+            let heap = &*(heap as *const ShenandoahHeap);
+            if heap.is_evacuation_in_progress() && heap.in_cset(object) {
+                return heap.evacuate(object, None);
+            }
+            break;
+        }
+    }
+    forwardee(object)
+}
+
+/// Enable write barrier evacuation for `heap`. Must be invoked in STW pause.
+pub fn begin_evacuation(heap: &'static ShenandoahHeap) {
+    HEAPS.write().push((
+        heap.start() as usize,
+        heap.end() as usize,
+        heap as *const ShenandoahHeap as usize,
+    ));
+    heap.set_evacuation_in_progress(true);
+    EVACUATING_HEAPS.fetch_add(1, Ordering::AcqRel);
+}
+
+/// Disable write barrier evacuation for `heap`.
+pub fn end_evacuation(heap: &'static ShenandoahHeap) {
+    let mut heaps = HEAPS.write();
+    let before = heaps.len();
+    heaps.retain(|&(_, _, x)| x != heap as *const ShenandoahHeap as usize);
+    if heaps.len() != before {
+        heap.set_evacuation_in_progress(false);
+        EVACUATING_HEAPS.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Post-store write barrier.
+///
+/// While marking is running it is incremental update barrier: marked object that was mutated is traced again. When
+/// heap contains references to from-space objects references in `object` are updated, so mutators never introduce
+/// from-space references to heap objects that were already processed by GC.
+#[inline]
+pub unsafe fn write_barrier_impl(
+    heap: &ShenandoahHeap,
+    marker: &ShenandoahMarker,
+    object: *mut HeapObjectHeader,
+) {
+    if marker.is_marking() {
+        // store to `object` must be visible to the marker before we read mark bit, otherwise marker might
+        // read old field value while we see object as marked.
+        fence(Ordering::SeqCst);
+        if heap.is_marked(object) {
+            marker
+                .marking_worklists()
+                .write_barrier_worklist()
+                .push(object as usize);
+        }
+    }
+    if heap.has_forwarded() {
+        heal_object(heap, object);
+    }
+}
+
+/// Update references in `object` to point to to-space copies.
+#[cold]
+pub unsafe fn heal_object(heap: &ShenandoahHeap, object: *mut HeapObjectHeader) {
+    let mut visitor = UpdateRefsVisitor::new(heap, None);
+    (*object).get_dyn().trace(&mut visitor);
+}
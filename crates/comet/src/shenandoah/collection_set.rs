@@ -1 +1,73 @@
-pub struct ShenandoahCollectionSet {}
+use super::region::ShenandoahHeapRegion;
+
+/// Set of regions that are evacuated in current GC cycle.
+pub struct ShenandoahCollectionSet {
+    map: Vec<bool>,
+    regions: Vec<usize>,
+    live: usize,
+    garbage: usize,
+    used: usize,
+}
+
+impl ShenandoahCollectionSet {
+    pub fn new(num_regions: usize) -> Self {
+        Self {
+            map: vec![false; num_regions],
+            regions: vec![],
+            live: 0,
+            garbage: 0,
+            used: 0,
+        }
+    }
+
+    /// Add region to collection set. Region state is not changed, caller is responsible for [ShenandoahHeapRegion::make_cset].
+    pub fn add_region(&mut self, region: &ShenandoahHeapRegion) {
+        debug_assert!(!self.map[region.index()]);
+        self.map[region.index()] = true;
+        self.regions.push(region.index());
+        self.live += region.live_data();
+        self.garbage += region.garbage();
+        self.used += region.used();
+    }
+
+    #[inline]
+    pub fn is_in(&self, index: usize) -> bool {
+        self.map[index]
+    }
+
+    /// Indexes of regions in collection set in the order they were added.
+    pub fn regions(&self) -> &[usize] {
+        &self.regions
+    }
+
+    pub fn count(&self) -> usize {
+        self.regions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    /// Number of live bytes that are going to be evacuated.
+    pub fn live(&self) -> usize {
+        self.live
+    }
+
+    /// Number of bytes reclaimed once collection set is evacuated.
+    pub fn garbage(&self) -> usize {
+        self.garbage
+    }
+
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    pub fn clear(&mut self) {
+        for index in self.regions.drain(..) {
+            self.map[index] = false;
+        }
+        self.live = 0;
+        self.garbage = 0;
+        self.used = 0;
+    }
+}
@@ -1,16 +1,242 @@
-use crate::bitmap::SpaceBitmap;
+use std::{cell::UnsafeCell, ptr::null_mut};
 
-pub struct ShenandoahFreeSet {
-    mutator_free_bitmap: SpaceBitmap<4096>,
-    collector_free_bitmap: SpaceBitmap<4096>,
+use super::region::ShenandoahHeapRegion;
+
+/// Partition that region belongs to. Mutator partition is used for TLABs and shared allocations, collector
+/// partition is reserved for evacuation so GC has space to copy objects to even when mutators used all the heap.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FreePartition {
+    NotFree,
+    Mutator,
+    Collector,
+}
 
-    max: usize,
+/// Regions will not be used for allocation anymore once their free space drops below this value.
+const MIN_USEFUL_FREE: usize = 256;
+
+/// Set of regions available for allocation. All methods must be invoked with heap free-set lock held.
+pub struct ShenandoahFreeSet {
+    partition: Vec<FreePartition>,
     mutator_leftmost: usize,
     mutator_rightmost: usize,
     collector_leftmost: usize,
     collector_rightmost: usize,
+    /// Free bytes in mutator partition at the moment free set was rebuilt.
     capacity: usize,
+    /// Bytes allocated in mutator partition since free set was rebuilt.
     used: usize,
 }
 
-impl ShenandoahFreeSet {}
+unsafe fn region(
+    regions: &[UnsafeCell<ShenandoahHeapRegion>],
+    index: usize,
+) -> &mut ShenandoahHeapRegion {
+    &mut *regions[index].get()
+}
+
+impl ShenandoahFreeSet {
+    pub fn new(num_regions: usize) -> Self {
+        Self {
+            partition: vec![FreePartition::NotFree; num_regions],
+            mutator_leftmost: num_regions,
+            mutator_rightmost: 0,
+            collector_leftmost: num_regions,
+            collector_rightmost: 0,
+            capacity: 0,
+            used: 0,
+        }
+    }
+
+    pub fn partition(&self, index: usize) -> FreePartition {
+        self.partition[index]
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// Number of bytes mutators can still allocate without running out of memory.
+    pub fn available(&self) -> usize {
+        self.capacity.saturating_sub(self.used)
+    }
+
+    fn set_partition(&mut self, index: usize, partition: FreePartition) {
+        self.partition[index] = partition;
+        match partition {
+            FreePartition::Mutator => {
+                self.mutator_leftmost = self.mutator_leftmost.min(index);
+                self.mutator_rightmost = self.mutator_rightmost.max(index);
+            }
+            FreePartition::Collector => {
+                self.collector_leftmost = self.collector_leftmost.min(index);
+                self.collector_rightmost = self.collector_rightmost.max(index);
+            }
+            FreePartition::NotFree => (),
+        }
+    }
+
+    /// Remove region from free set. Region is not used for allocations until free set is rebuilt.
+    pub fn remove(&mut self, index: usize) {
+        self.partition[index] = FreePartition::NotFree;
+    }
+
+    pub fn clear(&mut self) {
+        let num_regions = self.partition.len();
+        self.partition.fill(FreePartition::NotFree);
+        self.mutator_leftmost = num_regions;
+        self.mutator_rightmost = 0;
+        self.collector_leftmost = num_regions;
+        self.collector_rightmost = 0;
+        self.capacity = 0;
+        self.used = 0;
+    }
+
+    /// Rebuild free set from region states. Empty regions and regular regions with free space go to mutator partition,
+    /// then up to `reserve_regions` empty regions from the end of the heap are moved to collector partition.
+    ///
+    /// # Safety
+    ///
+    /// No other thread must be allocating in `regions`.
+    pub unsafe fn rebuild(
+        &mut self,
+        regions: &[UnsafeCell<ShenandoahHeapRegion>],
+        reserve_regions: usize,
+    ) {
+        self.clear();
+        for index in 0..regions.len() {
+            let region = region(regions, index);
+            if region.is_empty() || (region.is_regular() && region.free() >= MIN_USEFUL_FREE) {
+                self.set_partition(index, FreePartition::Mutator);
+            }
+        }
+        let mut reserved = 0;
+        for index in (0..regions.len()).rev() {
+            if reserved == reserve_regions {
+                break;
+            }
+            if self.partition[index] == FreePartition::Mutator && region(regions, index).is_empty()
+            {
+                self.set_partition(index, FreePartition::Collector);
+                reserved += 1;
+            }
+        }
+        for index in 0..regions.len() {
+            if self.partition[index] == FreePartition::Mutator {
+                self.capacity += region(regions, index).free();
+            }
+        }
+    }
+
+    unsafe fn allocate_in(
+        &mut self,
+        regions: &[UnsafeCell<ShenandoahHeapRegion>],
+        index: usize,
+        size: usize,
+    ) -> *mut u8 {
+        let region = region(regions, index);
+        if region.is_empty() {
+            region.make_regular_allocation();
+        }
+        let result = region.allocate(size);
+        if region.free() < MIN_USEFUL_FREE {
+            self.partition[index] = FreePartition::NotFree;
+        }
+        result
+    }
+
+    /// Allocate `size` bytes in mutator partition. Returns null if there is no region with enough space.
+    pub unsafe fn allocate_mutator(
+        &mut self,
+        regions: &[UnsafeCell<ShenandoahHeapRegion>],
+        size: usize,
+    ) -> *mut u8 {
+        while self.mutator_leftmost <= self.mutator_rightmost
+            && self.partition[self.mutator_leftmost] != FreePartition::Mutator
+        {
+            self.mutator_leftmost += 1;
+        }
+        let mut index = self.mutator_leftmost;
+        while index <= self.mutator_rightmost && index < regions.len() {
+            if self.partition[index] == FreePartition::Mutator
+                && region(regions, index).free() >= size
+            {
+                let result = self.allocate_in(regions, index, size);
+                self.used += size;
+                return result;
+            }
+            index += 1;
+        }
+        null_mut()
+    }
+
+    /// Allocate `size` bytes for evacuated objects. Collector partition is used first, if it is exhausted empty regions
+    /// are taken from mutator partition.
+    pub unsafe fn allocate_collector(
+        &mut self,
+        regions: &[UnsafeCell<ShenandoahHeapRegion>],
+        size: usize,
+    ) -> *mut u8 {
+        let mut index = self.collector_leftmost;
+        while index <= self.collector_rightmost && index < regions.len() {
+            if self.partition[index] == FreePartition::Collector
+                && region(regions, index).free() >= size
+            {
+                return self.allocate_in(regions, index, size);
+            }
+            index += 1;
+        }
+        // steal empty region from mutators, starting from the end of the heap to keep mutator allocations compact.
+        let mut index = self.mutator_rightmost.min(regions.len().saturating_sub(1));
+        while index >= self.mutator_leftmost && index < regions.len() {
+            if self.partition[index] == FreePartition::Mutator && region(regions, index).is_empty()
+            {
+                let free = region(regions, index).free();
+                self.capacity = self.capacity.saturating_sub(free);
+                self.set_partition(index, FreePartition::Collector);
+                return self.allocate_in(regions, index, size);
+            }
+            if index == 0 {
+                break;
+            }
+            index -= 1;
+        }
+        null_mut()
+    }
+
+    /// Find `count` contiguous empty regions in mutator partition and remove them from free set. Returns index of the first
+    /// region.
+    pub unsafe fn allocate_contiguous(
+        &mut self,
+        regions: &[UnsafeCell<ShenandoahHeapRegion>],
+        count: usize,
+    ) -> Option<usize> {
+        let mut start = self.mutator_leftmost;
+        while start + count <= regions.len() && start <= self.mutator_rightmost {
+            let mut found = 0;
+            while found < count
+                && self.partition[start + found] == FreePartition::Mutator
+                && region(regions, start + found).is_empty()
+            {
+                found += 1;
+            }
+            if found == count {
+                for index in start..start + count {
+                    self.partition[index] = FreePartition::NotFree;
+                    self.used += region(regions, index).capacity();
+                }
+                return Some(start);
+            }
+            start += found + 1;
+        }
+        None
+    }
+
+    /// Give back `size` bytes that were allocated in mutator partition but never used.
+    pub fn unallocate(&mut self, size: usize) {
+        self.used = self.used.saturating_sub(size);
+    }
+}
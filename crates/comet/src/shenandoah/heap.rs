@@ -1,7 +1,680 @@
-use crate::utils::mmap::Mmap;
+use std::{
+    cell::UnsafeCell,
+    mem::size_of,
+    ptr::null_mut,
+    sync::atomic::{fence, AtomicBool, AtomicUsize},
+};
 
+use atomic::Ordering;
+use parking_lot::{lock_api::RawMutex, RawMutex as Lock};
+
+use super::{
+    collection_set::ShenandoahCollectionSet,
+    free_set::ShenandoahFreeSet,
+    region::{ShenandoahHeapRegion, ShenandoahRegionSizes},
+};
+use crate::{
+    api::{HeapObjectHeader, Visitor},
+    bitmap::SpaceBitmap,
+    utils::{formatted_size, mmap::Mmap},
+};
+
+/// Size of Brooks forwarding pointer that is stored right before object header.
+pub const BROOKS_POINTER_SIZE: usize = size_of::<usize>();
+/// Largest object (including header) that fits into header size field. Larger objects are always humongous.
+pub const MAX_REGULAR_OBJECT_SIZE: usize = 8191 * 8;
+
+/// Returns Brooks forwarding pointer of `object`.
+#[inline(always)]
+pub fn brooks_pointer(object: *const HeapObjectHeader) -> *const AtomicUsize {
+    unsafe { object.cast::<AtomicUsize>().sub(1) }
+}
+
+/// Returns current location of `object`. For objects that were not evacuated it is `object` itself.
+#[inline(always)]
+pub unsafe fn forwardee(object: *mut HeapObjectHeader) -> *mut HeapObjectHeader {
+    (*brooks_pointer(object)).load(Ordering::Acquire) as _
+}
+
+#[inline(always)]
+pub unsafe fn is_forwarded(object: *mut HeapObjectHeader) -> bool {
+    forwardee(object) != object
+}
+
+/// Bump pointer buffer inside of one region. Used by mutators for thread local allocation and by GC for evacuation.
+pub struct LocalAllocBuffer {
+    top: *mut u8,
+    end: *mut u8,
+}
+
+impl LocalAllocBuffer {
+    pub const fn new() -> Self {
+        Self {
+            top: null_mut(),
+            end: null_mut(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn allocate(&mut self, size: usize) -> *mut u8 {
+        if (self.end as usize - self.top as usize) < size {
+            return null_mut();
+        }
+        let result = self.top;
+        self.top = unsafe { self.top.add(size) };
+        result
+    }
+
+    /// Undo the last allocation.
+    pub fn rollback(&mut self, memory: *mut u8, size: usize) {
+        if memory.wrapping_add(size) == self.top {
+            self.top = memory;
+        }
+    }
+
+    pub fn set(&mut self, start: *mut u8, end: *mut u8) {
+        self.top = start;
+        self.end = end;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.top == self.end
+    }
+}
+
+impl Default for LocalAllocBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Region based heap of Shenandoah GC.
+///
+/// Each object is prefixed with Brooks forwarding pointer: `[forwarding pointer][HeapObjectHeader][data]`. Forwarding
+/// pointer points to the object itself until object is evacuated, after that it points to the to-space copy.
 pub struct ShenandoahHeap {
-    num_regions: usize,
-    heap_region_special: bool,
-    heap_region: Mmap,
+    sizes: ShenandoahRegionSizes,
+    #[allow(dead_code)]
+    mmap: Mmap,
+    start: *mut u8,
+    end: *mut u8,
+    regions: Box<[UnsafeCell<ShenandoahHeapRegion>]>,
+    free_set: UnsafeCell<ShenandoahFreeSet>,
+    free_set_lock: Lock,
+    collection_set: UnsafeCell<ShenandoahCollectionSet>,
+    /// Bit is set for each allocated object start, used for conservative root scanning.
+    live_bitmap: SpaceBitmap<8>,
+    mark_bitmap: SpaceBitmap<8>,
+    /// Number of empty regions that are reserved for evacuation.
+    reserve_regions: usize,
+    evacuation_in_progress: AtomicBool,
+    /// Set when heap might contain references to from-space objects.
+    has_forwarded: AtomicBool,
+    evac_threads: AtomicUsize,
+    evac_oom: AtomicBool,
+    bytes_allocated_since_gc: AtomicUsize,
+    verbose: u8,
+}
+
+unsafe impl Send for ShenandoahHeap {}
+unsafe impl Sync for ShenandoahHeap {}
+
+impl ShenandoahHeap {
+    pub fn new(sizes: ShenandoahRegionSizes, evac_reserve: f64, verbose: u8) -> Self {
+        let size = sizes.region_count * sizes.region_size_bytes;
+        let mmap = Mmap::new(size, sizes.region_size_bytes);
+        let start = mmap.aligned_start();
+        let regions = (0..sizes.region_count)
+            .map(|index| {
+                UnsafeCell::new(ShenandoahHeapRegion::new(
+                    index,
+                    unsafe { start.add(index * sizes.region_size_bytes) },
+                    sizes.region_size_bytes,
+                ))
+            })
+            .collect::<Vec<_>>()
+            .into_boxed_slice();
+        if verbose > 0 {
+            eprintln!(
+                "[gc] Shenandoah heap {} with {} regions of {}",
+                formatted_size(size),
+                sizes.region_count,
+                formatted_size(sizes.region_size_bytes)
+            );
+        }
+        let reserve_regions = ((sizes.region_count as f64 * evac_reserve) as usize).max(1);
+        let this = Self {
+            sizes,
+            start,
+            end: unsafe { start.add(size) },
+            live_bitmap: SpaceBitmap::create("shenandoah live bitmap", start, size),
+            mark_bitmap: SpaceBitmap::create("shenandoah mark bitmap", start, size),
+            mmap,
+            regions,
+            free_set: UnsafeCell::new(ShenandoahFreeSet::new(sizes.region_count)),
+            free_set_lock: Lock::INIT,
+            collection_set: UnsafeCell::new(ShenandoahCollectionSet::new(sizes.region_count)),
+            reserve_regions,
+            evacuation_in_progress: AtomicBool::new(false),
+            has_forwarded: AtomicBool::new(false),
+            evac_threads: AtomicUsize::new(0),
+            evac_oom: AtomicBool::new(false),
+            bytes_allocated_since_gc: AtomicUsize::new(0),
+            verbose,
+        };
+        unsafe {
+            this.rebuild_free_set();
+        }
+        this
+    }
+
+    pub fn sizes(&self) -> &ShenandoahRegionSizes {
+        &self.sizes
+    }
+
+    pub fn start(&self) -> *mut u8 {
+        self.start
+    }
+
+    pub fn end(&self) -> *mut u8 {
+        self.end
+    }
+
+    pub fn num_regions(&self) -> usize {
+        self.regions.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.end as usize - self.start as usize
+    }
+
+    pub fn region_size(&self) -> usize {
+        self.sizes.region_size_bytes
+    }
+
+    pub fn reserve_regions(&self) -> usize {
+        self.reserve_regions
+    }
+
+    pub fn verbose(&self) -> u8 {
+        self.verbose
+    }
+
+    pub fn mark_bitmap(&self) -> &SpaceBitmap<8> {
+        &self.mark_bitmap
+    }
+
+    pub fn live_bitmap(&self) -> &SpaceBitmap<8> {
+        &self.live_bitmap
+    }
+
+    #[inline(always)]
+    pub fn contains(&self, pointer: *const u8) -> bool {
+        pointer >= self.start as *const u8 && pointer < self.end as *const u8
+    }
+
+    #[inline(always)]
+    pub fn region_index(&self, pointer: *const u8) -> usize {
+        (pointer as usize - self.start as usize) >> self.sizes.region_size_bytes_shift
+    }
+
+    /// Get region by its index.
+    ///
+    /// # Safety
+    ///
+    /// Caller must ensure region is not mutated concurrently: regions are mutated only in STW pauses, by GC thread
+    /// holding collector lock or with free-set lock held.
+    #[allow(clippy::mut_from_ref)]
+    #[inline(always)]
+    pub unsafe fn region(&self, index: usize) -> &mut ShenandoahHeapRegion {
+        &mut *self.regions[index].get()
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    #[inline(always)]
+    pub unsafe fn region_of(&self, pointer: *const u8) -> &mut ShenandoahHeapRegion {
+        self.region(self.region_index(pointer))
+    }
+
+    /// # Safety
+    ///
+    /// Free-set lock must be held.
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn free_set(&self) -> &mut ShenandoahFreeSet {
+        &mut *self.free_set.get()
+    }
+
+    pub fn lock_free_set(&self) {
+        self.free_set_lock.lock();
+    }
+
+    pub unsafe fn unlock_free_set(&self) {
+        self.free_set_lock.unlock();
+    }
+
+    /// # Safety
+    ///
+    /// Collection set is modified only in STW pauses.
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn collection_set(&self) -> &mut ShenandoahCollectionSet {
+        &mut *self.collection_set.get()
+    }
+
+    #[inline(always)]
+    pub fn in_cset(&self, object: *const HeapObjectHeader) -> bool {
+        unsafe { (*self.collection_set.get()).is_in(self.region_index(object.cast())) }
+    }
+
+    #[inline(always)]
+    pub fn is_evacuation_in_progress(&self) -> bool {
+        self.evacuation_in_progress.load(Ordering::Acquire)
+    }
+
+    pub fn set_evacuation_in_progress(&self, x: bool) {
+        self.evacuation_in_progress.store(x, Ordering::Release);
+    }
+
+    #[inline(always)]
+    pub fn has_forwarded(&self) -> bool {
+        self.has_forwarded.load(Ordering::Acquire)
+    }
+
+    pub fn set_has_forwarded(&self, x: bool) {
+        self.has_forwarded.store(x, Ordering::Release);
+    }
+
+    /// Returns `true` if some thread failed to allocate memory for evacuation in current cycle.
+    pub fn is_evac_oom(&self) -> bool {
+        self.evac_oom.load(Ordering::Acquire)
+    }
+
+    pub fn clear_evac_oom(&self) {
+        self.evac_oom.store(false, Ordering::Release);
+    }
+
+    pub fn bytes_allocated_since_gc(&self) -> usize {
+        self.bytes_allocated_since_gc.load(Ordering::Relaxed)
+    }
+
+    pub fn reset_bytes_allocated_since_gc(&self) {
+        self.bytes_allocated_since_gc.store(0, Ordering::Relaxed);
+    }
+
+    /// Bytes used by mutators.
+    pub fn used(&self) -> usize {
+        let mut used = 0;
+        for index in 0..self.num_regions() {
+            unsafe {
+                used += self.region(index).used();
+            }
+        }
+        used
+    }
+
+    /// Bytes used by humongous objects.
+    pub fn humongous_used(&self) -> usize {
+        let mut used = 0;
+        for index in 0..self.num_regions() {
+            unsafe {
+                let region = self.region(index);
+                if region.is_humongous() {
+                    used += region.used();
+                }
+            }
+        }
+        used
+    }
+
+    /// Bytes available to mutators before heap is exhausted.
+    pub fn available(&self) -> usize {
+        self.lock_free_set();
+        let available = unsafe { self.free_set().available() };
+        unsafe {
+            self.unlock_free_set();
+        }
+        available
+    }
+
+    /// Rebuild free set. Must be invoked when nobody allocates in heap.
+    pub unsafe fn rebuild_free_set(&self) {
+        self.lock_free_set();
+        self.free_set().rebuild(&self.regions, self.reserve_regions);
+        self.unlock_free_set();
+    }
+
+    /// Initialize object header and forwarding pointer at `memory`. `size` includes header but does not include forwarding pointer.
+    #[inline(always)]
+    pub unsafe fn install_object(&self, memory: *mut u8, size: usize) -> *mut HeapObjectHeader {
+        let object = memory.add(BROOKS_POINTER_SIZE).cast::<HeapObjectHeader>();
+        (*memory.cast::<AtomicUsize>()).store(object as usize, Ordering::Relaxed);
+        (*object).padding = 0;
+        (*object).padding2 = 0;
+        if size <= MAX_REGULAR_OBJECT_SIZE {
+            (*object).set_size(size);
+        } else {
+            (*object).set_large();
+        }
+        self.live_bitmap.atomic_test_and_set(object.cast());
+        object
+    }
+
+    /// Allocate `size` bytes in mutator partition. Returns null if heap is exhausted.
+    pub fn allocate_shared(&self, size: usize) -> *mut u8 {
+        self.lock_free_set();
+        let result = unsafe { self.free_set().allocate_mutator(&self.regions, size) };
+        unsafe {
+            self.unlock_free_set();
+        }
+        if !result.is_null() {
+            self.bytes_allocated_since_gc
+                .fetch_add(size, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Refill thread local allocation buffer. Returns `false` if heap is exhausted.
+    pub fn refill_lab(&self, lab: &mut LocalAllocBuffer, min_size: usize, collector: bool) -> bool {
+        self.retire_lab(lab, collector);
+        let size = self.sizes.max_tlab_size_bytes.max(min_size);
+        self.lock_free_set();
+        unsafe {
+            let free_set = self.free_set();
+            let mut memory = if collector {
+                free_set.allocate_collector(&self.regions, size)
+            } else {
+                free_set.allocate_mutator(&self.regions, size)
+            };
+            let mut lab_size = size;
+            if memory.is_null() {
+                // fall back to the smallest buffer that still fits the object.
+                lab_size = min_size;
+                memory = if collector {
+                    free_set.allocate_collector(&self.regions, min_size)
+                } else {
+                    free_set.allocate_mutator(&self.regions, min_size)
+                };
+            }
+            self.unlock_free_set();
+            if memory.is_null() {
+                return false;
+            }
+            if !collector {
+                self.bytes_allocated_since_gc
+                    .fetch_add(lab_size, Ordering::Relaxed);
+            }
+            lab.set(memory, memory.add(lab_size));
+        }
+        true
+    }
+
+    /// Give back unused part of `lab` to its region if possible.
+    pub fn retire_lab(&self, lab: &mut LocalAllocBuffer, collector: bool) {
+        if lab.top.is_null() {
+            return;
+        }
+        self.lock_free_set();
+        unsafe {
+            let region = self.region_of(lab.end.sub(1));
+            if region.top() == lab.end {
+                let unused = lab.end as usize - lab.top as usize;
+                region.set_top(lab.top);
+                if !collector {
+                    self.free_set().unallocate(unused);
+                }
+            }
+            self.unlock_free_set();
+        }
+        lab.set(null_mut(), null_mut());
+    }
+
+    /// Allocate humongous object that occupies `size` bytes. Returns null if there is no enough contiguous regions.
+    pub fn allocate_humongous(&self, size: usize) -> *mut u8 {
+        let count = (size + self.region_size() - 1) / self.region_size();
+        self.lock_free_set();
+        unsafe {
+            let start = match self.free_set().allocate_contiguous(&self.regions, count) {
+                Some(start) => start,
+                None => {
+                    self.unlock_free_set();
+                    return null_mut();
+                }
+            };
+            let mut remaining = size;
+            for index in start..start + count {
+                let region = self.region(index);
+                if index == start {
+                    region.make_humongous_start();
+                } else {
+                    region.make_humongous_cont();
+                }
+                region.allocate(remaining.min(region.capacity()));
+                remaining = remaining.saturating_sub(region.capacity());
+            }
+            self.unlock_free_set();
+            self.bytes_allocated_since_gc
+                .fetch_add(count * self.region_size(), Ordering::Relaxed);
+            self.region(start).bottom()
+        }
+    }
+
+    /// Returns object header if `pointer` points to the start of object in active region.
+    pub fn find_object(&self, pointer: *const u8) -> *mut HeapObjectHeader {
+        if !self.contains(pointer) || pointer as usize % 8 != 0 {
+            return null_mut();
+        }
+        unsafe {
+            let region = self.region_of(pointer);
+            if !region.is_active() || pointer >= region.top() as *const u8 {
+                return null_mut();
+            }
+        }
+        if self.live_bitmap.test(pointer) {
+            pointer as _
+        } else {
+            null_mut()
+        }
+    }
+
+    /// Mark object in mark bitmap. Returns `true` if object was not marked before.
+    #[inline(always)]
+    pub fn mark(&self, object: *mut HeapObjectHeader) -> bool {
+        !self.mark_bitmap.atomic_test_and_set(object.cast())
+    }
+
+    #[inline(always)]
+    pub fn is_marked(&self, object: *const HeapObjectHeader) -> bool {
+        self.mark_bitmap.test(object.cast())
+    }
+
+    /// Size of object including forwarding pointer.
+    pub unsafe fn object_size(&self, object: *mut HeapObjectHeader) -> usize {
+        if (*object).is_precise() {
+            let region = self.region_of(object.cast());
+            debug_assert!(region.is_humongous_start());
+            let mut size = 0;
+            let mut index = region.index();
+            loop {
+                size += self.region(index).used();
+                index += 1;
+                if index == self.num_regions() || !self.region(index).is_humongous_continuation() {
+                    break;
+                }
+            }
+            size
+        } else {
+            (*object).size() + BROOKS_POINTER_SIZE
+        }
+    }
+
+    /// Returns `true` if thread entered evacuation. If evacuation OOM happened this function waits until all threads
+    /// leave evacuation and returns `false`, from this point object copies are never created.
+    fn enter_evacuation(&self) -> bool {
+        if !self.evac_oom.load(Ordering::Acquire) {
+            self.evac_threads.fetch_add(1, Ordering::AcqRel);
+            if !self.evac_oom.load(Ordering::Acquire) {
+                return true;
+            }
+            self.evac_threads.fetch_sub(1, Ordering::AcqRel);
+        }
+        self.wait_for_evacuation_threads();
+        false
+    }
+
+    fn leave_evacuation(&self) {
+        self.evac_threads.fetch_sub(1, Ordering::AcqRel);
+    }
+
+    fn wait_for_evacuation_threads(&self) {
+        while self.evac_threads.load(Ordering::Acquire) != 0 {
+            std::thread::yield_now();
+        }
+    }
+
+    /// Copy `object` out of collection set. Returns to-space copy, which might be created by another thread.
+    /// When `lab` is `None` object is copied using shared allocation in collector reserve.
+    pub unsafe fn evacuate(
+        &self,
+        object: *mut HeapObjectHeader,
+        lab: Option<&mut LocalAllocBuffer>,
+    ) -> *mut HeapObjectHeader {
+        let current = forwardee(object);
+        if current != object {
+            return current;
+        }
+        if !self.enter_evacuation() {
+            return forwardee(object);
+        }
+        let size = (*object).size();
+        let alloc_size = size + BROOKS_POINTER_SIZE;
+        let (memory, lab) = match lab {
+            Some(lab) => {
+                let mut memory = lab.allocate(alloc_size);
+                if memory.is_null() && self.refill_lab(lab, alloc_size, true) {
+                    memory = lab.allocate(alloc_size);
+                }
+                (memory, Some(lab))
+            }
+            None => {
+                self.lock_free_set();
+                let memory = self
+                    .free_set()
+                    .allocate_collector(&self.regions, alloc_size);
+                self.unlock_free_set();
+                (memory, None)
+            }
+        };
+        if memory.is_null() {
+            if self.verbose > 0 && !self.evac_oom.load(Ordering::Relaxed) {
+                eprintln!(
+                    "[gc] Shenandoah evacuation failed to allocate {} bytes",
+                    alloc_size
+                );
+            }
+            self.evac_oom.store(true, Ordering::Release);
+            self.leave_evacuation();
+            self.wait_for_evacuation_threads();
+            return forwardee(object);
+        }
+        let copy = memory.add(BROOKS_POINTER_SIZE).cast::<HeapObjectHeader>();
+        std::ptr::copy_nonoverlapping(object.cast::<u8>(), copy.cast::<u8>(), size);
+        (*memory.cast::<AtomicUsize>()).store(copy as usize, Ordering::Relaxed);
+        // copy must be visible to other threads before it is published.
+        fence(Ordering::Release);
+        let result = match (*brooks_pointer(object)).compare_exchange(
+            object as usize,
+            copy as usize,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => {
+                self.live_bitmap.atomic_test_and_set(copy.cast());
+                self.mark_bitmap.atomic_test_and_set(copy.cast());
+                copy
+            }
+            Err(winner) => {
+                if let Some(lab) = lab {
+                    lab.rollback(memory, alloc_size);
+                }
+                winner as *mut HeapObjectHeader
+            }
+        };
+        self.leave_evacuation();
+        result
+    }
+
+    /// Evacuate all marked objects in collection set region `index`.
+    pub unsafe fn evacuate_region(&self, index: usize, lab: &mut LocalAllocBuffer) {
+        let region = self.region(index);
+        debug_assert!(region.is_cset());
+        let mut lab = Some(lab);
+        self.mark_bitmap
+            .visit_marked_range(region.bottom(), region.update_watermark(), |object| {
+                if !is_forwarded(object) {
+                    self.evacuate(object, lab.as_deref_mut());
+                }
+            });
+    }
+
+    /// Update references of all marked objects in region `index` to point to to-space copies.
+    ///
+    /// Marked objects above update watermark are evacuated copies, objects allocated by mutators after final mark have
+    /// their references updated by write barrier. Whole region is scanned so region top is not read concurrently with
+    /// mutators allocating in it.
+    pub unsafe fn update_region_refs(&self, index: usize, visitor: &mut dyn Visitor) {
+        let region = self.region(index);
+        self.mark_bitmap
+            .visit_marked_range(region.bottom(), region.end(), |object| {
+                if !is_forwarded(object) {
+                    (*object).get_dyn().trace(visitor);
+                }
+            });
+    }
+
+    /// Clear live bits of objects that did not survive marking. Objects above update watermark were allocated after
+    /// marking and are always live.
+    pub unsafe fn clean_live_bitmap(&self, index: usize) {
+        let region = self.region(index);
+        let begin = region.bottom() as usize - self.start as usize;
+        let end = region.update_watermark() as usize - self.start as usize;
+        let live = self.live_bitmap.begin();
+        let mark = self.mark_bitmap.begin();
+        let first = SpaceBitmap::<8>::offset_to_index(begin);
+        let last = SpaceBitmap::<8>::offset_to_index(end);
+        for word in first..last {
+            (*live.add(word))
+                .fetch_and((*mark.add(word)).load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+        let tail_bits = SpaceBitmap::<8>::offset_bit_index(end);
+        if tail_bits != 0 {
+            // keep live bits of objects above watermark.
+            let keep = !((1usize << tail_bits) - 1);
+            let mask = (*mark.add(last)).load(Ordering::Relaxed) | keep;
+            (*live.add(last)).fetch_and(mask, Ordering::Relaxed);
+        }
+    }
+
+    pub unsafe fn clear_mark_bitmap(&self, index: usize) {
+        let region = self.region(index);
+        self.mark_bitmap.clear_range(region.bottom(), region.end());
+    }
+
+    /// Turn trash region into empty one.
+    pub unsafe fn recycle_region(&self, index: usize) {
+        let region = self.region(index);
+        self.live_bitmap.clear_range(region.bottom(), region.end());
+        self.mark_bitmap.clear_range(region.bottom(), region.end());
+        region.recycle();
+    }
+
+    /// Recycle all trash regions. Returns number of bytes reclaimed.
+    pub unsafe fn recycle_trash(&self) -> usize {
+        let mut reclaimed = 0;
+        for index in 0..self.num_regions() {
+            if self.region(index).is_trash() {
+                reclaimed += self.region(index).used();
+                self.recycle_region(index);
+            }
+        }
+        reclaimed
+    }
 }
@@ -1,11 +1,105 @@
-use super::{collection_set::ShenandoahCollectionSet, region::ShenandoahHeapRegion};
+use std::time::Instant;
+
+use super::{
+    collection_set::ShenandoahCollectionSet, heap::ShenandoahHeap, region::ShenandoahHeapRegion,
+    ShenandoahOptions,
+};
 
 pub struct RegionData {
-    pub region: *mut ShenandoahHeapRegion,
+    pub region: usize,
     pub garbage: usize,
 }
 
-pub trait ShenandoahHeuristics {
+/// Sliding window of the last GC cycle durations.
+pub struct TimeHistory {
+    samples: [f64; 10],
+    count: usize,
+    next: usize,
+}
+
+impl TimeHistory {
+    pub const fn new() -> Self {
+        Self {
+            samples: [0.0; 10],
+            count: 0,
+            next: 0,
+        }
+    }
+
+    pub fn add(&mut self, x: f64) {
+        self.samples[self.next] = x;
+        self.next = (self.next + 1) % self.samples.len();
+        self.count = (self.count + 1).min(self.samples.len());
+    }
+
+    pub fn average(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.samples[..self.count].iter().sum::<f64>() / self.count as f64
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+}
+
+impl Default for TimeHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// State that is shared between all heuristics.
+pub struct HeuristicsData {
+    pub region_data: Vec<RegionData>,
+    pub degenerated_cycles_in_a_row: u32,
+    pub successful_cycles_in_a_row: u32,
+    /// Time of the start of current cycle in seconds since heuristics were created.
+    pub cycle_start: f64,
+    pub last_cycle_end: f64,
+    pub gc_times_learned: usize,
+    pub gc_time_penalties: isize,
+    pub gc_time_history: TimeHistory,
+    pub start: Instant,
+    pub capacity: usize,
+    pub region_size: usize,
+    pub evac_reserve: f64,
+    pub min_free_threshold: f64,
+    pub init_free_threshold: f64,
+    pub garbage_threshold: f64,
+    pub learning_steps: usize,
+}
+
+impl HeuristicsData {
+    pub fn new(options: &ShenandoahOptions, capacity: usize, region_size: usize) -> Self {
+        Self {
+            region_data: vec![],
+            degenerated_cycles_in_a_row: 0,
+            successful_cycles_in_a_row: 0,
+            cycle_start: 0.0,
+            last_cycle_end: 0.0,
+            gc_times_learned: 0,
+            gc_time_penalties: 0,
+            gc_time_history: TimeHistory::new(),
+            start: Instant::now(),
+            capacity,
+            region_size,
+            evac_reserve: options.evac_reserve,
+            min_free_threshold: options.min_free_threshold,
+            init_free_threshold: options.init_free_threshold,
+            garbage_threshold: options.garbage_threshold,
+            learning_steps: options.learning_steps,
+        }
+    }
+
+    pub fn elapsed(&self) -> f64 {
+        self.start.elapsed().as_secs_f64()
+    }
+}
+
+/// Heuristics decide when GC cycle starts and which regions are evacuated.
+pub trait ShenandoahHeuristics: Sized + 'static {
     /// recover from penalties
     const CONCURRENT_ADJUST: isize = -1;
     /// how much to penalize average GC duration history on Degenerated GC
@@ -13,27 +107,279 @@ pub trait ShenandoahHeuristics {
     /// how much to penalize average GC duration history on Full GC
     const FULL_PENALTY: isize = 20;
 
-    fn region_data(&self) -> *mut RegionData;
-    fn set_region_data(&mut self, data: *mut RegionData);
+    fn new(options: &ShenandoahOptions, capacity: usize, region_size: usize) -> Self;
+    fn name(&self) -> &'static str;
 
-    fn degenerated_cycles_in_a_row(&self) -> u32;
-    fn successful_cycles_in_a_row(&self) -> u32;
-    fn cycle_start(&self) -> f64;
-    fn last_cycle_end(&self) -> f64;
+    fn data(&self) -> &HeuristicsData;
+    fn data_mut(&mut self) -> &mut HeuristicsData;
 
-    fn gc_times_learned(&self) -> usize;
-    fn gc_time_penalties(&self) -> isize;
-    fn set_gc_time_penalties(&mut self, x: isize);
+    fn degenerated_cycles_in_a_row(&self) -> u32 {
+        self.data().degenerated_cycles_in_a_row
+    }
+    fn successful_cycles_in_a_row(&self) -> u32 {
+        self.data().successful_cycles_in_a_row
+    }
+    fn cycle_start(&self) -> f64 {
+        self.data().cycle_start
+    }
+    fn last_cycle_end(&self) -> f64 {
+        self.data().last_cycle_end
+    }
 
+    fn gc_times_learned(&self) -> usize {
+        self.data().gc_times_learned
+    }
+    fn gc_time_penalties(&self) -> isize {
+        self.data().gc_time_penalties
+    }
+    fn set_gc_time_penalties(&mut self, x: isize) {
+        self.data_mut().gc_time_penalties = x.clamp(0, 100);
+    }
+
+    /// Select regions from `data` (sorted by garbage, most garbage first) to collection set. `free` is number of bytes
+    /// available for evacuation.
     fn choose_collection_set_from_regiondata(
         &mut self,
+        heap: &ShenandoahHeap,
         set: &mut ShenandoahCollectionSet,
-        data: *mut RegionData,
-        data_size: usize,
+        data: &mut [RegionData],
         free: usize,
     );
 
-    fn should_start_gc(&self, _guaranteed_interval: f64) -> bool {
-        false
+    /// Returns `true` if GC cycle should be started. `available` is number of bytes mutators can still allocate.
+    fn should_start_gc(&mut self, available: usize, allocated_since_gc: usize) -> bool;
+
+    /// Build collection set. Must be invoked in STW pause after marking is finished.
+    fn choose_collection_set(
+        &mut self,
+        heap: &ShenandoahHeap,
+        set: &mut ShenandoahCollectionSet,
+        free: usize,
+    ) {
+        let mut data = std::mem::take(&mut self.data_mut().region_data);
+        data.clear();
+        for index in 0..heap.num_regions() {
+            let region: &ShenandoahHeapRegion = unsafe { heap.region(index) };
+            if region.is_regular() && !region.is_pinned() && region.garbage() > 0 {
+                data.push(RegionData {
+                    region: index,
+                    garbage: region.garbage(),
+                });
+            }
+        }
+        data.sort_by(|a, b| b.garbage.cmp(&a.garbage));
+        self.choose_collection_set_from_regiondata(heap, set, &mut data, free);
+        self.data_mut().region_data = data;
+    }
+
+    /// Build collection set of GC cycle forced by stress mode, see [stress](crate::stress). Every region with live
+    /// objects is evacuated as long as they fit into `free` bytes. Must be invoked in STW pause after marking is finished.
+    fn choose_stress_collection_set(
+        &mut self,
+        heap: &ShenandoahHeap,
+        set: &mut ShenandoahCollectionSet,
+        free: usize,
+    ) {
+        let max_cset = (free as f64 / EVAC_WASTE) as usize;
+        let mut cur_cset = 0;
+        for index in 0..heap.num_regions() {
+            let region: &ShenandoahHeapRegion = unsafe { heap.region(index) };
+            if !region.is_regular() || region.is_pinned() || region.live_data() == 0 {
+                continue;
+            }
+            if cur_cset + region.live_data() > max_cset {
+                break;
+            }
+            cur_cset += region.live_data();
+            set.add_region(region);
+        }
+    }
+
+    fn record_cycle_start(&mut self) {
+        let data = self.data_mut();
+        data.cycle_start = data.elapsed();
+    }
+
+    fn record_cycle_end(&mut self) {
+        let data = self.data_mut();
+        data.last_cycle_end = data.elapsed();
+        let duration = data.last_cycle_end - data.cycle_start;
+        data.gc_time_history.add(duration);
+        data.gc_times_learned += 1;
+    }
+
+    fn record_success_concurrent(&mut self) {
+        self.record_cycle_end();
+        let data = self.data_mut();
+        data.degenerated_cycles_in_a_row = 0;
+        data.successful_cycles_in_a_row += 1;
+        let penalties = self.gc_time_penalties() + Self::CONCURRENT_ADJUST;
+        self.set_gc_time_penalties(penalties);
+    }
+
+    fn record_success_degenerated(&mut self) {
+        self.record_cycle_end();
+        let data = self.data_mut();
+        data.degenerated_cycles_in_a_row += 1;
+        data.successful_cycles_in_a_row = 0;
+        let penalties = self.gc_time_penalties() + Self::DEGENERATE_PENALTY;
+        self.set_gc_time_penalties(penalties);
+    }
+
+    fn record_success_full(&mut self) {
+        self.record_cycle_end();
+        let data = self.data_mut();
+        data.degenerated_cycles_in_a_row = 0;
+        data.successful_cycles_in_a_row += 1;
+        let penalties = self.gc_time_penalties() + Self::FULL_PENALTY;
+        self.set_gc_time_penalties(penalties);
+    }
+}
+
+/// Overhead of evacuation: some space is wasted at the ends of GCLABs.
+const EVAC_WASTE: f64 = 1.2;
+
+/// Default heuristics. Cycle is started when average cycle time would exceed time left until heap is exhausted at current
+/// allocation rate. Collection set is made of regions with the most garbage and bounded by evacuation reserve.
+pub struct ShenandoahAdaptiveHeuristics {
+    data: HeuristicsData,
+    last_check: f64,
+    allocated_at_last_check: usize,
+    allocation_rate: f64,
+}
+
+impl ShenandoahHeuristics for ShenandoahAdaptiveHeuristics {
+    fn new(options: &ShenandoahOptions, capacity: usize, region_size: usize) -> Self {
+        Self {
+            data: HeuristicsData::new(options, capacity, region_size),
+            last_check: 0.0,
+            allocated_at_last_check: 0,
+            allocation_rate: 0.0,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "adaptive"
+    }
+
+    fn data(&self) -> &HeuristicsData {
+        &self.data
+    }
+
+    fn data_mut(&mut self) -> &mut HeuristicsData {
+        &mut self.data
+    }
+
+    fn should_start_gc(&mut self, available: usize, allocated_since_gc: usize) -> bool {
+        let capacity = self.data.capacity as f64;
+        let now = self.data.elapsed();
+        if allocated_since_gc < self.allocated_at_last_check {
+            self.allocated_at_last_check = 0;
+        }
+        let interval = now - self.last_check;
+        if interval > 0.01 {
+            let rate = (allocated_since_gc - self.allocated_at_last_check) as f64 / interval;
+            // exponential moving average smooths allocation spikes.
+            self.allocation_rate = if self.allocation_rate == 0.0 {
+                rate
+            } else {
+                self.allocation_rate * 0.7 + rate * 0.3
+            };
+            self.last_check = now;
+            self.allocated_at_last_check = allocated_since_gc;
+        }
+        let available = available as f64;
+        if available < capacity * self.data.min_free_threshold {
+            return true;
+        }
+        if self.data.gc_times_learned < self.data.learning_steps
+            && available < capacity * self.data.init_free_threshold
+        {
+            return true;
+        }
+        let average_cycle_time = self.data.gc_time_history.average()
+            * (1.0 + self.data.gc_time_penalties as f64 / 100.0);
+        // keep some headroom for allocation spikes during the cycle.
+        let headroom = (available - capacity * 0.05).max(0.0);
+        self.allocation_rate > 0.0 && average_cycle_time > headroom / self.allocation_rate
+    }
+
+    fn choose_collection_set_from_regiondata(
+        &mut self,
+        heap: &ShenandoahHeap,
+        set: &mut ShenandoahCollectionSet,
+        data: &mut [RegionData],
+        free: usize,
+    ) {
+        let region_size = self.data.region_size;
+        let garbage_threshold = (region_size as f64 * self.data.garbage_threshold) as usize;
+        let capacity = self.data.capacity as f64;
+        let max_cset = ((capacity * self.data.evac_reserve / EVAC_WASTE) as usize).min(free);
+        // regions with little garbage are evacuated only if it is needed to reach free threshold.
+        let free_target = (capacity * self.data.min_free_threshold) as usize + max_cset;
+        let min_garbage = free_target.saturating_sub(free);
+        let mut cur_cset = 0;
+        let mut cur_garbage = 0;
+        for entry in data.iter() {
+            let region = unsafe { heap.region(entry.region) };
+            let new_cset = cur_cset + region.live_data();
+            let new_garbage = cur_garbage + entry.garbage;
+            if new_cset > max_cset {
+                break;
+            }
+            if new_garbage < min_garbage || entry.garbage > garbage_threshold {
+                set.add_region(region);
+                cur_cset = new_cset;
+                cur_garbage = new_garbage;
+            }
+        }
+    }
+}
+
+/// Heuristics that start GC cycle as soon as possible and evacuate every region with garbage. Useful for testing.
+pub struct ShenandoahAggressiveHeuristics {
+    data: HeuristicsData,
+}
+
+impl ShenandoahHeuristics for ShenandoahAggressiveHeuristics {
+    fn new(options: &ShenandoahOptions, capacity: usize, region_size: usize) -> Self {
+        Self {
+            data: HeuristicsData::new(options, capacity, region_size),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "aggressive"
+    }
+
+    fn data(&self) -> &HeuristicsData {
+        &self.data
+    }
+
+    fn data_mut(&mut self) -> &mut HeuristicsData {
+        &mut self.data
+    }
+
+    fn should_start_gc(&mut self, _available: usize, _allocated_since_gc: usize) -> bool {
+        true
+    }
+
+    fn choose_collection_set_from_regiondata(
+        &mut self,
+        heap: &ShenandoahHeap,
+        set: &mut ShenandoahCollectionSet,
+        data: &mut [RegionData],
+        free: usize,
+    ) {
+        let max_cset = (free as f64 / EVAC_WASTE) as usize;
+        let mut cur_cset = 0;
+        for entry in data.iter() {
+            let region = unsafe { heap.region(entry.region) };
+            if cur_cset + region.live_data() > max_cset {
+                break;
+            }
+            cur_cset += region.live_data();
+            set.add_region(region);
+        }
     }
 }
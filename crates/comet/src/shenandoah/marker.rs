@@ -0,0 +1,241 @@
+use std::{
+    ptr::{null_mut, NonNull},
+    sync::atomic::{fence, AtomicBool, AtomicPtr},
+};
+
+use atomic::Ordering;
+use parking_lot::Mutex;
+
+use super::heap::{forwardee, LocalAllocBuffer, ShenandoahHeap};
+use crate::{
+    api::{EphemeronEntry, HeapObjectHeader, Visitor},
+    cms::marking_worklist::MarkingWorklists,
+};
+
+/// Concurrent marker of Shenandoah. Marked objects are recorded in heap mark bitmap, live data of region is
+/// accumulated when object is marked.
+pub struct ShenandoahMarker {
+    heap: &'static ShenandoahHeap,
+    marking_worklists: MarkingWorklists,
+    is_marking: AtomicBool,
+    /// Set in final marking pause and in Full GC, ephemerons found by tracing are recorded for
+    /// [ShenandoahMarker::process_ephemerons].
+    collect_ephemerons: AtomicBool,
+    /// Ephemerons whose keys are not known to be alive yet.
+    ephemerons: Mutex<Vec<usize>>,
+    /// Objects that contained ephemerons when they were traced concurrently. Mutator might move ephemerons inside of
+    /// them so these objects are traced again in final marking pause.
+    ephemeron_holders: Mutex<Vec<usize>>,
+}
+
+impl ShenandoahMarker {
+    pub fn new(heap: &'static ShenandoahHeap) -> Self {
+        Self {
+            heap,
+            marking_worklists: MarkingWorklists::new(),
+            is_marking: AtomicBool::new(false),
+            collect_ephemerons: AtomicBool::new(false),
+            ephemerons: Mutex::new(vec![]),
+            ephemeron_holders: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn marking_worklists(&self) -> &MarkingWorklists {
+        &self.marking_worklists
+    }
+
+    /// Returns `true` if marking is in progress and write barrier should be executed.
+    #[inline(always)]
+    pub fn is_marking(&self) -> bool {
+        self.is_marking.load(Ordering::Relaxed)
+    }
+
+    /// Must be invoked only in STW pause.
+    pub fn set_marking(&self, marking: bool) {
+        self.is_marking.store(marking, Ordering::Release);
+    }
+
+    /// Start or stop recording ephemerons for [ShenandoahMarker::process_ephemerons]. Must be invoked only in STW pause.
+    pub fn set_collect_ephemerons(&self, collect: bool) {
+        self.collect_ephemerons.store(collect, Ordering::Release);
+    }
+
+    /// Forget ephemerons recorded by abandoned marking. Must be invoked only in STW pause.
+    pub fn clear_ephemerons(&self) {
+        self.ephemerons.lock().clear();
+        self.ephemeron_holders.lock().clear();
+    }
+
+    pub fn visitor(&self) -> MarkingVisitor<'_> {
+        MarkingVisitor {
+            marker: self,
+            holder: null_mut(),
+        }
+    }
+
+    /// Mark object and push it to marking worklist.
+    pub unsafe fn mark(&self, object: *mut HeapObjectHeader) {
+        if self.heap.mark(object) {
+            let size = self.heap.object_size(object);
+            self.heap.region_of(object.cast()).increase_live_data(size);
+            self.marking_worklists
+                .marking_worklist()
+                .push(object as usize);
+        }
+    }
+
+    /// Mark from-space object that is still referenced from conservative roots. It is kept in heap but never traced,
+    /// its to-space copy must be marked separately.
+    pub unsafe fn mark_stale(&self, object: *mut HeapObjectHeader) {
+        self.heap.mark(object);
+    }
+
+    /// Trace marked objects. Stops after `budget` objects are processed, returns `true` if worklists are empty.
+    pub unsafe fn drain(&self, mut budget: usize) -> bool {
+        let mut visitor = self.visitor();
+        while budget != 0 {
+            let object = match self.marking_worklists.pop() {
+                Some(object) => object as *mut HeapObjectHeader,
+                None => return true,
+            };
+            // object is marked before its fields are read: store that happens after this point is caught by write barrier.
+            fence(Ordering::SeqCst);
+            visitor.holder = object;
+            (*object).get_dyn().trace(&mut visitor);
+            budget -= 1;
+        }
+        self.marking_worklists.is_empty()
+    }
+
+    /// Mark values of ephemerons with live keys until no more keys are found to be alive. Ephemerons with dead keys are
+    /// cleared. Must be invoked only in STW pause after worklists are drained and with
+    /// [ShenandoahMarker::set_collect_ephemerons] enabled.
+    pub unsafe fn process_ephemerons(&self) {
+        let heap = self.heap;
+        let mut visitor = self.visitor();
+        for holder in std::mem::take(&mut *self.ephemeron_holders.lock()) {
+            let holder = holder as *mut HeapObjectHeader;
+            if heap.is_marked(holder) {
+                visitor.holder = holder;
+                (*holder).get_dyn().trace(&mut visitor);
+            }
+        }
+        self.drain(usize::MAX);
+        loop {
+            let mut marked = false;
+            let ephemerons = std::mem::take(&mut *self.ephemerons.lock());
+            let mut pending = vec![];
+            for ephemeron in ephemerons {
+                let entry = ephemeron as *mut EphemeronEntry;
+                let alive = (*entry).resolve_key(|key| {
+                    let key = forwardee(key);
+                    if heap.is_marked(key) {
+                        key
+                    } else {
+                        null_mut()
+                    }
+                });
+                if alive {
+                    (*entry).mark_value(&mut visitor);
+                    marked = true;
+                } else {
+                    pending.push(ephemeron);
+                }
+            }
+            self.ephemerons.lock().extend(pending);
+            if !marked {
+                break;
+            }
+            // values might reference keys of other ephemerons
+            self.drain(usize::MAX);
+        }
+        for ephemeron in std::mem::take(&mut *self.ephemerons.lock()) {
+            (*(ephemeron as *mut EphemeronEntry)).clear();
+        }
+    }
+}
+
+/// Store `new` to `slot` if it still contains `old`. Slot might be concurrently updated by mutator, in that case mutator
+/// value wins.
+#[inline(always)]
+unsafe fn update_slot(
+    slot: &mut NonNull<HeapObjectHeader>,
+    old: *mut HeapObjectHeader,
+    new: *mut HeapObjectHeader,
+) {
+    let slot = &*(slot as *mut NonNull<HeapObjectHeader> as *const AtomicPtr<HeapObjectHeader>);
+    let _ = slot.compare_exchange(old, new, Ordering::AcqRel, Ordering::Relaxed);
+}
+
+/// Visitor that marks objects and pushes them to marker worklist. References to from-space objects are updated to
+/// point to their copies.
+pub struct MarkingVisitor<'a> {
+    marker: &'a ShenandoahMarker,
+    /// Object that is traced by this visitor or null when roots are traced.
+    holder: *mut HeapObjectHeader,
+}
+
+impl<'a> Visitor for MarkingVisitor<'a> {
+    fn mark_object(&mut self, root: &mut NonNull<HeapObjectHeader>) {
+        unsafe {
+            let object = root.as_ptr();
+            let resolved = forwardee(object);
+            if resolved != object {
+                update_slot(root, object, resolved);
+            }
+            self.marker.mark(resolved);
+        }
+    }
+
+    fn mark_ephemeron(&mut self, ephemeron: &mut EphemeronEntry) {
+        if self.marker.collect_ephemerons.load(Ordering::Acquire) {
+            self.marker
+                .ephemerons
+                .lock()
+                .push(ephemeron as *mut EphemeronEntry as usize);
+        } else if !self.holder.is_null() {
+            self.marker
+                .ephemeron_holders
+                .lock()
+                .push(self.holder as usize);
+        } else {
+            // roots and constraints traced in init mark pause are not traced again, ephemeron is treated as strong
+            // reference.
+            if let Some(mut key) = ephemeron.key() {
+                self.mark_object(&mut key);
+            }
+            ephemeron.mark_value(self);
+        }
+    }
+}
+
+/// Visitor that updates references to from-space objects. Objects in collection set that were not yet copied are
+/// evacuated when evacuation is still running.
+pub struct UpdateRefsVisitor<'a> {
+    heap: &'a ShenandoahHeap,
+    lab: Option<&'a mut LocalAllocBuffer>,
+}
+
+impl<'a> UpdateRefsVisitor<'a> {
+    pub fn new(heap: &'a ShenandoahHeap, lab: Option<&'a mut LocalAllocBuffer>) -> Self {
+        Self { heap, lab }
+    }
+}
+
+impl<'a> Visitor for UpdateRefsVisitor<'a> {
+    fn mark_object(&mut self, root: &mut NonNull<HeapObjectHeader>) {
+        unsafe {
+            let object = root.as_ptr();
+            let mut resolved = forwardee(object);
+            if resolved == object
+                && self.heap.is_evacuation_in_progress()
+                && self.heap.in_cset(object)
+            {
+                resolved = self.heap.evacuate(object, self.lab.as_deref_mut());
+            }
+            if resolved != object {
+                update_slot(root, object, resolved);
+            }
+        }
+    }
+}
@@ -1,6 +1,6 @@
-use std::{mem::size_of, sync::atomic::AtomicPtr};
+use std::{mem::size_of, ptr::null_mut};
 
-use atomic::Atomic;
+use atomic::{Atomic, Ordering};
 
 use crate::utils::{align_down, align_up, align_usize, formatted_size};
 
@@ -53,6 +53,10 @@ use crate::utils::{align_down, align_up, align_usize, formatted_size};
  quick reclamation without actual cleaning up.
  Transition from "Trash" to "Empty" is recycling. It cleans up the regions and corresponding metadata.
  Can be done asynchronously and in bulk.
+ Pinned regions are not separate states in this implementation: region is pinned for the duration of GC cycle
+ when conservative stack scan finds a pointer into it. Collection set region that still has conservative references
+ to from-space copies at the end of the cycle goes to "Retained" state instead of "Trash", it is reclaimed at one
+ of the next cycles once no conservative references point to it.
  Note how internal transitions disallow logic bugs:
    a) No region can go Empty, unless properly reclaimed/recycled;
    b) No region can go Uncommitted, unless reclaimed/recycled first;
@@ -66,28 +70,25 @@ use crate::utils::{align_down, align_up, align_usize, formatted_size};
    i) Empty cannot go Trash, avoiding useless work;
    j) ...
 */
-#[allow(dead_code)]
+
 pub struct ShenandoahHeapRegion {
     index: usize,
     bottom: *mut u8,
     end: *mut u8,
-
-    new_top: *mut u8,
-    empty_time: f64,
-
-    state: RegionState,
-
     top: *mut u8,
 
-    tlab_allocs: usize,
-    gclab_allocs: usize,
+    state: RegionState,
+    /// Set when conservative root points into this region during GC cycle. Objects in pinned region are never moved.
+    pinned: bool,
 
     live_data: Atomic<usize>,
-    update_watermark: AtomicPtr<u8>,
+    /// Value of `top` at final mark. Objects below watermark existed when marking finished, objects above it
+    /// were allocated after final mark or are evacuated copies.
+    update_watermark: *mut u8,
 }
 
 #[derive(Default, Clone, Copy)]
-pub struct ShenandoahOptions {
+pub struct ShenandoahRegionSizes {
     pub region_size_bytes: usize,
     pub region_size_words: usize,
     pub region_size_bytes_shift: usize,
@@ -101,9 +102,9 @@ pub struct ShenandoahOptions {
     pub max_tlab_size_bytes: usize,
     pub max_heap_size: usize,
 }
-impl std::fmt::Debug for ShenandoahOptions {
+impl std::fmt::Debug for ShenandoahRegionSizes {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "ShenandoahOptions:")?;
+        writeln!(f, "ShenandoahRegionSizes:")?;
         writeln!(
             f,
             "\tregion_size_bytes: {}",
@@ -150,6 +151,212 @@ impl std::fmt::Debug for ShenandoahOptions {
     }
 }
 impl ShenandoahHeapRegion {
+    pub fn new(index: usize, bottom: *mut u8, size: usize) -> Self {
+        Self {
+            index,
+            bottom,
+            end: unsafe { bottom.add(size) },
+            top: bottom,
+            state: RegionState::EmptyUncommitted,
+            pinned: false,
+            live_data: Atomic::new(0),
+            update_watermark: bottom,
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn bottom(&self) -> *mut u8 {
+        self.bottom
+    }
+
+    pub fn end(&self) -> *mut u8 {
+        self.end
+    }
+
+    pub fn top(&self) -> *mut u8 {
+        self.top
+    }
+
+    pub fn set_top(&mut self, top: *mut u8) {
+        debug_assert!(top >= self.bottom && top <= self.end);
+        self.top = top;
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.end as usize - self.bottom as usize
+    }
+
+    pub fn used(&self) -> usize {
+        self.top as usize - self.bottom as usize
+    }
+
+    pub fn free(&self) -> usize {
+        self.end as usize - self.top as usize
+    }
+
+    /// Bump allocate `size` bytes in this region. Returns null if region does not have enough space.
+    pub fn allocate(&mut self, size: usize) -> *mut u8 {
+        if self.free() < size {
+            return null_mut();
+        }
+        let result = self.top;
+        self.top = unsafe { self.top.add(size) };
+        result
+    }
+
+    pub fn live_data(&self) -> usize {
+        self.live_data.load(Ordering::Relaxed)
+    }
+
+    pub fn increase_live_data(&self, bytes: usize) {
+        self.live_data.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn clear_live_data(&self) {
+        self.live_data.store(0, Ordering::Relaxed);
+    }
+
+    pub fn garbage(&self) -> usize {
+        self.used().saturating_sub(self.live_data())
+    }
+
+    pub fn update_watermark(&self) -> *mut u8 {
+        self.update_watermark
+    }
+
+    pub fn set_update_watermark(&mut self, watermark: *mut u8) {
+        self.update_watermark = watermark;
+    }
+
+    pub fn is_pinned(&self) -> bool {
+        self.pinned
+    }
+
+    pub fn set_pinned(&mut self, pinned: bool) {
+        self.pinned = pinned;
+    }
+
+    pub fn state(&self) -> RegionState {
+        self.state
+    }
+
+    pub fn is_empty(&self) -> bool {
+        matches!(
+            self.state,
+            RegionState::EmptyUncommitted | RegionState::EmptyCommitted
+        )
+    }
+
+    pub fn is_regular(&self) -> bool {
+        self.state == RegionState::Regular
+    }
+
+    pub fn is_humongous_start(&self) -> bool {
+        self.state == RegionState::HumongousStart
+    }
+
+    pub fn is_humongous_continuation(&self) -> bool {
+        self.state == RegionState::HumongousCont
+    }
+
+    pub fn is_humongous(&self) -> bool {
+        self.is_humongous_start() || self.is_humongous_continuation()
+    }
+
+    pub fn is_cset(&self) -> bool {
+        self.state == RegionState::CSet
+    }
+
+    pub fn is_trash(&self) -> bool {
+        self.state == RegionState::Trash
+    }
+
+    pub fn is_retained(&self) -> bool {
+        self.state == RegionState::Retained
+    }
+
+    /// Returns `true` if region might contain objects.
+    pub fn is_active(&self) -> bool {
+        !self.is_empty() && !self.is_trash()
+    }
+
+    fn transition(&mut self, allowed: &[RegionState], to: RegionState) {
+        if !allowed.contains(&self.state) {
+            panic!(
+                "Illegal region state transition for region #{}: {:?} -> {:?}",
+                self.index, self.state, to
+            );
+        }
+        self.state = to;
+    }
+
+    pub fn make_regular_allocation(&mut self) {
+        self.transition(
+            &[
+                RegionState::EmptyUncommitted,
+                RegionState::EmptyCommitted,
+                RegionState::Regular,
+            ],
+            RegionState::Regular,
+        );
+    }
+
+    /// Turn collection set region back to regular one, used when GC cycle is abandoned by full GC.
+    pub fn make_regular_bypass(&mut self) {
+        self.transition(
+            &[RegionState::CSet, RegionState::Regular],
+            RegionState::Regular,
+        );
+    }
+
+    pub fn make_humongous_start(&mut self) {
+        self.transition(
+            &[RegionState::EmptyUncommitted, RegionState::EmptyCommitted],
+            RegionState::HumongousStart,
+        );
+    }
+
+    pub fn make_humongous_cont(&mut self) {
+        self.transition(
+            &[RegionState::EmptyUncommitted, RegionState::EmptyCommitted],
+            RegionState::HumongousCont,
+        );
+    }
+
+    pub fn make_cset(&mut self) {
+        debug_assert!(!self.pinned, "pinned region can't be in collection set");
+        self.transition(&[RegionState::Regular], RegionState::CSet);
+    }
+
+    pub fn make_retained(&mut self) {
+        self.transition(&[RegionState::CSet], RegionState::Retained);
+    }
+
+    pub fn make_trash(&mut self) {
+        debug_assert!(!self.pinned, "pinned region can't be reclaimed");
+        self.transition(
+            &[
+                RegionState::Regular,
+                RegionState::HumongousStart,
+                RegionState::HumongousCont,
+                RegionState::CSet,
+                RegionState::Retained,
+            ],
+            RegionState::Trash,
+        );
+    }
+
+    /// Make trash region empty again. Bitmaps covering this region must be cleared by the caller.
+    pub fn recycle(&mut self) {
+        self.transition(&[RegionState::Trash], RegionState::EmptyCommitted);
+        self.top = self.bottom;
+        self.update_watermark = self.bottom;
+        self.clear_live_data();
+    }
+
     pub const MIN_REGION_SIZE: usize = 256 * 1024;
     pub const MIN_NUM_REGIONS: usize = 10;
     pub const MAX_REGION_SIZE: usize = 32 * 1024 * 1024;
@@ -158,8 +365,8 @@ impl ShenandoahHeapRegion {
         min_region_size: Option<usize>,
         target_num_regions: Option<usize>,
         max_region_size: Option<usize>,
-    ) -> ShenandoahOptions {
-        let mut opts = ShenandoahOptions::default();
+    ) -> ShenandoahRegionSizes {
+        let mut opts = ShenandoahRegionSizes::default();
         let mut region_size;
         let min_region_size = min_region_size
             .map(|x| {
@@ -211,8 +418,6 @@ impl ShenandoahHeapRegion {
     }
 }
 
-/// Shenandoah in OpenJDK actually supports pinning but we do not support it there.
-
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub enum RegionState {
     EmptyUncommitted,
@@ -221,5 +426,8 @@ pub enum RegionState {
     HumongousStart,
     HumongousCont,
     CSet,
+    /// Former collection set region that is kept alive because conservative roots might point to from-space
+    /// copies of its objects.
+    Retained,
     Trash,
 }
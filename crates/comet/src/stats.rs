@@ -0,0 +1,127 @@
+//! GC statistics.
+//!
+//! Every GC policy records its cycles and pauses into [HeapStats] which is returned by
+//! [GcBase::stats](crate::gc_base::GcBase::stats) and [MutatorRef::heap_stats](crate::mutator::MutatorRef::heap_stats).
+//! Byte counts are measured with granularity of underlying space (e.g Immix accounts lines and blocks instead of
+//! objects).
+use std::time::Duration;
+
+/// Structured GC telemetry of a heap.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Number of finished GC cycles.
+    pub collections: usize,
+    /// Number of finished GC cycles that collected only young objects.
+    pub minor_collections: usize,
+    /// Number of finished GC cycles that collected the whole heap.
+    pub full_collections: usize,
+    /// Number of stop-the-world pauses. Concurrent policies perform more than one pause per GC cycle.
+    pub pauses: usize,
+    /// Sum of all pause times.
+    pub total_pause_time: Duration,
+    /// Duration of last pause.
+    pub last_pause_time: Duration,
+    /// Sum of mark phase durations.
+    pub total_mark_time: Duration,
+    /// Duration of mark phase of last GC cycle. Copying policies report copying phase as mark phase.
+    pub last_mark_time: Duration,
+    /// Sum of sweep phase durations.
+    pub total_sweep_time: Duration,
+    /// Duration of sweep phase of last GC cycle.
+    pub last_sweep_time: Duration,
+    /// Sum of times mutators needed to reach safepoint.
+    pub total_time_to_safepoint: Duration,
+    /// Time mutators needed to reach safepoint of last pause.
+    pub last_time_to_safepoint: Duration,
+    /// Number of bytes allocated since heap was created.
+    pub bytes_allocated: usize,
+    /// Number of bytes that survived last GC cycle.
+    pub live_bytes: usize,
+    /// Heap usage at which next GC cycle is triggered.
+    pub target_footprint: usize,
+    /// Number of bytes allocated in large object space.
+    pub large_object_bytes: usize,
+}
+
+impl HeapStats {
+    /// Record stop-the-world pause.
+    pub fn record_pause(&mut self, pause: Duration, time_to_safepoint: Duration) {
+        self.pauses += 1;
+        self.total_pause_time += pause;
+        self.last_pause_time = pause;
+        self.total_time_to_safepoint += time_to_safepoint;
+        self.last_time_to_safepoint = time_to_safepoint;
+    }
+
+    /// Record finished GC cycle. `used` is heap usage before sweeping and `live_bytes` is heap usage after it.
+    pub fn record_cycle(
+        &mut self,
+        minor: bool,
+        mark: Duration,
+        sweep: Duration,
+        used: usize,
+        live_bytes: usize,
+    ) {
+        self.collections += 1;
+        if minor {
+            self.minor_collections += 1;
+        } else {
+            self.full_collections += 1;
+        }
+        self.total_mark_time += mark;
+        self.last_mark_time = mark;
+        self.total_sweep_time += sweep;
+        self.last_sweep_time = sweep;
+        // everything above live bytes of previous cycle was allocated since then.
+        self.bytes_allocated += used.saturating_sub(self.live_bytes);
+        self.live_bytes = live_bytes;
+    }
+
+    /// Returns statistics updated with current heap state. `used` is number of bytes allocated in heap right now.
+    pub fn snapshot(
+        &self,
+        used: usize,
+        target_footprint: usize,
+        large_object_bytes: usize,
+    ) -> Self {
+        Self {
+            bytes_allocated: self.bytes_allocated + used.saturating_sub(self.live_bytes),
+            target_footprint,
+            large_object_bytes,
+            ..*self
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocated_bytes_are_accumulated_across_cycles() {
+        let mut stats = HeapStats::default();
+        stats.record_pause(Duration::from_millis(2), Duration::from_millis(1));
+        stats.record_cycle(true, Duration::from_millis(1), Duration::ZERO, 100, 40);
+        stats.record_pause(Duration::from_millis(4), Duration::from_millis(1));
+        stats.record_cycle(
+            false,
+            Duration::from_millis(3),
+            Duration::from_millis(1),
+            140,
+            30,
+        );
+        let stats = stats.snapshot(50, 200, 10);
+        assert_eq!(stats.collections, 2);
+        assert_eq!(stats.minor_collections, 1);
+        assert_eq!(stats.full_collections, 1);
+        assert_eq!(stats.total_pause_time, Duration::from_millis(6));
+        assert_eq!(stats.last_pause_time, Duration::from_millis(4));
+        assert_eq!(stats.total_mark_time, Duration::from_millis(4));
+        assert_eq!(stats.total_time_to_safepoint, Duration::from_millis(2));
+        // 100 before first cycle, 100 between cycles and 20 since last cycle
+        assert_eq!(stats.bytes_allocated, 220);
+        assert_eq!(stats.live_bytes, 30);
+        assert_eq!(stats.target_footprint, 200);
+        assert_eq!(stats.large_object_bytes, 10);
+    }
+}
@@ -1 +1,926 @@
+//! # StickyImmix: Generational Immix with sticky mark bits
+//!
+//! StickyImmix uses the same heap layout as [Immix](crate::immix) but performs generational collections without copying nursery.
+//! Objects that survived GC keep their mark ("sticky" mark bits) and are considered old: *nursery* collection
+//! traces only objects that were allocated since the last GC and frees only dead young objects. Line marks are sticky too so
+//! lines occupied by old objects are never reused before full collection.
+//!
+//! Old objects that are modified after GC are logged to remembered set by object-logging write barrier (`ParentKnown` header bit
+//! is used as "logged" bit) and are used as roots during nursery collection. You ***must*** invoke [MutatorRef::write_barrier] after storing GC pointer into GC object.
+//!
+//! *Full* collection marks entire heap and is performed when nursery collection does not free enough memory or when requested explicitly
+//! by [MutatorRef::collect].
+//!
+//! # Colours
+//!
+//! All objects are allocated with [GC_WHITE] colour. Marked (old) objects have `mark_color` which flips between [GC_BLACK] and [GC_GREY]
+//! on each full collection, so at the start of full GC all objects are unmarked without touching them.
 
+use crate::{
+    api::{
+        vtable_of, Collectable, EphemeronEntry, Gc, HeapObjectHeader, Trace, Visitor, Weak,
+        GC_BLACK, GC_GREY, GC_WHITE,
+    },
+    gc_base::{
+        AllocError, AllocationSpace, GcBase, MarkingConstraint, MarkingConstraintRuns, NoHelp,
+        NoOpStackDecoder, NoReadBarrier, StackValueDecoder,
+    },
+    immix::{
+        block::{BlockState, ImmixBlock, IMMIX_BLOCK_SIZE},
+        space::ImmixSpace,
+        GetImmixSpace, ImmixAllocator,
+    },
+    large_space::{LargeObjectSpace, PreciseAllocation},
+    make_small_type_id,
+    mutator::{JoinData, Mutator, MutatorRef, ThreadState},
+    poison::FreedMemory,
+    safepoint::{GlobalSafepoint, SafepointScope},
+    small_type_id,
+    stats::HeapStats,
+    stress::GcStress,
+    utils::{align_usize, formatted_size, stack_bounds::StackBounds},
+};
+use atomic::Ordering;
+use parking_lot::{lock_api::RawMutex, RawMutex as Lock};
+use std::{
+    any::TypeId,
+    cell::UnsafeCell,
+    mem::size_of,
+    ptr::{null_mut, NonNull},
+    sync::Arc,
+};
+
+/// StickyImmix GC implementation. Read top level module documentation for more information
+pub struct StickyImmix<Decoder: 'static + StackValueDecoder = NoOpStackDecoder> {
+    space: &'static ImmixSpace,
+    pub(crate) global_heap_lock: Lock,
+    pub(crate) large_space_lock: Lock,
+    pub(crate) large_space: LargeObjectSpace,
+    pub(crate) mutators: Vec<*mut Mutator<Self>>,
+    pub(crate) safepoint: GlobalSafepoint,
+    pub(crate) mark_stack: Vec<*mut HeapObjectHeader>,
+    pub(crate) verbose: u8,
+    pub(crate) mark_color: u8,
+    /// Old objects that were modified since the last GC.
+    remembered_set: Vec<*mut HeapObjectHeader>,
+    remembered_set_lock: Lock,
+    total_gcs: usize,
+    weak_refs: Vec<Weak<dyn Collectable, Self>>,
+    /// Ephemerons found by marking whose keys are not known to be alive yet.
+    ephemerons: Vec<*mut EphemeronEntry>,
+    constraints: Vec<Box<dyn MarkingConstraint>>,
+    growth_multiplier: f64,
+    full_gc_threshold: f64,
+    stats: HeapStats,
+}
+
+impl<Decoder: StackValueDecoder> GetImmixSpace for StickyImmix<Decoder> {
+    fn immix_space(&self) -> &'static ImmixSpace {
+        self.space
+    }
+}
+
+pub struct StickyImmixOptions {
+    /// Determines by how much heap grows after full GC cycle. By default set to 1.75
+    pub growth_multiplier: f64,
+    /// Full GC is performed when heap after nursery collection occupies more than this fraction of heap size
+    /// that triggers GC. By default set to 0.875
+    pub full_gc_threshold: f64,
+    /// Entire Immix heap area size. Set to 128MB by default, minimal supported value is 4MB (single chunk size)
+    pub heap_size: usize,
+    /// Initial heap size before triggering GC cycle. By default set to 32MB
+    pub initial_size: usize,
+    /// Minimal heap size before triggering GC cycle. By default set to 4MB, if `initial_size` is lesser than min_heap_size then it is set to min_heap_size.
+    pub min_heap_size: usize,
+    /// Maximal heap size before triggering GC cycle. By default set to 128MB
+    pub max_heap_size: usize,
+    /// Enables verbose loggig to stdout.
+    pub verbose: u8,
+    /// GC stress mode, see [stress](crate::stress). Overridden by `COMET_GC_STRESS` environment variable. Disabled
+    /// by default.
+    pub stress: GcStress,
+    /// What happens to memory of dead objects, see [poison](crate::poison). Overridden by `COMET_FREED_MEMORY`
+    /// environment variable. Memory is reused by default.
+    pub freed_memory: FreedMemory,
+}
+
+impl StickyImmixOptions {
+    /// Set growth multiplier. Panics if x <= 1
+    pub fn with_growth_multiplier(mut self, x: f64) -> Self {
+        if x <= 1.0 {
+            panic!("Growth multiplier is too small")
+        }
+        self.growth_multiplier = x;
+        self
+    }
+    /// Set full GC threshold. Panics if x is not in `(0, 1]` range.
+    pub fn with_full_gc_threshold(mut self, x: f64) -> Self {
+        if x <= 0.0 || x > 1.0 {
+            panic!("Full GC threshold must be in (0, 1] range");
+        }
+        self.full_gc_threshold = x;
+        self
+    }
+
+    pub fn with_heap_size(mut self, x: usize) -> Self {
+        if x < 4 * 1024 * 1024 {
+            panic!("Heap size too small; Minimal heap size is 1 Immix chunk which is 4MB");
+        }
+        self.heap_size = x;
+        self
+    }
+
+    pub fn with_initial_size(mut self, x: usize) -> Self {
+        self.initial_size = x;
+        self
+    }
+
+    pub fn with_min_heap_size(mut self, x: usize) -> Self {
+        self.min_heap_size = x;
+        self
+    }
+
+    pub fn with_max_heap_size(mut self, x: usize) -> Self {
+        self.max_heap_size = x;
+        self
+    }
+    pub fn with_verbose(mut self, x: u8) -> Self {
+        self.verbose = x;
+        self
+    }
+
+    /// Set GC stress mode.
+    pub fn with_stress(mut self, x: GcStress) -> Self {
+        self.stress = x;
+        self
+    }
+
+    /// Set what happens to memory of dead objects.
+    pub fn with_freed_memory(mut self, x: FreedMemory) -> Self {
+        self.freed_memory = x;
+        self
+    }
+}
+
+impl Default for StickyImmixOptions {
+    fn default() -> Self {
+        Self {
+            growth_multiplier: 1.75,
+            full_gc_threshold: 0.875,
+            heap_size: 128 * 1024 * 1024,
+            min_heap_size: 4 * 1024 * 1024,
+            max_heap_size: 128 * 1024 * 1024,
+            initial_size: 32 * 1024 * 1024,
+            verbose: 0,
+            stress: GcStress::Disabled,
+            freed_memory: FreedMemory::Reuse,
+        }
+    }
+}
+
+pub fn instantiate_sticky_immix<Decoder: StackValueDecoder>(
+    options: StickyImmixOptions,
+) -> MutatorRef<StickyImmix<Decoder>> {
+    let space = Box::leak(Box::new(ImmixSpace::new(
+        options.heap_size,
+        options.initial_size,
+        options.min_heap_size,
+        options.max_heap_size,
+        options.verbose > 0,
+    )));
+    space.init_bitmap();
+    space.freed_memory = FreedMemory::from_env_or(options.freed_memory);
+    let immix = Arc::new(UnsafeCell::new(StickyImmix {
+        space,
+        large_space: LargeObjectSpace::new(),
+        large_space_lock: Lock::INIT,
+        verbose: options.verbose,
+        global_heap_lock: Lock::INIT,
+        mutators: vec![],
+        safepoint: GlobalSafepoint::new(),
+        mark_color: GC_BLACK,
+        mark_stack: Vec::new(),
+        remembered_set: vec![],
+        remembered_set_lock: Lock::INIT,
+        total_gcs: 0,
+        weak_refs: vec![],
+        ephemerons: vec![],
+        constraints: vec![],
+        growth_multiplier: options.growth_multiplier,
+        full_gc_threshold: options.full_gc_threshold,
+        stats: HeapStats::default(),
+    }));
+    let href = unsafe { &mut *immix.get() };
+    href.large_space.freed_memory = href.space.freed_memory;
+    let join_data = JoinData::new();
+    let mut mutator = MutatorRef::new(Mutator::new(
+        immix.clone(),
+        &href.safepoint,
+        join_data.internal.clone(),
+    ));
+    href.mutators.push(&mut *mutator);
+    href.safepoint
+        .n_mutators
+        .fetch_add(1, atomic::Ordering::Relaxed);
+    mutator.stack_bounds = StackBounds::current_thread_stack_bounds();
+    mutator.state_set(ThreadState::Safe, ThreadState::Unsafe);
+
+    mutator.set_gc_stress(GcStress::from_env_or(options.stress));
+    mutator
+}
+
+impl<Decoder: StackValueDecoder> StickyImmix<Decoder> {
+    unsafe fn after_mark_constraints(&mut self) {
+        let this = self as *mut Self;
+        (*this).constraints.retain_mut(|constraint| {
+            if constraint.is_over() {
+                false
+            } else {
+                if constraint.runs_at() == MarkingConstraintRuns::AfterMark {
+                    constraint.run(self);
+                }
+                true
+            }
+        });
+    }
+    unsafe fn before_mark_constraints(&mut self) {
+        let this = self as *mut Self;
+        (*this).constraints.retain_mut(|constraint| {
+            if constraint.is_over() {
+                false
+            } else {
+                if constraint.runs_at() == MarkingConstraintRuns::BeforeMark {
+                    constraint.run(self);
+                }
+                true
+            }
+        });
+    }
+    #[cold]
+    unsafe fn collect_and_alloc<T: Collectable + Sized + 'static>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        mut value: T,
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
+        self.collect_alloc_failure(mutator, &mut [&mut value]);
+
+        mutator.tlab.set_emergency_collection(true);
+        let value = self.alloc_inline(mutator, value, AllocationSpace::New);
+        mutator.tlab.set_emergency_collection(false);
+        value
+    }
+
+    #[cold]
+    unsafe fn collect_and_alloc_raw(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        size: usize,
+        vtable: usize,
+        type_id: TypeId,
+    ) -> *mut HeapObjectHeader {
+        self.collect_alloc_failure(mutator, &mut []);
+
+        mutator.tlab.set_emergency_collection(true);
+        let value = self.allocate_raw(mutator, size, type_id, vtable);
+        mutator.tlab.set_emergency_collection(false);
+        value
+    }
+
+    /// Put old object into remembered set. Invoked by write barrier only once per object between GC cycles.
+    #[cold]
+    fn log_object(&mut self, object: *mut HeapObjectHeader) {
+        unsafe {
+            self.remembered_set_lock.lock();
+            if !(*object).parent_known_bit() {
+                (*object).set_parent_known_bit(true);
+                self.remembered_set.push(object);
+            }
+            self.remembered_set_lock.unlock();
+        }
+    }
+
+    unsafe fn walk_stack(&mut self, mut start: *mut *mut u8, mut end: *mut *mut u8) {
+        if end < start {
+            std::mem::swap(&mut start, &mut end);
+        }
+        let mut cursor = start;
+        while cursor < end {
+            let pointer = cursor.read();
+            if pointer.is_null() {
+                cursor = cursor.add(1);
+                continue;
+            }
+            let pointer = Decoder::decode(pointer);
+            if self.space.has_address(pointer) && pointer as usize % 8 == 0 {
+                let block = ImmixBlock::from_object(pointer);
+                if (*block).state != BlockState::Unallocated
+                    && self.space.mark_bitmap.test(pointer)
+                {
+                    let mut header = NonNull::new_unchecked(pointer.cast::<HeapObjectHeader>());
+                    if self.verbose > 1 {
+                        eprintln!(
+                            "[GC] Found Immix space object {:p} from {:p} at {:p}",
+                            header, pointer, cursor
+                        );
+                    }
+                    self.mark_object(&mut header);
+                    cursor = cursor.add(1);
+                    continue;
+                }
+            }
+
+            if let Some(mut header) = NonNull::new(self.large_space.contains(pointer)) {
+                self.mark_object(&mut header);
+            }
+
+            cursor = cursor.add(1);
+        }
+    }
+
+    /// Mark objects reachable from roots. When `full` is false old objects are not traced and remembered set is used as additional roots.
+    unsafe fn mark(&mut self, full: bool, keep: &mut [&mut dyn Trace]) {
+        self.large_space.prepare_for_marking(!full);
+        self.large_space.prepare_for_conservative_scan();
+        self.space.prepare(full);
+        for i in 0..self.mutators.len() {
+            let mutator = self.mutators[i];
+            (*mutator).reset_tlab();
+            self.walk_stack(
+                (*mutator).stack_bounds.origin.cast(),
+                (*mutator).last_sp.get().cast(),
+            );
+            let (start, end) = (*mutator).saved_registers();
+            self.walk_stack(start, end);
+            (*mutator).shadow_stack().walk(|entry| {
+                entry.trace(self);
+            });
+        }
+        self.before_mark_constraints();
+        for object in keep {
+            object.trace(self);
+        }
+        for object in std::mem::take(&mut self.remembered_set) {
+            (*object).set_parent_known_bit(false);
+            if !full {
+                (*object).get_dyn().trace(self);
+            }
+        }
+        while let Some(object) = self.mark_stack.pop() {
+            (*object).get_dyn().trace(self);
+        }
+        self.after_mark_constraints();
+        while let Some(object) = self.mark_stack.pop() {
+            (*object).get_dyn().trace(self);
+        }
+        self.process_ephemerons();
+    }
+
+    /// Mark values of ephemerons with live keys until no more keys are found to be alive. Ephemerons with dead keys are
+    /// cleared. Old keys are alive during nursery GC since they have mark colour.
+    unsafe fn process_ephemerons(&mut self) {
+        let mark_color = self.mark_color;
+        loop {
+            let mut marked = false;
+            for ephemeron in std::mem::take(&mut self.ephemerons) {
+                let alive = (*ephemeron).resolve_key(|key| {
+                    if (*key).get_color() == mark_color {
+                        key
+                    } else {
+                        null_mut()
+                    }
+                });
+                if alive {
+                    (*ephemeron).mark_value(self);
+                    marked = true;
+                } else {
+                    self.ephemerons.push(ephemeron);
+                }
+            }
+            if !marked {
+                break;
+            }
+            // values might reference keys of other ephemerons
+            while let Some(object) = self.mark_stack.pop() {
+                (*object).get_dyn().trace(self);
+            }
+        }
+        for ephemeron in self.ephemerons.drain(..) {
+            (*ephemeron).clear();
+        }
+    }
+
+    unsafe fn sweep(&mut self, full: bool) {
+        let mark_color = self.mark_color;
+        self.weak_refs.retain_mut(|object| {
+            let header = object.base();
+            if (*header).get_color() == mark_color {
+                object.after_mark(|header| {
+                    if (*header).get_color() == mark_color {
+                        header
+                    } else {
+                        null_mut()
+                    }
+                });
+                true
+            } else {
+                false
+            }
+        });
+        self.space.num_bytes_allocated.store(0, Ordering::Relaxed);
+        self.large_space.prepare_for_allocation(!full);
+        self.large_space.sweep_with_finalizer(|object| {
+            if (*object).finalizable_bit() {
+                (*object).get_dyn().finalize();
+            }
+            false
+        });
+        self.space.release_unmarked(mark_color);
+    }
+
+    /// Performs single GC cycle. Must be invoked only when all mutators are suspended and heap locks are acquired.
+    unsafe fn collect_internal(&mut self, mut full: bool, keep: &mut [&mut dyn Trace]) {
+        loop {
+            let time = std::time::Instant::now();
+            let prev =
+                self.space.num_bytes_allocated.load(Ordering::Relaxed) + self.large_space.bytes;
+            if full {
+                // all objects become unmarked
+                self.mark_color = if self.mark_color == GC_BLACK {
+                    GC_GREY
+                } else {
+                    GC_BLACK
+                };
+                self.large_space.begin_marking(true);
+                self.safepoint.next_epoch();
+            }
+            let mark_phase = std::time::Instant::now();
+            self.mark(full, keep);
+            let mark_phase = mark_phase.elapsed();
+            let sweep_phase = std::time::Instant::now();
+            self.sweep(full);
+            let sweep_phase = sweep_phase.elapsed();
+            let bytes_allocated =
+                self.space.num_bytes_allocated.load(Ordering::Relaxed) + self.large_space.bytes;
+            let mut target_size = self.space.target_footprint.load(Ordering::Relaxed);
+            if full {
+                target_size = self
+                    .space
+                    .min_heap_size
+                    .max((bytes_allocated as f64 * self.growth_multiplier) as usize)
+                    .min(self.space.max_heap_size);
+                self.space
+                    .target_footprint
+                    .store(target_size, Ordering::Relaxed);
+            }
+
+            self.stats
+                .record_cycle(!full, mark_phase, sweep_phase, prev, bytes_allocated);
+            if self.verbose > 0 {
+                let elapsed = time.elapsed();
+                eprintln!(
+                    "[gc] GC({}) Pause {} StickyImmix collection {}->{}({}) {:.4}ms (mark {:.4}ms, sweep {:.4}ms)",
+                    self.total_gcs,
+                    if full { "Full" } else { "Young" },
+                    formatted_size(prev),
+                    formatted_size(bytes_allocated),
+                    formatted_size(target_size),
+                    elapsed.as_micros() as f64 / 1000.0,
+                    mark_phase.as_micros() as f64 / 1000.0,
+                    sweep_phase.as_micros() as f64 / 1000.0
+                );
+            }
+            self.total_gcs += 1;
+            // nursery collection did not free enough memory, heap is exhausted by old objects.
+            if !full && bytes_allocated as f64 >= target_size as f64 * self.full_gc_threshold {
+                full = true;
+                continue;
+            }
+            break;
+        }
+    }
+
+    fn collect_with(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        full: bool,
+        keep: &mut [&mut dyn Trace],
+    ) {
+        match SafepointScope::new(mutator.clone()) {
+            Some(safepoint) => unsafe {
+                mutator.save_conservative_roots();
+                let time = std::time::Instant::now();
+                self.global_heap_lock.lock();
+                self.large_space_lock.lock();
+                self.collect_internal(full, keep);
+                self.stats
+                    .record_pause(time.elapsed(), safepoint.time_to_safepoint());
+                drop(safepoint);
+                self.global_heap_lock.unlock();
+                self.large_space_lock.unlock();
+            },
+            None => (),
+        }
+    }
+}
+
+impl<Decoder: StackValueDecoder> GcBase for StickyImmix<Decoder> {
+    type TLAB = ImmixAllocator;
+    const SUPPORTS_TLAB: bool = false;
+    type ReadBarrier = NoReadBarrier;
+    const LARGE_ALLOCATION_SIZE: usize = IMMIX_BLOCK_SIZE / 2;
+
+    fn inline_allocation_helpers(&self) -> Self::InlineAllocationHelpers {
+        NoHelp
+    }
+
+    fn add_constraint<T: MarkingConstraint + 'static>(&mut self, constraint: T) {
+        self.global_lock();
+        self.constraints.push(Box::new(constraint));
+        self.global_unlock();
+    }
+    fn stats(&self) -> HeapStats {
+        self.global_heap_lock.lock();
+        let stats = self.stats.snapshot(
+            self.space.num_bytes_allocated.load(Ordering::Relaxed) + self.large_space.bytes,
+            self.space.target_footprint.load(Ordering::Relaxed),
+            self.large_space.bytes,
+        );
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        stats
+    }
+    fn inspect(&self, mut f: impl FnMut(Gc<dyn Collectable, Self>) -> bool) -> bool {
+        unsafe {
+            self.large_space_lock.lock();
+            self.large_space.allocations.iter().for_each(|alloc| {
+                f(Gc::from_raw((**alloc).cell()));
+            });
+            self.large_space_lock.unlock();
+            let start = self.space.map.start();
+            let end = self.space.map.end();
+            self.space.mark_bitmap.visit_marked_range(start, end, |ptr| {
+                f(Gc::from_raw(ptr));
+            });
+        }
+        true
+    }
+    fn allocate_raw(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        size: usize,
+        type_id: std::any::TypeId,
+        vtable: usize,
+    ) -> *mut HeapObjectHeader {
+        let size = align_usize(size + size_of::<HeapObjectHeader>(), 8);
+        unsafe {
+            let large = size >= Self::LARGE_ALLOCATION_SIZE;
+            let object = if large {
+                self.large_space_lock.lock();
+                let object = self.large_space.allocate(size);
+                self.large_space_lock.unlock();
+                object
+            } else {
+                mutator.tlab.alloc(size).cast::<HeapObjectHeader>()
+            };
+            if object.is_null() {
+                if mutator.tlab.is_emergency_collection() {
+                    return null_mut();
+                }
+                return self.collect_and_alloc_raw(mutator, size, vtable, type_id);
+            }
+            if !large {
+                (*object).set_size(size);
+            }
+            // memory might contain stale headers of dead objects.
+            (*object).padding = 0;
+            (*object).set_vtable(vtable);
+            (*object).type_id = make_small_type_id(type_id);
+            (*object).force_set_color(GC_WHITE);
+            // type of raw object is unknown so it is always finalized.
+            self.space.add_finalizable(object);
+            object
+        }
+    }
+    fn allocate_weak<T: Collectable + ?Sized>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        value: Gc<T, Self>,
+    ) -> Result<Weak<T, Self>, AllocError<Gc<T, Self>>> {
+        let weak_ref = unsafe { Weak::<T, Self>::try_create(mutator, value)? };
+        self.global_heap_lock.lock();
+        self.weak_refs.push(weak_ref.to_dyn_untraced());
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        Ok(weak_ref)
+    }
+    #[inline]
+    fn alloc_inline<T: Collectable + Sized + 'static>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        value: T,
+        _space: AllocationSpace,
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
+        let size = align_usize(value.allocation_size() + size_of::<HeapObjectHeader>(), 8);
+        unsafe {
+            let memory = mutator.tlab.alloc(size);
+
+            if memory.is_null() {
+                if mutator.tlab.is_emergency_collection() {
+                    // heap is exhausted even after emergency GC.
+                    return Err(AllocError::new(value));
+                }
+                return self.collect_and_alloc(mutator, value);
+            }
+            let object = memory.cast::<HeapObjectHeader>();
+            // holes might contain stale headers of dead objects.
+            (*object).padding = 0;
+            (*object).set_metadata(vtable_of::<T>());
+            (*object).type_id = small_type_id::<T>();
+            (*object).set_size(size);
+            ((*object).data() as *mut T).write(value);
+            let gced = Gc::from_raw(object);
+            self.post_alloc(gced);
+            Ok(gced)
+        }
+    }
+
+    fn allocate_large<T: Collectable + Sized + 'static>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        mut value: T,
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
+        unsafe {
+            let size = value.allocation_size() + size_of::<HeapObjectHeader>();
+            self.large_space_lock.lock();
+            let mut object = self.large_space.allocate(size);
+            if object.is_null() {
+                // dead large objects might free enough memory.
+                self.large_space_lock.unlock();
+                self.collect_alloc_failure(mutator, &mut [&mut value]);
+                self.large_space_lock.lock();
+                object = self.large_space.allocate(size);
+                if object.is_null() {
+                    self.large_space_lock.unlock();
+                    return Err(AllocError::new(value));
+                }
+            }
+            (*object).padding = 0;
+            (*object).set_metadata(vtable_of::<T>());
+            (*object).type_id = small_type_id::<T>();
+            let gc = Gc::from_raw(object);
+            ((*object).data() as *mut T).write(value);
+            self.large_space_lock.unlock();
+            self.post_alloc(gc);
+            Ok(gc)
+        }
+    }
+
+    #[inline(always)]
+    fn post_alloc<T: Collectable + Sized + 'static>(&mut self, value: Gc<T, Self>) {
+        unsafe {
+            (*value.base.as_ptr()).force_set_color(GC_WHITE);
+            if T::needs_finalization() {
+                self.space.add_finalizable(value.base.as_ptr());
+            }
+        }
+    }
+
+    #[inline]
+    fn write_barrier(&mut self, _mutator: &mut MutatorRef<Self>, object: Gc<dyn Collectable, Self>) {
+        let base = object.base.as_ptr();
+        unsafe {
+            // young objects are traced by nursery GC anyway, only old objects are logged.
+            if (*base).get_color() == self.mark_color && !(*base).parent_known_bit() {
+                self.log_object(base);
+            }
+        }
+    }
+
+    fn minor_collection(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
+        self.collect_with(mutator, false, keep);
+    }
+
+    fn collect_alloc_failure(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
+        self.minor_collection(mutator, keep);
+    }
+
+    fn collect(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
+        self.collect_with(mutator, true, keep);
+    }
+
+    fn alloc_tlab_area(&mut self, _mutator: &MutatorRef<Self>, _size: usize) -> *mut u8 {
+        null_mut()
+    }
+    fn safepoint(&self) -> &GlobalSafepoint {
+        &self.safepoint
+    }
+
+    fn attach_current_thread(&mut self, mutator: *mut Mutator<Self>) {
+        self.global_heap_lock.lock();
+        self.safepoint.n_mutators.fetch_add(1, Ordering::Relaxed);
+        self.mutators.push(mutator);
+        unsafe { self.global_heap_lock.unlock() };
+    }
+
+    fn detach_current_thread(&mut self, mutator: *mut Mutator<Self>) {
+        self.global_heap_lock.lock();
+
+        let mut detached = false;
+        self.mutators.retain(|x| {
+            if *x == mutator {
+                detached = true;
+                false
+            } else {
+                true
+            }
+        });
+        self.safepoint.n_mutators.fetch_sub(1, Ordering::Relaxed);
+        assert!(detached, "mutator must be detached");
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+    }
+
+    fn global_lock(&self) {
+        self.global_heap_lock.lock();
+    }
+    fn global_unlock(&self) {
+        unsafe {
+            debug_assert!(self.global_heap_lock.is_locked());
+            self.global_heap_lock.unlock();
+        }
+    }
+
+    fn mutators(&self) -> &[*mut Mutator<Self>] {
+        assert!(self.global_heap_lock.is_locked());
+        &self.mutators
+    }
+}
+
+impl<Decoder: StackValueDecoder> Visitor for StickyImmix<Decoder> {
+    fn mark_object(&mut self, root: &mut NonNull<HeapObjectHeader>) {
+        let object = root.as_ptr();
+        unsafe {
+            let color = (*object).get_color();
+            // objects with mark colour are either marked in this cycle or old ones during nursery GC.
+            if color != self.mark_color && !(*object).set_color(color, self.mark_color) {
+                if self.space.has_address(object.cast()) {
+                    self.space.mark_lines(object);
+                } else {
+                    (*PreciseAllocation::from_cell(object)).test_and_set_marked();
+                }
+                self.mark_stack.push(object);
+            }
+        }
+    }
+
+    fn mark_ephemeron(&mut self, ephemeron: &mut EphemeronEntry) {
+        self.ephemerons.push(ephemeron);
+    }
+}
+
+impl<Decoder: StackValueDecoder> Drop for StickyImmix<Decoder> {
+    fn drop(&mut self) {
+        unsafe {
+            if self.verbose > 0 {
+                eprintln!("Dispose StickyImmix space at {:p}", self);
+            }
+            drop(Box::from_raw(
+                self.space as *const ImmixSpace as *mut ImmixSpace,
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::{Ephemeron, Finalize},
+        letroot,
+    };
+
+    struct Node {
+        value: usize,
+        next: Option<Gc<Node, StickyImmix>>,
+    }
+
+    unsafe impl Trace for Node {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.next.trace(vis);
+        }
+    }
+    unsafe impl Finalize for Node {}
+    impl Collectable for Node {}
+
+    struct Table {
+        entries: Vec<Ephemeron<Node, Node, StickyImmix>>,
+    }
+
+    unsafe impl Trace for Table {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.entries.trace(vis);
+        }
+    }
+    unsafe impl Finalize for Table {}
+    impl Collectable for Table {}
+
+    fn node(
+        mutator: &mut MutatorRef<StickyImmix>,
+        value: usize,
+        next: Option<Gc<Node, StickyImmix>>,
+    ) -> Gc<Node, StickyImmix> {
+        mutator.allocate(Node { value, next }, AllocationSpace::New)
+    }
+
+    /// Add entries whose values reference their own keys. Keys are not reachable from anywhere else.
+    #[inline(never)]
+    fn add_unreachable_entries(
+        mutator: &mut MutatorRef<StickyImmix>,
+        table: &mut Gc<Table, StickyImmix>,
+    ) {
+        for i in 0..100 {
+            let key = node(mutator, i, None);
+            let value = node(mutator, i, Some(key));
+            table.entries.push(Ephemeron::new(key, value));
+        }
+    }
+
+    #[test]
+    fn old_objects_are_not_traced_by_nursery_gc() {
+        let mut mutator =
+            instantiate_sticky_immix::<NoOpStackDecoder>(StickyImmixOptions::default());
+        let stack = mutator.shadow_stack();
+        letroot!(
+            old = stack,
+            mutator.allocate(
+                Node {
+                    value: 0,
+                    next: None
+                },
+                AllocationSpace::New
+            )
+        );
+        mutator.minor_collection(&mut []);
+        let mark_color = mutator.heap_ref().mark_color;
+        assert_eq!(unsafe { old.base.as_ref().get_color() }, mark_color);
+
+        let young = mutator.allocate(
+            Node {
+                value: 42,
+                next: None,
+            },
+            AllocationSpace::New,
+        );
+        old.next = Some(young);
+        mutator.write_barrier(old.to_dyn());
+        assert!(unsafe { old.base.as_ref().parent_known_bit() });
+        mutator.minor_collection(&mut []);
+        assert!(unsafe { !old.base.as_ref().parent_known_bit() });
+        let young = old.next.unwrap();
+        assert_eq!(unsafe { young.base.as_ref().get_color() }, mark_color);
+        assert_eq!(young.value, 42);
+
+        mutator.collect(&mut []);
+        assert_ne!(mutator.heap_ref().mark_color, mark_color);
+        assert_eq!(old.next.unwrap().value, 42);
+    }
+
+    #[test]
+    fn ephemerons_with_dead_keys_are_cleared() {
+        let mut mutator =
+            instantiate_sticky_immix::<NoOpStackDecoder>(StickyImmixOptions::default());
+        let stack = mutator.shadow_stack();
+        letroot!(
+            table = stack,
+            mutator.allocate(Table { entries: vec![] }, AllocationSpace::New)
+        );
+        letroot!(root = stack, node(&mut mutator, 1, None));
+        // key of the first entry is reachable only from value of the second entry
+        let key = node(&mut mutator, 2, None);
+        let value = node(&mut mutator, 20, None);
+        table.entries.push(Ephemeron::new(key, value));
+        let value = node(&mut mutator, 10, Some(key));
+        table.entries.push(Ephemeron::new(*root, value));
+        add_unreachable_entries(&mut mutator, &mut table);
+
+        mutator.minor_collection(&mut []);
+        // few keys might be kept alive by conservative stack scanning.
+        let cleared = |table: &Gc<Table, StickyImmix>| {
+            table.entries[2..]
+                .iter()
+                .filter(|entry| entry.key().is_none() && entry.value().is_none())
+                .count()
+        };
+        assert!(cleared(&table) >= 90);
+        assert_eq!(table.entries[0].value().unwrap().value, 20);
+        assert_eq!(table.entries[1].value().unwrap().next.unwrap().value, 2);
+
+        mutator.collect(&mut []);
+        assert_eq!(table.entries[0].key().unwrap().value, 2);
+        assert_eq!(table.entries[1].key().unwrap().value, 1);
+        assert!(cleared(&table) >= 90);
+    }
+}
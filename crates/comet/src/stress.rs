@@ -0,0 +1,75 @@
+//! GC stress mode.
+//!
+//! In stress mode mutator performs GC cycle every N allocations or on every [Mutator::safepoint](crate::mutator::Mutator::safepoint)
+//! call. Objects that are not rooted (e.g `Gc` stored in Rust local variable without `letroot!`) are freed or moved
+//! almost immediately, so missing roots crash close to the place where they are used instead of random place much later.
+//! Moving policies move as many objects as they can in stress cycles: Immix evacuates objects from every block when
+//! defragmentation is enabled, Shenandoah evacuates every region with live objects that fits into evacuation reserve,
+//! SemiSpace and MiniMark copy all (young) objects in any cycle. StickyImmix, CMS and MarkSweep never move objects and
+//! perform full collection in stress cycles.
+//!
+//! Stress mode is configured by `with_stress` method of policy options and can be overridden by `COMET_GC_STRESS`
+//! environment variable:
+//! - `COMET_GC_STRESS=N` performs GC cycle every N allocations;
+//! - `COMET_GC_STRESS=safepoint` performs GC cycle on every safepoint;
+//! - `COMET_GC_STRESS=0` disables stress mode.
+//!
+//! Stress mode is *very* slow and is meant for testing only.
+
+/// Name of environment variable that configures stress mode.
+pub const GC_STRESS_ENV: &str = "COMET_GC_STRESS";
+
+/// When GC cycles are forced.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GcStress {
+    /// GC cycles are performed only when heap needs them.
+    #[default]
+    Disabled,
+    /// Perform GC cycle every N allocations made by mutator.
+    Allocations(usize),
+    /// Perform GC cycle on every [Mutator::safepoint](crate::mutator::Mutator::safepoint) call.
+    Safepoints,
+}
+
+impl GcStress {
+    /// Parse stress mode from [GC_STRESS_ENV] environment variable. Returns `None` if variable is not set, panics if
+    /// value is invalid.
+    pub fn from_env() -> Option<Self> {
+        let value = std::env::var(GC_STRESS_ENV).ok()?;
+        match Self::parse(&value) {
+            Some(stress) => Some(stress),
+            None => panic!(
+                "Invalid {} value '{}', expected number of allocations or 'safepoint'",
+                GC_STRESS_ENV, value
+            ),
+        }
+    }
+
+    /// Stress mode from environment variable if it is set or `default` otherwise.
+    pub fn from_env_or(default: Self) -> Self {
+        Self::from_env().unwrap_or(default)
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "safepoint" => Some(Self::Safepoints),
+            value => match value.parse::<usize>().ok()? {
+                0 => Some(Self::Disabled),
+                n => Some(Self::Allocations(n)),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_stress_mode() {
+        assert_eq!(GcStress::parse("0"), Some(GcStress::Disabled));
+        assert_eq!(GcStress::parse("100"), Some(GcStress::Allocations(100)));
+        assert_eq!(GcStress::parse("safepoint"), Some(GcStress::Safepoints));
+        assert_eq!(GcStress::parse("sometimes"), None);
+    }
+}
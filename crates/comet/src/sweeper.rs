@@ -0,0 +1,243 @@
+//! # Parallel sweeping
+//!
+//! Space is split into chunks that are swept by pool threads. Chunks are aligned to page size so that each word of
+//! live bitmap is owned by single thread and dead objects can be cleared in live bitmap without atomic operations.
+//!
+//! Dead objects are freed concurrently too. Rosalloc keeps runs and free bitmaps per size bracket, so
+//! [RosAllocSpace::sweep_callback] groups each batch of dead objects by size class and frees every group with
+//! `Rosalloc::bulk_free` while holding only the lock of its class, see [FreeLocks]. Threads that free objects of
+//! different brackets never wait for each other, objects of the same bracket are never freed by two threads at once.
+//! Objects that are too large for thread local brackets share one class.
+use crate::{bitmap::SpaceBitmap, rosalloc_space::RosAllocSpace};
+use parking_lot::{lock_api::RawMutex, RawMutex as Lock};
+use rosalloc::defs::PAGE_SIZE;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of chunks each thread sweeps on average. Threads take chunks dynamically so that threads that got chunks with
+/// a lot of dead objects do not delay the others.
+const CHUNKS_PER_THREAD: usize = 4;
+
+/// Sweep rosalloc space using `pool` threads. Returns number of freed bytes.
+///
+/// # Safety
+///
+/// Must be invoked only in STW pause after marking. Thread local runs of mutators must be revoked before sweeping.
+pub unsafe fn rosalloc_parallel_sweep(
+    pool: &mut scoped_threadpool::Pool,
+    space: *mut RosAllocSpace,
+) -> usize {
+    let num_threads = pool.thread_count() as usize;
+    if num_threads <= 1 {
+        let (freed, _) = (*space).sweep(false, |pointers, _| {
+            (*space).sweep_callback(pointers, false)
+        });
+        return freed;
+    }
+    if (*space).get_live_bitmap() == (*space).get_mark_bitmap() {
+        return 0;
+    }
+    let begin = (*space).begin() as usize;
+    let end = (*space).end() as usize;
+    let live_bitmap = &*(*space).get_live_bitmap();
+    let mark_bitmap = &*(*space).get_mark_bitmap();
+    let space = space as usize;
+    parallel_sweep_walk(pool, live_bitmap, mark_bitmap, begin, end, |pointers| {
+        (*(space as *const RosAllocSpace)).sweep_callback(pointers, false)
+    })
+}
+
+/// Find objects between `begin` and `end` that are set in `live_bitmap` but not in `mark_bitmap` using `pool` threads.
+/// `callback` is invoked concurrently with batches of dead objects, objects of batches that are processed at the same
+/// time never share a word of live bitmap. Returns sum of values returned by `callback`.
+pub fn parallel_sweep_walk(
+    pool: &mut scoped_threadpool::Pool,
+    live_bitmap: &SpaceBitmap<8>,
+    mark_bitmap: &SpaceBitmap<8>,
+    begin: usize,
+    end: usize,
+    callback: impl Fn(&[*mut u8]) -> usize + Sync,
+) -> usize {
+    let num_threads = pool.thread_count() as usize;
+    let chunk_size = crate::utils::align_usize(
+        ((end - begin) / (num_threads * CHUNKS_PER_THREAD)).max(PAGE_SIZE),
+        PAGE_SIZE,
+    );
+    let cursor = AtomicUsize::new(begin);
+    let freed = AtomicUsize::new(0);
+    pool.scoped(|scope| {
+        for _ in 0..num_threads {
+            let cursor = &cursor;
+            let freed = &freed;
+            let callback = &callback;
+            scope.execute(move || unsafe {
+                let mut local_freed = 0;
+                loop {
+                    let chunk_begin = cursor.fetch_add(chunk_size, Ordering::Relaxed);
+                    if chunk_begin >= end {
+                        break;
+                    }
+                    let chunk_end = (chunk_begin + chunk_size).min(end);
+                    SpaceBitmap::<8>::sweep_walk(
+                        live_bitmap,
+                        mark_bitmap,
+                        chunk_begin,
+                        chunk_end,
+                        |count, pointers| {
+                            let pointers =
+                                std::slice::from_raw_parts(pointers.cast::<*mut u8>(), count);
+                            local_freed += callback(pointers);
+                        },
+                    );
+                }
+                freed.fetch_add(local_freed, Ordering::Relaxed);
+            });
+        }
+    });
+    freed.load(Ordering::Relaxed)
+}
+
+/// Per size class locks used to free dead objects from multiple threads at once.
+pub struct FreeLocks {
+    locks: Box<[Lock]>,
+}
+
+impl FreeLocks {
+    /// Create locks for `classes` size classes.
+    pub fn new(classes: usize) -> Self {
+        Self {
+            locks: (0..classes).map(|_| Lock::INIT).collect(),
+        }
+    }
+
+    /// Group `pointers` by class returned by `class_of` and pass each group to `free` while holding lock of its class.
+    /// Returns sum of values returned by `free`.
+    pub fn free_grouped(
+        &self,
+        pointers: &[*mut u8],
+        class_of: impl Fn(*mut u8) -> usize,
+        mut free: impl FnMut(&[*mut u8]) -> usize,
+    ) -> usize {
+        let mut classes = pointers
+            .iter()
+            .map(|&pointer| (class_of(pointer), pointer))
+            .collect::<Vec<_>>();
+        classes.sort_unstable_by_key(|&(class, _)| class);
+        let mut freed = 0;
+        let mut group = Vec::with_capacity(classes.len());
+        for objects in classes.chunk_by(|a, b| a.0 == b.0) {
+            group.clear();
+            group.extend(objects.iter().map(|&(_, pointer)| pointer));
+            let lock = &self.locks[objects[0].0];
+            lock.lock();
+            freed += free(&group);
+            unsafe {
+                lock.unlock();
+            }
+        }
+        freed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn dead_objects_are_swept_by_multiple_threads() {
+        const OBJECT_SIZE: usize = 16;
+        let size = 64 * PAGE_SIZE;
+        let heap = vec![0u8; size + PAGE_SIZE];
+        let begin = crate::utils::align_usize(heap.as_ptr() as usize, PAGE_SIZE);
+        let live_bitmap = SpaceBitmap::<8>::create("live", begin as _, size);
+        let mark_bitmap = SpaceBitmap::<8>::create("mark", begin as _, size);
+        let objects = (begin..begin + size)
+            .step_by(OBJECT_SIZE)
+            .map(|object| object as *mut u8)
+            .collect::<Vec<_>>();
+        for (i, &object) in objects.iter().enumerate() {
+            live_bitmap.set(object);
+            if i % 3 == 0 {
+                mark_bitmap.set(object);
+            }
+        }
+
+        let swept = Mutex::new(vec![]);
+        let mut pool = scoped_threadpool::Pool::new(4);
+        let freed = parallel_sweep_walk(
+            &mut pool,
+            &live_bitmap,
+            &mark_bitmap,
+            begin,
+            begin + size,
+            |pointers| {
+                for &object in pointers {
+                    live_bitmap.clear(object);
+                }
+                swept
+                    .lock()
+                    .unwrap()
+                    .extend(pointers.iter().map(|&p| p as usize));
+                pointers.len() * OBJECT_SIZE
+            },
+        );
+
+        let mut swept = swept.into_inner().unwrap();
+        swept.sort_unstable();
+        let dead = objects
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| i % 3 != 0)
+            .map(|(_, &object)| object as usize)
+            .collect::<Vec<_>>();
+        assert_eq!(swept, dead);
+        assert_eq!(freed, dead.len() * OBJECT_SIZE);
+        for &object in objects.iter() {
+            assert_eq!(live_bitmap.test(object), mark_bitmap.test(object));
+        }
+    }
+
+    #[test]
+    fn objects_of_different_classes_are_freed_concurrently() {
+        const CLASSES: usize = 4;
+        let size = 64 * PAGE_SIZE;
+        let heap = vec![0u8; size + PAGE_SIZE];
+        let begin = crate::utils::align_usize(heap.as_ptr() as usize, PAGE_SIZE);
+        let live_bitmap = SpaceBitmap::<8>::create("live", begin as _, size);
+        let mark_bitmap = SpaceBitmap::<8>::create("mark", begin as _, size);
+        for object in (begin..begin + size).step_by(64) {
+            live_bitmap.set(object as _);
+        }
+        // every page holds objects of a single class like rosalloc runs do.
+        let class_of = |object: *mut u8| (object as usize - begin) / PAGE_SIZE % CLASSES;
+
+        let locks = FreeLocks::new(CLASSES);
+        let in_flight = AtomicUsize::new(0);
+        let max_in_flight = AtomicUsize::new(0);
+        let in_flight_per_class = (0..CLASSES)
+            .map(|_| AtomicUsize::new(0))
+            .collect::<Vec<_>>();
+        let mut pool = scoped_threadpool::Pool::new(4);
+        let freed = parallel_sweep_walk(
+            &mut pool,
+            &live_bitmap,
+            &mark_bitmap,
+            begin,
+            begin + size,
+            |pointers| {
+                locks.free_grouped(pointers, class_of, |group| {
+                    let class = &in_flight_per_class[class_of(group[0])];
+                    assert_eq!(class.fetch_add(1, Ordering::SeqCst), 0);
+                    let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_in_flight.fetch_max(now, Ordering::SeqCst);
+                    std::thread::sleep(std::time::Duration::from_micros(100));
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    class.fetch_sub(1, Ordering::SeqCst);
+                    group.len()
+                })
+            },
+        );
+        assert_eq!(freed, size / 64);
+        assert!(max_in_flight.load(Ordering::SeqCst) > 1);
+    }
+}
@@ -1,10 +1,4 @@
-use std::{
-    cell::UnsafeCell,
-    marker::PhantomData,
-    mem::size_of,
-    ptr::{null_mut, NonNull},
-    sync::Arc,
-};
+use std::{cell::UnsafeCell, mem::size_of, ptr::null_mut, sync::Arc};
 
 use crate::{
     api::{vtable_of, Collectable, Gc, HeapObjectHeader, VTable},
@@ -55,10 +49,7 @@ impl<H: GcBase<TLAB = Self>> TLAB<H> for SimpleTLAB<H> {
             (*header).set_size(size);
             ((*header).data() as *mut T).write(value);
             let h = &mut *self.heap.get();
-            let gc = Gc {
-                base: NonNull::new_unchecked(header),
-                marker: PhantomData,
-            };
+            let gc = Gc::from_raw(header);
             h.post_alloc(gc);
             Ok(gc)
         }
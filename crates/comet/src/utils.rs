@@ -40,11 +40,12 @@ pub struct ParentKnown;
 
 pub struct Pinned;
 
-impl BitFieldTrait<1, 1> for Pinned {
+// Bit 1 is used by GC_GREY colour so pinned bit is stored right after colour bits.
+impl BitFieldTrait<2, 1> for Pinned {
     type Next = ParentKnown;
 }
 
-impl BitFieldTrait<1, 1> for ParentKnown {
+impl BitFieldTrait<4, 1> for ParentKnown {
     type Next = MarkBit;
 }
 
@@ -64,6 +65,27 @@ impl BitFieldTrait<0, 13> for SizeBitField {
     type Next = MarkedBitField;
 }
 
+/// Set while object is copied by parallel marking worker, other workers wait until object is forwarded.
+pub struct BeingForwarded;
+
+impl BitFieldTrait<5, 1> for BeingForwarded {
+    type Next = Self;
+}
+
+/// Set at allocation time for objects whose type needs finalization, see [Finalize::needs_finalization](crate::api::Finalize::needs_finalization).
+pub struct FinalizableBit;
+
+impl BitFieldTrait<6, 1> for FinalizableBit {
+    type Next = Self;
+}
+
+/// Set at allocation time for objects whose type may be finalized on finalizer thread, see [Finalize::finalize_on_thread](crate::api::Finalize::finalize_on_thread).
+pub struct FinalizeOnThreadBit;
+
+impl BitFieldTrait<7, 1> for FinalizeOnThreadBit {
+    type Next = Self;
+}
+
 pub struct MarkBit;
 
 impl BitFieldTrait<14, 1> for MarkBit {
@@ -117,6 +139,7 @@ pub const fn align_usize(value: usize, align: usize) -> usize {
 }
 
 pub mod mmap;
+pub mod registers;
 pub mod retain_mut;
 pub mod stack_bounds;
 #[inline]
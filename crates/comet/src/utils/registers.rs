@@ -0,0 +1,95 @@
+//! Callee-saved registers of mutator threads.
+//!
+//! Conservative stack scanning only sees values that were spilled to the stack. Pointer that lives in a callee-saved
+//! register of the thread that starts GC cycle or of a thread that parks at safepoint would be missed, so mutators
+//! spill these registers to [Registers] whenever they record their stack pointer and GC scans them together with the
+//! stack. Caller-saved registers do not need to be captured: live values in them are spilled to the stack by compiler
+//! before any call.
+//!
+//! General purpose registers are captured on x86_64 and aarch64, on other architectures [Registers] is empty.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+use std::arch::asm;
+
+/// rbx, rbp, r12-r15.
+#[cfg(target_arch = "x86_64")]
+const WORDS: usize = 6;
+/// x19-x29.
+#[cfg(target_arch = "aarch64")]
+const WORDS: usize = 11;
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const WORDS: usize = 0;
+
+/// Callee-saved registers captured by [Registers::capture].
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+pub struct Registers {
+    words: [usize; WORDS],
+}
+
+impl Registers {
+    /// Number of captured registers.
+    pub const WORDS: usize = WORDS;
+
+    /// Spill callee-saved registers of the current thread. Never inlined so that registers hold values of the caller.
+    #[inline(never)]
+    pub fn capture(&mut self) {
+        #[cfg(target_arch = "x86_64")]
+        unsafe {
+            asm!(
+                "mov [rdi], rbx",
+                "mov [rdi + 8], rbp",
+                "mov [rdi + 16], r12",
+                "mov [rdi + 24], r13",
+                "mov [rdi + 32], r14",
+                "mov [rdi + 40], r15",
+                in("rdi") self.words.as_mut_ptr(),
+                options(nostack, preserves_flags)
+            );
+        }
+        #[cfg(target_arch = "aarch64")]
+        unsafe {
+            asm!(
+                "stp x19, x20, [x0]",
+                "stp x21, x22, [x0, #16]",
+                "stp x23, x24, [x0, #32]",
+                "stp x25, x26, [x0, #48]",
+                "stp x27, x28, [x0, #64]",
+                "str x29, [x0, #80]",
+                in("x0") self.words.as_mut_ptr(),
+                options(nostack, preserves_flags)
+            );
+        }
+    }
+
+    /// Captured registers.
+    pub fn words(&self) -> &[usize] {
+        &self.words
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+
+    extern "C" fn capture(registers: &mut Registers) {
+        registers.capture();
+    }
+
+    #[test]
+    fn callee_saved_registers_are_captured() {
+        let value = 0x1234_5678_9abc_def0usize;
+        let mut registers = Registers::default();
+        unsafe {
+            asm!(
+                "mov r12, {value}",
+                "call {capture}",
+                value = in(reg) value,
+                capture = sym capture,
+                in("rdi") &mut registers,
+                out("r12") _,
+                clobber_abi("C"),
+            );
+        }
+        assert!(registers.words().contains(&value));
+    }
+}
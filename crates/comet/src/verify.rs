@@ -0,0 +1,143 @@
+//! Heap verification.
+//!
+//! Verifier traces the heap from its roots and checks every reached object, so a broken `Trace` implementation is
+//! reported at the next GC pause with the bad reference, the object whose `Trace::trace` visited it and the root it
+//! was reached from instead of crashing the process much later. Every reached object is validated by policy
+//! specific check (address belongs to allocated object, object is not dead or evacuated etc.) before it is read,
+//! then its header size is compared with [Collectable::allocation_size](crate::api::Collectable::allocation_size).
+//!
+//! Any word of conservatively scanned stacks and registers may look like a reference, so candidates reported as
+//! [RootSource::ConservativeStack] that fail the policy check are skipped instead of reported, candidates that pass it
+//! are verified and traced like other roots.
+//!
+//! Only Immix is verified: MarkSweep would need a check of rosalloc live slots, which is out of scope for now.
+//! Enabled by [ImmixOptions::with_verify](crate::immix::ImmixOptions::with_verify).
+use std::{collections::HashSet, fmt, mem::size_of, ptr::NonNull};
+
+use crate::{
+    api::{EphemeronEntry, HeapObjectHeader, Visitor},
+    gc_base::GcBase,
+    heap_snapshot::RootSource,
+    large_space::PreciseAllocation,
+    utils::align_usize,
+};
+
+/// Corrupted reference found by heap verifier.
+#[derive(Debug)]
+pub struct VerifyError {
+    /// Description of the problem.
+    pub problem: String,
+    /// Invalid reference.
+    pub object: *mut HeapObjectHeader,
+    /// Object whose `Trace::trace` visited invalid reference and its type name, `None` if reference is a root.
+    pub parent: Option<(*mut HeapObjectHeader, &'static str)>,
+    /// Root `object` was reached from.
+    pub root: RootSource,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {:p}", self.problem, self.object)?;
+        match self.parent {
+            Some((parent, type_name)) => write!(f, ", visited by {:p} ({})", parent, type_name)?,
+            None => write!(f, ", referenced by root")?,
+        }
+        write!(f, ", reached from {:?}", self.root)
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Collects strong references of traced object. Weak references and ephemeron keys do not keep objects alive and
+/// might point to objects that are about to be cleared.
+struct ReferenceCollector<'a> {
+    references: &'a mut Vec<*mut HeapObjectHeader>,
+}
+
+impl Visitor for ReferenceCollector<'_> {
+    fn mark_object(&mut self, root: &mut NonNull<HeapObjectHeader>) {
+        self.references.push(root.as_ptr());
+    }
+    fn mark_weak(&mut self, _root: &mut NonNull<HeapObjectHeader>) {}
+    fn mark_ephemeron(&mut self, ephemeron: &mut EphemeronEntry) {
+        ephemeron.mark_value(self);
+    }
+}
+
+/// Returns description of the problem if size stored in `object` header does not agree with its
+/// [allocation_size](crate::api::Collectable::allocation_size).
+///
+/// # Safety
+///
+/// `object` must be valid object.
+pub unsafe fn check_object_size(object: *mut HeapObjectHeader) -> Result<(), String> {
+    let size = if (*object).is_precise() {
+        (*PreciseAllocation::from_cell(object)).cell_size()
+    } else {
+        (*object).size()
+    };
+    let expected = (*object).get_dyn().allocation_size() + size_of::<HeapObjectHeader>();
+    if align_usize(size, 8) != align_usize(expected, 8) {
+        return Err(format!(
+            "header size {} of {} does not match allocation size {}",
+            size,
+            (*object).get_dyn().type_name(),
+            expected
+        ));
+    }
+    Ok(())
+}
+
+/// Trace `heap` from roots reported by [GcBase::snapshot_roots] and verify every reached object. `check` is invoked
+/// on every object before it is read and must return description of the problem if reference is not valid, conservative
+/// roots it rejects are skipped. Returns number of verified objects.
+///
+/// # Safety
+///
+/// Must be invoked only when all mutators are suspended.
+pub unsafe fn verify_heap<H: GcBase>(
+    heap: &mut H,
+    mut check: impl FnMut(*mut HeapObjectHeader) -> Result<(), String>,
+) -> Result<usize, VerifyError> {
+    let mut roots = vec![];
+    heap.snapshot_roots(&mut |source, object| roots.push((source.clone(), object)));
+    let mut visited = HashSet::new();
+    let mut stack = vec![];
+    let mut references = vec![];
+    for (root, object) in roots.iter() {
+        if visited.contains(object) {
+            continue;
+        }
+        if matches!(root, RootSource::ConservativeStack(_)) && check(*object).is_err() {
+            // not a reference, just a word that looks like one.
+            continue;
+        }
+        let mut verify = |object, parent| {
+            if let Err(problem) = check(object).and_then(|_| check_object_size(object)) {
+                return Err(VerifyError {
+                    problem,
+                    object,
+                    parent,
+                    root: root.clone(),
+                });
+            }
+            Ok(())
+        };
+        visited.insert(*object);
+        verify(*object, None)?;
+        stack.push(*object);
+        while let Some(object) = stack.pop() {
+            references.clear();
+            (*object).get_dyn().trace(&mut ReferenceCollector {
+                references: &mut references,
+            });
+            for &reference in references.iter() {
+                if visited.insert(reference) {
+                    verify(reference, Some((object, (*object).get_dyn().type_name())))?;
+                    stack.push(reference);
+                }
+            }
+        }
+    }
+    Ok(visited.len())
+}